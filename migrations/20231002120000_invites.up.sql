@@ -0,0 +1,21 @@
+-- Add up migration script here
+
+CREATE TABLE
+    "invites" (
+        id UUID NOT NULL PRIMARY KEY DEFAULT (uuid_generate_v4()),
+        code VARCHAR(64) NOT NULL UNIQUE,
+        role user_role NOT NULL DEFAULT 'user',
+        created_by UUID REFERENCES users (id) ON DELETE SET NULL,
+        used_by UUID REFERENCES users (id) ON DELETE SET NULL,
+        expires_at TIMESTAMP
+        WITH
+            TIME ZONE,
+            used_at TIMESTAMP
+        WITH
+            TIME ZONE,
+            created_at TIMESTAMP
+        WITH
+            TIME ZONE DEFAULT NOW()
+    );
+
+CREATE INDEX invites_code_idx ON invites (code);
@@ -0,0 +1,24 @@
+-- Add up migration script here
+
+CREATE TABLE
+    "impersonation_sessions" (
+        id UUID NOT NULL PRIMARY KEY DEFAULT (uuid_generate_v4()),
+        impersonator_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        target_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        reason VARCHAR(255),
+        ip_address VARCHAR(45),
+        user_agent VARCHAR(512),
+        started_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW(),
+            expires_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL,
+            ended_at TIMESTAMP
+        WITH
+            TIME ZONE
+    );
+
+CREATE INDEX impersonation_sessions_impersonator_idx ON impersonation_sessions (impersonator_id);
+
+CREATE INDEX impersonation_sessions_target_idx ON impersonation_sessions (target_id);
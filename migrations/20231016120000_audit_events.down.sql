@@ -0,0 +1,7 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS "audit_events";
+
+DROP TYPE IF EXISTS audit_outcome;
+
+DROP TYPE IF EXISTS audit_event_type;
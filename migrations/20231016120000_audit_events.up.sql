@@ -0,0 +1,36 @@
+-- Add up migration script here
+
+CREATE TYPE audit_event_type AS ENUM (
+    'login',
+    'login_failed',
+    'logout',
+    'register',
+    'password_changed',
+    'role_changed',
+    'invite_created',
+    'impersonation_started',
+    'impersonation_stopped'
+);
+
+CREATE TYPE audit_outcome AS ENUM ('success', 'failure');
+
+CREATE TABLE
+    "audit_events" (
+        id BIGSERIAL PRIMARY KEY,
+        event_type audit_event_type NOT NULL,
+        outcome audit_outcome NOT NULL,
+        actor_id UUID,
+        target_id UUID,
+        ip_address VARCHAR(45),
+        user_agent VARCHAR(512),
+        metadata JSONB NOT NULL DEFAULT '{}',
+        created_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW()
+    );
+
+CREATE INDEX audit_events_actor_idx ON audit_events (actor_id);
+
+CREATE INDEX audit_events_target_idx ON audit_events (target_id);
+
+CREATE INDEX audit_events_type_created_at_idx ON audit_events (event_type, created_at);
@@ -0,0 +1,17 @@
+-- Add up migration script here
+
+-- Existing rows are chained at startup, in id order, before the server accepts requests.
+ALTER TABLE audit_events ADD COLUMN prev_hash VARCHAR(64);
+
+ALTER TABLE audit_events ADD COLUMN hash VARCHAR(64);
+
+CREATE TABLE
+    "audit_checkpoints" (
+        id BIGSERIAL PRIMARY KEY,
+        event_id BIGINT NOT NULL,
+        event_hash VARCHAR(64) NOT NULL,
+        signature VARCHAR(64) NOT NULL,
+        created_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW()
+    );
@@ -0,0 +1,10 @@
+-- Add down migration script here
+
+-- Postgres cannot drop enum values, so the audit_event_type values added by
+-- the up migration are left in place.
+
+DROP TABLE IF EXISTS "oauth_refresh_tokens";
+
+DROP TABLE IF EXISTS "oauth_authorization_codes";
+
+DROP TABLE IF EXISTS "oauth_clients";
@@ -0,0 +1,58 @@
+-- Add up migration script here
+
+ALTER TYPE audit_event_type ADD VALUE IF NOT EXISTS 'oauth_client_created';
+
+ALTER TYPE audit_event_type ADD VALUE IF NOT EXISTS 'oauth_consent';
+
+CREATE TABLE
+    "oauth_clients" (
+        id UUID NOT NULL PRIMARY KEY DEFAULT (uuid_generate_v4()),
+        client_id VARCHAR(64) NOT NULL UNIQUE,
+        client_secret VARCHAR(100),
+        name VARCHAR(100) NOT NULL,
+        redirect_uris TEXT [] NOT NULL DEFAULT '{}',
+        created_by UUID REFERENCES users (id) ON DELETE SET NULL,
+        created_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW()
+    );
+
+CREATE TABLE
+    "oauth_authorization_codes" (
+        code_hash VARCHAR(64) NOT NULL PRIMARY KEY,
+        client_id VARCHAR(64) NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+        user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        redirect_uri TEXT NOT NULL,
+        scope TEXT NOT NULL DEFAULT '',
+        code_challenge VARCHAR(128) NOT NULL,
+        code_challenge_method VARCHAR(10) NOT NULL,
+        expires_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL,
+            used_at TIMESTAMP
+        WITH
+            TIME ZONE,
+            created_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW()
+    );
+
+CREATE TABLE
+    "oauth_refresh_tokens" (
+        id UUID NOT NULL PRIMARY KEY DEFAULT (uuid_generate_v4()),
+        token_hash VARCHAR(64) NOT NULL UNIQUE,
+        client_id VARCHAR(64) NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+        user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        scope TEXT NOT NULL DEFAULT '',
+        expires_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL,
+            revoked_at TIMESTAMP
+        WITH
+            TIME ZONE,
+            created_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW()
+    );
+
+CREATE INDEX oauth_refresh_tokens_user_idx ON oauth_refresh_tokens (user_id);
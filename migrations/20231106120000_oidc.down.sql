@@ -0,0 +1,7 @@
+-- Add down migration script here
+
+ALTER TABLE oauth_refresh_tokens DROP COLUMN IF EXISTS auth_time;
+
+ALTER TABLE oauth_authorization_codes DROP COLUMN IF EXISTS auth_time;
+
+ALTER TABLE oauth_authorization_codes DROP COLUMN IF EXISTS nonce;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+
+ALTER TABLE oauth_authorization_codes ADD COLUMN nonce TEXT;
+
+ALTER TABLE oauth_authorization_codes ADD COLUMN auth_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW();
+
+ALTER TABLE oauth_refresh_tokens ADD COLUMN auth_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW();
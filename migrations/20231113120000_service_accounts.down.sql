@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+-- Postgres cannot drop enum values, so service_token_issued is left in place.
+
+ALTER TABLE "oauth_clients" DROP COLUMN IF EXISTS role;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+
+ALTER TYPE audit_event_type ADD VALUE IF NOT EXISTS 'service_token_issued';
+
+-- Clients with a role are service accounts and may use the client_credentials grant.
+ALTER TABLE "oauth_clients" ADD COLUMN role user_role;
@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+-- Postgres cannot drop enum values, so token_revoked is left in place.
+
+DROP TABLE IF EXISTS "revoked_tokens";
@@ -0,0 +1,17 @@
+-- Add up migration script here
+
+ALTER TYPE audit_event_type ADD VALUE IF NOT EXISTS 'token_revoked';
+
+-- Revoked JWTs are remembered by their jti until they would have expired anyway.
+CREATE TABLE
+    "revoked_tokens" (
+        jti UUID NOT NULL PRIMARY KEY,
+        expires_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL,
+            revoked_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW()
+    );
+
+CREATE INDEX revoked_tokens_expires_at_idx ON revoked_tokens (expires_at);
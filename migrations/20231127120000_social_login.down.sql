@@ -0,0 +1,7 @@
+-- Add down migration script here
+
+-- Postgres cannot drop enum values, so identity_linked is left in place.
+
+DROP TABLE IF EXISTS "social_login_requests";
+
+DROP TABLE IF EXISTS "user_identities";
@@ -0,0 +1,31 @@
+-- Add up migration script here
+
+ALTER TYPE audit_event_type ADD VALUE IF NOT EXISTS 'identity_linked';
+
+CREATE TABLE
+    "user_identities" (
+        id UUID NOT NULL PRIMARY KEY DEFAULT (uuid_generate_v4()),
+        user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        provider VARCHAR(50) NOT NULL,
+        subject VARCHAR(255) NOT NULL,
+        email VARCHAR(255),
+        created_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW(),
+            UNIQUE (provider, subject)
+    );
+
+CREATE INDEX user_identities_user_idx ON user_identities (user_id);
+
+CREATE TABLE
+    "social_login_requests" (
+        state_hash VARCHAR(64) NOT NULL PRIMARY KEY,
+        provider VARCHAR(50) NOT NULL,
+        code_verifier VARCHAR(128) NOT NULL,
+        expires_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL,
+            created_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW()
+    );
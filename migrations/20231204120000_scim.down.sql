@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+-- Postgres cannot drop enum values, so user_updated and user_deleted are left in place.
+
+ALTER TABLE "users" DROP COLUMN IF EXISTS active;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+
+ALTER TYPE audit_event_type ADD VALUE IF NOT EXISTS 'user_updated';
+
+ALTER TYPE audit_event_type ADD VALUE IF NOT EXISTS 'user_deleted';
+
+ALTER TABLE "users" ADD COLUMN active BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,12 @@
+-- Add up migration script here
+
+CREATE TABLE
+    "saml_requests" (
+        request_id VARCHAR(64) NOT NULL PRIMARY KEY,
+        expires_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL,
+            created_at TIMESTAMP
+        WITH
+            TIME ZONE NOT NULL DEFAULT NOW()
+    );
@@ -0,0 +1,3 @@
+-- Add down migration script here
+
+-- Postgres cannot drop enum values, so reauthenticated is left in place.
@@ -0,0 +1,5 @@
+-- Add up migration script here
+
+-- Access tokens issued before this time are rejected, so every session of a
+-- user can be ended at once.
+ALTER TABLE users ADD COLUMN sessions_revoked_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,480 @@
+use std::{fmt, time::Duration};
+
+use async_trait::async_trait;
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use super::{AuthBackend, Authenticated, BackendError};
+use crate::{
+  config::LdapConfig,
+  db::{DBClient, SocialExt, UserExt},
+  models::UserRole,
+  utils::{oauth, password},
+};
+
+/// Identities of directory users are stored with this provider name.
+const PROVIDER: &str = "ldap";
+
+/// LDAP result code for a failed bind (RFC 4511 appendix A.1).
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Checks passwords against the directory. Users are provisioned on first
+/// login and, when groups are mapped, their role follows their directory
+/// groups.
+#[derive(Debug)]
+pub struct LdapBackend {
+  config: LdapConfig,
+  directory: Box<dyn Directory>,
+}
+
+/// Where users are looked up and their passwords checked.
+#[async_trait]
+pub trait Directory: fmt::Debug + Send + Sync {
+  /// Returns `Ok(None)` unless exactly one entry matches the email and the
+  /// password is theirs.
+  async fn bind(&self, email: &str, password: &str) -> Result<Option<DirectoryUser>, BackendError>;
+}
+
+/// The directory entry that matched the login email.
+#[derive(Debug, Clone)]
+pub struct DirectoryUser {
+  pub dn: String,
+  pub email: String,
+  pub name: String,
+  pub groups: Vec<String>,
+}
+
+/// An LDAP server: searches for the user, then binds as them.
+#[derive(Debug)]
+pub struct LdapDirectory {
+  config: LdapConfig,
+}
+
+impl LdapBackend {
+  pub fn new(config: LdapConfig) -> Self {
+    let directory = Box::new(LdapDirectory {
+      config: config.clone(),
+    });
+    LdapBackend::with_directory(config, directory)
+  }
+
+  pub fn with_directory(config: LdapConfig, directory: Box<dyn Directory>) -> Self {
+    LdapBackend { config, directory }
+  }
+
+  /// The mapped role, or `None` when roles are not managed by the directory.
+  fn role_for(&self, groups: &[String]) -> Option<UserRole> {
+    if self.config.admin_groups.is_empty() && self.config.moderator_groups.is_empty() {
+      return None;
+    }
+
+    let member_of = |mapped: &[String]| {
+      groups
+        .iter()
+        .any(|group| mapped.iter().any(|m| m.eq_ignore_ascii_case(group)))
+    };
+
+    Some(if member_of(&self.config.admin_groups) {
+      UserRole::Admin
+    } else if member_of(&self.config.moderator_groups) {
+      UserRole::Moderator
+    } else {
+      UserRole::User
+    })
+  }
+}
+
+#[async_trait]
+impl Directory for LdapDirectory {
+  async fn bind(&self, email: &str, password: &str) -> Result<Option<DirectoryUser>, BackendError> {
+    let settings = LdapConnSettings::new()
+      .set_conn_timeout(Duration::from_secs(5))
+      .set_starttls(self.config.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+    ldap3::drive!(conn);
+
+    if let (Some(bind_dn), Some(bind_password)) = (&self.config.bind_dn, &self.config.bind_password)
+    {
+      ldap.simple_bind(bind_dn, bind_password).await?.success()?;
+    }
+
+    let filter = self
+      .config
+      .user_filter
+      .replace("{email}", &ldap_escape(email));
+    let (mut entries, _) = ldap
+      .search(
+        &self.config.base_dn,
+        Scope::Subtree,
+        &filter,
+        vec!["mail", "cn", "displayName", &self.config.group_attribute],
+      )
+      .await?
+      .success()?;
+
+    // Ambiguous matches are rejected rather than guessing which entry is meant.
+    if entries.len() != 1 {
+      ldap.unbind().await?;
+      return Ok(None);
+    }
+    let entry = SearchEntry::construct(entries.remove(0));
+
+    let bound = ldap.simple_bind(&entry.dn, password).await?;
+    ldap.unbind().await?;
+    if bound.rc == INVALID_CREDENTIALS {
+      return Ok(None);
+    }
+    bound.success()?;
+
+    let attribute = |name: &str| entry.attrs.get(name).and_then(|values| values.first());
+
+    Ok(Some(DirectoryUser {
+      email: attribute("mail").cloned().unwrap_or(email.to_string()),
+      name: attribute("displayName")
+        .or(attribute("cn"))
+        .cloned()
+        .unwrap_or(email.to_string()),
+      groups: entry
+        .attrs
+        .get(&self.config.group_attribute)
+        .cloned()
+        .unwrap_or_default(),
+      dn: entry.dn,
+    }))
+  }
+}
+
+#[async_trait]
+impl AuthBackend for LdapBackend {
+  fn name(&self) -> &'static str {
+    PROVIDER
+  }
+
+  async fn authenticate(
+    &self,
+    db_client: &DBClient,
+    email: &str,
+    password: &str,
+  ) -> Result<Option<Authenticated>, BackendError> {
+    // A simple bind with an empty password is an unauthenticated bind, which
+    // many servers accept.
+    if password.is_empty() {
+      return Ok(None);
+    }
+
+    let directory_user = match self.directory.bind(email, password).await? {
+      Some(directory_user) => directory_user,
+      None => return Ok(None),
+    };
+
+    let mut provisioned = false;
+    let user = match db_client
+      .get_user_by_identity(PROVIDER, &directory_user.dn)
+      .await?
+    {
+      Some(user) => user,
+      None => match db_client
+        .get_user(None, None, Some(&directory_user.email))
+        .await?
+      {
+        // Whoever controls the directory entry does not necessarily own the
+        // local account with the same email; linking needs its password too.
+        Some(user) if password::compare(password, &user.password).unwrap_or(false) => {
+          db_client
+            .save_user_identity(
+              user.id,
+              PROVIDER,
+              &directory_user.dn,
+              Some(&directory_user.email),
+            )
+            .await?;
+          user
+        }
+        Some(user) => {
+          tracing::warn!(
+            user_id = %user.id,
+            dn = directory_user.dn,
+            "Not linking directory user to an existing account with another password"
+          );
+          return Ok(None);
+        }
+        None => {
+          // The directory owns the password; store a random, unusable one.
+          let hashed_password =
+            password::hash(oauth::generate_token()).map_err(|e| e.to_string())?;
+          provisioned = true;
+          db_client
+            .save_social_user(
+              &directory_user.name,
+              &directory_user.email,
+              &hashed_password,
+              PROVIDER,
+              &directory_user.dn,
+            )
+            .await?
+        }
+      },
+    };
+
+    let role = match self.role_for(&directory_user.groups) {
+      Some(role) if role != user.role => role,
+      _ => {
+        return Ok(Some(Authenticated {
+          user,
+          provisioned,
+          previous_role: None,
+        }))
+      }
+    };
+
+    let previous_role = (!provisioned).then_some(user.role);
+    let user = db_client.update_user_role(user.id, role).await?;
+
+    Ok(Some(Authenticated {
+      user,
+      provisioned,
+      previous_role,
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use async_trait::async_trait;
+
+  use super::{Directory, DirectoryUser, LdapBackend, PROVIDER};
+  use crate::{
+    backends::{AuthBackend, BackendError},
+    config::LdapConfig,
+    db::{DBClient, SocialExt, UserExt},
+    models::UserRole,
+    test_utils,
+    utils::password,
+  };
+
+  const ADMINS: &str = "cn=admins,ou=groups,dc=example,dc=com";
+  const MODERATORS: &str = "cn=moderators,ou=groups,dc=example,dc=com";
+
+  /// A directory with a single entry.
+  #[derive(Debug)]
+  struct FakeDirectory {
+    user: DirectoryUser,
+    password: &'static str,
+  }
+
+  #[async_trait]
+  impl Directory for FakeDirectory {
+    async fn bind(
+      &self,
+      email: &str,
+      password: &str,
+    ) -> Result<Option<DirectoryUser>, BackendError> {
+      match email == self.user.email && password == self.password {
+        true => Ok(Some(self.user.clone())),
+        false => Ok(None),
+      }
+    }
+  }
+
+  fn config(admin_groups: &[&str], moderator_groups: &[&str]) -> LdapConfig {
+    LdapConfig {
+      url: "ldap://127.0.0.1:1".to_string(),
+      starttls: false,
+      bind_dn: None,
+      bind_password: None,
+      base_dn: "dc=example,dc=com".to_string(),
+      user_filter: "(mail={email})".to_string(),
+      group_attribute: "memberOf".to_string(),
+      admin_groups: admin_groups.iter().map(|group| group.to_string()).collect(),
+      moderator_groups: moderator_groups
+        .iter()
+        .map(|group| group.to_string())
+        .collect(),
+    }
+  }
+
+  fn directory_user(email: &str, groups: &[&str]) -> DirectoryUser {
+    DirectoryUser {
+      dn: format!("uid={},ou=people,dc=example,dc=com", email),
+      email: email.to_string(),
+      name: "Directory User".to_string(),
+      groups: groups.iter().map(|group| group.to_string()).collect(),
+    }
+  }
+
+  fn backend(config: LdapConfig, user: DirectoryUser, password: &'static str) -> LdapBackend {
+    LdapBackend::with_directory(config, Box::new(FakeDirectory { user, password }))
+  }
+
+  async fn db_client() -> DBClient {
+    test_utils::app_state(test_utils::config())
+      .await
+      .0
+      .db_client
+  }
+
+  #[test]
+  fn groups_map_to_roles() {
+    let unmapped = LdapBackend::new(config(&[], &[]));
+    assert_eq!(unmapped.role_for(&[ADMINS.to_string()]), None);
+
+    let mapped = LdapBackend::new(config(&[ADMINS], &[MODERATORS]));
+    let role_for = |groups: &[&str]| {
+      let groups: Vec<String> = groups.iter().map(|group| group.to_string()).collect();
+      mapped.role_for(&groups)
+    };
+    assert_eq!(role_for(&[MODERATORS, ADMINS]), Some(UserRole::Admin));
+    assert_eq!(
+      role_for(&["CN=Moderators,OU=Groups,DC=example,DC=com"]),
+      Some(UserRole::Moderator)
+    );
+    assert_eq!(
+      role_for(&["cn=staff,ou=groups,dc=example,dc=com"]),
+      Some(UserRole::User)
+    );
+    assert_eq!(role_for(&[]), Some(UserRole::User));
+  }
+
+  #[actix_web::test]
+  async fn unreachable_directory_fails_the_login() {
+    let db_client = db_client().await;
+    let backend = LdapBackend::new(config(&[], &[]));
+
+    let result = backend
+      .authenticate(&db_client, &test_utils::unique_email(), "password")
+      .await;
+    assert!(result.is_err());
+  }
+
+  #[actix_web::test]
+  async fn rejected_bind_is_not_accepted() {
+    let db_client = db_client().await;
+    let email = test_utils::unique_email();
+    let backend = backend(config(&[], &[]), directory_user(&email, &[]), "secret");
+
+    let result = backend.authenticate(&db_client, &email, "wrong").await;
+    assert!(result.unwrap().is_none());
+    let result = backend.authenticate(&db_client, &email, "").await;
+    assert!(result.unwrap().is_none());
+    assert!(db_client
+      .get_user(None, None, Some(&email))
+      .await
+      .unwrap()
+      .is_none());
+  }
+
+  #[actix_web::test]
+  async fn provisions_user_on_first_login() {
+    let db_client = db_client().await;
+    let email = test_utils::unique_email();
+    let directory_user = directory_user(&email, &[ADMINS]);
+    let dn = directory_user.dn.clone();
+    let backend = backend(config(&[ADMINS], &[]), directory_user, "secret");
+
+    let authenticated = backend
+      .authenticate(&db_client, &email, "secret")
+      .await
+      .unwrap()
+      .expect("Directory user was not accepted");
+    assert!(authenticated.provisioned);
+    assert_eq!(authenticated.previous_role, None);
+    assert_eq!(authenticated.user.role, UserRole::Admin);
+    assert_eq!(authenticated.user.name, "Directory User");
+    let linked = db_client
+      .get_user_by_identity(PROVIDER, &dn)
+      .await
+      .unwrap()
+      .expect("Identity was not saved");
+    assert_eq!(linked.id, authenticated.user.id);
+
+    let again = backend
+      .authenticate(&db_client, &email, "secret")
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(!again.provisioned);
+    assert_eq!(again.user.id, authenticated.user.id);
+
+    db_client.delete_user(linked.id).await.unwrap();
+  }
+
+  #[actix_web::test]
+  async fn syncs_role_from_groups_only_when_mapped() {
+    let db_client = db_client().await;
+    let email = test_utils::unique_email();
+    let directory_user = directory_user(&email, &[]);
+
+    let user = backend(config(&[], &[]), directory_user.clone(), "secret")
+      .authenticate(&db_client, &email, "secret")
+      .await
+      .unwrap()
+      .unwrap()
+      .user;
+    db_client
+      .update_user_role(user.id, UserRole::Admin)
+      .await
+      .unwrap();
+
+    // Without mapped groups the role is managed here and left alone.
+    let authenticated = backend(config(&[], &[]), directory_user.clone(), "secret")
+      .authenticate(&db_client, &email, "secret")
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(authenticated.user.role, UserRole::Admin);
+    assert_eq!(authenticated.previous_role, None);
+
+    let authenticated = backend(config(&[ADMINS], &[MODERATORS]), directory_user, "secret")
+      .authenticate(&db_client, &email, "secret")
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(authenticated.user.role, UserRole::User);
+    assert_eq!(authenticated.previous_role, Some(UserRole::Admin));
+
+    db_client.delete_user(user.id).await.unwrap();
+  }
+
+  #[actix_web::test]
+  async fn links_existing_account_only_with_its_password() {
+    let db_client = db_client().await;
+    let email = test_utils::unique_email();
+    let directory_user = directory_user(&email, &[]);
+    let dn = directory_user.dn.clone();
+
+    let hashed_password = password::hash("local-password").unwrap();
+    let local = db_client
+      .save_user("Local User", &email, &hashed_password)
+      .await
+      .unwrap();
+
+    let result = backend(
+      config(&[], &[]),
+      directory_user.clone(),
+      "directory-password",
+    )
+    .authenticate(&db_client, &email, "directory-password")
+    .await;
+    assert!(result.unwrap().is_none());
+    assert!(db_client
+      .get_user_by_identity(PROVIDER, &dn)
+      .await
+      .unwrap()
+      .is_none());
+
+    let authenticated = backend(config(&[], &[]), directory_user, "local-password")
+      .authenticate(&db_client, &email, "local-password")
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(!authenticated.provisioned);
+    assert_eq!(authenticated.user.id, local.id);
+    let linked = db_client
+      .get_user_by_identity(PROVIDER, &dn)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(linked.id, local.id);
+
+    db_client.delete_user(local.id).await.unwrap();
+  }
+}
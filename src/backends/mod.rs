@@ -0,0 +1,54 @@
+use std::fmt;
+
+use async_trait::async_trait;
+
+use crate::{
+  config::{AuthBackendKind, Config},
+  db::DBClient,
+  models::{User, UserRole},
+};
+
+pub mod ldap;
+pub mod password;
+
+pub type BackendError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A user whose credentials a backend accepted.
+#[derive(Debug)]
+pub struct Authenticated {
+  pub user: User,
+  /// The user was created during this login (just-in-time provisioning).
+  pub provisioned: bool,
+  /// Set when the backend changed the user's role during this login.
+  pub previous_role: Option<UserRole>,
+}
+
+/// A source of truth for email and password credentials. `login` asks each
+/// configured backend in turn until one accepts.
+#[async_trait]
+pub trait AuthBackend: fmt::Debug + Send + Sync {
+  fn name(&self) -> &'static str;
+
+  /// Returns `Ok(None)` when the credentials are not valid for this backend.
+  async fn authenticate(
+    &self,
+    db_client: &DBClient,
+    email: &str,
+    password: &str,
+  ) -> Result<Option<Authenticated>, BackendError>;
+}
+
+pub fn from_config(config: &Config) -> Vec<Box<dyn AuthBackend>> {
+  config
+    .auth_backends
+    .iter()
+    .map(|kind| -> Box<dyn AuthBackend> {
+      match kind {
+        AuthBackendKind::Password => Box::new(password::PasswordBackend),
+        AuthBackendKind::Ldap => Box::new(ldap::LdapBackend::new(
+          config.ldap.clone().expect("LDAP is not configured"),
+        )),
+      }
+    })
+    .collect()
+}
@@ -0,0 +1,40 @@
+use async_trait::async_trait;
+
+use super::{AuthBackend, Authenticated, BackendError};
+use crate::{
+  db::{DBClient, UserExt},
+  utils::password,
+};
+
+/// Argon2 password hashes stored in the `users` table.
+#[derive(Debug)]
+pub struct PasswordBackend;
+
+#[async_trait]
+impl AuthBackend for PasswordBackend {
+  fn name(&self) -> &'static str {
+    "password"
+  }
+
+  async fn authenticate(
+    &self,
+    db_client: &DBClient,
+    email: &str,
+    password: &str,
+  ) -> Result<Option<Authenticated>, BackendError> {
+    let user = match db_client.get_user(None, None, Some(email)).await? {
+      Some(user) => user,
+      None => return Ok(None),
+    };
+
+    if !password::compare(password, &user.password).unwrap_or(false) {
+      return Ok(None);
+    }
+
+    Ok(Some(Authenticated {
+      user,
+      provisioned: false,
+      previous_role: None,
+    }))
+  }
+}
@@ -0,0 +1,292 @@
+use std::io::{BufRead, IsTerminal, Write};
+
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use sqlx::{migrate::Migrate, Pool, Postgres};
+use uuid::Uuid;
+
+use crate::{
+  config::Config,
+  db::{self, DBClient, UserExt},
+  models::{AuditEventType, User, UserRole},
+  utils::{
+    audit::{self, NewAuditEvent},
+    password,
+  },
+};
+
+#[derive(Parser)]
+#[command(version, about = "Authentication API server and admin tool")]
+pub struct Cli {
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+/// Users are given by id or email address. Passwords are read from stdin, so
+/// they can be piped in and never show up in the shell history.
+#[derive(Subcommand)]
+pub enum Command {
+  /// Run the API server (the default)
+  Serve,
+  /// Apply or revert database migrations
+  #[command(subcommand)]
+  Migrate(MigrateCommand),
+  /// Create an admin account
+  CreateAdmin {
+    #[arg(long)]
+    name: String,
+    #[arg(long)]
+    email: String,
+  },
+  /// Set a new password for a user
+  ResetPassword { user: String },
+  /// Change the role of a user
+  SetRole {
+    user: String,
+    #[arg(value_parser = parse_role)]
+    role: UserRole,
+  },
+  /// End every session of a user, including refresh tokens
+  RevokeSessions { user: String },
+  /// List users
+  ListUsers {
+    #[arg(long, default_value_t = 1)]
+    page: u32,
+    #[arg(long, default_value_t = 20)]
+    limit: usize,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum MigrateCommand {
+  /// Apply every pending migration
+  Run,
+  /// Revert the most recently applied migration
+  Revert,
+}
+
+/// Runs an admin command against the configured database.
+pub async fn run(command: Command, config: &Config) -> Result<(), String> {
+  let pool = DBClient::connect(&config.database_url, 1, 1)
+    .await
+    .map_err(|e| format!("Could not connect to the database: {}", e))?;
+
+  if let Command::Migrate(command) = command {
+    return migrate(command, &pool).await;
+  }
+
+  let db_client = DBClient::new(pool);
+  let result = match command {
+    Command::CreateAdmin { name, email } => create_admin(&db_client, config, name, email).await,
+    Command::ResetPassword { user } => reset_password(&db_client, config, &user).await,
+    Command::SetRole { user, role } => set_role(&db_client, &user, role).await,
+    Command::RevokeSessions { user } => revoke_sessions(&db_client, &user).await,
+    Command::ListUsers { page, limit } => list_users(&db_client, page, limit).await,
+    Command::Serve | Command::Migrate(_) => Ok(()),
+  };
+  db_client.close().await;
+
+  result
+}
+
+async fn migrate(command: MigrateCommand, pool: &Pool<Postgres>) -> Result<(), String> {
+  match command {
+    MigrateCommand::Run => {
+      db::MIGRATOR.run(pool).await.map_err(|e| e.to_string())?;
+      println!("Migrations are up to date");
+    }
+    MigrateCommand::Revert => {
+      let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+      let mut applied: Vec<i64> = conn
+        .list_applied_migrations()
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+      drop(conn);
+      applied.sort_unstable();
+
+      let Some(latest) = applied.pop() else {
+        println!("No migrations have been applied");
+        return Ok(());
+      };
+      let target = applied.last().copied().unwrap_or(0);
+      db::MIGRATOR
+        .undo(pool, target)
+        .await
+        .map_err(|e| e.to_string())?;
+
+      let description = db::MIGRATOR
+        .iter()
+        .find(|migration| migration.version == latest)
+        .map(|migration| migration.description.to_string())
+        .unwrap_or_default();
+      println!("Reverted {} {}", latest, description);
+    }
+  }
+
+  Ok(())
+}
+
+async fn create_admin(
+  db_client: &DBClient,
+  config: &Config,
+  name: String,
+  email: String,
+) -> Result<(), String> {
+  let email = email.trim().to_string();
+  if name.trim().is_empty() {
+    return Err("Name is required".to_string());
+  }
+  if !validator::validate_email(&email) {
+    return Err("Email is invalid".to_string());
+  }
+  if db_client
+    .get_user(None, None, Some(&email))
+    .await
+    .map_err(|e| e.to_string())?
+    .is_some()
+  {
+    return Err(format!("A user with email {} already exists", email));
+  }
+
+  let hashed_password = read_password(config)?;
+  let user = db_client
+    .save_admin_user(name.trim().to_string(), email, hashed_password)
+    .await
+    .map_err(|e| e.to_string())?;
+
+  let event = NewAuditEvent::success(AuditEventType::Register)
+    .target(user.id)
+    .metadata(json!({"source": "cli", "role": user.role.to_str()}));
+  audit::record(db_client, event).await;
+
+  println!("Created admin {} ({})", user.email, user.id);
+  Ok(())
+}
+
+async fn reset_password(db_client: &DBClient, config: &Config, user: &str) -> Result<(), String> {
+  let user = find_user(db_client, user).await?;
+  let hashed_password = read_password(config)?;
+
+  db_client
+    .update_user_password(user.id, &hashed_password)
+    .await
+    .map_err(|e| e.to_string())?;
+
+  let event = NewAuditEvent::success(AuditEventType::PasswordChanged)
+    .target(user.id)
+    .metadata(json!({"source": "cli"}));
+  audit::record(db_client, event).await;
+
+  println!("Password of {} was reset", user.email);
+  Ok(())
+}
+
+async fn set_role(db_client: &DBClient, user: &str, role: UserRole) -> Result<(), String> {
+  let user = find_user(db_client, user).await?;
+  let updated_user = db_client
+    .update_user_role(user.id, role)
+    .await
+    .map_err(|e| e.to_string())?;
+
+  let event = NewAuditEvent::success(AuditEventType::RoleChanged)
+    .target(user.id)
+    .metadata(json!({
+      "source": "cli",
+      "from": user.role.to_str(),
+      "to": updated_user.role.to_str(),
+    }));
+  audit::record(db_client, event).await;
+
+  println!(
+    "Role of {} changed from {} to {}",
+    user.email,
+    user.role.to_str(),
+    updated_user.role.to_str()
+  );
+  Ok(())
+}
+
+async fn revoke_sessions(db_client: &DBClient, user: &str) -> Result<(), String> {
+  let user = find_user(db_client, user).await?;
+  let refresh_tokens = db_client
+    .revoke_user_sessions(user.id)
+    .await
+    .map_err(|e| e.to_string())?;
+
+  let event = NewAuditEvent::success(AuditEventType::TokenRevoked)
+    .target(user.id)
+    .metadata(json!({
+      "source": "cli",
+      "tokenType": "all",
+      "refreshTokens": refresh_tokens,
+    }));
+  audit::record(db_client, event).await;
+
+  println!(
+    "Sessions of {} were revoked ({} refresh tokens)",
+    user.email, refresh_tokens
+  );
+  Ok(())
+}
+
+async fn list_users(db_client: &DBClient, page: u32, limit: usize) -> Result<(), String> {
+  let users = db_client
+    .get_users(page.max(1), limit)
+    .await
+    .map_err(|e| e.to_string())?;
+
+  println!(
+    "{:<36}  {:<9}  {:<8}  {:<32}  NAME",
+    "ID", "ROLE", "ACTIVE", "EMAIL"
+  );
+  for user in users {
+    println!(
+      "{:<36}  {:<9}  {:<8}  {:<32}  {}",
+      user.id,
+      user.role.to_str(),
+      user.active,
+      user.email,
+      user.name
+    );
+  }
+
+  Ok(())
+}
+
+async fn find_user(db_client: &DBClient, user: &str) -> Result<User, String> {
+  let result = match Uuid::parse_str(user) {
+    Ok(user_id) => db_client.get_user(Some(user_id), None, None).await,
+    Err(_) => db_client.get_user(None, None, Some(user.trim())).await,
+  };
+
+  result
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| format!("User {} not found", user))
+}
+
+/// Reads a password from the first line of stdin, prompting for it when stdin
+/// is a terminal, and returns its hash once it satisfies the password policy.
+fn read_password(config: &Config) -> Result<String, String> {
+  let stdin = std::io::stdin();
+  if stdin.is_terminal() {
+    eprint!("Password: ");
+    std::io::stderr().flush().ok();
+  }
+
+  let mut line = String::new();
+  stdin
+    .lock()
+    .read_line(&mut line)
+    .map_err(|e| e.to_string())?;
+  let password = line.trim_end_matches(['\r', '\n']);
+
+  password::check_policy(password, &config.password_policy).map_err(|e| e.to_string())?;
+  password::hash(password).map_err(|e| e.to_string())
+}
+
+fn parse_role(value: &str) -> Result<UserRole, String> {
+  UserRole::parse(value).ok_or_else(|| "must be one of admin, moderator or user".to_string())
+}
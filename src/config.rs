@@ -1,26 +1,938 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+use actix_web::{
+  cookie::SameSite,
+  http::{header::HeaderName, Method},
+};
+
+use ipnet::IpNet;
+
+use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey, RsaPrivateKey};
+
+use crate::utils::{password, saml::SamlIdp};
+
+/// Settings from the TOML file named by `CONFIG_FILE`, where every value can
+/// be overridden by an environment variable. Problems are collected rather
+/// than panicking on the first one, so startup can report all of them.
+struct Source {
+  file: toml::Table,
+  errors: Vec<String>,
+}
+
+impl Source {
+  fn load() -> Source {
+    let mut source = Source {
+      file: toml::Table::new(),
+      errors: vec![],
+    };
+
+    if let Ok(path) = std::env::var("CONFIG_FILE") {
+      match std::fs::read_to_string(&path).map(|contents| contents.parse::<toml::Table>()) {
+        Ok(Ok(file)) => source.file = file,
+        Ok(Err(e)) => source.error(format!("{}: {}", path, e)),
+        Err(e) => source.error(format!("{}: {}", path, e)),
+      }
+    }
+
+    source
+  }
+
+  fn error(&mut self, message: impl Into<String>) {
+    self.errors.push(message.into());
+  }
+
+  /// Looks up a dotted `key` such as `jwt.maxage` in the file.
+  fn file_value(&self, key: &str) -> Option<&toml::Value> {
+    let mut parts = key.split('.');
+    let mut value = self.file.get(parts.next()?)?;
+    for part in parts {
+      value = value.get(part)?;
+    }
+    Some(value)
+  }
+
+  fn string(&self, key: &str, env: &str) -> Option<String> {
+    if let Ok(value) = std::env::var(env) {
+      return Some(value);
+    }
+
+    match self.file_value(key)? {
+      toml::Value::String(value) => Some(value.clone()),
+      value => Some(value.to_string()),
+    }
+  }
+
+  fn required(&mut self, key: &str, env: &str) -> String {
+    match self.string(key, env) {
+      Some(value) if !value.is_empty() => value,
+      _ => {
+        self.error(format!("{} ({}) must be set", key, env));
+        String::new()
+      }
+    }
+  }
+
+  fn parse<T>(&mut self, key: &str, env: &str, default: T) -> T
+  where
+    T: FromStr,
+    T::Err: Display,
+  {
+    match self.string(key, env) {
+      Some(value) => value.trim().parse::<T>().unwrap_or_else(|e| {
+        self.error(format!("{} ({}): {}", key, env, e));
+        default
+      }),
+      None => default,
+    }
+  }
+
+  fn flag(&mut self, key: &str, env: &str, default: bool) -> bool {
+    match self.string(key, env).map(|value| value.to_lowercase()) {
+      Some(value) if matches!(value.as_str(), "true" | "1" | "yes") => true,
+      Some(value) if matches!(value.as_str(), "false" | "0" | "no") => false,
+      Some(_) => {
+        self.error(format!("{} ({}) must be true or false", key, env));
+        default
+      }
+      None => default,
+    }
+  }
+
+  /// Contents of the file whose path the setting names.
+  fn file(&mut self, key: &str, env: &str) -> Option<String> {
+    let path = self.string(key, env)?;
+    std::fs::read_to_string(&path)
+      .map_err(|e| self.error(format!("{} ({}): {}: {}", key, env, path, e)))
+      .ok()
+  }
+
+  /// A TOML array, or a string split on `separator` as used in env vars.
+  fn list(&self, key: &str, env: &str, separator: char) -> Vec<String> {
+    let values: Vec<String> = match (std::env::var(env), self.file_value(key)) {
+      (Err(_), Some(toml::Value::Array(values))) => values
+        .iter()
+        .map(|value| match value {
+          toml::Value::String(value) => value.clone(),
+          value => value.to_string(),
+        })
+        .collect(),
+      _ => self
+        .string(key, env)
+        .unwrap_or_default()
+        .split(separator)
+        .map(str::to_string)
+        .collect(),
+    };
+
+    values
+      .into_iter()
+      .map(|value| value.trim().to_string())
+      .filter(|value| !value.is_empty())
+      .collect()
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegistrationMode {
+  Open,
+  InviteOnly,
+  DomainRestricted,
+}
+
+impl RegistrationMode {
+  fn parse(value: &str) -> Option<Self> {
+    match value.to_lowercase().as_str() {
+      "open" => Some(RegistrationMode::Open),
+      "invite" => Some(RegistrationMode::InviteOnly),
+      "domain" => Some(RegistrationMode::DomainRestricted),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthBackendKind {
+  Password,
+  Ldap,
+}
+
+impl AuthBackendKind {
+  fn parse(value: &str) -> Option<Self> {
+    match value.trim().to_lowercase().as_str() {
+      "password" => Some(AuthBackendKind::Password),
+      "ldap" => Some(AuthBackendKind::Ldap),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+  pub url: String,
+  pub starttls: bool,
+  /// Account used to look users up. Anonymous search is used when unset.
+  pub bind_dn: Option<String>,
+  pub bind_password: Option<String>,
+  pub base_dn: String,
+  /// Search filter where `{email}` is replaced with the escaped login email.
+  pub user_filter: String,
+  pub group_attribute: String,
+  /// Roles are only synced from the directory when at least one group is mapped.
+  pub admin_groups: Vec<String>,
+  pub moderator_groups: Vec<String>,
+}
+
+impl LdapConfig {
+  fn load(source: &mut Source) -> LdapConfig {
+    LdapConfig {
+      url: source.required("ldap.url", "LDAP_URL"),
+      starttls: source.flag("ldap.starttls", "LDAP_STARTTLS", false),
+      bind_dn: source.string("ldap.bind_dn", "LDAP_BIND_DN"),
+      bind_password: source.string("ldap.bind_password", "LDAP_BIND_PASSWORD"),
+      base_dn: source.required("ldap.base_dn", "LDAP_BASE_DN"),
+      user_filter: source
+        .string("ldap.user_filter", "LDAP_USER_FILTER")
+        .unwrap_or("(mail={email})".to_owned()),
+      group_attribute: source
+        .string("ldap.group_attribute", "LDAP_GROUP_ATTRIBUTE")
+        .unwrap_or("memberOf".to_owned()),
+      // Group DNs contain commas, so lists of them are separated by semicolons.
+      admin_groups: source.list("ldap.admin_groups", "LDAP_ADMIN_GROUPS", ';'),
+      moderator_groups: source.list("ldap.moderator_groups", "LDAP_MODERATOR_GROUPS", ';'),
+    }
+  }
+}
+
+/// SAML 2.0 service provider settings, enabled by `SAML_IDP_METADATA_PATH`.
+#[derive(Debug, Clone)]
+pub struct SamlConfig {
+  /// The identity provider described by the metadata file.
+  pub idp: SamlIdp,
+  pub sp_entity_id: String,
+  /// Attribute holding the email address. The NameID is used when unset.
+  pub email_attribute: Option<String>,
+  pub name_attribute: String,
+  pub group_attribute: String,
+  /// Roles are only synced from the IdP when at least one group is mapped.
+  pub admin_groups: Vec<String>,
+  pub moderator_groups: Vec<String>,
+  pub login_redirect: Option<String>,
+}
+
+impl SamlConfig {
+  fn load(source: &mut Source, oidc_issuer: &str) -> Option<SamlConfig> {
+    let metadata = source.file("saml.idp_metadata_path", "SAML_IDP_METADATA_PATH")?;
+    let idp = SamlIdp::from_metadata(&metadata)
+      .map_err(|e| {
+        source.error(format!(
+          "saml.idp_metadata_path (SAML_IDP_METADATA_PATH) contains invalid metadata: {}",
+          e
+        ))
+      })
+      .ok()?;
+
+    Some(SamlConfig {
+      idp,
+      sp_entity_id: source
+        .string("saml.sp_entity_id", "SAML_SP_ENTITY_ID")
+        .unwrap_or(format!("{}/api/auth/saml/metadata", oidc_issuer)),
+      email_attribute: source.string("saml.email_attribute", "SAML_EMAIL_ATTRIBUTE"),
+      name_attribute: source
+        .string("saml.name_attribute", "SAML_NAME_ATTRIBUTE")
+        .unwrap_or("displayName".to_owned()),
+      group_attribute: source
+        .string("saml.group_attribute", "SAML_GROUP_ATTRIBUTE")
+        .unwrap_or("groups".to_owned()),
+      // Group names may be distinguished names, so lists are separated by semicolons.
+      admin_groups: source.list("saml.admin_groups", "SAML_ADMIN_GROUPS", ';'),
+      moderator_groups: source.list("saml.moderator_groups", "SAML_MODERATOR_GROUPS", ';'),
+      login_redirect: source.string("saml.login_redirect", "SAML_LOGIN_REDIRECT"),
+    })
+  }
+}
+
+/// Attributes of the session and CSRF cookies.
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+  pub secure: bool,
+  pub same_site: SameSite,
+  pub domain: Option<String>,
+  /// Names the cookies `__Host-*`, which browsers only accept when they are
+  /// secure, host-only and scoped to `/`.
+  pub host_prefix: bool,
+}
+
+impl CookieConfig {
+  fn load(source: &mut Source, oidc_issuer: &str) -> CookieConfig {
+    let secure = source.flag(
+      "cookie.secure",
+      "COOKIE_SECURE",
+      oidc_issuer.starts_with("https://"),
+    );
+    let same_site = match source
+      .string("cookie.same_site", "COOKIE_SAME_SITE")
+      .unwrap_or("lax".to_owned())
+      .to_lowercase()
+      .as_str()
+    {
+      "strict" => SameSite::Strict,
+      "lax" => SameSite::Lax,
+      "none" => SameSite::None,
+      _ => {
+        source.error("cookie.same_site (COOKIE_SAME_SITE) must be one of strict, lax or none");
+        SameSite::Lax
+      }
+    };
+    let domain = source
+      .string("cookie.domain", "COOKIE_DOMAIN")
+      .filter(|domain| !domain.is_empty());
+    let host_prefix = source.flag("cookie.host_prefix", "COOKIE_HOST_PREFIX", false);
+
+    if same_site == SameSite::None && !secure {
+      source.error("cookie.secure (COOKIE_SECURE) must be enabled when cookie.same_site is none");
+    }
+    if host_prefix && (!secure || domain.is_some()) {
+      source.error(
+        "cookie.host_prefix (COOKIE_HOST_PREFIX) requires cookie.secure and no cookie.domain",
+      );
+    }
+
+    CookieConfig {
+      secure,
+      same_site,
+      domain,
+      host_prefix,
+    }
+  }
+
+  pub fn session_name(&self) -> &'static str {
+    if self.host_prefix {
+      "__Host-token"
+    } else {
+      "token"
+    }
+  }
+
+  pub fn csrf_name(&self) -> &'static str {
+    if self.host_prefix {
+      "__Host-csrf_token"
+    } else {
+      "csrf_token"
+    }
+  }
+
+  pub fn social_state_name(&self) -> &'static str {
+    if self.host_prefix {
+      "__Host-social_state"
+    } else {
+      "social_state"
+    }
+  }
+
+  pub fn saml_request_name(&self) -> &'static str {
+    if self.host_prefix {
+      "__Host-saml_request"
+    } else {
+      "saml_request"
+    }
+  }
+}
+
+/// Cross-origin access for browser frontends.
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+  /// Exact origins, subdomain patterns such as `https://*.example.com`, or
+  /// `*` for any origin.
+  pub allowed_origins: Vec<String>,
+  pub allowed_methods: Vec<String>,
+  pub allowed_headers: Vec<String>,
+  pub allow_credentials: bool,
+  /// How long browsers may cache a preflight response, in seconds.
+  pub max_age: Option<usize>,
+}
+
+impl CorsConfig {
+  fn load(source: &mut Source) -> CorsConfig {
+    let list =
+      |source: &Source, key: &str, env: &str, default: &[&str]| match source.list(key, env, ',') {
+        values if values.is_empty() => default.iter().map(|value| value.to_string()).collect(),
+        values => values,
+      };
+
+    let cors = CorsConfig {
+      allowed_origins: list(
+        source,
+        "cors.allowed_origins",
+        "CORS_ALLOWED_ORIGINS",
+        &[
+          "http://localhost:3000",
+          "http://localhost:8000",
+          "http://localhost:8080",
+        ],
+      ),
+      allowed_methods: list(
+        source,
+        "cors.allowed_methods",
+        "CORS_ALLOWED_METHODS",
+        &["GET", "POST", "PUT", "PATCH", "DELETE"],
+      ),
+      allowed_headers: list(
+        source,
+        "cors.allowed_headers",
+        "CORS_ALLOWED_HEADERS",
+        &[
+          "content-type",
+          "authorization",
+          "accept",
+          "x-csrf-token",
+          "x-request-id",
+        ],
+      ),
+      allow_credentials: source.flag("cors.allow_credentials", "CORS_ALLOW_CREDENTIALS", true),
+      max_age: source
+        .string("cors.max_age", "CORS_MAX_AGE")
+        .map(|_| source.parse("cors.max_age", "CORS_MAX_AGE", 0)),
+    };
+
+    let any_origin = cors.allowed_origins.iter().any(|origin| origin == "*");
+    if any_origin && cors.allowed_origins.len() > 1 {
+      source.error("cors.allowed_origins (CORS_ALLOWED_ORIGINS) cannot mix * with other origins");
+    }
+    if any_origin && cors.allow_credentials {
+      // Browsers refuse credentialed responses for `*`, and reflecting any
+      // origin instead would let every site act with the user's cookies.
+      source.error("cors.allowed_origins (CORS_ALLOWED_ORIGINS) cannot be * when cors.allow_credentials is enabled");
+    }
+    for origin in cors.allowed_origins.iter().filter(|origin| *origin != "*") {
+      if !is_origin_pattern(origin) {
+        source.error(format!(
+          "cors.allowed_origins (CORS_ALLOWED_ORIGINS): {} is not a scheme://host[:port] origin or https://*.domain pattern",
+          origin
+        ));
+      }
+    }
+    for method in &cors.allowed_methods {
+      if Method::from_bytes(method.as_bytes()).is_err() {
+        source.error(format!(
+          "cors.allowed_methods (CORS_ALLOWED_METHODS): {} is not a valid method",
+          method
+        ));
+      }
+    }
+    for header in &cors.allowed_headers {
+      if HeaderName::from_bytes(header.as_bytes()).is_err() {
+        source.error(format!(
+          "cors.allowed_headers (CORS_ALLOWED_HEADERS): {} is not a valid header name",
+          header
+        ));
+      }
+    }
+
+    cors
+  }
+}
+
+/// Whether `origin` is a serialized http(s) origin, optionally with `*` as the
+/// leftmost host label.
+fn is_origin_pattern(origin: &str) -> bool {
+  let candidate = origin.replacen("://*.", "://wildcard.", 1);
+  if candidate.contains('*') {
+    return false;
+  }
+
+  match url::Url::parse(&candidate) {
+    Ok(url) if matches!(url.scheme(), "http" | "https") => {
+      url.origin().ascii_serialization() == candidate.to_lowercase()
+    }
+    _ => false,
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SocialProviderKind {
+  /// GitHub's OAuth 2.0 API, which has no OpenID Connect support.
+  Github,
+  Oidc,
+}
+
+/// An upstream identity provider for social login, configured through a
+/// `[social.<name>]` table or `SOCIAL_<NAME>_*` variables. `github` and `google` have built-in endpoints;
+/// any other name is a generic OpenID Connect provider that needs an issuer.
+#[derive(Debug, Clone)]
+pub struct SocialProvider {
+  pub name: String,
+  pub kind: SocialProviderKind,
+  pub client_id: String,
+  pub client_secret: String,
+  pub issuer: Option<String>,
+  pub authorize_url: Option<String>,
+  pub token_url: Option<String>,
+  pub userinfo_url: Option<String>,
+  pub scopes: String,
+}
+
+impl SocialProvider {
+  fn load(source: &mut Source, name: &str) -> SocialProvider {
+    let key = |key: &str| format!("social.{}.{}", name, key);
+    let env = |key: &str| format!("SOCIAL_{}_{}", name.to_uppercase(), key.to_uppercase());
+    let var = |source: &Source, k: &str| source.string(&key(k), &env(k));
+
+    let (kind, issuer, authorize_url, token_url, userinfo_url, scopes) = match name {
+      "github" => (
+        SocialProviderKind::Github,
+        None,
+        Some("https://github.com/login/oauth/authorize"),
+        Some("https://github.com/login/oauth/access_token"),
+        Some("https://api.github.com/user"),
+        "read:user user:email",
+      ),
+      "google" => (
+        SocialProviderKind::Oidc,
+        Some("https://accounts.google.com"),
+        None,
+        None,
+        None,
+        "openid email profile",
+      ),
+      _ => (
+        SocialProviderKind::Oidc,
+        None,
+        None,
+        None,
+        None,
+        "openid email profile",
+      ),
+    };
+
+    let provider = SocialProvider {
+      name: name.to_string(),
+      kind,
+      client_id: source.required(&key("client_id"), &env("client_id")),
+      client_secret: source.required(&key("client_secret"), &env("client_secret")),
+      issuer: var(source, "issuer")
+        .or(issuer.map(str::to_string))
+        .map(|issuer| issuer.trim_end_matches('/').to_string()),
+      authorize_url: var(source, "authorize_url").or(authorize_url.map(str::to_string)),
+      token_url: var(source, "token_url").or(token_url.map(str::to_string)),
+      userinfo_url: var(source, "userinfo_url").or(userinfo_url.map(str::to_string)),
+      scopes: var(source, "scopes").unwrap_or(scopes.to_string()),
+    };
+
+    let has_endpoints = provider.authorize_url.is_some()
+      && provider.token_url.is_some()
+      && provider.userinfo_url.is_some();
+    if provider.issuer.is_none() && !has_endpoints {
+      source.error(format!(
+        "{} ({}) must be set for OpenID Connect providers",
+        key("issuer"),
+        env("issuer")
+      ));
+    }
+
+    provider
+  }
+}
+
+/// Rules new passwords must satisfy. Existing passwords are not rechecked.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+  pub min_length: usize,
+  pub require_uppercase: bool,
+  pub require_lowercase: bool,
+  pub require_digit: bool,
+  pub require_symbol: bool,
+}
+
+impl PasswordPolicy {
+  fn load(source: &mut Source) -> PasswordPolicy {
+    let policy = PasswordPolicy {
+      min_length: source.parse("password.min_length", "PASSWORD_MIN_LENGTH", 6),
+      require_uppercase: source.flag(
+        "password.require_uppercase",
+        "PASSWORD_REQUIRE_UPPERCASE",
+        false,
+      ),
+      require_lowercase: source.flag(
+        "password.require_lowercase",
+        "PASSWORD_REQUIRE_LOWERCASE",
+        false,
+      ),
+      require_digit: source.flag("password.require_digit", "PASSWORD_REQUIRE_DIGIT", false),
+      require_symbol: source.flag("password.require_symbol", "PASSWORD_REQUIRE_SYMBOL", false),
+    };
+
+    if !(1..=password::MAX_PASSWORD_LENGTH).contains(&policy.min_length) {
+      source.error(format!(
+        "password.min_length (PASSWORD_MIN_LENGTH) must be between 1 and {}",
+        password::MAX_PASSWORD_LENGTH
+      ));
+    }
+
+    policy
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+  Json,
+  Text,
+}
+
+/// Logging and trace export.
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+  pub log_format: LogFormat,
+  /// `tracing_subscriber::EnvFilter` directives, e.g. `info,sqlx=warn`.
+  pub log_filter: String,
+  /// OTLP/HTTP collector base URL, e.g. `http://localhost:4318`. Spans are
+  /// only exported when set.
+  pub otlp_endpoint: Option<String>,
+  pub service_name: String,
+}
+
+impl TelemetryConfig {
+  fn load(source: &mut Source) -> TelemetryConfig {
+    let log_format = match source
+      .string("log.format", "LOG_FORMAT")
+      .unwrap_or("json".to_owned())
+      .to_lowercase()
+      .as_str()
+    {
+      "json" => LogFormat::Json,
+      "text" => LogFormat::Text,
+      _ => {
+        source.error("log.format (LOG_FORMAT) must be json or text");
+        LogFormat::Json
+      }
+    };
+    let telemetry = TelemetryConfig {
+      log_format,
+      log_filter: source
+        .string("log.filter", "RUST_LOG")
+        .unwrap_or("info".to_owned()),
+      otlp_endpoint: source
+        .string("otlp.endpoint", "OTEL_EXPORTER_OTLP_ENDPOINT")
+        .filter(|endpoint| !endpoint.is_empty())
+        .map(|endpoint| endpoint.trim_end_matches('/').to_string()),
+      service_name: source
+        .string("otlp.service_name", "OTEL_SERVICE_NAME")
+        .unwrap_or(env!("CARGO_PKG_NAME").to_owned()),
+    };
+
+    if let Err(e) = tracing_subscriber::EnvFilter::try_new(&telemetry.log_filter) {
+      source.error(format!("log.filter (RUST_LOG): {}", e));
+    }
+    if let Some(endpoint) = &telemetry.otlp_endpoint {
+      if !matches!(url::Url::parse(endpoint), Ok(url) if matches!(url.scheme(), "http" | "https")) {
+        source.error("otlp.endpoint (OTEL_EXPORTER_OTLP_ENDPOINT) must be an http(s) URL");
+      }
+    }
+
+    telemetry
+  }
+}
+
+/// The Prometheus `/metrics` endpoint.
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+  pub enabled: bool,
+  /// Bearer token scrapers must send. The endpoint is open when unset.
+  pub token: Option<String>,
+  /// Serves `/metrics` on its own port instead of the API port, so it can be
+  /// kept off the public network.
+  pub port: Option<u16>,
+}
+
+impl MetricsConfig {
+  fn load(source: &mut Source, api_port: u16) -> MetricsConfig {
+    let metrics = MetricsConfig {
+      enabled: source.flag("metrics.enabled", "METRICS_ENABLED", true),
+      token: source
+        .string("metrics.token", "METRICS_TOKEN")
+        .filter(|token| !token.is_empty()),
+      port: source
+        .string("metrics.port", "METRICS_PORT")
+        .map(|_| source.parse("metrics.port", "METRICS_PORT", 0)),
+    };
+
+    if metrics.port == Some(api_port) {
+      source.error("metrics.port (METRICS_PORT) must differ from port");
+    }
+
+    metrics
+  }
+}
+
+/// SMTP relay for outgoing mail, enabled by `mailer.host`. Nothing sends mail
+/// yet; the readiness probe only checks that the relay is reachable.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct MailerConfig {
+  pub host: String,
+  pub port: u16,
+  pub username: Option<String>,
+  pub password: Option<String>,
+  pub from: String,
+  pub starttls: bool,
+}
+
+impl MailerConfig {
+  fn load(source: &mut Source) -> Option<MailerConfig> {
+    let host = source
+      .string("mailer.host", "MAILER_HOST")
+      .filter(|host| !host.is_empty())?;
+
+    let mailer = MailerConfig {
+      host,
+      port: source.parse("mailer.port", "MAILER_PORT", 587),
+      username: source.string("mailer.username", "MAILER_USERNAME"),
+      password: source.string("mailer.password", "MAILER_PASSWORD"),
+      from: source.required("mailer.from", "MAILER_FROM"),
+      starttls: source.flag("mailer.starttls", "MAILER_STARTTLS", true),
+    };
+
+    if !mailer.from.is_empty() && !mailer.from.contains('@') {
+      source.error("mailer.from (MAILER_FROM) must be an email address");
+    }
+    if mailer.username.is_some() != mailer.password.is_some() {
+      source.error("mailer.username and mailer.password must be set together");
+    }
+
+    Some(mailer)
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
   pub database_url: String,
+  pub database_max_connections: u32,
+  /// Connection attempts at startup before giving up, one second apart at first.
+  pub database_connect_attempts: u32,
   pub jwt_secret: String,
   pub jwt_maxage: i64,
+  pub impersonation_maxage: i64,
+  pub oauth_refresh_maxage: i64,
+  /// Lifetime of tokens issued by the token exchange grant, in minutes.
+  pub token_exchange_maxage: i64,
+  /// Downstream services a token may be exchanged for. Empty disables the grant.
+  pub token_exchange_audiences: Vec<String>,
   pub port: u16,
+  /// Seconds in-flight requests are given to finish once a shutdown starts.
+  pub shutdown_timeout: u64,
+  /// Reverse proxies whose `X-Forwarded-For` header is believed.
+  pub trusted_proxies: Vec<IpNet>,
+  pub registration_mode: RegistrationMode,
+  pub allowed_email_domains: Vec<String>,
+  pub audit_signing_key: String,
+  pub audit_checkpoint_interval: u64,
+  pub oidc_issuer: String,
+  /// Signs ID tokens. An ephemeral key is generated when unset.
+  pub oidc_private_key: Option<RsaPrivateKey>,
+  pub social_providers: Vec<SocialProvider>,
+  pub social_login_redirect: Option<String>,
+  pub auth_backends: Vec<AuthBackendKind>,
+  pub ldap: Option<LdapConfig>,
+  pub scim_token: Option<String>,
+  pub saml: Option<SamlConfig>,
+  pub cookie: CookieConfig,
+  pub cors: CorsConfig,
+  pub password_policy: PasswordPolicy,
+  pub mailer: Option<MailerConfig>,
+  pub metrics: MetricsConfig,
+  /// Serves Swagger UI at `/api/docs`. The OpenAPI document is always served.
+  pub docs_ui: bool,
+  pub telemetry: TelemetryConfig,
 }
 
 impl Config {
-  pub fn init() -> Config {
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL mut be set");
-    let jwt_secret = std::env::var("JWT_SECRET_KEY").expect("JWT_SECRET_KEY mut be set");
-    let jwt_maxage = std::env::var("JWT_MAXAGE").expect("JWT_MAXAGE mut be set");
-    let port = std::env::var("PORT")
-      .unwrap_or("8000".to_owned())
-      .parse::<u16>()
-      .unwrap();
-
-    Config {
+  /// Loads the `CONFIG_FILE` TOML file, if any, with environment variables
+  /// taking precedence. Returns every problem found when it is invalid.
+  pub fn init() -> Result<Config, Vec<String>> {
+    let mut source = Source::load();
+
+    let database_url = source.required("database.url", "DATABASE_URL");
+    let database_max_connections =
+      source.parse("database.max_connections", "DATABASE_MAX_CONNECTIONS", 10);
+    let database_connect_attempts =
+      source.parse("database.connect_attempts", "DATABASE_CONNECT_ATTEMPTS", 5);
+    let jwt_secret = source.required("jwt.secret", "JWT_SECRET_KEY");
+    let jwt_maxage = source.parse("jwt.maxage", "JWT_MAXAGE", 60);
+    let impersonation_maxage = source.parse("impersonation.maxage", "IMPERSONATION_MAXAGE", 15);
+    let oauth_refresh_maxage = source.parse("oauth.refresh_maxage", "OAUTH_REFRESH_MAXAGE", 43200);
+    let token_exchange_maxage = source.parse("token_exchange.maxage", "TOKEN_EXCHANGE_MAXAGE", 5);
+    let token_exchange_audiences =
+      source.list("token_exchange.audiences", "TOKEN_EXCHANGE_AUDIENCES", ',');
+    let port = source.parse("port", "PORT", 8000);
+    let shutdown_timeout = source.parse("shutdown_timeout", "SHUTDOWN_TIMEOUT", 30);
+    let mut trusted_proxies = vec![];
+    for proxy in source.list("trusted_proxies", "TRUSTED_PROXIES", ',') {
+      match proxy
+        .parse::<IpNet>()
+        .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+      {
+        Ok(proxy) => trusted_proxies.push(proxy),
+        Err(_) => source.error(format!(
+          "trusted_proxies (TRUSTED_PROXIES): {} is not an IP address or CIDR range",
+          proxy
+        )),
+      }
+    }
+    let registration_mode = source
+      .string("registration.mode", "REGISTRATION_MODE")
+      .map(|mode| RegistrationMode::parse(&mode))
+      .unwrap_or(Some(RegistrationMode::Open))
+      .unwrap_or_else(|| {
+        source.error("registration.mode (REGISTRATION_MODE) must be one of open, invite or domain");
+        RegistrationMode::Open
+      });
+    let allowed_email_domains: Vec<String> = source
+      .list(
+        "registration.allowed_email_domains",
+        "ALLOWED_EMAIL_DOMAINS",
+        ',',
+      )
+      .into_iter()
+      .map(|domain| domain.to_lowercase())
+      .collect();
+
+    // Checkpoint signatures are HMACs, so whoever can verify them can also
+    // create them: sharing the JWT secret would let them forge tokens too.
+    let audit_signing_key = source.required("audit.signing_key", "AUDIT_SIGNING_KEY");
+    if !audit_signing_key.is_empty() && audit_signing_key == jwt_secret {
+      source.error("audit.signing_key (AUDIT_SIGNING_KEY) must differ from jwt.secret");
+    }
+    let audit_checkpoint_interval =
+      source.parse("audit.checkpoint_interval", "AUDIT_CHECKPOINT_INTERVAL", 60);
+    let oidc_issuer = source
+      .string("oidc.issuer", "OIDC_ISSUER")
+      .unwrap_or(format!("http://localhost:{}", port))
+      .trim_end_matches('/')
+      .to_string();
+    let oidc_private_key = source
+      .file("oidc.private_key_path", "OIDC_PRIVATE_KEY_PATH")
+      .and_then(|pem| {
+        RsaPrivateKey::from_pkcs8_pem(&pem)
+          .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+          .map_err(|_| {
+            source.error(
+              "oidc.private_key_path (OIDC_PRIVATE_KEY_PATH) must contain an RSA private key in PEM format",
+            )
+          })
+          .ok()
+      });
+    let social_providers = source
+      .list("social.providers", "SOCIAL_PROVIDERS", ',')
+      .into_iter()
+      .map(|name| SocialProvider::load(&mut source, &name.to_lowercase()))
+      .collect();
+    let social_login_redirect = source.string("social.login_redirect", "SOCIAL_LOGIN_REDIRECT");
+    let mut auth_backends = vec![];
+    for backend in source.list("auth.backends", "AUTH_BACKENDS", ',') {
+      match AuthBackendKind::parse(&backend) {
+        Some(backend) => auth_backends.push(backend),
+        None => source.error("auth.backends (AUTH_BACKENDS) must only contain password or ldap"),
+      }
+    }
+    if source.string("auth.backends", "AUTH_BACKENDS").is_none() {
+      auth_backends.push(AuthBackendKind::Password);
+    }
+    let ldap = auth_backends
+      .contains(&AuthBackendKind::Ldap)
+      .then(|| LdapConfig::load(&mut source));
+
+    let scim_token = source
+      .string("scim.token", "SCIM_TOKEN")
+      .filter(|token| !token.is_empty());
+
+    let saml = SamlConfig::load(&mut source, &oidc_issuer);
+    let cookie = CookieConfig::load(&mut source, &oidc_issuer);
+    let cors = CorsConfig::load(&mut source);
+    let password_policy = PasswordPolicy::load(&mut source);
+    let mailer = MailerConfig::load(&mut source);
+    let metrics = MetricsConfig::load(&mut source, port);
+    let telemetry = TelemetryConfig::load(&mut source);
+    let docs_ui = source.flag("docs.ui", "DOCS_UI", true);
+
+    let positive = [
+      ("jwt.maxage (JWT_MAXAGE)", jwt_maxage),
+      (
+        "impersonation.maxage (IMPERSONATION_MAXAGE)",
+        impersonation_maxage,
+      ),
+      (
+        "oauth.refresh_maxage (OAUTH_REFRESH_MAXAGE)",
+        oauth_refresh_maxage,
+      ),
+      (
+        "token_exchange.maxage (TOKEN_EXCHANGE_MAXAGE)",
+        token_exchange_maxage,
+      ),
+      (
+        "database.max_connections (DATABASE_MAX_CONNECTIONS)",
+        database_max_connections as i64,
+      ),
+      (
+        "database.connect_attempts (DATABASE_CONNECT_ATTEMPTS)",
+        database_connect_attempts as i64,
+      ),
+    ];
+    for (name, value) in positive {
+      if value <= 0 {
+        source.error(format!("{} must be greater than zero", name));
+      }
+    }
+    if registration_mode == RegistrationMode::DomainRestricted && allowed_email_domains.is_empty() {
+      source.error(
+        "registration.allowed_email_domains (ALLOWED_EMAIL_DOMAINS) must be set when registration.mode is domain",
+      );
+    }
+
+    if !source.errors.is_empty() {
+      return Err(source.errors);
+    }
+
+    Ok(Config {
       database_url,
+      database_max_connections,
+      database_connect_attempts,
       jwt_secret,
-      jwt_maxage: jwt_maxage.parse::<i64>().unwrap(),
+      jwt_maxage,
+      impersonation_maxage,
+      oauth_refresh_maxage,
+      token_exchange_maxage,
+      token_exchange_audiences,
       port,
-    }
+      shutdown_timeout,
+      trusted_proxies,
+      registration_mode,
+      allowed_email_domains,
+      audit_signing_key,
+      audit_checkpoint_interval,
+      oidc_issuer,
+      oidc_private_key,
+      social_providers,
+      social_login_redirect,
+      auth_backends,
+      ldap,
+      scim_token,
+      saml,
+      cookie,
+      cors,
+      password_policy,
+      mailer,
+      metrics,
+      docs_ui,
+      telemetry,
+    })
+  }
+
+  /// Whether `email` may register under the domain-restricted policy.
+  pub fn is_email_domain_allowed(&self, email: &str) -> bool {
+    let domain = email
+      .rsplit_once('@')
+      .map(|(_, domain)| domain.to_lowercase())
+      .unwrap_or_default();
+
+    self.allowed_email_domains.contains(&domain)
   }
 }
@@ -1,8 +1,34 @@
+use std::{collections::HashMap, time::Duration};
+
 use async_trait::async_trait;
-use sqlx::{Pool, Postgres};
+use chrono::{DateTime, SubsecRound, Utc};
+use sqlx::{
+  migrate::{Migrate, Migrator},
+  postgres::PgPoolOptions,
+  Pool, Postgres,
+};
+use tracing::instrument;
 use uuid::Uuid;
 
-use crate::models::{User, UserRole};
+use crate::{
+  models::{
+    AuditCheckpoint, AuditEvent, AuditEventType, AuditOutcome, AuthorizationCode,
+    ImpersonationSession, Invite, OAuthClient, RefreshToken, SamlRequest, SocialLoginRequest, User,
+    UserIdentity, UserRole,
+  },
+  utils::{
+    audit::{self, NewAuditEvent},
+    oauth::NewAuthorizationCode,
+    scim::{self, ScimUserFilter, UserAttributes},
+  },
+};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// How long a single connection attempt at startup may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest wait between two connection attempts at startup.
+const MAX_CONNECT_DELAY: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Clone)]
 pub struct DBClient {
@@ -13,6 +39,62 @@ impl DBClient {
   pub fn new(pool: Pool<Postgres>) -> Self {
     DBClient { pool }
   }
+
+  /// Connects to the database, retrying with exponential backoff so the
+  /// server can start alongside a database that is still booting.
+  pub async fn connect(
+    url: &str,
+    max_connections: u32,
+    attempts: u32,
+  ) -> Result<Pool<Postgres>, sqlx::Error> {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+      let pool = PgPoolOptions::new()
+        .max_connections(max_connections)
+        .connect(url);
+      match actix_web::rt::time::timeout(CONNECT_TIMEOUT, pool)
+        .await
+        .unwrap_or(Err(sqlx::Error::PoolTimedOut))
+      {
+        Ok(pool) => return Ok(pool),
+        Err(e) if attempt < attempts => {
+          tracing::warn!(
+            attempt,
+            attempts,
+            retry_in_secs = delay.as_secs(),
+            error = %e,
+            "Database is unavailable"
+          );
+          actix_web::rt::time::sleep(delay).await;
+          delay = (delay * 2).min(MAX_CONNECT_DELAY);
+          attempt += 1;
+        }
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  /// Waits for checked-out connections to be returned, then closes them all.
+  pub async fn close(&self) {
+    self.pool.close().await;
+  }
+
+  /// Open and idle connections in the pool.
+  pub fn pool_stats(&self) -> (u32, usize) {
+    (self.pool.size(), self.pool.num_idle())
+  }
+
+  /// Removes a test fixture. The API only ever deactivates users, so their
+  /// audit and revocation trail is kept.
+  #[cfg(test)]
+  pub async fn delete_user(&self, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+      .execute(&self.pool)
+      .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
 }
 
 #[async_trait]
@@ -31,16 +113,27 @@ pub trait UserExt {
     email: T,
     password: T,
   ) -> Result<User, sqlx::Error>;
+  async fn update_user_password(&self, user_id: Uuid, password: &str) -> Result<(), sqlx::Error>;
+  async fn update_user_role(&self, user_id: Uuid, role: UserRole) -> Result<User, sqlx::Error>;
   async fn save_admin_user<T: Into<String> + Send>(
     &self,
     name: T,
     email: T,
     password: T,
   ) -> Result<User, sqlx::Error>;
+
+  /// Rejects every access token issued to the user so far and revokes their
+  /// refresh tokens. Returns the number of refresh tokens revoked.
+  async fn revoke_user_sessions(&self, user_id: Uuid) -> Result<u64, sqlx::Error>;
+
+  /// Whether a token issued to the user at `issued_at`, in seconds since the
+  /// epoch, predates the last revocation of their sessions.
+  async fn is_session_revoked(&self, user_id: Uuid, issued_at: i64) -> Result<bool, sqlx::Error>;
 }
 
 #[async_trait]
 impl UserExt for DBClient {
+  #[instrument(skip_all)]
   async fn get_user(
     &self,
     user_id: Option<Uuid>,
@@ -54,7 +147,7 @@ impl UserExt for DBClient {
         User,
         r#"
             SELECT id, name, email, password, photo, verified, created_at,
-            updated_at, role as "role: UserRole" FROM users WHERE id = $1
+            updated_at, active, role as "role: UserRole" FROM users WHERE id = $1
         "#,
         user_id
       )
@@ -65,7 +158,7 @@ impl UserExt for DBClient {
         User,
         r#"
             SELECT id, name, email, password, photo, verified, created_at,
-            updated_at, role as "role: UserRole" FROM users WHERE name = $1
+            updated_at, active, role as "role: UserRole" FROM users WHERE name = $1
         "#,
         name
       )
@@ -76,7 +169,7 @@ impl UserExt for DBClient {
         User,
         r#"
             SELECT id, name, email, password, photo, verified, created_at,
-            updated_at, role as "role: UserRole" FROM users WHERE email = $1
+            updated_at, active, role as "role: UserRole" FROM users WHERE email = $1
         "#,
         email
       )
@@ -88,6 +181,7 @@ impl UserExt for DBClient {
   }
   //
 
+  #[instrument(skip_all)]
   async fn get_users(&self, page: u32, limit: usize) -> Result<Vec<User>, sqlx::Error> {
     let offset = (page - 1) * limit as u32;
 
@@ -95,7 +189,7 @@ impl UserExt for DBClient {
       User,
       r#"
         SELECT id, name, email, password, photo, verified, created_at,
-        updated_at, role as "role: UserRole" FROM users LIMIT $1 OFFSET $2
+        updated_at, active, role as "role: UserRole" FROM users LIMIT $1 OFFSET $2
       "#,
       limit as i64,
       offset as i64
@@ -106,6 +200,7 @@ impl UserExt for DBClient {
     Ok(users)
   }
 
+  #[instrument(skip_all)]
   async fn save_user<T: Into<String> + Send>(
     &self,
     name: T,
@@ -117,7 +212,7 @@ impl UserExt for DBClient {
       r#"
         INSERT INTO users (name, email, password) VALUES($1, $2, $3)
         RETURNING id, name, email, password, photo, verified, created_at,
-        updated_at, role as "role: UserRole"
+        updated_at, active, role as "role: UserRole"
       "#,
       name.into(),
       email.into(),
@@ -128,6 +223,7 @@ impl UserExt for DBClient {
 
     Ok(user)
   }
+  #[instrument(skip_all)]
   async fn save_admin_user<T: Into<String> + Send>(
     &self,
     name: T,
@@ -138,7 +234,7 @@ impl UserExt for DBClient {
       User,
       r#"
         INSERT INTO users (name, email, password, role) VALUES ($1, $2, $3, $4) RETURNING 
-        id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole"
+        id, name, email, password, photo, verified, created_at, updated_at, active, role as "role: UserRole"
       "#,
       name.into(),
       email.into(),
@@ -150,4 +246,1442 @@ impl UserExt for DBClient {
 
     Ok(user)
   }
+
+  #[instrument(skip_all)]
+  async fn update_user_password(&self, user_id: Uuid, password: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+      "UPDATE users SET password = $1, updated_at = NOW() WHERE id = $2",
+      password,
+      user_id
+    )
+    .execute(&self.pool)
+    .await?;
+
+    Ok(())
+  }
+
+  #[instrument(skip_all)]
+  async fn update_user_role(&self, user_id: Uuid, role: UserRole) -> Result<User, sqlx::Error> {
+    let user = sqlx::query_as!(
+      User,
+      r#"
+        UPDATE users SET role = $1, updated_at = NOW() WHERE id = $2
+        RETURNING id, name, email, password, photo, verified, created_at,
+        updated_at, active, role as "role: UserRole"
+      "#,
+      role as UserRole,
+      user_id
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(user)
+  }
+
+  #[instrument(skip_all)]
+  async fn revoke_user_sessions(&self, user_id: Uuid) -> Result<u64, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+
+    sqlx::query!(
+      "UPDATE users SET sessions_revoked_at = NOW() WHERE id = $1",
+      user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let result = sqlx::query!(
+      "UPDATE oauth_refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+      user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(result.rows_affected())
+  }
+
+  #[instrument(skip_all)]
+  async fn is_session_revoked(&self, user_id: Uuid, issued_at: i64) -> Result<bool, sqlx::Error> {
+    let revoked = sqlx::query_scalar!(
+      r#"SELECT COALESCE(sessions_revoked_at >= to_timestamp($2), false) as "revoked!"
+        FROM users WHERE id = $1"#,
+      user_id,
+      issued_at as f64
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(revoked.unwrap_or(false))
+  }
+}
+
+#[async_trait]
+pub trait InviteExt {
+  async fn save_invite(
+    &self,
+    code: &str,
+    role: UserRole,
+    created_by: Uuid,
+    expires_at: Option<DateTime<Utc>>,
+  ) -> Result<Invite, sqlx::Error>;
+
+  async fn get_invites(&self, page: u32, limit: usize) -> Result<Vec<Invite>, sqlx::Error>;
+
+  /// Claims an unused, unexpired invite and creates the user with the invite's role
+  /// in a single transaction. Returns `None` if the code cannot be redeemed.
+  async fn save_invited_user<T: Into<String> + Send>(
+    &self,
+    name: T,
+    email: T,
+    password: T,
+    code: &str,
+  ) -> Result<Option<User>, sqlx::Error>;
+}
+
+#[async_trait]
+impl InviteExt for DBClient {
+  #[instrument(skip_all)]
+  async fn save_invite(
+    &self,
+    code: &str,
+    role: UserRole,
+    created_by: Uuid,
+    expires_at: Option<DateTime<Utc>>,
+  ) -> Result<Invite, sqlx::Error> {
+    let invite = sqlx::query_as!(
+      Invite,
+      r#"
+        INSERT INTO invites (code, role, created_by, expires_at) VALUES ($1, $2, $3, $4)
+        RETURNING id, code, role as "role: UserRole", created_by, used_by, expires_at,
+        used_at, created_at
+      "#,
+      code,
+      role as UserRole,
+      created_by,
+      expires_at
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(invite)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_invites(&self, page: u32, limit: usize) -> Result<Vec<Invite>, sqlx::Error> {
+    let offset = (page - 1) * limit as u32;
+
+    let invites = sqlx::query_as!(
+      Invite,
+      r#"
+        SELECT id, code, role as "role: UserRole", created_by, used_by, expires_at,
+        used_at, created_at FROM invites ORDER BY created_at DESC LIMIT $1 OFFSET $2
+      "#,
+      limit as i64,
+      offset as i64
+    )
+    .fetch_all(&self.pool)
+    .await?;
+
+    Ok(invites)
+  }
+
+  #[instrument(skip_all)]
+  async fn save_invited_user<T: Into<String> + Send>(
+    &self,
+    name: T,
+    email: T,
+    password: T,
+    code: &str,
+  ) -> Result<Option<User>, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+
+    let role = sqlx::query_scalar!(
+      r#"
+        UPDATE invites SET used_at = NOW()
+        WHERE code = $1 AND used_at IS NULL AND (expires_at IS NULL OR expires_at > NOW())
+        RETURNING role as "role: UserRole"
+      "#,
+      code
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(role) = role else {
+      return Ok(None);
+    };
+
+    let user = sqlx::query_as!(
+      User,
+      r#"
+        INSERT INTO users (name, email, password, role) VALUES ($1, $2, $3, $4)
+        RETURNING id, name, email, password, photo, verified, created_at,
+        updated_at, active, role as "role: UserRole"
+      "#,
+      name.into(),
+      email.into(),
+      password.into(),
+      role as UserRole
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+      "UPDATE invites SET used_by = $1 WHERE code = $2",
+      user.id,
+      code
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Some(user))
+  }
+}
+
+#[async_trait]
+pub trait ImpersonationExt {
+  async fn start_impersonation(
+    &self,
+    impersonator_id: Uuid,
+    target_id: Uuid,
+    reason: Option<&str>,
+    ip_address: Option<&str>,
+    user_agent: Option<&str>,
+    expires_at: DateTime<Utc>,
+  ) -> Result<ImpersonationSession, sqlx::Error>;
+
+  async fn get_active_impersonation(
+    &self,
+    session_id: Uuid,
+  ) -> Result<Option<ImpersonationSession>, sqlx::Error>;
+
+  async fn end_impersonation(
+    &self,
+    session_id: Uuid,
+  ) -> Result<Option<ImpersonationSession>, sqlx::Error>;
+
+  async fn get_impersonations(
+    &self,
+    page: u32,
+    limit: usize,
+  ) -> Result<Vec<ImpersonationSession>, sqlx::Error>;
+}
+
+#[async_trait]
+impl ImpersonationExt for DBClient {
+  #[instrument(skip_all)]
+  async fn start_impersonation(
+    &self,
+    impersonator_id: Uuid,
+    target_id: Uuid,
+    reason: Option<&str>,
+    ip_address: Option<&str>,
+    user_agent: Option<&str>,
+    expires_at: DateTime<Utc>,
+  ) -> Result<ImpersonationSession, sqlx::Error> {
+    let session = sqlx::query_as!(
+      ImpersonationSession,
+      r#"
+        INSERT INTO impersonation_sessions
+        (impersonator_id, target_id, reason, ip_address, user_agent, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, impersonator_id, target_id, reason, ip_address, user_agent,
+        started_at, expires_at, ended_at
+      "#,
+      impersonator_id,
+      target_id,
+      reason,
+      ip_address,
+      user_agent,
+      expires_at
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(session)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_active_impersonation(
+    &self,
+    session_id: Uuid,
+  ) -> Result<Option<ImpersonationSession>, sqlx::Error> {
+    let session = sqlx::query_as!(
+      ImpersonationSession,
+      r#"
+        SELECT id, impersonator_id, target_id, reason, ip_address, user_agent,
+        started_at, expires_at, ended_at FROM impersonation_sessions
+        WHERE id = $1 AND ended_at IS NULL AND expires_at > NOW()
+      "#,
+      session_id
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(session)
+  }
+
+  #[instrument(skip_all)]
+  async fn end_impersonation(
+    &self,
+    session_id: Uuid,
+  ) -> Result<Option<ImpersonationSession>, sqlx::Error> {
+    let session = sqlx::query_as!(
+      ImpersonationSession,
+      r#"
+        UPDATE impersonation_sessions SET ended_at = NOW()
+        WHERE id = $1 AND ended_at IS NULL
+        RETURNING id, impersonator_id, target_id, reason, ip_address, user_agent,
+        started_at, expires_at, ended_at
+      "#,
+      session_id
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(session)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_impersonations(
+    &self,
+    page: u32,
+    limit: usize,
+  ) -> Result<Vec<ImpersonationSession>, sqlx::Error> {
+    let offset = (page - 1) * limit as u32;
+
+    let sessions = sqlx::query_as!(
+      ImpersonationSession,
+      r#"
+        SELECT id, impersonator_id, target_id, reason, ip_address, user_agent,
+        started_at, expires_at, ended_at FROM impersonation_sessions
+        ORDER BY started_at DESC LIMIT $1 OFFSET $2
+      "#,
+      limit as i64,
+      offset as i64
+    )
+    .fetch_all(&self.pool)
+    .await?;
+
+    Ok(sessions)
+  }
+}
+
+/// Key for the transaction-level advisory lock that serialises appends to the audit chain.
+const AUDIT_CHAIN_LOCK: i64 = 0x0061_7564_6974;
+
+#[async_trait]
+pub trait AuditExt {
+  /// Appends an event to the audit chain, linking it to the hash of the latest event.
+  async fn save_audit_event(&self, event: &NewAuditEvent) -> Result<AuditEvent, sqlx::Error>;
+
+  /// Lists events newest first. `user_id` matches either the actor or the target.
+  async fn get_audit_events(
+    &self,
+    user_id: Option<Uuid>,
+    event_type: Option<AuditEventType>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    page: u32,
+    limit: usize,
+  ) -> Result<Vec<AuditEvent>, sqlx::Error>;
+
+  /// Lists events in chain order, starting after `after_id`.
+  async fn get_audit_events_after(
+    &self,
+    after_id: i64,
+    limit: usize,
+  ) -> Result<Vec<AuditEvent>, sqlx::Error>;
+
+  async fn get_audit_event(&self, id: i64) -> Result<Option<AuditEvent>, sqlx::Error>;
+
+  async fn get_latest_audit_event(&self) -> Result<Option<AuditEvent>, sqlx::Error>;
+
+  /// Chains events recorded before hash chaining existed. Returns how many were sealed.
+  async fn seal_audit_events(&self) -> Result<u64, sqlx::Error>;
+
+  async fn save_audit_checkpoint(
+    &self,
+    event_id: i64,
+    event_hash: &str,
+    signature: &str,
+    created_at: DateTime<Utc>,
+  ) -> Result<AuditCheckpoint, sqlx::Error>;
+
+  async fn get_latest_audit_checkpoint(&self) -> Result<Option<AuditCheckpoint>, sqlx::Error>;
+
+  async fn get_audit_checkpoints(
+    &self,
+    page: u32,
+    limit: usize,
+  ) -> Result<Vec<AuditCheckpoint>, sqlx::Error>;
+}
+
+#[async_trait]
+impl AuditExt for DBClient {
+  #[instrument(skip_all)]
+  async fn save_audit_event(&self, event: &NewAuditEvent) -> Result<AuditEvent, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+
+    sqlx::query!("SELECT pg_advisory_xact_lock($1)", AUDIT_CHAIN_LOCK)
+      .execute(&mut *tx)
+      .await?;
+
+    let prev_hash = sqlx::query_scalar!(
+      "SELECT hash FROM audit_events WHERE hash IS NOT NULL ORDER BY id DESC LIMIT 1"
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten()
+    .unwrap_or(audit::GENESIS_HASH.to_string());
+
+    let created_at = Utc::now().trunc_subsecs(6);
+    let hash = event.chain_hash(&prev_hash, created_at);
+
+    let event = sqlx::query_as!(
+      AuditEvent,
+      r#"
+        INSERT INTO audit_events
+        (event_type, outcome, actor_id, target_id, ip_address, user_agent, metadata,
+        created_at, prev_hash, hash)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        RETURNING id, event_type as "event_type: AuditEventType",
+        outcome as "outcome: AuditOutcome", actor_id, target_id, ip_address, user_agent,
+        metadata, created_at, prev_hash, hash
+      "#,
+      event.event_type as AuditEventType,
+      event.outcome as AuditOutcome,
+      event.actor_id,
+      event.target_id,
+      event.ip_address,
+      event.user_agent,
+      event.metadata,
+      created_at,
+      prev_hash,
+      hash
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(event)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_audit_events(
+    &self,
+    user_id: Option<Uuid>,
+    event_type: Option<AuditEventType>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    page: u32,
+    limit: usize,
+  ) -> Result<Vec<AuditEvent>, sqlx::Error> {
+    let offset = (page - 1) * limit as u32;
+
+    let events = sqlx::query_as!(
+      AuditEvent,
+      r#"
+        SELECT id, event_type as "event_type: AuditEventType",
+        outcome as "outcome: AuditOutcome", actor_id, target_id, ip_address, user_agent,
+        metadata, created_at, prev_hash, hash FROM audit_events
+        WHERE ($1::uuid IS NULL OR actor_id = $1 OR target_id = $1)
+        AND ($2::audit_event_type IS NULL OR event_type = $2)
+        AND ($3::timestamptz IS NULL OR created_at >= $3)
+        AND ($4::timestamptz IS NULL OR created_at < $4)
+        ORDER BY id DESC LIMIT $5 OFFSET $6
+      "#,
+      user_id,
+      event_type as Option<AuditEventType>,
+      from,
+      to,
+      limit as i64,
+      offset as i64
+    )
+    .fetch_all(&self.pool)
+    .await?;
+
+    Ok(events)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_audit_events_after(
+    &self,
+    after_id: i64,
+    limit: usize,
+  ) -> Result<Vec<AuditEvent>, sqlx::Error> {
+    let events = sqlx::query_as!(
+      AuditEvent,
+      r#"
+        SELECT id, event_type as "event_type: AuditEventType",
+        outcome as "outcome: AuditOutcome", actor_id, target_id, ip_address, user_agent,
+        metadata, created_at, prev_hash, hash FROM audit_events
+        WHERE id > $1 ORDER BY id LIMIT $2
+      "#,
+      after_id,
+      limit as i64
+    )
+    .fetch_all(&self.pool)
+    .await?;
+
+    Ok(events)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_audit_event(&self, id: i64) -> Result<Option<AuditEvent>, sqlx::Error> {
+    let event = sqlx::query_as!(
+      AuditEvent,
+      r#"
+        SELECT id, event_type as "event_type: AuditEventType",
+        outcome as "outcome: AuditOutcome", actor_id, target_id, ip_address, user_agent,
+        metadata, created_at, prev_hash, hash FROM audit_events WHERE id = $1
+      "#,
+      id
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(event)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_latest_audit_event(&self) -> Result<Option<AuditEvent>, sqlx::Error> {
+    let event = sqlx::query_as!(
+      AuditEvent,
+      r#"
+        SELECT id, event_type as "event_type: AuditEventType",
+        outcome as "outcome: AuditOutcome", actor_id, target_id, ip_address, user_agent,
+        metadata, created_at, prev_hash, hash FROM audit_events ORDER BY id DESC LIMIT 1
+      "#
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(event)
+  }
+
+  #[instrument(skip_all)]
+  async fn seal_audit_events(&self) -> Result<u64, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+
+    sqlx::query!("SELECT pg_advisory_xact_lock($1)", AUDIT_CHAIN_LOCK)
+      .execute(&mut *tx)
+      .await?;
+
+    let unsealed = sqlx::query_as!(
+      AuditEvent,
+      r#"
+        SELECT id, event_type as "event_type: AuditEventType",
+        outcome as "outcome: AuditOutcome", actor_id, target_id, ip_address, user_agent,
+        metadata, created_at, prev_hash, hash FROM audit_events
+        WHERE hash IS NULL ORDER BY id
+      "#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let Some(first) = unsealed.first() else {
+      return Ok(0);
+    };
+
+    let mut prev_hash = sqlx::query_scalar!(
+      "SELECT hash FROM audit_events WHERE id < $1 AND hash IS NOT NULL ORDER BY id DESC LIMIT 1",
+      first.id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten()
+    .unwrap_or(audit::GENESIS_HASH.to_string());
+
+    for event in &unsealed {
+      let hash = NewAuditEvent::from(event).chain_hash(&prev_hash, event.created_at);
+
+      sqlx::query!(
+        "UPDATE audit_events SET prev_hash = $1, hash = $2 WHERE id = $3",
+        prev_hash,
+        hash,
+        event.id
+      )
+      .execute(&mut *tx)
+      .await?;
+
+      prev_hash = hash;
+    }
+
+    tx.commit().await?;
+
+    Ok(unsealed.len() as u64)
+  }
+
+  #[instrument(skip_all)]
+  async fn save_audit_checkpoint(
+    &self,
+    event_id: i64,
+    event_hash: &str,
+    signature: &str,
+    created_at: DateTime<Utc>,
+  ) -> Result<AuditCheckpoint, sqlx::Error> {
+    let checkpoint = sqlx::query_as!(
+      AuditCheckpoint,
+      r#"
+        INSERT INTO audit_checkpoints (event_id, event_hash, signature, created_at)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, event_id, event_hash, signature, created_at
+      "#,
+      event_id,
+      event_hash,
+      signature,
+      created_at
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(checkpoint)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_latest_audit_checkpoint(&self) -> Result<Option<AuditCheckpoint>, sqlx::Error> {
+    let checkpoint = sqlx::query_as!(
+      AuditCheckpoint,
+      r#"
+        SELECT id, event_id, event_hash, signature, created_at FROM audit_checkpoints
+        ORDER BY id DESC LIMIT 1
+      "#
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(checkpoint)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_audit_checkpoints(
+    &self,
+    page: u32,
+    limit: usize,
+  ) -> Result<Vec<AuditCheckpoint>, sqlx::Error> {
+    let offset = (page - 1) * limit as u32;
+
+    let checkpoints = sqlx::query_as!(
+      AuditCheckpoint,
+      r#"
+        SELECT id, event_id, event_hash, signature, created_at FROM audit_checkpoints
+        ORDER BY id DESC LIMIT $1 OFFSET $2
+      "#,
+      limit as i64,
+      offset as i64
+    )
+    .fetch_all(&self.pool)
+    .await?;
+
+    Ok(checkpoints)
+  }
+}
+
+#[async_trait]
+pub trait OAuthExt {
+  async fn save_oauth_client(
+    &self,
+    client_id: &str,
+    client_secret: Option<&str>,
+    name: &str,
+    redirect_uris: &[String],
+    role: Option<UserRole>,
+    created_by: Uuid,
+  ) -> Result<OAuthClient, sqlx::Error>;
+
+  async fn get_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClient>, sqlx::Error>;
+
+  async fn get_oauth_clients(
+    &self,
+    page: u32,
+    limit: usize,
+  ) -> Result<Vec<OAuthClient>, sqlx::Error>;
+
+  async fn save_authorization_code(
+    &self,
+    code: &NewAuthorizationCode,
+  ) -> Result<AuthorizationCode, sqlx::Error>;
+
+  /// Marks an unused, unexpired code as used and returns it. Codes are single-use.
+  async fn consume_authorization_code(
+    &self,
+    code_hash: &str,
+  ) -> Result<Option<AuthorizationCode>, sqlx::Error>;
+
+  async fn save_refresh_token(
+    &self,
+    token_hash: &str,
+    client_id: &str,
+    user_id: Uuid,
+    scope: &str,
+    auth_time: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+  ) -> Result<RefreshToken, sqlx::Error>;
+
+  /// Revokes an active refresh token and returns it, so a new one can be issued in its place.
+  async fn consume_refresh_token(
+    &self,
+    token_hash: &str,
+  ) -> Result<Option<RefreshToken>, sqlx::Error>;
+
+  async fn get_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshToken>, sqlx::Error>;
+
+  /// Adds a JWT to the revocation list and prunes entries that have expired.
+  async fn revoke_access_token(
+    &self,
+    jti: Uuid,
+    expires_at: DateTime<Utc>,
+  ) -> Result<(), sqlx::Error>;
+
+  async fn is_access_token_revoked(&self, jti: Uuid) -> Result<bool, sqlx::Error>;
+}
+
+#[async_trait]
+impl OAuthExt for DBClient {
+  #[instrument(skip_all)]
+  async fn save_oauth_client(
+    &self,
+    client_id: &str,
+    client_secret: Option<&str>,
+    name: &str,
+    redirect_uris: &[String],
+    role: Option<UserRole>,
+    created_by: Uuid,
+  ) -> Result<OAuthClient, sqlx::Error> {
+    let client = sqlx::query_as!(
+      OAuthClient,
+      r#"
+        INSERT INTO oauth_clients (client_id, client_secret, name, redirect_uris, role, created_by)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, client_id, client_secret, name, redirect_uris, role as "role: UserRole",
+        created_by, created_at
+      "#,
+      client_id,
+      client_secret,
+      name,
+      redirect_uris,
+      role as Option<UserRole>,
+      created_by
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(client)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClient>, sqlx::Error> {
+    let client = sqlx::query_as!(
+      OAuthClient,
+      r#"
+        SELECT id, client_id, client_secret, name, redirect_uris, role as "role: UserRole",
+        created_by, created_at
+        FROM oauth_clients WHERE client_id = $1
+      "#,
+      client_id
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(client)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_oauth_clients(
+    &self,
+    page: u32,
+    limit: usize,
+  ) -> Result<Vec<OAuthClient>, sqlx::Error> {
+    let offset = (page - 1) * limit as u32;
+
+    let clients = sqlx::query_as!(
+      OAuthClient,
+      r#"
+        SELECT id, client_id, client_secret, name, redirect_uris, role as "role: UserRole",
+        created_by, created_at
+        FROM oauth_clients ORDER BY created_at DESC LIMIT $1 OFFSET $2
+      "#,
+      limit as i64,
+      offset as i64
+    )
+    .fetch_all(&self.pool)
+    .await?;
+
+    Ok(clients)
+  }
+
+  #[instrument(skip_all)]
+  async fn save_authorization_code(
+    &self,
+    code: &NewAuthorizationCode,
+  ) -> Result<AuthorizationCode, sqlx::Error> {
+    let code = sqlx::query_as!(
+      AuthorizationCode,
+      r#"
+        INSERT INTO oauth_authorization_codes
+        (code_hash, client_id, user_id, redirect_uri, scope, code_challenge,
+        code_challenge_method, nonce, auth_time, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        RETURNING code_hash, client_id, user_id, redirect_uri, scope, code_challenge,
+        code_challenge_method, nonce, auth_time, expires_at, used_at, created_at
+      "#,
+      code.code_hash,
+      code.client_id,
+      code.user_id,
+      code.redirect_uri,
+      code.scope,
+      code.code_challenge,
+      code.code_challenge_method,
+      code.nonce,
+      code.auth_time,
+      code.expires_at
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(code)
+  }
+
+  #[instrument(skip_all)]
+  async fn consume_authorization_code(
+    &self,
+    code_hash: &str,
+  ) -> Result<Option<AuthorizationCode>, sqlx::Error> {
+    let code = sqlx::query_as!(
+      AuthorizationCode,
+      r#"
+        UPDATE oauth_authorization_codes SET used_at = NOW()
+        WHERE code_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING code_hash, client_id, user_id, redirect_uri, scope, code_challenge,
+        code_challenge_method, nonce, auth_time, expires_at, used_at, created_at
+      "#,
+      code_hash
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(code)
+  }
+
+  #[instrument(skip_all)]
+  async fn save_refresh_token(
+    &self,
+    token_hash: &str,
+    client_id: &str,
+    user_id: Uuid,
+    scope: &str,
+    auth_time: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+  ) -> Result<RefreshToken, sqlx::Error> {
+    let token = sqlx::query_as!(
+      RefreshToken,
+      r#"
+        INSERT INTO oauth_refresh_tokens
+        (token_hash, client_id, user_id, scope, auth_time, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, token_hash, client_id, user_id, scope, auth_time, expires_at, revoked_at,
+        created_at
+      "#,
+      token_hash,
+      client_id,
+      user_id,
+      scope,
+      auth_time,
+      expires_at
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(token)
+  }
+
+  #[instrument(skip_all)]
+  async fn consume_refresh_token(
+    &self,
+    token_hash: &str,
+  ) -> Result<Option<RefreshToken>, sqlx::Error> {
+    let token = sqlx::query_as!(
+      RefreshToken,
+      r#"
+        UPDATE oauth_refresh_tokens SET revoked_at = NOW()
+        WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        RETURNING id, token_hash, client_id, user_id, scope, auth_time, expires_at, revoked_at,
+        created_at
+      "#,
+      token_hash
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(token)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshToken>, sqlx::Error> {
+    let token = sqlx::query_as!(
+      RefreshToken,
+      r#"
+        SELECT id, token_hash, client_id, user_id, scope, auth_time, expires_at, revoked_at,
+        created_at
+        FROM oauth_refresh_tokens WHERE token_hash = $1
+      "#,
+      token_hash
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(token)
+  }
+
+  #[instrument(skip_all)]
+  async fn revoke_access_token(
+    &self,
+    jti: Uuid,
+    expires_at: DateTime<Utc>,
+  ) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at < NOW()")
+      .execute(&self.pool)
+      .await?;
+
+    sqlx::query!(
+      r#"
+        INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2)
+        ON CONFLICT (jti) DO NOTHING
+      "#,
+      jti,
+      expires_at
+    )
+    .execute(&self.pool)
+    .await?;
+
+    Ok(())
+  }
+
+  #[instrument(skip_all)]
+  async fn is_access_token_revoked(&self, jti: Uuid) -> Result<bool, sqlx::Error> {
+    let revoked = sqlx::query_scalar!(
+      r#"SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1) as "revoked!""#,
+      jti
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(revoked)
+  }
+}
+
+#[async_trait]
+pub trait SocialExt {
+  async fn save_social_login_request(
+    &self,
+    state_hash: &str,
+    provider: &str,
+    code_verifier: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<SocialLoginRequest, sqlx::Error>;
+
+  /// Deletes an unexpired login request and returns it. Each `state` is single-use.
+  async fn consume_social_login_request(
+    &self,
+    state_hash: &str,
+    provider: &str,
+  ) -> Result<Option<SocialLoginRequest>, sqlx::Error>;
+
+  async fn get_user_by_identity(
+    &self,
+    provider: &str,
+    subject: &str,
+  ) -> Result<Option<User>, sqlx::Error>;
+
+  async fn save_user_identity(
+    &self,
+    user_id: Uuid,
+    provider: &str,
+    subject: &str,
+    email: Option<&str>,
+  ) -> Result<UserIdentity, sqlx::Error>;
+
+  /// Creates a user and links the external identity in one transaction.
+  async fn save_social_user(
+    &self,
+    name: &str,
+    email: &str,
+    password: &str,
+    provider: &str,
+    subject: &str,
+  ) -> Result<User, sqlx::Error>;
+}
+
+#[async_trait]
+impl SocialExt for DBClient {
+  #[instrument(skip_all)]
+  async fn save_social_login_request(
+    &self,
+    state_hash: &str,
+    provider: &str,
+    code_verifier: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<SocialLoginRequest, sqlx::Error> {
+    sqlx::query!("DELETE FROM social_login_requests WHERE expires_at < NOW()")
+      .execute(&self.pool)
+      .await?;
+
+    let request = sqlx::query_as!(
+      SocialLoginRequest,
+      r#"
+        INSERT INTO social_login_requests (state_hash, provider, code_verifier, expires_at)
+        VALUES ($1, $2, $3, $4)
+        RETURNING state_hash, provider, code_verifier, expires_at, created_at
+      "#,
+      state_hash,
+      provider,
+      code_verifier,
+      expires_at
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(request)
+  }
+
+  #[instrument(skip_all)]
+  async fn consume_social_login_request(
+    &self,
+    state_hash: &str,
+    provider: &str,
+  ) -> Result<Option<SocialLoginRequest>, sqlx::Error> {
+    let request = sqlx::query_as!(
+      SocialLoginRequest,
+      r#"
+        DELETE FROM social_login_requests
+        WHERE state_hash = $1 AND provider = $2 AND expires_at > NOW()
+        RETURNING state_hash, provider, code_verifier, expires_at, created_at
+      "#,
+      state_hash,
+      provider
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(request)
+  }
+
+  #[instrument(skip_all)]
+  async fn get_user_by_identity(
+    &self,
+    provider: &str,
+    subject: &str,
+  ) -> Result<Option<User>, sqlx::Error> {
+    let user = sqlx::query_as!(
+      User,
+      r#"
+        SELECT u.id, u.name, u.email, u.password, u.photo, u.verified, u.created_at,
+        u.updated_at, u.active, u.role as "role: UserRole"
+        FROM users u JOIN user_identities i ON i.user_id = u.id
+        WHERE i.provider = $1 AND i.subject = $2
+      "#,
+      provider,
+      subject
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(user)
+  }
+
+  #[instrument(skip_all)]
+  async fn save_user_identity(
+    &self,
+    user_id: Uuid,
+    provider: &str,
+    subject: &str,
+    email: Option<&str>,
+  ) -> Result<UserIdentity, sqlx::Error> {
+    let identity = sqlx::query_as!(
+      UserIdentity,
+      r#"
+        INSERT INTO user_identities (user_id, provider, subject, email)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, user_id, provider, subject, email, created_at
+      "#,
+      user_id,
+      provider,
+      subject,
+      email
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(identity)
+  }
+
+  #[instrument(skip_all)]
+  async fn save_social_user(
+    &self,
+    name: &str,
+    email: &str,
+    password: &str,
+    provider: &str,
+    subject: &str,
+  ) -> Result<User, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+
+    let user = sqlx::query_as!(
+      User,
+      r#"
+        INSERT INTO users (name, email, password, verified) VALUES ($1, $2, $3, true)
+        RETURNING id, name, email, password, photo, verified, created_at,
+        updated_at, active, role as "role: UserRole"
+      "#,
+      name,
+      email,
+      password
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+      r#"
+        INSERT INTO user_identities (user_id, provider, subject, email)
+        VALUES ($1, $2, $3, $4)
+      "#,
+      user.id,
+      provider,
+      subject,
+      email
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(user)
+  }
+}
+
+#[async_trait]
+pub trait SamlExt {
+  async fn save_saml_request(
+    &self,
+    request_id: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<SamlRequest, sqlx::Error>;
+
+  /// Deletes an unexpired AuthnRequest and returns it, so every response the IdP
+  /// sends back can be redeemed only once.
+  async fn consume_saml_request(
+    &self,
+    request_id: &str,
+  ) -> Result<Option<SamlRequest>, sqlx::Error>;
+}
+
+#[async_trait]
+impl SamlExt for DBClient {
+  #[instrument(skip_all)]
+  async fn save_saml_request(
+    &self,
+    request_id: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<SamlRequest, sqlx::Error> {
+    sqlx::query!("DELETE FROM saml_requests WHERE expires_at < NOW()")
+      .execute(&self.pool)
+      .await?;
+
+    let request = sqlx::query_as!(
+      SamlRequest,
+      r#"
+        INSERT INTO saml_requests (request_id, expires_at)
+        VALUES ($1, $2)
+        RETURNING request_id, expires_at, created_at
+      "#,
+      request_id,
+      expires_at
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok(request)
+  }
+
+  #[instrument(skip_all)]
+  async fn consume_saml_request(
+    &self,
+    request_id: &str,
+  ) -> Result<Option<SamlRequest>, sqlx::Error> {
+    let request = sqlx::query_as!(
+      SamlRequest,
+      r#"
+        DELETE FROM saml_requests
+        WHERE request_id = $1 AND expires_at > NOW()
+        RETURNING request_id, expires_at, created_at
+      "#,
+      request_id
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+
+    Ok(request)
+  }
+}
+
+#[async_trait]
+pub trait ScimExt {
+  /// Returns one page of users matching `filter` and the total number of matches.
+  async fn get_scim_users(
+    &self,
+    filter: &ScimUserFilter,
+    offset: i64,
+    limit: i64,
+  ) -> Result<(Vec<User>, i64), sqlx::Error>;
+
+  /// Maps user ids to the `externalId` the provisioning client assigned them.
+  async fn get_scim_external_ids(
+    &self,
+    user_ids: &[Uuid],
+  ) -> Result<HashMap<Uuid, String>, sqlx::Error>;
+
+  /// Creates a verified user and records its `externalId` in one transaction.
+  async fn save_scim_user(
+    &self,
+    attributes: &UserAttributes,
+    password: &str,
+  ) -> Result<User, sqlx::Error>;
+
+  /// Replaces the user's attributes. The password is kept when `password` is `None`.
+  async fn update_scim_user(
+    &self,
+    user_id: Uuid,
+    attributes: &UserAttributes,
+    password: Option<&str>,
+  ) -> Result<Option<User>, sqlx::Error>;
+}
+
+#[async_trait]
+impl ScimExt for DBClient {
+  #[instrument(skip_all)]
+  async fn get_scim_users(
+    &self,
+    filter: &ScimUserFilter,
+    offset: i64,
+    limit: i64,
+  ) -> Result<(Vec<User>, i64), sqlx::Error> {
+    let users = sqlx::query_as!(
+      User,
+      r#"
+        SELECT id, name, email, password, photo, verified, created_at,
+        updated_at, active, role as "role: UserRole" FROM users
+        WHERE ($1::uuid IS NULL OR id = $1)
+        AND ($2::text IS NULL OR lower(email) = lower($2))
+        AND ($3::text IS NULL OR EXISTS (
+          SELECT 1 FROM user_identities i
+          WHERE i.user_id = users.id AND i.provider = $4 AND i.subject = $3
+        ))
+        AND ($5::bool IS NULL OR active = $5)
+        ORDER BY created_at, id LIMIT $6 OFFSET $7
+      "#,
+      filter.id,
+      filter.email,
+      filter.external_id,
+      scim::IDENTITY_PROVIDER,
+      filter.active,
+      limit,
+      offset
+    )
+    .fetch_all(&self.pool)
+    .await?;
+
+    let total = sqlx::query_scalar!(
+      r#"
+        SELECT COUNT(*) as "count!" FROM users
+        WHERE ($1::uuid IS NULL OR id = $1)
+        AND ($2::text IS NULL OR lower(email) = lower($2))
+        AND ($3::text IS NULL OR EXISTS (
+          SELECT 1 FROM user_identities i
+          WHERE i.user_id = users.id AND i.provider = $4 AND i.subject = $3
+        ))
+        AND ($5::bool IS NULL OR active = $5)
+      "#,
+      filter.id,
+      filter.email,
+      filter.external_id,
+      scim::IDENTITY_PROVIDER,
+      filter.active
+    )
+    .fetch_one(&self.pool)
+    .await?;
+
+    Ok((users, total))
+  }
+
+  #[instrument(skip_all)]
+  async fn get_scim_external_ids(
+    &self,
+    user_ids: &[Uuid],
+  ) -> Result<HashMap<Uuid, String>, sqlx::Error> {
+    let rows = sqlx::query!(
+      r#"
+        SELECT user_id, subject FROM user_identities
+        WHERE provider = $1 AND user_id = ANY($2)
+      "#,
+      scim::IDENTITY_PROVIDER,
+      user_ids
+    )
+    .fetch_all(&self.pool)
+    .await?;
+
+    Ok(
+      rows
+        .into_iter()
+        .map(|row| (row.user_id, row.subject))
+        .collect(),
+    )
+  }
+
+  #[instrument(skip_all)]
+  async fn save_scim_user(
+    &self,
+    attributes: &UserAttributes,
+    password: &str,
+  ) -> Result<User, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+
+    let user = sqlx::query_as!(
+      User,
+      r#"
+        INSERT INTO users (name, email, password, role, active, verified)
+        VALUES ($1, $2, $3, $4, $5, true)
+        RETURNING id, name, email, password, photo, verified, created_at,
+        updated_at, active, role as "role: UserRole"
+      "#,
+      attributes.name,
+      attributes.email,
+      password,
+      attributes.role as UserRole,
+      attributes.active
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if let Some(external_id) = &attributes.external_id {
+      sqlx::query!(
+        r#"
+          INSERT INTO user_identities (user_id, provider, subject, email)
+          VALUES ($1, $2, $3, $4)
+        "#,
+        user.id,
+        scim::IDENTITY_PROVIDER,
+        external_id,
+        user.email
+      )
+      .execute(&mut *tx)
+      .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(user)
+  }
+
+  #[instrument(skip_all)]
+  async fn update_scim_user(
+    &self,
+    user_id: Uuid,
+    attributes: &UserAttributes,
+    password: Option<&str>,
+  ) -> Result<Option<User>, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+
+    let user = sqlx::query_as!(
+      User,
+      r#"
+        UPDATE users SET name = $1, email = $2, role = $3, active = $4,
+        password = COALESCE($5, password), updated_at = NOW() WHERE id = $6
+        RETURNING id, name, email, password, photo, verified, created_at,
+        updated_at, active, role as "role: UserRole"
+      "#,
+      attributes.name,
+      attributes.email,
+      attributes.role as UserRole,
+      attributes.active,
+      password,
+      user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user = match user {
+      Some(user) => user,
+      None => return Ok(None),
+    };
+
+    sqlx::query!(
+      "DELETE FROM user_identities WHERE user_id = $1 AND provider = $2",
+      user.id,
+      scim::IDENTITY_PROVIDER
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(external_id) = &attributes.external_id {
+      sqlx::query!(
+        r#"
+          INSERT INTO user_identities (user_id, provider, subject, email)
+          VALUES ($1, $2, $3, $4)
+        "#,
+        user.id,
+        scim::IDENTITY_PROVIDER,
+        external_id,
+        user.email
+      )
+      .execute(&mut *tx)
+      .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Some(user))
+  }
+}
+
+#[async_trait]
+pub trait HealthExt {
+  async fn ping(&self) -> Result<(), sqlx::Error>;
+
+  /// Number of migrations bundled with this build that have not been applied.
+  async fn pending_migrations(&self) -> Result<usize, sqlx::Error>;
+}
+
+#[async_trait]
+impl HealthExt for DBClient {
+  #[instrument(skip_all)]
+  async fn ping(&self) -> Result<(), sqlx::Error> {
+    sqlx::query!("SELECT 1 AS one")
+      .fetch_one(&self.pool)
+      .await?;
+
+    Ok(())
+  }
+
+  #[instrument(skip_all)]
+  async fn pending_migrations(&self) -> Result<usize, sqlx::Error> {
+    let mut conn = self.pool.acquire().await?;
+    let applied: Vec<i64> = conn
+      .list_applied_migrations()
+      .await?
+      .into_iter()
+      .map(|migration| migration.version)
+      .collect();
+
+    let pending = MIGRATOR
+      .iter()
+      .filter(|migration| !migration.migration_type.is_down_migration())
+      .filter(|migration| !applied.contains(&migration.version))
+      .count();
+
+    Ok(pending)
+  }
 }
@@ -1,10 +1,19 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use validator::Validate;
+use utoipa::{IntoParams, ToSchema};
+use validator::{Validate, ValidationError};
 
-use crate::models::User;
+use crate::{
+  models::{
+    AuditCheckpoint, AuditEvent, AuditEventType, ImpersonationSession, Invite, OAuthClient, User,
+    UserRole,
+  },
+  utils::token::ActorClaim,
+};
 
-#[derive(Validate, Debug, Serialize, Deserialize)]
+#[derive(Validate, Debug, Serialize, Deserialize, ToSchema)]
 pub struct RegisterUserDto {
   #[validate(length(min = 1, message = "Name is required"))]
   pub name: String,
@@ -13,10 +22,7 @@ pub struct RegisterUserDto {
     email(message = "Email is invalid")
   )]
   pub email: String,
-  #[validate(
-    length(min = 1, message = "Password is required"),
-    length(min = 6, message = "Password is must be at least 6 character")
-  )]
+  #[validate(length(min = 1, message = "Password is required"))]
   pub password: String,
   #[validate(
     length(min = 1, message = "Please confirm your password"),
@@ -24,23 +30,50 @@ pub struct RegisterUserDto {
   )]
   #[serde(rename = "passwordConfirm")]
   pub password_confirm: String,
+  #[serde(rename = "inviteCode")]
+  pub invite_code: Option<String>,
 }
 
-#[derive(Validate, Debug, Serialize, Deserialize)]
+#[derive(Validate, Debug, Serialize, Deserialize, ToSchema)]
 pub struct LoginUserDto {
   #[validate(
     length(min = 1, message = "Email is required"),
     email(message = "Email is invalid")
   )]
   pub email: String,
+  #[validate(length(min = 1, message = "Password is required"))]
+  pub password: String,
+}
+
+#[derive(Validate, Debug, Serialize, Deserialize, ToSchema)]
+pub struct ReauthenticateDto {
+  #[validate(length(min = 1, message = "Password is required"))]
+  pub password: String,
+}
+
+#[derive(Validate, Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChangePasswordDto {
+  #[validate(length(min = 1, message = "Current password is required"))]
+  #[serde(rename = "currentPassword")]
+  pub current_password: String,
+  #[validate(length(min = 1, message = "New password is required"))]
+  #[serde(rename = "newPassword")]
+  pub new_password: String,
   #[validate(
-    length(min = 1, message = "Password is required"),
-    length(min = 6, message = "Password must be at least 6 characters")
+    length(min = 1, message = "Please confirm your new password"),
+    must_match(other = "new_password", message = "Passwords do not match")
   )]
-  pub password: String,
+  #[serde(rename = "newPasswordConfirm")]
+  pub new_password_confirm: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UpdateUserRoleDto {
+  pub role: UserRole,
 }
 
-#[derive(Validate, Debug, Serialize, Deserialize)]
+#[derive(Validate, Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct RequestQueryDto {
   #[validate(range(min = 1))]
   pub page: Option<usize>,
@@ -48,7 +81,7 @@ pub struct RequestQueryDto {
   pub limit: Option<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct FilterUserDto {
   pub id: String,
   pub name: String,
@@ -56,6 +89,7 @@ pub struct FilterUserDto {
   pub role: String,
   pub photo: String,
   pub verified: bool,
+  pub active: bool,
   pub created_at: DateTime<Utc>,
   pub updated_at: DateTime<Utc>,
 }
@@ -69,6 +103,7 @@ impl FilterUserDto {
       role: user.role.to_str().to_string(),
       photo: user.photo.to_string(),
       verified: user.verified,
+      active: user.active,
       created_at: user.created_at.unwrap(),
       updated_at: user.updated_at.unwrap(),
     }
@@ -79,26 +114,416 @@ impl FilterUserDto {
   }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VerifyQueryDto {
+  /// Comma-separated roles, one of which the principal must have.
+  pub role: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct UserData {
   pub user: FilterUserDto,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub impersonator: Option<FilterUserDto>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct UserResponseDto {
   pub status: String,
   pub data: UserData,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct UserListResponseDto {
   pub status: String,
   pub users: Vec<FilterUserDto>,
   pub results: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct UserLoginResponseDto {
   pub status: String,
   pub token: String,
 }
+
+#[derive(Validate, Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateInviteDto {
+  pub role: Option<UserRole>,
+  #[validate(range(
+    min = 1,
+    max = 720,
+    message = "Invite expiry must be between 1 and 720 hours"
+  ))]
+  #[serde(rename = "expiresInHours")]
+  pub expires_in_hours: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct FilterInviteDto {
+  pub id: String,
+  pub code: String,
+  pub role: String,
+  #[serde(rename = "createdBy")]
+  pub created_by: Option<String>,
+  #[serde(rename = "usedBy")]
+  pub used_by: Option<String>,
+  #[serde(rename = "expiresAt")]
+  pub expires_at: Option<DateTime<Utc>>,
+  #[serde(rename = "usedAt")]
+  pub used_at: Option<DateTime<Utc>>,
+  #[serde(rename = "createdAt")]
+  pub created_at: DateTime<Utc>,
+}
+
+impl FilterInviteDto {
+  pub fn filter_invite(invite: &Invite) -> Self {
+    FilterInviteDto {
+      id: invite.id.to_string(),
+      code: invite.code.to_string(),
+      role: invite.role.to_str().to_string(),
+      created_by: invite.created_by.map(|id| id.to_string()),
+      used_by: invite.used_by.map(|id| id.to_string()),
+      expires_at: invite.expires_at,
+      used_at: invite.used_at,
+      created_at: invite.created_at.unwrap(),
+    }
+  }
+
+  pub fn filter_invites(invites: &[Invite]) -> Vec<FilterInviteDto> {
+    invites.iter().map(FilterInviteDto::filter_invite).collect()
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct InviteResponseDto {
+  pub status: String,
+  pub invite: FilterInviteDto,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct InviteListResponseDto {
+  pub status: String,
+  pub invites: Vec<FilterInviteDto>,
+  pub results: usize,
+}
+
+#[derive(Validate, Debug, Serialize, Deserialize, ToSchema)]
+pub struct StartImpersonationDto {
+  #[validate(length(max = 255, message = "Reason must not be more than 255 characters"))]
+  pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ImpersonationResponseDto {
+  pub status: String,
+  pub token: String,
+  pub session: ImpersonationSession,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ImpersonationListResponseDto {
+  pub status: String,
+  pub sessions: Vec<ImpersonationSession>,
+  pub results: usize,
+}
+
+#[derive(Validate, Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQueryDto {
+  #[serde(rename = "userId")]
+  pub user_id: Option<uuid::Uuid>,
+  #[serde(rename = "eventType")]
+  pub event_type: Option<AuditEventType>,
+  pub from: Option<DateTime<Utc>>,
+  pub to: Option<DateTime<Utc>>,
+  #[validate(range(min = 1))]
+  pub page: Option<usize>,
+  #[validate(range(min = 1, max = 50))]
+  pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditEventListResponseDto {
+  pub status: String,
+  pub events: Vec<AuditEvent>,
+  pub results: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditCheckpointListResponseDto {
+  pub status: String,
+  pub checkpoints: Vec<AuditCheckpoint>,
+  pub results: usize,
+}
+
+fn validate_redirect_uris(uris: &[String]) -> Result<(), ValidationError> {
+  for uri in uris {
+    match url::Url::parse(uri) {
+      Ok(url) if url.fragment().is_none() && !url.cannot_be_a_base() => {}
+      _ => {
+        return Err(ValidationError::new(
+          "Redirect URIs must be absolute URLs without fragments",
+        ))
+      }
+    }
+  }
+
+  Ok(())
+}
+
+#[derive(Validate, Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateOAuthClientDto {
+  #[validate(length(min = 1, max = 100, message = "Name is required"))]
+  pub name: String,
+  #[validate(custom = "validate_redirect_uris")]
+  #[serde(rename = "redirectUris", default)]
+  pub redirect_uris: Vec<String>,
+  pub confidential: Option<bool>,
+  /// Makes the client a service account acting with this role.
+  pub role: Option<UserRole>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OAuthClientResponseDto {
+  pub status: String,
+  pub client: OAuthClient,
+  #[serde(rename = "clientSecret", skip_serializing_if = "Option::is_none")]
+  pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OAuthClientListResponseDto {
+  pub status: String,
+  pub clients: Vec<OAuthClient>,
+  pub results: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuthorizeRequestDto {
+  pub response_type: Option<String>,
+  pub client_id: Option<String>,
+  pub redirect_uri: Option<String>,
+  pub scope: Option<String>,
+  pub state: Option<String>,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
+  pub nonce: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ConsentDto {
+  #[serde(flatten)]
+  pub request: AuthorizeRequestDto,
+  pub decision: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TokenRequestDto {
+  pub grant_type: Option<String>,
+  pub code: Option<String>,
+  pub redirect_uri: Option<String>,
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+  pub code_verifier: Option<String>,
+  pub refresh_token: Option<String>,
+  pub scope: Option<String>,
+  pub subject_token: Option<String>,
+  pub subject_token_type: Option<String>,
+  pub requested_token_type: Option<String>,
+  pub audience: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct IntrospectRequestDto {
+  pub token: Option<String>,
+  pub token_type_hint: Option<String>,
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+}
+
+/// Introspection response (RFC 7662 section 2.2). Only `active` is sent for
+/// tokens that are invalid, expired or revoked.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct IntrospectResponseDto {
+  pub active: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sub: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub username: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub role: Option<UserRole>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub client_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub scope: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub token_type: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub exp: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub iat: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub aud: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub act: Option<ActorClaim>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RevokeRequestDto {
+  pub token: Option<String>,
+  pub token_type_hint: Option<String>,
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SocialCallbackDto {
+  pub code: Option<String>,
+  pub state: Option<String>,
+  pub error: Option<String>,
+  pub error_description: Option<String>,
+}
+
+/// Form posted by the IdP to the assertion consumer service (HTTP-POST binding).
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SamlAcsDto {
+  #[serde(rename = "SAMLResponse")]
+  pub saml_response: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SocialProviderListResponseDto {
+  pub status: String,
+  pub providers: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TokenResponseDto {
+  pub access_token: String,
+  pub token_type: String,
+  pub expires_in: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub refresh_token: Option<String>,
+  pub scope: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_token: Option<String>,
+  /// Only sent by the token exchange grant (RFC 8693 section 2.2.1).
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub issued_token_type: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct HealthResponseDto {
+  pub status: String,
+  pub version: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub commit: Option<String>,
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub checks: BTreeMap<String, HealthCheckDto>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct HealthCheckDto {
+  pub status: String,
+  pub latency_ms: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub pending: Option<usize>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ScimListQueryDto {
+  pub filter: Option<String>,
+  #[serde(rename = "startIndex")]
+  pub start_index: Option<i64>,
+  pub count: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScimListResponseDto<T> {
+  pub schemas: Vec<String>,
+  #[serde(rename = "totalResults")]
+  pub total_results: i64,
+  #[serde(rename = "startIndex")]
+  pub start_index: i64,
+  #[serde(rename = "itemsPerPage")]
+  pub items_per_page: i64,
+  #[serde(rename = "Resources")]
+  pub resources: Vec<T>,
+}
+
+/// A SCIM User resource (RFC 7643 section 4.1), used for requests and responses.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUserDto {
+  #[serde(default)]
+  pub schemas: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub external_id: Option<String>,
+  pub user_name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name: Option<ScimNameDto>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub display_name: Option<String>,
+  #[serde(default)]
+  pub emails: Vec<ScimMultiValuedDto>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub active: Option<bool>,
+  #[serde(default)]
+  pub roles: Vec<ScimMultiValuedDto>,
+  #[serde(skip_serializing)]
+  pub password: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub meta: Option<ScimMetaDto>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimNameDto {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub formatted: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub given_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub family_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScimMultiValuedDto {
+  pub value: String,
+  #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+  pub kind: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub primary: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimMetaDto {
+  pub resource_type: String,
+  pub created: Option<DateTime<Utc>>,
+  pub last_modified: Option<DateTime<Utc>>,
+  pub location: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScimPatchDto {
+  #[serde(default)]
+  pub schemas: Vec<String>,
+  #[serde(rename = "Operations")]
+  pub operations: Vec<ScimPatchOperationDto>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScimPatchOperationDto {
+  pub op: String,
+  pub path: Option<String>,
+  pub value: Option<serde_json::Value>,
+}
@@ -1,30 +1,44 @@
-use std::fmt;
+use std::{collections::BTreeMap, fmt};
 
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{
+  error::{JsonPayloadError, PathError, QueryPayloadError, UrlencodedError},
+  http::{header, StatusCode},
+  web, HttpRequest, HttpResponse, ResponseError,
+};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use validator::ValidationErrors;
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-pub struct ErrorResponse {
-  pub status: String,
-  pub message: String,
-}
+use crate::utils::scim;
 
-impl fmt::Display for ErrorResponse {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "{}", serde_json::to_string(&self).unwrap())
-  }
-}
+/// Content type of problem details responses (RFC 7807).
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Response {
-  pub status: &'static str,
-  pub message: String,
+/// Problem details body (RFC 7807) of every error outside the OAuth and SCIM
+/// protocol endpoints, which use the formats of their own specifications.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+  /// Always `about:blank`: `code` identifies the problem instead.
+  #[serde(rename = "type")]
+  pub problem_type: String,
+  /// Reason phrase of the status code.
+  pub title: String,
+  pub status: u16,
+  /// Human-readable explanation, which may change between releases.
+  pub detail: String,
+  /// Stable machine-readable error code, e.g. `wrong_credentials`.
+  pub code: String,
+  /// Validation messages by field, for `validation_failed` errors.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub errors: Option<BTreeMap<String, Vec<String>>>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum ErrorMessage {
   EmptyPassword,
   ExceededMaxPasswordLength(usize),
+  PasswordTooShort(usize),
+  PasswordMissingCharacter(&'static str),
   HashingError,
   InvalidHashFormat,
   InvalidToken,
@@ -34,14 +48,49 @@ pub enum ErrorMessage {
   UserNoLongerExist,
   TokenNotProvided,
   PermissionDenied,
+  InviteCodeRequired,
+  InvalidInviteCode,
+  EmailDomainNotAllowed,
+  UserNotFound,
+  ImpersonationForbidden,
+  InvalidImpersonationTarget,
+  NotImpersonating,
+  CannotChangeOwnRole,
+  ServiceAccountForbidden,
+  UnknownProvider,
+  InvalidLoginState,
+  ExternalEmailNotVerified,
+  AccountNotVerified,
+  AccountDisabled,
+  InvalidSamlResponse,
+  ReauthenticationRequired,
+  InvalidCsrfToken,
+  ValidationFailed,
+  InvalidRequestBody(String),
+  InvalidQuery(String),
+  InvalidPath(String),
+  RouteNotFound,
+  UnknownRole(String),
+  RedirectUriRequired,
+  ServiceAccountNotConfidential,
+  ClientIdRequired,
+  UnknownClient,
+  RedirectUriNotRegistered,
+  DocsDisabled,
+  MetricsDisabled,
+  ProviderNotConfigured,
+  ProviderLoginFailed(String, String),
+  ProviderError(String),
 }
 
+#[allow(clippy::to_string_trait_impl)]
 impl ToString for ErrorMessage {
   fn to_string(&self) -> String {
     self.to_str().to_owned()
   }
 }
 
+#[allow(clippy::from_over_into)]
 impl Into<String> for ErrorMessage {
   fn into(self) -> String {
     self.to_string()
@@ -64,95 +113,266 @@ impl ErrorMessage {
       ErrorMessage::ExceededMaxPasswordLength(max_len) => {
         format!("Password must not be more that {} characters", max_len)
       }
+      ErrorMessage::PasswordTooShort(min_len) => {
+        format!("Password must be at least {} characters", min_len)
+      }
+      ErrorMessage::PasswordMissingCharacter(kind) => {
+        format!("Password must contain at least one {}", kind)
+      }
       ErrorMessage::InvalidToken => "Authentication token is invalid or expired".to_string(),
       ErrorMessage::TokenNotProvided => "You are not logged in, please provide token".to_string(),
       ErrorMessage::PermissionDenied => "You are not allowed to perform this action".to_string(),
+      ErrorMessage::InviteCodeRequired => "Registration requires an invite code".to_string(),
+      ErrorMessage::InvalidInviteCode => {
+        "Invite code is invalid, expired or already used".to_string()
+      }
+      ErrorMessage::EmailDomainNotAllowed => {
+        "Registration is not open to this email domain".to_string()
+      }
+      ErrorMessage::UserNotFound => "User not found".to_string(),
+      ErrorMessage::ImpersonationForbidden => {
+        "This action is not allowed while impersonating a user".to_string()
+      }
+      ErrorMessage::InvalidImpersonationTarget => {
+        "Admins cannot impersonate themselves or other admins".to_string()
+      }
+      ErrorMessage::NotImpersonating => "You are not impersonating a user".to_string(),
+      ErrorMessage::CannotChangeOwnRole => "You cannot change your own role".to_string(),
+      ErrorMessage::ServiceAccountForbidden => {
+        "This action is not available to service accounts".to_string()
+      }
+      ErrorMessage::UnknownProvider => "Unknown identity provider".to_string(),
+      ErrorMessage::InvalidLoginState => "Login request is invalid or expired".to_string(),
+      ErrorMessage::ExternalEmailNotVerified => {
+        "The identity provider did not return a verified email".to_string()
+      }
+      ErrorMessage::AccountNotVerified => {
+        "An unverified account already uses this email; sign in with its password instead"
+          .to_string()
+      }
+      ErrorMessage::AccountDisabled => "This account has been deactivated".to_string(),
+      ErrorMessage::InvalidSamlResponse => "The SAML response is invalid or expired".to_string(),
+      ErrorMessage::InvalidCsrfToken => "Missing or invalid CSRF token".to_string(),
+      ErrorMessage::ReauthenticationRequired => {
+        "Please confirm your password to continue".to_string()
+      }
+      ErrorMessage::ValidationFailed => "The request contains invalid values".to_string(),
+      ErrorMessage::InvalidRequestBody(reason) => format!("Request body is invalid: {}", reason),
+      ErrorMessage::InvalidQuery(reason) => format!("Query string is invalid: {}", reason),
+      ErrorMessage::InvalidPath(reason) => format!("URL path is invalid: {}", reason),
+      ErrorMessage::RouteNotFound => "No such endpoint".to_string(),
+      ErrorMessage::UnknownRole(role) => format!("Unknown role: {}", role),
+      ErrorMessage::RedirectUriRequired => "At least one redirect URI is required".to_string(),
+      ErrorMessage::ServiceAccountNotConfidential => {
+        "Service accounts must be confidential clients".to_string()
+      }
+      ErrorMessage::ClientIdRequired => "client_id is required".to_string(),
+      ErrorMessage::UnknownClient => "Unknown client_id".to_string(),
+      ErrorMessage::RedirectUriNotRegistered => {
+        "redirect_uri is not registered for this client".to_string()
+      }
+      ErrorMessage::DocsDisabled => "API docs are disabled".to_string(),
+      ErrorMessage::MetricsDisabled => "Metrics are disabled".to_string(),
+      ErrorMessage::ProviderNotConfigured => "Provider has no issuer configured".to_string(),
+      ErrorMessage::ProviderLoginFailed(provider, reason) => {
+        format!("Sign in with {} failed: {}", provider, reason)
+      }
+      ErrorMessage::ProviderError(reason) => reason.clone(),
       // ErrorMessage::_ => "".to_string(),
     }
   }
+
+  /// Stable identifier of the error for clients, unlike the message which
+  /// carries details and may be reworded.
+  pub fn code(&self) -> &'static str {
+    match self {
+      ErrorMessage::EmptyPassword => "empty_password",
+      ErrorMessage::ExceededMaxPasswordLength(_) => "password_too_long",
+      ErrorMessage::PasswordTooShort(_) => "password_too_short",
+      ErrorMessage::PasswordMissingCharacter(_) => "password_missing_character",
+      ErrorMessage::HashingError => "hashing_error",
+      ErrorMessage::InvalidHashFormat => "invalid_hash_format",
+      ErrorMessage::InvalidToken => "invalid_token",
+      ErrorMessage::ServerError => "server_error",
+      ErrorMessage::WrongCredentials => "wrong_credentials",
+      ErrorMessage::EmailExist => "email_exists",
+      ErrorMessage::UserNoLongerExist => "user_no_longer_exists",
+      ErrorMessage::TokenNotProvided => "token_not_provided",
+      ErrorMessage::PermissionDenied => "permission_denied",
+      ErrorMessage::InviteCodeRequired => "invite_code_required",
+      ErrorMessage::InvalidInviteCode => "invalid_invite_code",
+      ErrorMessage::EmailDomainNotAllowed => "email_domain_not_allowed",
+      ErrorMessage::UserNotFound => "user_not_found",
+      ErrorMessage::ImpersonationForbidden => "impersonation_forbidden",
+      ErrorMessage::InvalidImpersonationTarget => "invalid_impersonation_target",
+      ErrorMessage::NotImpersonating => "not_impersonating",
+      ErrorMessage::CannotChangeOwnRole => "cannot_change_own_role",
+      ErrorMessage::ServiceAccountForbidden => "service_account_forbidden",
+      ErrorMessage::UnknownProvider => "unknown_provider",
+      ErrorMessage::InvalidLoginState => "invalid_login_state",
+      ErrorMessage::ExternalEmailNotVerified => "external_email_not_verified",
+      ErrorMessage::AccountNotVerified => "account_not_verified",
+      ErrorMessage::AccountDisabled => "account_disabled",
+      ErrorMessage::InvalidSamlResponse => "invalid_saml_response",
+      ErrorMessage::ReauthenticationRequired => "reauthentication_required",
+      ErrorMessage::InvalidCsrfToken => "invalid_csrf_token",
+      ErrorMessage::ValidationFailed => "validation_failed",
+      ErrorMessage::InvalidRequestBody(_) => "invalid_body",
+      ErrorMessage::InvalidQuery(_) => "invalid_query",
+      ErrorMessage::InvalidPath(_) => "invalid_path",
+      ErrorMessage::RouteNotFound => "route_not_found",
+      ErrorMessage::UnknownRole(_) => "unknown_role",
+      ErrorMessage::RedirectUriRequired => "redirect_uri_required",
+      ErrorMessage::ServiceAccountNotConfidential => "service_account_not_confidential",
+      ErrorMessage::ClientIdRequired => "client_id_required",
+      ErrorMessage::UnknownClient => "unknown_client",
+      ErrorMessage::RedirectUriNotRegistered => "redirect_uri_not_registered",
+      ErrorMessage::DocsDisabled => "docs_disabled",
+      ErrorMessage::MetricsDisabled => "metrics_disabled",
+      ErrorMessage::ProviderNotConfigured => "provider_not_configured",
+      ErrorMessage::ProviderLoginFailed(..) => "provider_login_failed",
+      ErrorMessage::ProviderError(_) => "provider_error",
+    }
+  }
+}
+
+/// Message of an `HttpError`. Messages built from an `ErrorMessage` keep its
+/// code; free-form ones get a generic code for their status.
+#[derive(Debug, Clone)]
+pub struct ErrorDetail {
+  message: String,
+  code: Option<&'static str>,
+}
+
+impl From<ErrorMessage> for ErrorDetail {
+  fn from(message: ErrorMessage) -> Self {
+    ErrorDetail {
+      code: Some(message.code()),
+      message: message.to_string(),
+    }
+  }
+}
+
+impl From<String> for ErrorDetail {
+  fn from(message: String) -> Self {
+    ErrorDetail {
+      message,
+      code: None,
+    }
+  }
+}
+
+impl From<&str> for ErrorDetail {
+  fn from(message: &str) -> Self {
+    message.to_string().into()
+  }
 }
 
 #[derive(Debug, Clone)]
 pub struct HttpError {
   pub message: String,
   pub status: u16,
+  pub code: Option<&'static str>,
+  pub errors: Option<BTreeMap<String, Vec<String>>>,
 }
 
 impl HttpError {
-  pub fn new(message: impl Into<String>, status: u16) -> Self {
+  pub fn new(message: impl Into<ErrorDetail>, status: u16) -> Self {
+    let detail = message.into();
     HttpError {
-      message: message.into(),
-      status: status,
+      message: detail.message,
+      status,
+      code: detail.code,
+      errors: None,
     }
   }
 
-  pub fn server_error(message: impl Into<String>) -> Self {
-    HttpError {
-      message: message.into(),
-      status: 500,
-    }
+  pub fn server_error(message: impl Into<ErrorDetail>) -> Self {
+    HttpError::new(message, 500)
   }
 
-  pub fn bad_request(message: impl Into<String>) -> Self {
-    HttpError {
-      message: message.into(),
-      status: 400,
-    }
+  pub fn bad_request(message: impl Into<ErrorDetail>) -> Self {
+    HttpError::new(message, 400)
   }
 
-  pub fn unique_constraint_voilation(message: impl Into<String>) -> Self {
-    HttpError {
-      message: message.into(),
-      status: 409,
-    }
+  pub fn unique_constraint_voilation(message: impl Into<ErrorDetail>) -> Self {
+    HttpError::new(message, 409)
   }
 
-  pub fn unauthorized(message: impl Into<String>) -> Self {
-    HttpError {
-      message: message.into(),
-      status: 401,
-    }
+  pub fn unauthorized(message: impl Into<ErrorDetail>) -> Self {
+    HttpError::new(message, 401)
   }
-  // pub fn not_found(message: impl Into<String>) -> Self {
-  //   HttpError {
-  //     message: message.into(),
-  //     status: 404,
-  //   }
-  // }
 
-  pub fn into_http_response(self) -> HttpResponse {
-    match self.status {
-      400 => HttpResponse::BadRequest().json(Response {
-        status: "fail",
-        message: self.message.into(),
-      }),
-
-      401 => HttpResponse::Unauthorized().json(Response {
-        status: "fail",
-        message: self.message.into(),
-      }),
-
-      409 => HttpResponse::Conflict().json(Response {
-        status: "fail",
-        message: self.message.into(),
-      }),
-
-      500 => HttpResponse::InternalServerError().json(Response {
-        status: "error",
-        message: self.message.into(),
-      }),
+  pub fn forbidden(message: impl Into<ErrorDetail>) -> Self {
+    HttpError::new(message, 403)
+  }
+
+  pub fn not_found(message: impl Into<ErrorDetail>) -> Self {
+    HttpError::new(message, 404)
+  }
+
+  pub fn bad_gateway(message: impl Into<ErrorDetail>) -> Self {
+    HttpError::new(message, 502)
+  }
 
+  pub fn into_http_response(self) -> HttpResponse {
+    let status = match StatusCode::from_u16(self.status) {
+      Ok(status) if status.is_client_error() || status.is_server_error() => status,
       _ => {
-        eprintln!(
-          "Warning: Missing pattern match. Converted status code {} to 500.",
-          self.status
+        tracing::warn!(
+          status = self.status,
+          "Missing pattern match, converted status code to 500"
         );
 
-        HttpResponse::InternalServerError().json(Response {
-          status: "error",
-          message: ErrorMessage::ServerError.into(),
-        })
+        return HttpError::server_error(ErrorMessage::ServerError).into_http_response();
       }
+    };
+
+    let code = self.code.unwrap_or(match status {
+      StatusCode::BAD_REQUEST => "bad_request",
+      StatusCode::UNAUTHORIZED => "unauthorized",
+      StatusCode::FORBIDDEN => "forbidden",
+      StatusCode::NOT_FOUND => "not_found",
+      StatusCode::CONFLICT => "conflict",
+      StatusCode::BAD_GATEWAY => "bad_gateway",
+      _ if status.is_client_error() => "client_error",
+      _ => "server_error",
+    });
+
+    HttpResponse::build(status)
+      .content_type(PROBLEM_CONTENT_TYPE)
+      .json(ErrorResponse {
+        problem_type: "about:blank".to_string(),
+        title: status.canonical_reason().unwrap_or("Error").to_string(),
+        status: status.as_u16(),
+        detail: self.message,
+        code: code.to_string(),
+        errors: self.errors,
+      })
+  }
+}
+
+/// Validation failures list the messages of every invalid field.
+impl From<ValidationErrors> for HttpError {
+  fn from(errors: ValidationErrors) -> Self {
+    let fields = errors
+      .field_errors()
+      .into_iter()
+      .map(|(field, errors)| {
+        let messages = errors
+          .iter()
+          .map(|error| match &error.message {
+            Some(message) => message.to_string(),
+            None => error.code.to_string(),
+          })
+          .collect();
+        (field.to_string(), messages)
+      })
+      .collect();
+
+    HttpError {
+      errors: Some(fields),
+      ..HttpError::bad_request(ErrorMessage::ValidationFailed)
     }
   }
 }
@@ -170,8 +390,220 @@ impl fmt::Display for HttpError {
 impl std::error::Error for HttpError {}
 
 impl ResponseError for HttpError {
+  fn status_code(&self) -> StatusCode {
+    StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+  }
+
   fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
     let cloned = self.clone();
     cloned.into_http_response()
   }
 }
+
+/// Extractor settings that render malformed requests as problem details. The
+/// status of the extractor error is kept, e.g. 413 for an oversized body.
+pub fn json_config() -> web::JsonConfig {
+  web::JsonConfig::default().error_handler(|err: JsonPayloadError, _: &HttpRequest| {
+    HttpError::new(
+      ErrorMessage::InvalidRequestBody(err.to_string()),
+      err.status_code().as_u16(),
+    )
+    .into()
+  })
+}
+
+pub fn form_config() -> web::FormConfig {
+  web::FormConfig::default().error_handler(|err: UrlencodedError, _: &HttpRequest| {
+    HttpError::new(
+      ErrorMessage::InvalidRequestBody(err.to_string()),
+      err.status_code().as_u16(),
+    )
+    .into()
+  })
+}
+
+pub fn query_config() -> web::QueryConfig {
+  web::QueryConfig::default().error_handler(|err: QueryPayloadError, _: &HttpRequest| {
+    HttpError::new(
+      ErrorMessage::InvalidQuery(err.to_string()),
+      err.status_code().as_u16(),
+    )
+    .into()
+  })
+}
+
+pub fn path_config() -> web::PathConfig {
+  web::PathConfig::default().error_handler(|err: PathError, _: &HttpRequest| {
+    HttpError::new(
+      ErrorMessage::InvalidPath(err.to_string()),
+      err.status_code().as_u16(),
+    )
+    .into()
+  })
+}
+
+/// Fallback for requests that match no route.
+pub async fn route_not_found() -> Result<HttpResponse, HttpError> {
+  Err(HttpError::not_found(ErrorMessage::RouteNotFound))
+}
+
+/// Error response of the OAuth token endpoint (RFC 6749 section 5.2).
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct OAuthError {
+  pub error: &'static str,
+  pub error_description: String,
+  #[serde(skip)]
+  pub status: u16,
+}
+
+impl OAuthError {
+  fn new(error: &'static str, description: impl Into<String>, status: u16) -> Self {
+    OAuthError {
+      error,
+      error_description: description.into(),
+      status,
+    }
+  }
+
+  pub fn invalid_request(description: impl Into<String>) -> Self {
+    OAuthError::new("invalid_request", description, 400)
+  }
+
+  pub fn invalid_client(description: impl Into<String>) -> Self {
+    OAuthError::new("invalid_client", description, 401)
+  }
+
+  pub fn invalid_grant(description: impl Into<String>) -> Self {
+    OAuthError::new("invalid_grant", description, 400)
+  }
+
+  pub fn unauthorized_client(description: impl Into<String>) -> Self {
+    OAuthError::new("unauthorized_client", description, 400)
+  }
+
+  pub fn invalid_scope(description: impl Into<String>) -> Self {
+    OAuthError::new("invalid_scope", description, 400)
+  }
+
+  pub fn invalid_target(description: impl Into<String>) -> Self {
+    OAuthError::new("invalid_target", description, 400)
+  }
+
+  pub fn unsupported_token_type(description: impl Into<String>) -> Self {
+    OAuthError::new("unsupported_token_type", description, 400)
+  }
+
+  pub fn unsupported_grant_type(description: impl Into<String>) -> Self {
+    OAuthError::new("unsupported_grant_type", description, 400)
+  }
+
+  pub fn insufficient_scope(description: impl Into<String>) -> Self {
+    OAuthError::new("insufficient_scope", description, 403)
+  }
+
+  pub fn server_error(description: impl Into<String>) -> Self {
+    OAuthError::new("server_error", description, 500)
+  }
+}
+
+impl fmt::Display for OAuthError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "OAuthError: error: {}, description: {}",
+      self.error, self.error_description
+    )
+  }
+}
+
+impl std::error::Error for OAuthError {}
+
+impl ResponseError for OAuthError {
+  fn status_code(&self) -> actix_web::http::StatusCode {
+    actix_web::http::StatusCode::from_u16(self.status)
+      .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
+  }
+
+  fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+    let mut response = HttpResponse::build(self.status_code());
+    response.insert_header((header::CACHE_CONTROL, "no-store"));
+    if self.status == 401 {
+      response.insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"oauth\""));
+    }
+    response.json(self)
+  }
+}
+
+/// Error response of the SCIM API (RFC 7644 section 3.12).
+#[derive(Debug, Clone)]
+pub struct ScimError {
+  pub scim_type: Option<&'static str>,
+  pub detail: String,
+  pub status: u16,
+}
+
+impl ScimError {
+  fn new(scim_type: Option<&'static str>, detail: impl Into<String>, status: u16) -> Self {
+    ScimError {
+      scim_type,
+      detail: detail.into(),
+      status,
+    }
+  }
+
+  pub fn bad_request(scim_type: &'static str, detail: impl Into<String>) -> Self {
+    ScimError::new(Some(scim_type), detail, 400)
+  }
+
+  pub fn unauthorized(detail: impl Into<String>) -> Self {
+    ScimError::new(None, detail, 401)
+  }
+
+  pub fn not_found(detail: impl Into<String>) -> Self {
+    ScimError::new(None, detail, 404)
+  }
+
+  pub fn conflict(detail: impl Into<String>) -> Self {
+    ScimError::new(Some("uniqueness"), detail, 409)
+  }
+
+  pub fn server_error(detail: impl Into<String>) -> Self {
+    ScimError::new(None, detail, 500)
+  }
+}
+
+impl fmt::Display for ScimError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "ScimError: detail: {}, status: {}",
+      self.detail, self.status
+    )
+  }
+}
+
+impl std::error::Error for ScimError {}
+
+impl ResponseError for ScimError {
+  fn status_code(&self) -> actix_web::http::StatusCode {
+    actix_web::http::StatusCode::from_u16(self.status)
+      .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
+  }
+
+  fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+    let mut body = serde_json::json!({
+      "schemas": [scim::ERROR_SCHEMA],
+      "status": self.status.to_string(),
+      "detail": self.detail,
+    });
+    if let Some(scim_type) = self.scim_type {
+      body["scimType"] = scim_type.into();
+    }
+
+    let mut response = HttpResponse::build(self.status_code());
+    if self.status == 401 {
+      response.insert_header((header::WWW_AUTHENTICATE, "Bearer realm=\"scim\""));
+    }
+    response.content_type(scim::CONTENT_TYPE).json(body)
+  }
+}
@@ -1,8 +1,7 @@
 use std::rc::Rc;
 
 use actix_web::{
-  dev::{Service, ServiceRequest, ServiceResponse, Transform},
-  error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
+  dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform},
   http, web, HttpMessage,
 };
 use futures_util::{
@@ -12,10 +11,11 @@ use futures_util::{
 use uuid::Uuid;
 
 use crate::{
-  db::UserExt,
-  error::{ErrorMessage, ErrorResponse, HttpError},
-  models::{User, UserRole},
-  utils, AppState,
+  db::{ImpersonationExt, OAuthExt, UserExt},
+  error::{ErrorMessage, HttpError},
+  models::{OAuthClient, User, UserRole},
+  utils::{self, csrf, metrics, token::TokenClaims},
+  AppState,
 };
 
 pub struct RequireAuth;
@@ -38,6 +38,40 @@ where
     ready(Ok(AuthMiddleware {
       service: Rc::new(service),
       allowed_roles: vec![UserRole::User, UserRole::Moderator, UserRole::Admin],
+      allow_impersonation: true,
+      allow_service_accounts: false,
+      allow_client_tokens: false,
+      check_csrf: true,
+    }))
+  }
+}
+
+/// Like [`RequireAuth`], but rejects tokens minted through admin impersonation.
+/// Use it for sensitive actions such as changing a password.
+pub struct RequireNoImpersonation;
+
+impl<S> Transform<S, ServiceRequest> for RequireNoImpersonation
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<actix_web::body::BoxBody>,
+      Error = actix_web::Error,
+    > + 'static,
+{
+  type Response = ServiceResponse<actix_web::body::BoxBody>;
+  type Error = actix_web::Error;
+  type Transform = AuthMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(AuthMiddleware {
+      service: Rc::new(service),
+      allowed_roles: vec![UserRole::User, UserRole::Moderator, UserRole::Admin],
+      allow_impersonation: false,
+      allow_service_accounts: false,
+      allow_client_tokens: false,
+      check_csrf: true,
     }))
   }
 }
@@ -62,6 +96,104 @@ where
     ready(Ok(AuthMiddleware {
       service: Rc::new(service),
       allowed_roles: vec![UserRole::Admin],
+      allow_impersonation: false,
+      allow_service_accounts: false,
+      allow_client_tokens: false,
+      check_csrf: true,
+    }))
+  }
+}
+
+/// Like [`RequireOnlyAdmin`], but also accepts service accounts with the admin
+/// role. Handlers must not assume a [`User`] is present.
+pub struct RequireAdminOrServiceAccount;
+
+impl<S> Transform<S, ServiceRequest> for RequireAdminOrServiceAccount
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<actix_web::body::BoxBody>,
+      Error = actix_web::Error,
+    > + 'static,
+{
+  type Response = ServiceResponse<actix_web::body::BoxBody>;
+  type Error = actix_web::Error;
+  type Transform = AuthMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(AuthMiddleware {
+      service: Rc::new(service),
+      allowed_roles: vec![UserRole::Admin],
+      allow_impersonation: false,
+      allow_service_accounts: true,
+      allow_client_tokens: false,
+      check_csrf: true,
+    }))
+  }
+}
+
+/// Accepts every authenticated principal: users of any role, impersonation
+/// tokens and service accounts. Cookie sessions are not CSRF-checked, since
+/// this is for forward-auth subrequests: proxies pass neither the body nor a
+/// CSRF header along, so the upstream must defend itself against cross-site
+/// requests.
+pub struct RequireForwardAuth;
+
+impl<S> Transform<S, ServiceRequest> for RequireForwardAuth
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<actix_web::body::BoxBody>,
+      Error = actix_web::Error,
+    > + 'static,
+{
+  type Response = ServiceResponse<actix_web::body::BoxBody>;
+  type Error = actix_web::Error;
+  type Transform = AuthMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(AuthMiddleware {
+      service: Rc::new(service),
+      allowed_roles: vec![UserRole::User, UserRole::Moderator, UserRole::Admin],
+      allow_impersonation: true,
+      allow_service_accounts: true,
+      allow_client_tokens: false,
+      check_csrf: false,
+    }))
+  }
+}
+
+/// Like [`RequireAuth`], but also accepts access tokens issued to OAuth
+/// clients on behalf of a user. Only for endpoints that check the token's
+/// scopes themselves, such as userinfo.
+pub struct RequireClientToken;
+
+impl<S> Transform<S, ServiceRequest> for RequireClientToken
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<actix_web::body::BoxBody>,
+      Error = actix_web::Error,
+    > + 'static,
+{
+  type Response = ServiceResponse<actix_web::body::BoxBody>;
+  type Error = actix_web::Error;
+  type Transform = AuthMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(AuthMiddleware {
+      service: Rc::new(service),
+      allowed_roles: vec![UserRole::User, UserRole::Moderator, UserRole::Admin],
+      allow_impersonation: true,
+      allow_service_accounts: false,
+      allow_client_tokens: true,
+      check_csrf: true,
     }))
   }
 }
@@ -69,6 +201,51 @@ where
 pub struct AuthMiddleware<S> {
   service: Rc<S>,
   allowed_roles: Vec<UserRole>,
+  allow_impersonation: bool,
+  allow_service_accounts: bool,
+  allow_client_tokens: bool,
+  check_csrf: bool,
+}
+
+/// The machine client behind a client credentials token. Inserted into the
+/// request extensions instead of a [`User`].
+#[derive(Debug, Clone)]
+pub struct ServiceAccount {
+  pub client: OAuthClient,
+  pub role: UserRole,
+}
+
+/// The admin behind an impersonation token. Inserted into the request
+/// extensions next to the impersonated [`User`].
+#[derive(Debug, Clone)]
+pub struct Impersonator {
+  pub user: User,
+  pub session_id: Uuid,
+}
+
+/// The CSRF token of a cookie session, for handlers that render HTML forms.
+#[derive(Debug, Clone)]
+pub struct CsrfToken(pub String);
+
+/// Reads the CSRF token from a URL-encoded form body and puts the body back
+/// for the handler.
+async fn csrf_form_field(req: &mut ServiceRequest) -> Result<Option<String>, actix_web::Error> {
+  let is_form = req
+    .headers()
+    .get(http::header::CONTENT_TYPE)
+    .and_then(|h| h.to_str().ok())
+    .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+  if !is_form {
+    return Ok(None);
+  }
+
+  let body = req.extract::<web::Bytes>().await?;
+  let field = url::form_urlencoded::parse(&body)
+    .find(|(name, _)| name == csrf::FORM_FIELD)
+    .map(|(_, value)| value.into_owned());
+  req.set_payload(Payload::from(body));
+
+  Ok(field)
 }
 
 impl<S> Service<ServiceRequest> for AuthMiddleware<S>
@@ -91,63 +268,200 @@ where
   }
 
   fn call(&self, req: ServiceRequest) -> Self::Future {
-    let token = req
-      .cookie("token")
-      .map(|c| c.value().to_string())
-      .or_else(|| {
-        req
-          .headers()
-          .get(http::header::AUTHORIZATION)
-          .map(|h| h.to_str().unwrap().split_at(7).1.to_string())
-      });
-
-    if token.is_none() {
-      let json_error = ErrorResponse {
-        status: "fail".to_string(),
-        message: ErrorMessage::TokenNotProvided.to_string(),
-      };
-      return Box::pin(ready(Err(ErrorUnauthorized(json_error))));
-    }
-
     let app_state = req.app_data::<web::Data<AppState>>().unwrap();
-    let user_id =
-      match utils::token::decode_token(&token.unwrap(), app_state.env.jwt_secret.as_bytes()) {
-        Ok(jwt_payload_user_id) => jwt_payload_user_id,
+
+    // An explicit bearer token wins over the cookie. Browsers attach cookies to
+    // cross-site requests on their own, so only cookie sessions need CSRF checks.
+    let bearer_token = req
+      .headers()
+      .get(http::header::AUTHORIZATION)
+      .and_then(|h| h.to_str().ok())
+      .and_then(|h| h.strip_prefix("Bearer "))
+      .map(str::to_string);
+    let (token, from_cookie) = match bearer_token {
+      Some(token) => (token, false),
+      None => match req.cookie(app_state.env.cookie.session_name()) {
+        Some(cookie) => (cookie.value().to_string(), true),
+        None => {
+          metrics::record_token_rejected(&ErrorMessage::TokenNotProvided);
+          let error = HttpError::unauthorized(ErrorMessage::TokenNotProvided);
+          return Box::pin(ready(Err(error.into())));
+        }
+      },
+    };
+
+    let claims =
+      match utils::token::decode_token(token.as_str(), app_state.env.jwt_secret.as_bytes()) {
+        Ok(claims) => claims,
         Err(jwt_decode_error) => {
-          return Box::pin(ready(Err(ErrorUnauthorized(ErrorResponse {
-            status: "fail".to_string(),
-            message: jwt_decode_error.message,
-          }))));
+          metrics::record_token_rejected(&ErrorMessage::InvalidToken);
+          return Box::pin(ready(Err(jwt_decode_error.into())));
         }
       };
 
+    // Exchanged tokens are meant for the downstream service named in `aud`.
+    if claims.aud.is_some() {
+      metrics::record_token_rejected(&ErrorMessage::InvalidToken);
+      return Box::pin(ready(Err(
+        HttpError::unauthorized(ErrorMessage::InvalidToken).into(),
+      )));
+    }
+
+    // A token a user granted to a third-party client carries that client's
+    // scopes, not the user's full session.
+    if claims.client_id.is_some() && !claims.is_service_account() && !self.allow_client_tokens {
+      metrics::record_token_rejected(&ErrorMessage::InvalidToken);
+      return Box::pin(ready(Err(
+        HttpError::unauthorized(ErrorMessage::InvalidToken).into(),
+      )));
+    }
+
+    if claims.act.is_some() && !self.allow_impersonation {
+      return Box::pin(ready(Err(
+        HttpError::forbidden(ErrorMessage::ImpersonationForbidden).into(),
+      )));
+    }
+
+    if claims.is_service_account() && !self.allow_service_accounts {
+      return Box::pin(ready(Err(
+        HttpError::forbidden(ErrorMessage::ServiceAccountForbidden).into(),
+      )));
+    }
+
     let cloned_app_state = app_state.clone();
     let allowed_roles = self.allowed_roles.clone();
+    let check_csrf = self.check_csrf;
     let srv = Rc::clone(&self.service);
 
+    let mut req = req;
     async move {
-      let user_id = Uuid::parse_str(&user_id).unwrap();
+      if from_cookie {
+        let secret = cloned_app_state.env.jwt_secret.as_bytes();
+        if check_csrf && csrf::is_protected(req.method()) {
+          let candidate = match req.headers().get(csrf::HEADER) {
+            Some(header) => header.to_str().ok().map(str::to_string),
+            None => csrf_form_field(&mut req).await?,
+          };
+          if !candidate.is_some_and(|candidate| csrf::verify(&candidate, &token, secret)) {
+            return Err(HttpError::forbidden(ErrorMessage::InvalidCsrfToken).into());
+          }
+        }
+
+        req
+          .extensions_mut()
+          .insert::<CsrfToken>(CsrfToken(csrf::token(&token, secret)));
+      }
+
+      let invalid_token = || {
+        metrics::record_token_rejected(&ErrorMessage::InvalidToken);
+        actix_web::Error::from(HttpError::unauthorized(ErrorMessage::InvalidToken))
+      };
+
+      let permission_denied =
+        || actix_web::Error::from(HttpError::forbidden(ErrorMessage::PermissionDenied));
+
+      if let Some(jti) = claims.jti() {
+        let revoked = cloned_app_state
+          .db_client
+          .is_access_token_revoked(jti)
+          .await
+          .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        if revoked {
+          return Err(invalid_token());
+        }
+      }
+
+      if claims.is_service_account() {
+        let client = cloned_app_state
+          .db_client
+          .get_oauth_client(&claims.sub)
+          .await
+          .map_err(|e| HttpError::server_error(e.to_string()))?
+          .ok_or_else(invalid_token)?;
+        let role = client.role.ok_or_else(invalid_token)?;
+
+        if !allowed_roles.contains(&role) {
+          return Err(permission_denied());
+        }
+
+        req
+          .extensions_mut()
+          .insert::<ServiceAccount>(ServiceAccount { client, role });
+        req.extensions_mut().insert::<TokenClaims>(claims);
+        return srv.call(req).await;
+      }
+
+      let user_id = Uuid::parse_str(&claims.sub).map_err(|_| invalid_token())?;
       let result = cloned_app_state
         .db_client
-        .get_user(Some(user_id.clone()), None, None)
+        .get_user(Some(user_id), None, None)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+      let user = result.ok_or_else(|| {
+        metrics::record_token_rejected(&ErrorMessage::UserNoLongerExist);
+        actix_web::Error::from(HttpError::unauthorized(ErrorMessage::UserNoLongerExist))
+      })?;
+
+      if !user.active {
+        metrics::record_token_rejected(&ErrorMessage::AccountDisabled);
+        return Err(HttpError::unauthorized(ErrorMessage::AccountDisabled).into());
+      }
+
+      let session_revoked = cloned_app_state
+        .db_client
+        .is_session_revoked(user.id, claims.iat as i64)
         .await
-        .map_err(|e| ErrorInternalServerError(HttpError::server_error(e.to_string())))?;
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+      if session_revoked {
+        return Err(invalid_token());
+      }
+
+      let impersonator = match claims.act.clone() {
+        Some(actor) => {
+          let session_id = actor
+            .sid
+            .and_then(|sid| Uuid::parse_str(&sid).ok())
+            .ok_or_else(invalid_token)?;
+
+          let session = cloned_app_state
+            .db_client
+            .get_active_impersonation(session_id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?
+            .ok_or_else(invalid_token)?;
+
+          if session.impersonator_id.to_string() != actor.sub || session.target_id != user.id {
+            return Err(invalid_token());
+          }
 
-      let user = result.ok_or(ErrorUnauthorized(ErrorResponse {
-        status: "fail".to_string(),
-        message: ErrorMessage::UserNoLongerExist.to_string(),
-      }))?;
+          let impersonator = cloned_app_state
+            .db_client
+            .get_user(Some(session.impersonator_id), None, None)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?
+            .filter(|impersonator| impersonator.role == UserRole::Admin && impersonator.active)
+            .ok_or_else(invalid_token)?;
+
+          Some(Impersonator {
+            user: impersonator,
+            session_id,
+          })
+        }
+        None => None,
+      };
 
       if allowed_roles.contains(&user.role) {
         req.extensions_mut().insert::<User>(user);
+        req.extensions_mut().insert::<TokenClaims>(claims);
+        if let Some(impersonator) = impersonator {
+          req.extensions_mut().insert::<Impersonator>(impersonator);
+        }
         let res = srv.call(req).await?;
         Ok(res)
       } else {
-        let json_error = ErrorResponse {
-          status: "fail".to_string(),
-          message: ErrorMessage::PermissionDenied.to_string(),
-        };
-        Err(ErrorForbidden(json_error))
+        Err(permission_denied())
       }
     }
     .boxed_local()
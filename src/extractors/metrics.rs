@@ -0,0 +1,77 @@
+use std::{rc::Rc, time::Instant};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use metrics::{counter, histogram};
+
+use crate::utils::metrics::{HTTP_REQUESTS, HTTP_REQUEST_DURATION};
+
+/// Counts requests and their latency. Routes are labelled by their pattern,
+/// such as `/api/users/{id}`, so that ids do not end up in label values.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Transform = RequestMetricsMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RequestMetricsMiddleware {
+      service: Rc::new(service),
+    }))
+  }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+  service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, actix_web::Error>>;
+
+  fn poll_ready(
+    &self,
+    ctx: &mut core::task::Context<'_>,
+  ) -> std::task::Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(ctx)
+  }
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+      .match_pattern()
+      .unwrap_or_else(|| "unmatched".to_string());
+    let srv = Rc::clone(&self.service);
+
+    Box::pin(async move {
+      let result = srv.call(req).await;
+      let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+      };
+
+      let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", status.as_u16().to_string()),
+      ];
+      counter!(HTTP_REQUESTS, &labels).increment(1);
+      histogram!(HTTP_REQUEST_DURATION, &labels).record(started.elapsed().as_secs_f64());
+
+      result
+    })
+  }
+}
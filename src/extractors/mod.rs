@@ -1 +1,5 @@
 pub mod auth;
+pub mod metrics;
+pub mod recent_auth;
+pub mod request_id;
+pub mod scim;
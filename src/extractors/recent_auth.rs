@@ -0,0 +1,108 @@
+use std::rc::Rc;
+
+use actix_web::{
+  dev::{Service, ServiceRequest, ServiceResponse, Transform},
+  error::InternalError,
+  http::header::{self, HeaderValue},
+  HttpMessage,
+};
+use chrono::{Duration, Utc};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+
+use crate::{
+  error::{ErrorMessage, HttpError},
+  utils::token::TokenClaims,
+};
+
+/// How recent a login must be for sensitive actions, such as changing a password.
+pub const REAUTH_MAXAGE_MINUTES: i64 = 10;
+
+/// Rejects tokens whose `auth_time` is older than the given number of minutes,
+/// so long-lived sessions must re-enter credentials at
+/// `/api/auth/reauthenticate` first. It reads the claims verified by an auth
+/// middleware and must be wrapped inside one:
+///
+/// ```ignore
+/// web::post()
+///   .to(change_password)
+///   .wrap(RequireRecentAuth(REAUTH_MAXAGE_MINUTES))
+///   .wrap(RequireNoImpersonation)
+/// ```
+pub struct RequireRecentAuth(pub i64);
+
+impl<S> Transform<S, ServiceRequest> for RequireRecentAuth
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<actix_web::body::BoxBody>,
+      Error = actix_web::Error,
+    > + 'static,
+{
+  type Response = ServiceResponse<actix_web::body::BoxBody>;
+  type Error = actix_web::Error;
+  type Transform = RecentAuthMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RecentAuthMiddleware {
+      service: Rc::new(service),
+      max_age: Duration::minutes(self.0),
+    }))
+  }
+}
+
+pub struct RecentAuthMiddleware<S> {
+  service: Rc<S>,
+  max_age: Duration,
+}
+
+impl<S> Service<ServiceRequest> for RecentAuthMiddleware<S>
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<actix_web::body::BoxBody>,
+      Error = actix_web::Error,
+    > + 'static,
+{
+  type Response = ServiceResponse<actix_web::body::BoxBody>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, actix_web::Error>>;
+
+  fn poll_ready(
+    &self,
+    ctx: &mut core::task::Context<'_>,
+  ) -> std::task::Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(ctx)
+  }
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    // Tokens issued before `auth_time` was recorded never count as recent.
+    let recent = req
+      .extensions()
+      .get::<TokenClaims>()
+      .and_then(|claims| claims.auth_time())
+      .is_some_and(|auth_time| auth_time + self.max_age >= Utc::now());
+
+    if !recent {
+      // Step-up challenge as described in RFC 9470 section 3.
+      let mut response =
+        HttpError::unauthorized(ErrorMessage::ReauthenticationRequired).into_http_response();
+      let challenge = format!(
+        "Bearer error=\"insufficient_user_authentication\", max_age={}",
+        self.max_age.num_seconds()
+      );
+      if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+        response
+          .headers_mut()
+          .insert(header::WWW_AUTHENTICATE, challenge);
+      }
+      let message = ErrorMessage::ReauthenticationRequired.to_string();
+      return Box::pin(ready(Err(
+        InternalError::from_response(message, response).into(),
+      )));
+    }
+
+    Box::pin(self.service.call(req))
+  }
+}
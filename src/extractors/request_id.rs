@@ -0,0 +1,127 @@
+use std::{rc::Rc, time::Instant};
+
+use actix_web::{
+  dev::{Service, ServiceRequest, ServiceResponse, Transform},
+  error::InternalError,
+  http::header::{HeaderName, HeaderValue},
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::utils::telemetry;
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Runs every request inside a span carrying its id, and logs one line per
+/// request. A well-formed `X-Request-Id` from the caller is reused, otherwise
+/// a new one is generated; either way it is echoed in the response.
+/// Bodies and headers are never logged, and secrets in the query are redacted.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Transform = RequestTracingMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RequestTracingMiddleware {
+      service: Rc::new(service),
+    }))
+  }
+}
+
+pub struct RequestTracingMiddleware<S> {
+  service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, actix_web::Error>>;
+
+  fn poll_ready(
+    &self,
+    ctx: &mut core::task::Context<'_>,
+  ) -> std::task::Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(ctx)
+  }
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let started = Instant::now();
+    let request_id = req
+      .headers()
+      .get(&HEADER)
+      .and_then(|value| value.to_str().ok())
+      .filter(|value| is_valid(value))
+      .map(str::to_string)
+      .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!(
+      "request",
+      request_id = %request_id,
+      method = %req.method(),
+      route = req.match_pattern().as_deref().unwrap_or("unmatched"),
+      status = tracing::field::Empty,
+    );
+    let path = req.path().to_string();
+    let query = telemetry::redact_query(req.query_string());
+
+    let srv = Rc::clone(&self.service);
+    let request_span = span.clone();
+    Box::pin(
+      async move {
+        let result = srv.call(req).await;
+        let status = match &result {
+          Ok(res) => res.status(),
+          Err(e) => e.as_response_error().status_code(),
+        };
+
+        request_span.record("status", status.as_u16());
+        tracing::info!(
+          path = %path,
+          query = %query,
+          status = status.as_u16(),
+          latency_ms = started.elapsed().as_millis() as u64,
+          "request completed"
+        );
+
+        let value = HeaderValue::from_str(&request_id).ok();
+        match result {
+          Ok(mut res) => {
+            if let Some(value) = value {
+              res.headers_mut().insert(HEADER, value);
+            }
+            Ok(res)
+          }
+          // Errors raised by middleware are rendered later by the server, so
+          // the header is attached to the response they will be rendered as.
+          Err(e) => {
+            let mut response = e.error_response();
+            if let Some(value) = value {
+              response.headers_mut().insert(HEADER, value);
+            }
+            Err(InternalError::from_response(e, response).into())
+          }
+        }
+      }
+      .instrument(span),
+    )
+  }
+}
+
+/// Accepts caller-supplied ids of up to 128 visible ASCII characters, so they
+/// cannot forge log lines or bloat them.
+fn is_valid(value: &str) -> bool {
+  !value.is_empty() && value.len() <= 128 && value.bytes().all(|b| b.is_ascii_graphic())
+}
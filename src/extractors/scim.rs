@@ -0,0 +1,82 @@
+use std::rc::Rc;
+
+use actix_web::{
+  dev::{Service, ServiceRequest, ServiceResponse, Transform},
+  http, web,
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+
+use crate::{error::ScimError, utils::oauth, AppState};
+
+/// Authenticates the SCIM provisioning client by the `SCIM_TOKEN` bearer token.
+/// Every request is rejected while no token is configured.
+pub struct RequireScimToken;
+
+impl<S> Transform<S, ServiceRequest> for RequireScimToken
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<actix_web::body::BoxBody>,
+      Error = actix_web::Error,
+    > + 'static,
+{
+  type Response = ServiceResponse<actix_web::body::BoxBody>;
+  type Error = actix_web::Error;
+  type Transform = ScimTokenMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(ScimTokenMiddleware {
+      service: Rc::new(service),
+    }))
+  }
+}
+
+pub struct ScimTokenMiddleware<S> {
+  service: Rc<S>,
+}
+
+impl<S> Service<ServiceRequest> for ScimTokenMiddleware<S>
+where
+  S: Service<
+      ServiceRequest,
+      Response = ServiceResponse<actix_web::body::BoxBody>,
+      Error = actix_web::Error,
+    > + 'static,
+{
+  type Response = ServiceResponse<actix_web::body::BoxBody>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, actix_web::Error>>;
+
+  fn poll_ready(
+    &self,
+    ctx: &mut core::task::Context<'_>,
+  ) -> std::task::Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(ctx)
+  }
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let app_state = req.app_data::<web::Data<AppState>>().unwrap();
+    let token = req
+      .headers()
+      .get(http::header::AUTHORIZATION)
+      .and_then(|h| h.to_str().ok())
+      .and_then(|h| h.strip_prefix("Bearer "));
+
+    // Compare digests rather than the raw strings so timing does not reveal
+    // how much of the token matched.
+    let authorized = match (&app_state.env.scim_token, token) {
+      (Some(expected), Some(token)) => oauth::hash_token(expected) == oauth::hash_token(token),
+      _ => false,
+    };
+
+    if !authorized {
+      return Box::pin(ready(Err(
+        ScimError::unauthorized("A valid SCIM bearer token is required").into(),
+      )));
+    }
+
+    Box::pin(self.service.call(req))
+  }
+}
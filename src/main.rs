@@ -1,84 +1,176 @@
-use actix_cors::Cors;
-use actix_web::{get, http::header, middleware::Logger, web, App, HttpServer, Responder};
+use actix_web::{web, App, HttpServer};
+use backends::AuthBackend;
+use clap::Parser;
+use cli::{Cli, Command};
 use config::Config;
-use db::DBClient;
-use sqlx::postgres::PgPoolOptions;
+use db::{AuditExt, DBClient};
+use extractors::{metrics::RequestMetrics, request_id::RequestTracing};
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::{fmt::Display, sync::Arc};
+use utils::{oidc::OidcKeys, telemetry::Telemetry};
 
+mod backends;
+mod cli;
 mod config;
 mod db;
 mod dtos;
 mod error;
 mod extractors;
 mod models;
+mod openapi;
 mod scopes;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
   pub env: Config,
   pub db_client: DBClient,
+  pub oidc_keys: Arc<OidcKeys>,
+  pub auth_backends: Arc<Vec<Box<dyn AuthBackend>>>,
+  pub metrics: Option<PrometheusHandle>,
 }
 
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-  if std::env::var_os("RUST_LOG").is_none() {
-    std::env::set_var("RUST_LOG", "actix_web=info");
-  }
-
+  let cli = Cli::parse();
   dotenv::dotenv().ok();
-  env_logger::init();
 
-  let config = Config::init();
+  let config = match Config::init() {
+    Ok(config) => config,
+    Err(problems) => {
+      eprintln!("Invalid configuration:");
+      for problem in problems {
+        eprintln!("  - {}", problem);
+      }
+      std::process::exit(1);
+    }
+  };
+
+  match cli.command.unwrap_or(Command::Serve) {
+    Command::Serve => {}
+    command => {
+      if let Err(e) = cli::run(command, &config).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+      }
+      return Ok(());
+    }
+  }
 
-  let pool = PgPoolOptions::new()
-    .max_connections(10)
-    .connect(&config.database_url)
-    .await?;
+  let telemetry = utils::telemetry::init(&config.telemetry);
 
-  match sqlx::migrate!("./migrations").run(&pool).await {
-    Ok(_) => println!("Migrations executed successfully."),
-    Err(e) => eprintln!("Error executing migrations: {}", e),
+  let pool = match DBClient::connect(
+    &config.database_url,
+    config.database_max_connections,
+    config.database_connect_attempts,
+  )
+  .await
+  {
+    Ok(pool) => pool,
+    Err(e) => exit(&telemetry, "Could not connect to the database", e),
+  };
+
+  match db::MIGRATOR.run(&pool).await {
+    Ok(_) => tracing::info!("Migrations executed successfully"),
+    Err(e) => exit(&telemetry, "Error executing migrations", e),
   }
 
   let db_client = DBClient::new(pool);
+
+  match db_client.seal_audit_events().await {
+    Ok(0) => {}
+    Ok(sealed) => tracing::info!(sealed, "Chained existing audit events"),
+    Err(e) => tracing::error!(error = %e, "Error chaining audit events"),
+  }
+  utils::audit::spawn_checkpoints(
+    db_client.clone(),
+    config.audit_signing_key.clone(),
+    config.audit_checkpoint_interval,
+  );
+
+  let oidc_keys = match OidcKeys::init(&config) {
+    Ok(oidc_keys) => oidc_keys,
+    Err(e) => exit(&telemetry, "Could not set up the OIDC signing key", e),
+  };
+  let metrics = match config.metrics.enabled.then(utils::metrics::install) {
+    Some(Ok(handle)) => Some(handle),
+    Some(Err(e)) => exit(&telemetry, "Could not install the metrics recorder", e),
+    None => None,
+  };
+
   let app_state: AppState = AppState {
     env: config.clone(),
     db_client,
+    oidc_keys: Arc::new(oidc_keys),
+    auth_backends: Arc::new(backends::from_config(&config)),
+    metrics,
   };
 
-  println!(
-    "{}",
-    format!("Server is running on http://127.0.0.1:{}", config.port)
-  );
+  tracing::info!(port = config.port, "Server is running");
+
+  let metrics_on_api_port = config.metrics.enabled && config.metrics.port.is_none();
+  let metrics_state = app_state.clone();
+  let db_client = app_state.db_client.clone();
 
-  HttpServer::new(move || {
-    let cors = Cors::default()
-      .allowed_origin("http://localhost:3000")
-      .allowed_origin("http://localhost:8000")
-      .allowed_origin("http://localhost:8080")
-      .allowed_methods(vec!["GET", "POST"])
-      .allowed_headers(vec![
-        header::CONTENT_TYPE,
-        header::AUTHORIZATION,
-        header::ACCEPT,
-      ])
-      .supports_credentials();
+  let api_server = HttpServer::new(move || {
+    let cors = utils::cors::middleware(&app_state.env.cors);
     App::new()
       .app_data(web::Data::new(app_state.clone()))
+      .app_data(error::json_config())
+      .app_data(error::form_config())
+      .app_data(error::query_config())
+      .app_data(error::path_config())
       .wrap(cors)
-      .wrap(Logger::default())
-      .service(scopes::auth::auth_scope())
-      .service(scopes::users::user_scope())
-      .service(health_check)
+      .wrap(RequestTracing)
+      .wrap(RequestMetrics)
+      .configure(scopes::configure(metrics_on_api_port))
+      .default_service(web::to(error::route_not_found))
   })
+  .shutdown_timeout(config.shutdown_timeout)
   .bind(format!("0.0.0.0:{}", config.port))?
-  .run()
-  .await?;
+  .run();
+
+  // SIGTERM stops both servers from accepting connections and waits up to
+  // `shutdown_timeout` for in-flight requests, which record their own audit
+  // events before responding.
+  let served = match config.metrics.port.filter(|_| config.metrics.enabled) {
+    Some(port) => {
+      tracing::info!(port, "Metrics are served on a separate port");
+      let metrics_server = HttpServer::new(move || {
+        App::new()
+          .app_data(web::Data::new(metrics_state.clone()))
+          .configure(|cfg| scopes::metrics::metrics_scope().register(cfg))
+      })
+      .workers(1)
+      .bind(format!("0.0.0.0:{}", port))?
+      .run();
+
+      futures_util::future::try_join(api_server, metrics_server)
+        .await
+        .map(|_| ())
+    }
+    None => api_server.await,
+  };
+  tracing::info!("Server stopped, shutting down");
+
+  if config.audit_checkpoint_interval > 0 {
+    if let Err(e) =
+      utils::audit::create_checkpoint(&db_client, config.audit_signing_key.as_bytes()).await
+    {
+      tracing::error!(error = %e, "Error creating audit checkpoint");
+    }
+  }
+  db_client.close().await;
+  telemetry.shutdown();
 
-  Ok(())
+  Ok(served?)
 }
 
-#[get("/")]
-async fn health_check() -> impl Responder {
-  "working".to_owned()
+/// Logs why the server cannot start and exits once the log has been flushed.
+fn exit(telemetry: &Telemetry, message: &str, error: impl Display) -> ! {
+  tracing::error!(error = %error, "{}", message);
+  telemetry.shutdown();
+  std::process::exit(1);
 }
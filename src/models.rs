@@ -1,8 +1,10 @@
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy, sqlx::Type, PartialEq, Default)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, sqlx::Type, PartialEq, Default, ToSchema)]
 #[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum UserRole {
   Admin,
   Moderator,
@@ -11,6 +13,7 @@ pub enum UserRole {
 }
 
 impl UserRole {
+  #[allow(clippy::wrong_self_convention)]
   pub fn to_str(&self) -> &str {
     match self {
       UserRole::Admin => "admin",
@@ -18,6 +21,15 @@ impl UserRole {
       UserRole::Moderator => "moderator",
     }
   }
+
+  pub fn parse(value: &str) -> Option<Self> {
+    match value.trim().to_lowercase().as_str() {
+      "admin" => Some(UserRole::Admin),
+      "user" => Some(UserRole::User),
+      "moderator" => Some(UserRole::Moderator),
+      _ => None,
+    }
+  }
 }
 
 #[derive(Debug, Deserialize, Serialize, sqlx::FromRow, sqlx::Type, Clone, Default)]
@@ -29,8 +41,185 @@ pub struct User {
   pub role: UserRole,
   pub photo: String,
   pub verified: bool,
+  pub active: bool,
   #[serde(rename = "createdAt")]
   pub created_at: Option<DateTime<Utc>>,
   #[serde(rename = "updatedAt")]
   pub updated_at: Option<DateTime<Utc>>,
 }
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone)]
+pub struct Invite {
+  pub id: uuid::Uuid,
+  pub code: String,
+  pub role: UserRole,
+  pub created_by: Option<uuid::Uuid>,
+  pub used_by: Option<uuid::Uuid>,
+  #[serde(rename = "expiresAt")]
+  pub expires_at: Option<DateTime<Utc>>,
+  #[serde(rename = "usedAt")]
+  pub used_at: Option<DateTime<Utc>>,
+  #[serde(rename = "createdAt")]
+  pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone, ToSchema)]
+pub struct ImpersonationSession {
+  pub id: uuid::Uuid,
+  #[serde(rename = "impersonatorId")]
+  pub impersonator_id: uuid::Uuid,
+  #[serde(rename = "targetId")]
+  pub target_id: uuid::Uuid,
+  pub reason: Option<String>,
+  #[serde(rename = "ipAddress")]
+  pub ip_address: Option<String>,
+  #[serde(rename = "userAgent")]
+  pub user_agent: Option<String>,
+  #[serde(rename = "startedAt")]
+  pub started_at: DateTime<Utc>,
+  #[serde(rename = "expiresAt")]
+  pub expires_at: DateTime<Utc>,
+  #[serde(rename = "endedAt")]
+  pub ended_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, sqlx::Type, PartialEq, ToSchema)]
+#[sqlx(type_name = "audit_event_type", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventType {
+  Login,
+  LoginFailed,
+  Logout,
+  Register,
+  PasswordChanged,
+  RoleChanged,
+  InviteCreated,
+  ImpersonationStarted,
+  ImpersonationStopped,
+  OauthClientCreated,
+  OauthConsent,
+  ServiceTokenIssued,
+  TokenRevoked,
+  IdentityLinked,
+  UserUpdated,
+  UserDeleted,
+  TokenExchanged,
+  Reauthenticated,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, sqlx::Type, PartialEq, ToSchema)]
+#[sqlx(type_name = "audit_outcome", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+  Success,
+  Failure,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone, ToSchema)]
+pub struct AuditEvent {
+  pub id: i64,
+  #[serde(rename = "eventType")]
+  pub event_type: AuditEventType,
+  pub outcome: AuditOutcome,
+  #[serde(rename = "actorId")]
+  pub actor_id: Option<uuid::Uuid>,
+  #[serde(rename = "targetId")]
+  pub target_id: Option<uuid::Uuid>,
+  #[serde(rename = "ipAddress")]
+  pub ip_address: Option<String>,
+  #[serde(rename = "userAgent")]
+  pub user_agent: Option<String>,
+  pub metadata: serde_json::Value,
+  #[serde(rename = "createdAt")]
+  pub created_at: DateTime<Utc>,
+  #[serde(rename = "prevHash")]
+  pub prev_hash: Option<String>,
+  pub hash: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone, ToSchema)]
+pub struct AuditCheckpoint {
+  pub id: i64,
+  #[serde(rename = "eventId")]
+  pub event_id: i64,
+  #[serde(rename = "eventHash")]
+  pub event_hash: String,
+  pub signature: String,
+  #[serde(rename = "createdAt")]
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone, ToSchema)]
+pub struct OAuthClient {
+  pub id: uuid::Uuid,
+  #[serde(rename = "clientId")]
+  pub client_id: String,
+  #[serde(skip_serializing)]
+  pub client_secret: Option<String>,
+  pub name: String,
+  #[serde(rename = "redirectUris")]
+  pub redirect_uris: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub role: Option<UserRole>,
+  #[serde(rename = "createdBy")]
+  pub created_by: Option<uuid::Uuid>,
+  #[serde(rename = "createdAt")]
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone)]
+pub struct AuthorizationCode {
+  pub code_hash: String,
+  pub client_id: String,
+  pub user_id: uuid::Uuid,
+  pub redirect_uri: String,
+  pub scope: String,
+  pub code_challenge: String,
+  pub code_challenge_method: String,
+  pub nonce: Option<String>,
+  pub auth_time: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+  pub used_at: Option<DateTime<Utc>>,
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone)]
+pub struct RefreshToken {
+  pub id: uuid::Uuid,
+  pub token_hash: String,
+  pub client_id: String,
+  pub user_id: uuid::Uuid,
+  pub scope: String,
+  pub auth_time: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+  pub revoked_at: Option<DateTime<Utc>>,
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone)]
+pub struct UserIdentity {
+  pub id: uuid::Uuid,
+  #[serde(rename = "userId")]
+  pub user_id: uuid::Uuid,
+  pub provider: String,
+  pub subject: String,
+  pub email: Option<String>,
+  #[serde(rename = "createdAt")]
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone)]
+pub struct SocialLoginRequest {
+  pub state_hash: String,
+  pub provider: String,
+  pub code_verifier: String,
+  pub expires_at: DateTime<Utc>,
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow, Clone)]
+pub struct SamlRequest {
+  pub request_id: String,
+  pub expires_at: DateTime<Utc>,
+  pub created_at: DateTime<Utc>,
+}
@@ -0,0 +1,305 @@
+use utoipa::{
+  openapi::{
+    security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    RefOr,
+  },
+  Modify, OpenApi,
+};
+
+use crate::{
+  error::PROBLEM_CONTENT_TYPE,
+  scopes::{
+    audit, auth, docs, health, impersonation, invites, metrics, oauth, oidc, saml, scim, social,
+    users,
+  },
+};
+
+/// OpenAPI document of every route registered by `scopes::configure`. Request
+/// and response schemas are collected from the handler annotations.
+#[derive(OpenApi)]
+#[openapi(
+  info(description = "Authentication, user management, OAuth 2.0 and SCIM provisioning."),
+  paths(
+    auth::login,
+    auth::register,
+    auth::logout,
+    auth::reauthenticate,
+    auth::verify,
+    social::get_providers,
+    social::start_login,
+    social::callback,
+    saml::metadata,
+    saml::start_login,
+    saml::acs,
+    users::get_users,
+    users::get_me,
+    users::change_password,
+    users::update_user_role,
+    invites::create_invite,
+    invites::get_invites,
+    impersonation::get_impersonations,
+    impersonation::stop_impersonation,
+    impersonation::start_impersonation,
+    audit::get_audit_events,
+    audit::verify_audit_chain,
+    audit::get_audit_checkpoints,
+    audit::create_audit_checkpoint,
+    oauth::create_client,
+    oauth::get_clients,
+    oauth::authorize,
+    oauth::consent,
+    oauth::token,
+    oauth::introspect,
+    oauth::revoke,
+    oidc::userinfo,
+    oidc::discovery,
+    oidc::jwks,
+    scim::service_provider_config,
+    scim::get_resource_types,
+    scim::get_resource_type,
+    scim::get_schemas,
+    scim::get_schema,
+    scim::get_users,
+    scim::create_user,
+    scim::get_user,
+    scim::replace_user,
+    scim::patch_user,
+    scim::delete_user,
+    health::live,
+    health::ready,
+    health::root,
+    metrics::render,
+    docs::spec,
+    docs::ui,
+  ),
+  modifiers(&SecuritySchemes, &ProblemResponses),
+  tags(
+    (name = "auth", description = "Password sign-in and sessions"),
+    (name = "social", description = "Sign-in with external OAuth providers"),
+    (name = "saml", description = "SP-initiated SAML 2.0 single sign-on"),
+    (name = "users", description = "User accounts"),
+    (name = "invites", description = "Invite codes for invite-only registration"),
+    (name = "impersonation", description = "Admins acting as another user"),
+    (name = "audit", description = "Tamper-evident audit log"),
+    (name = "oauth", description = "OAuth 2.0 authorization server"),
+    (name = "oidc", description = "OpenID Connect discovery and claims"),
+    (name = "scim", description = "SCIM 2.0 user provisioning"),
+    (name = "health", description = "Orchestrator probes"),
+    (name = "metrics", description = "Prometheus scraping"),
+    (name = "docs", description = "This document"),
+  )
+)]
+pub struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+  fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+    let components = openapi.components.get_or_insert_with(Default::default);
+
+    components.add_security_scheme(
+      "bearer_auth",
+      SecurityScheme::Http(
+        HttpBuilder::new()
+          .scheme(HttpAuthScheme::Bearer)
+          .bearer_format("JWT")
+          .build(),
+      ),
+    );
+    components.add_security_scheme(
+      "cookie_auth",
+      SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+        "token",
+        "Session cookie set by the sign-in endpoints, named `__Host-token` when \
+         `cookie.host_prefix` is set. Unsafe methods also need the `X-CSRF-Token` header.",
+      ))),
+    );
+    components.add_security_scheme(
+      "client_basic",
+      SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Basic).build()),
+    );
+    components.add_security_scheme(
+      "scim_token",
+      SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+    );
+    components.add_security_scheme(
+      "metrics_token",
+      SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+    );
+  }
+}
+
+/// Handlers declare `body = ErrorResponse`, which is served as problem details
+/// rather than plain JSON.
+struct ProblemResponses;
+
+impl Modify for ProblemResponses {
+  fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+    let operations = openapi.paths.paths.values_mut().flat_map(|item| {
+      [
+        &mut item.get,
+        &mut item.post,
+        &mut item.put,
+        &mut item.patch,
+        &mut item.delete,
+      ]
+    });
+
+    for operation in operations.flatten() {
+      for response in operation.responses.responses.values_mut() {
+        let RefOr::T(response) = response else {
+          continue;
+        };
+        let is_problem = response.content.get("application/json").is_some_and(|content| {
+          matches!(&content.schema, Some(RefOr::Ref(schema)) if schema.ref_location.ends_with("/ErrorResponse"))
+        });
+        if !is_problem {
+          continue;
+        }
+
+        if let Some(content) = response.content.shift_remove("application/json") {
+          response
+            .content
+            .insert(PROBLEM_CONTENT_TYPE.to_string(), content);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::RefCell, collections::BTreeSet, rc::Rc};
+
+  use actix_web::{
+    dev::{Service, ServiceResponse},
+    http::Method,
+    test, web, App, HttpResponse,
+  };
+  use utoipa::{
+    openapi::{path::Operation, HttpMethod, PathItem},
+    OpenApi,
+  };
+
+  use super::ApiDoc;
+  use crate::{
+    scopes::{self, Endpoint},
+    test_utils,
+  };
+
+  const UNMATCHED: &str = "x-unmatched-route";
+  const METHODS: [(HttpMethod, Method); 5] = [
+    (HttpMethod::Get, Method::GET),
+    (HttpMethod::Post, Method::POST),
+    (HttpMethod::Put, Method::PUT),
+    (HttpMethod::Patch, Method::PATCH),
+    (HttpMethod::Delete, Method::DELETE),
+  ];
+
+  /// Every registered route must be documented, and every documented
+  /// operation must be served by a route with exactly the documented path.
+  #[actix_web::test]
+  async fn spec_matches_routes() {
+    let matched = Rc::new(RefCell::new(None));
+    let recorder = Rc::clone(&matched);
+    let app = test::init_service(
+      App::new()
+        .app_data(web::Data::new(test_utils::offline_app_state(
+          test_utils::config(),
+        )))
+        .configure(scopes::configure(true))
+        .default_service(web::to(|| async {
+          HttpResponse::NotFound()
+            .insert_header((UNMATCHED, "1"))
+            .finish()
+        }))
+        .wrap_fn(move |req, srv| {
+          *recorder.borrow_mut() = req.match_pattern();
+          srv.call(req)
+        }),
+    )
+    .await;
+
+    let endpoints: Vec<Endpoint> = scopes::routes(true)
+      .iter()
+      .flat_map(|routes| routes.endpoints().to_vec())
+      .collect();
+    let spec = ApiDoc::openapi();
+    let mut problems = BTreeSet::new();
+
+    for endpoint in &endpoints {
+      let item = spec.paths.paths.get(&endpoint.path);
+      for (documented, method) in METHODS {
+        if endpoint
+          .method
+          .as_ref()
+          .is_some_and(|served| *served != method)
+        {
+          continue;
+        }
+        if item.and_then(|item| operation(item, &documented)).is_none() {
+          problems.insert(format!(
+            "{} {} is served but not documented",
+            method, endpoint.path
+          ));
+        }
+      }
+    }
+
+    for (path, item) in spec.paths.paths.iter() {
+      let uri = path
+        .split('/')
+        .map(|segment| match segment.starts_with('{') {
+          true => "00000000-0000-0000-0000-000000000000",
+          false => segment,
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+      for (documented, method) in METHODS {
+        if operation(item, &documented).is_none() {
+          continue;
+        }
+
+        let registered = endpoints.iter().any(|endpoint| {
+          endpoint.path == *path && endpoint.method.as_ref().is_none_or(|m| *m == method)
+        });
+        let req = test::TestRequest::default()
+          .method(method.clone())
+          .uri(&uri)
+          .to_request();
+        let served = match app.call(req).await {
+          Ok(res) => !is_unmatched(&res),
+          Err(_) => true,
+        };
+        let pattern = matched.borrow_mut().take();
+
+        if !registered || !served {
+          problems.insert(format!("{} {} is documented but not served", method, path));
+        } else if pattern.as_deref() != Some(path) {
+          problems.insert(format!(
+            "{} {} is documented but served as {:?}",
+            method, path, pattern
+          ));
+        }
+      }
+    }
+
+    assert!(problems.is_empty(), "{:#?}", problems);
+  }
+
+  fn operation<'a>(item: &'a PathItem, method: &HttpMethod) -> Option<&'a Operation> {
+    match method {
+      HttpMethod::Get => item.get.as_ref(),
+      HttpMethod::Post => item.post.as_ref(),
+      HttpMethod::Put => item.put.as_ref(),
+      HttpMethod::Patch => item.patch.as_ref(),
+      HttpMethod::Delete => item.delete.as_ref(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn is_unmatched(res: &ServiceResponse) -> bool {
+    res.headers().contains_key(UNMATCHED)
+  }
+}
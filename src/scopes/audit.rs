@@ -0,0 +1,159 @@
+use actix_web::{http::Method, web, HttpResponse};
+use serde_json::json;
+use validator::Validate;
+
+use crate::{
+  db::AuditExt,
+  dtos::{
+    AuditCheckpointListResponseDto, AuditEventListResponseDto, AuditQueryDto, RequestQueryDto,
+  },
+  error::{ErrorResponse, HttpError},
+  extractors::auth::RequireAdminOrServiceAccount,
+  scopes::Routes,
+  utils::audit,
+  AppState,
+};
+
+pub fn audit_scope() -> Routes {
+  Routes::new("/api/audit")
+    .route(Method::GET, "", |route| {
+      route
+        .to(get_audit_events)
+        .wrap(RequireAdminOrServiceAccount)
+    })
+    .route(Method::GET, "/verify", |route| {
+      route
+        .to(verify_audit_chain)
+        .wrap(RequireAdminOrServiceAccount)
+    })
+    .route(Method::GET, "/checkpoints", |route| {
+      route
+        .to(get_audit_checkpoints)
+        .wrap(RequireAdminOrServiceAccount)
+    })
+    .route(Method::POST, "/checkpoints", |route| {
+      route
+        .to(create_audit_checkpoint)
+        .wrap(RequireAdminOrServiceAccount)
+    })
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/audit",
+  tag = "audit",
+  params(AuditQueryDto),
+  responses(
+    (status = 200, description = "A page of audit events, newest first", body = AuditEventListResponseDto),
+    (status = 400, description = "Invalid filter or paging parameters", body = ErrorResponse),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+    (status = 403, description = "Not an admin or service account", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn get_audit_events(
+  state: web::Data<AppState>,
+  query: web::Query<AuditQueryDto>,
+) -> Result<HttpResponse, HttpError> {
+  let query_params = query.into_inner();
+  query_params.validate().map_err(HttpError::from)?;
+
+  let page = query_params.page.unwrap_or(1);
+  let limit = query_params.limit.unwrap_or(10);
+
+  let events = state
+    .db_client
+    .get_audit_events(
+      query_params.user_id,
+      query_params.event_type,
+      query_params.from,
+      query_params.to,
+      page as u32,
+      limit,
+    )
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  Ok(HttpResponse::Ok().json(AuditEventListResponseDto {
+    status: "success".to_owned(),
+    results: events.len(),
+    events,
+  }))
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/audit/verify",
+  tag = "audit",
+  responses(
+    (status = 200, description = "Result of checking the hash chain and checkpoint signatures", body = Object),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+    (status = 403, description = "Not an admin or service account", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn verify_audit_chain(state: web::Data<AppState>) -> Result<HttpResponse, HttpError> {
+  let report = audit::verify_chain(&state.db_client, state.env.audit_signing_key.as_bytes())
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  Ok(HttpResponse::Ok().json(json!({"status": "success", "report": report})))
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/audit/checkpoints",
+  tag = "audit",
+  params(RequestQueryDto),
+  responses(
+    (status = 200, description = "A page of signed checkpoints", body = AuditCheckpointListResponseDto),
+    (status = 400, description = "Invalid paging parameters", body = ErrorResponse),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+    (status = 403, description = "Not an admin or service account", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn get_audit_checkpoints(
+  state: web::Data<AppState>,
+  query: web::Query<RequestQueryDto>,
+) -> Result<HttpResponse, HttpError> {
+  let query_params = query.into_inner();
+  query_params.validate().map_err(HttpError::from)?;
+
+  let page = query_params.page.unwrap_or(1);
+  let limit = query_params.limit.unwrap_or(10);
+
+  let checkpoints = state
+    .db_client
+    .get_audit_checkpoints(page as u32, limit)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  Ok(HttpResponse::Ok().json(AuditCheckpointListResponseDto {
+    status: "success".to_owned(),
+    results: checkpoints.len(),
+    checkpoints,
+  }))
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/audit/checkpoints",
+  tag = "audit",
+  responses(
+    (status = 200, description = "The new checkpoint, or null if nothing was recorded since the last one", body = Object),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+    (status = 403, description = "Not an admin or service account", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn create_audit_checkpoint(
+  state: web::Data<AppState>,
+) -> Result<HttpResponse, HttpError> {
+  let checkpoint =
+    audit::create_checkpoint(&state.db_client, state.env.audit_signing_key.as_bytes())
+      .await
+      .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  Ok(HttpResponse::Ok().json(json!({"status": "success", "checkpoint": checkpoint})))
+}
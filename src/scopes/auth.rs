@@ -1,102 +1,347 @@
 use actix_web::{
-  cookie::{time::Duration as ActixWebDuration, Cookie},
-  web, HttpResponse, Responder, Scope,
+  http::{header, Method},
+  web, HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 use serde_json::json;
 use validator::Validate;
 
 use crate::{
-  db::UserExt,
+  backends::Authenticated,
+  config::RegistrationMode,
+  db::{InviteExt, UserExt},
   dtos::{
-    FilterUserDto, LoginUserDto, RegisterUserDto, UserData, UserLoginResponseDto, UserResponseDto,
+    FilterUserDto, LoginUserDto, ReauthenticateDto, RegisterUserDto, UserData,
+    UserLoginResponseDto, UserResponseDto, VerifyQueryDto,
+  },
+  error::{ErrorMessage, ErrorResponse, HttpError},
+  extractors::auth::{
+    Impersonator, RequireAuth, RequireForwardAuth, RequireNoImpersonation, ServiceAccount,
+  },
+  models::{AuditEventType, User, UserRole},
+  scopes::{saml, social, Routes},
+  utils::{
+    audit::{self, NewAuditEvent},
+    cookie, password, token,
   },
-  error::{ErrorMessage, HttpError},
-  extractors::auth::RequireAuth,
-  utils::{password, token},
   AppState,
 };
 
-pub fn auth_scope() -> Scope {
-  web::scope("/api/auth")
-    .route("/login", web::post().to(login))
-    .route("/register", web::post().to(register))
-    .route("/logout", web::post().to(logout).wrap(RequireAuth))
+pub fn auth_scope() -> Routes {
+  Routes::new("/api/auth")
+    .route(Method::POST, "/login", |route| route.to(login))
+    .route(Method::POST, "/register", |route| route.to(register))
+    .route(Method::POST, "/logout", |route| {
+      route.to(logout).wrap(RequireAuth)
+    })
+    .route(Method::POST, "/reauthenticate", |route| {
+      route.to(reauthenticate).wrap(RequireNoImpersonation)
+    })
+    .any("/verify", |route| route.to(verify).wrap(RequireForwardAuth))
+    .nest(social::social_scope())
+    .nest(saml::saml_scope())
 }
 
-pub async fn login(state: web::Data<AppState>, body: web::Json<LoginUserDto>) -> impl Responder {
-  dbg!(&body);
-  body
-    .validate()
-    .map_err(|e| HttpError::bad_request(e.to_string()))?;
-
-  let result = state
-    .db_client
-    .get_user(None, None, Some(&body.email))
-    .await
-    .map_err(|e| HttpError::server_error(e.to_string()))?;
-
-  let user = result.ok_or(HttpError::unauthorized(ErrorMessage::WrongCredentials))?;
-
-  // dbg!(&user);
-
-  let password_matches = password::compare(&body.password, &user.password)
-    .map_err(|_| HttpError::unauthorized(ErrorMessage::WrongCredentials))?;
-
-  if password_matches {
-    let token = token::create_token(
-      &user.id.to_string(),
-      state.env.jwt_secret.as_bytes(),
-      state.env.jwt_maxage,
-    )
-    .map_err(|e| HttpError::server_error(e.to_string()))?;
-
-    let cookie_with_token = Cookie::build("token", token.to_owned())
-      .path("/")
-      .max_age(ActixWebDuration::new(60 * &state.env.jwt_maxage, 0))
-      .http_only(true)
-      .finish();
-
-    Ok(
-      HttpResponse::Ok()
-        .cookie(cookie_with_token)
-        .json(UserLoginResponseDto {
-          status: "success".to_string(),
-          token,
-        }),
-    )
-  } else {
-    Err(HttpError::unauthorized(ErrorMessage::WrongCredentials))
+#[utoipa::path(
+  post,
+  path = "/api/auth/login",
+  tag = "auth",
+  request_body = LoginUserDto,
+  responses(
+    (status = 200, description = "Signed in, the token is also set as a session cookie", body = UserLoginResponseDto),
+    (status = 400, description = "Invalid request body", body = ErrorResponse),
+    (status = 401, description = "Wrong email or password", body = ErrorResponse),
+    (status = 403, description = "Account is disabled", body = ErrorResponse),
+  )
+)]
+pub async fn login(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  body: web::Json<LoginUserDto>,
+) -> impl Responder {
+  body.validate().map_err(HttpError::from)?;
+
+  let (backend, authenticated) = match authenticate(&state, &body.email, &body.password).await? {
+    Some(authenticated) => authenticated,
+    None => {
+      let user = state
+        .db_client
+        .get_user(None, None, Some(&body.email))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+      let mut event = NewAuditEvent::failure(AuditEventType::LoginFailed).request(&req);
+      event = match user {
+        Some(user) => event
+          .target(user.id)
+          .metadata(json!({"email": body.email, "reason": "wrong_password"})),
+        None => event.metadata(json!({"email": body.email, "reason": "unknown_email"})),
+      };
+      audit::record(&state.db_client, event).await;
+
+      return Err(HttpError::unauthorized(ErrorMessage::WrongCredentials));
+    }
+  };
+  let user = authenticated.user;
+
+  if !user.active {
+    let event = NewAuditEvent::failure(AuditEventType::LoginFailed)
+      .target(user.id)
+      .request(&req)
+      .metadata(json!({"email": body.email, "reason": "account_disabled", "backend": backend}));
+    audit::record(&state.db_client, event).await;
+
+    return Err(HttpError::forbidden(ErrorMessage::AccountDisabled));
+  }
+
+  if authenticated.provisioned {
+    let event = NewAuditEvent::success(AuditEventType::Register)
+      .actor(user.id)
+      .target(user.id)
+      .request(&req)
+      .metadata(json!({"role": user.role.to_str(), "backend": backend}));
+    audit::record(&state.db_client, event).await;
   }
 
-  // Ok::<std::string::String, Box<dyn std::error::Error>>( serde_json::to_string(&body.into_inner()).unwrap_or("login".to_string()))
+  record_role_change(&req, &state, backend, &user, authenticated.previous_role).await;
+
+  let token = token::create_token(
+    &user.id.to_string(),
+    token::AMR_PASSWORD,
+    state.env.jwt_secret.as_bytes(),
+    state.env.jwt_maxage,
+  )
+  .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let [session_cookie, csrf_cookie] = cookie::session_cookies(&state.env, &token);
+
+  let event = NewAuditEvent::success(AuditEventType::Login)
+    .actor(user.id)
+    .target(user.id)
+    .request(&req)
+    .metadata(json!({"backend": backend}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(
+    HttpResponse::Ok()
+      .cookie(session_cookie)
+      .cookie(csrf_cookie)
+      .json(UserLoginResponseDto {
+        status: "success".to_string(),
+        token,
+      }),
+  )
 }
 
+/// Step-up authentication: the signed-in user enters their password again and
+/// gets a token with a fresh `auth_time`, which `RequireRecentAuth` accepts.
+#[utoipa::path(
+  post,
+  path = "/api/auth/reauthenticate",
+  tag = "auth",
+  request_body = ReauthenticateDto,
+  responses(
+    (status = 200, description = "Token with a fresh authentication time", body = UserLoginResponseDto),
+    (status = 401, description = "Wrong password", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn reauthenticate(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  body: web::Json<ReauthenticateDto>,
+) -> Result<HttpResponse, HttpError> {
+  body.validate().map_err(HttpError::from)?;
+
+  let user = req
+    .extensions()
+    .get::<User>()
+    .cloned()
+    .ok_or_else(|| HttpError::server_error(ErrorMessage::ServerError))?;
+
+  // Only accept the credentials of the signed-in user, whichever backend owns them.
+  let authenticated = authenticate(&state, &user.email, &body.password)
+    .await?
+    .filter(|(_, authenticated)| authenticated.user.id == user.id);
+
+  let (backend, authenticated) = match authenticated {
+    Some(authenticated) => authenticated,
+    None => {
+      let event = NewAuditEvent::failure(AuditEventType::Reauthenticated)
+        .actor(user.id)
+        .target(user.id)
+        .request(&req)
+        .metadata(json!({"reason": "wrong_password"}));
+      audit::record(&state.db_client, event).await;
+
+      return Err(HttpError::unauthorized(ErrorMessage::WrongCredentials));
+    }
+  };
+  let user = authenticated.user;
+
+  record_role_change(&req, &state, backend, &user, authenticated.previous_role).await;
+
+  let token = token::create_token(
+    &user.id.to_string(),
+    token::AMR_PASSWORD,
+    state.env.jwt_secret.as_bytes(),
+    state.env.jwt_maxage,
+  )
+  .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let [session_cookie, csrf_cookie] = cookie::session_cookies(&state.env, &token);
+
+  let event = NewAuditEvent::success(AuditEventType::Reauthenticated)
+    .actor(user.id)
+    .target(user.id)
+    .request(&req)
+    .metadata(json!({"backend": backend}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(
+    HttpResponse::Ok()
+      .cookie(session_cookie)
+      .cookie(csrf_cookie)
+      .json(UserLoginResponseDto {
+        status: "success".to_string(),
+        token,
+      }),
+  )
+}
+
+/// Asks each configured backend in turn until one accepts the credentials.
+/// Fails only if no backend accepted them and at least one was unavailable.
+async fn authenticate(
+  state: &AppState,
+  email: &str,
+  password: &str,
+) -> Result<Option<(&'static str, Authenticated)>, HttpError> {
+  let mut backend_failed = false;
+  for backend in state.auth_backends.iter() {
+    match backend
+      .authenticate(&state.db_client, email, password)
+      .await
+    {
+      Ok(Some(result)) => return Ok(Some((backend.name(), result))),
+      Ok(None) => {}
+      Err(e) => {
+        tracing::error!(backend = backend.name(), error = %e, "Error authenticating");
+        backend_failed = true;
+      }
+    }
+  }
+
+  if backend_failed {
+    return Err(HttpError::server_error(ErrorMessage::ServerError));
+  }
+
+  Ok(None)
+}
+
+async fn record_role_change(
+  req: &HttpRequest,
+  state: &AppState,
+  backend: &str,
+  user: &User,
+  previous_role: Option<UserRole>,
+) {
+  if let Some(previous_role) = previous_role {
+    let event = NewAuditEvent::success(AuditEventType::RoleChanged)
+      .target(user.id)
+      .request(req)
+      .metadata(json!({
+        "from": previous_role.to_str(),
+        "to": user.role.to_str(),
+        "backend": backend,
+      }));
+    audit::record(&state.db_client, event).await;
+  }
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/auth/register",
+  tag = "auth",
+  request_body = RegisterUserDto,
+  responses(
+    (status = 200, description = "Account created", body = UserResponseDto),
+    (status = 400, description = "Invalid request body or password policy violation", body = ErrorResponse),
+    (status = 403, description = "Registration is closed or the invite code is invalid", body = ErrorResponse),
+    (status = 409, description = "Email is already registered", body = ErrorResponse),
+  )
+)]
 pub async fn register(
+  req: HttpRequest,
   state: web::Data<AppState>,
   body: web::Json<RegisterUserDto>,
 ) -> impl Responder {
-  // dbg!(&body);
-  body
-    .validate()
-    .map_err(|e| HttpError::bad_request(e.to_string()))?;
+  body.validate().map_err(HttpError::from)?;
+  password::check_policy(&body.password, &state.env.password_policy)
+    .map_err(HttpError::bad_request)?;
 
-  let hashed_password =
-    password::hash(&body.password).map_err(|e| HttpError::server_error(e.to_string()))?;
+  let rejected = |reason: &str| {
+    NewAuditEvent::failure(AuditEventType::Register)
+      .request(&req)
+      .metadata(json!({"email": body.email, "reason": reason}))
+  };
 
-  let result = state
-    .db_client
-    .save_user(&body.name, &body.email, &hashed_password)
-    .await;
+  match state.env.registration_mode {
+    RegistrationMode::Open => {}
+    RegistrationMode::InviteOnly => {
+      if body.invite_code.is_none() {
+        audit::record(&state.db_client, rejected("invite_required")).await;
+        return Err(HttpError::forbidden(ErrorMessage::InviteCodeRequired));
+      }
+    }
+    RegistrationMode::DomainRestricted => {
+      if !state.env.is_email_domain_allowed(&body.email) {
+        audit::record(&state.db_client, rejected("domain_not_allowed")).await;
+        return Err(HttpError::forbidden(ErrorMessage::EmailDomainNotAllowed));
+      }
+    }
+  }
+
+  let hashed_password = password::hash(&body.password).map_err(HttpError::server_error)?;
+
+  let result = match &body.invite_code {
+    Some(code) => match state
+      .db_client
+      .save_invited_user(&body.name, &body.email, &hashed_password, code)
+      .await
+    {
+      Ok(Some(user)) => Ok(user),
+      Ok(None) => {
+        audit::record(&state.db_client, rejected("invalid_invite")).await;
+        return Err(HttpError::forbidden(ErrorMessage::InvalidInviteCode));
+      }
+      Err(e) => Err(e),
+    },
+    None => {
+      state
+        .db_client
+        .save_user(&body.name, &body.email, &hashed_password)
+        .await
+    }
+  };
 
   match result {
-    Ok(user) => Ok(HttpResponse::Ok().json(UserResponseDto {
-      status: "success".to_owned(),
-      data: UserData {
-        user: FilterUserDto::filter_user(&user),
-      },
-    })),
+    Ok(user) => {
+      let event = NewAuditEvent::success(AuditEventType::Register)
+        .actor(user.id)
+        .target(user.id)
+        .request(&req)
+        .metadata(json!({"role": user.role.to_str(), "invited": body.invite_code.is_some()}));
+      audit::record(&state.db_client, event).await;
+
+      Ok(HttpResponse::Ok().json(UserResponseDto {
+        status: "success".to_owned(),
+        data: UserData {
+          user: FilterUserDto::filter_user(&user),
+          impersonator: None,
+        },
+      }))
+    }
     Err(sqlx::Error::Database(db_err)) => {
       if db_err.is_unique_violation() {
+        audit::record(&state.db_client, rejected("email_exists")).await;
         Err(HttpError::unique_constraint_voilation(
           ErrorMessage::EmailExist,
         ))
@@ -108,14 +353,100 @@ pub async fn register(
   }
 }
 
-pub async fn logout() -> impl Responder {
-  let cookie = Cookie::build("token", "")
-    .path("/")
-    .max_age(ActixWebDuration::new(-1, 0))
-    .http_only(true)
-    .finish();
+#[utoipa::path(
+  post,
+  path = "/api/auth/logout",
+  tag = "auth",
+  responses(
+    (status = 200, description = "Session cookies cleared", body = Object, example = json!({"status": "success"})),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn logout(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+  let user_id = req.extensions().get::<User>().map(|user| user.id);
+  if let Some(user_id) = user_id {
+    let event = NewAuditEvent::success(AuditEventType::Logout)
+      .actor(user_id)
+      .target(user_id)
+      .request(&req);
+    audit::record(&state.db_client, event).await;
+  }
+
+  let [session_cookie, csrf_cookie] = cookie::removal_cookies(&state.env);
 
   HttpResponse::Ok()
-    .cookie(cookie)
+    .cookie(session_cookie)
+    .cookie(csrf_cookie)
     .json(json!({"status": "success"}))
 }
+
+/// Forward-auth check for reverse proxies (nginx `auth_request`, Traefik
+/// ForwardAuth). Authentication is done by the middleware; on success the
+/// principal is described in `X-Auth-*` headers for the upstream.
+#[utoipa::path(
+  method(get, post, put, patch, delete),
+  path = "/api/auth/verify",
+  tag = "auth",
+  params(VerifyQueryDto),
+  responses(
+    (status = 200, description = "Authenticated, see the `X-Auth-*` headers"),
+    (status = 401, description = "Not authenticated", body = ErrorResponse),
+    (status = 403, description = "Principal lacks every required role, or `role` is invalid", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn verify(
+  req: HttpRequest,
+  query: Result<web::Query<VerifyQueryDto>, actix_web::Error>,
+) -> Result<HttpResponse, HttpError> {
+  // Proxies treat anything but 2xx, 401 and 403 as a failure of their own,
+  // so a misconfigured role check denies access instead.
+  let required_roles = query
+    .map_err(|e| ErrorMessage::InvalidQuery(e.to_string()))
+    .and_then(|query| match &query.role {
+      Some(roles) => roles
+        .split(',')
+        .map(|role| {
+          UserRole::parse(role).ok_or_else(|| ErrorMessage::UnknownRole(role.trim().to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>(),
+      None => Ok(Vec::new()),
+    })
+    .map_err(|e| {
+      tracing::warn!(error = e.to_string(), "Invalid forward-auth role check");
+      HttpError::forbidden(ErrorMessage::PermissionDenied)
+    })?;
+
+  let mut response = HttpResponse::Ok();
+  response.insert_header((header::CACHE_CONTROL, "no-store"));
+
+  let role = if let Some(user) = req.extensions().get::<User>() {
+    response
+      .insert_header(("X-Auth-User-Id", user.id.to_string()))
+      .insert_header(("X-Auth-User-Email", user.email.as_str()));
+    user.role
+  } else if let Some(service_account) = req.extensions().get::<ServiceAccount>() {
+    response.insert_header((
+      "X-Auth-Client-Id",
+      service_account.client.client_id.as_str(),
+    ));
+    service_account.role
+  } else {
+    return Err(HttpError::server_error(ErrorMessage::ServerError));
+  };
+
+  if let Some(impersonator) = req.extensions().get::<Impersonator>() {
+    response.insert_header(("X-Auth-Impersonator-Id", impersonator.user.id.to_string()));
+  }
+
+  if !required_roles.is_empty() && !required_roles.contains(&role) {
+    return Err(HttpError::forbidden(ErrorMessage::PermissionDenied));
+  }
+
+  Ok(
+    response
+      .insert_header(("X-Auth-User-Role", role.to_str()))
+      .finish(),
+  )
+}
@@ -0,0 +1,80 @@
+use std::sync::OnceLock;
+
+use actix_web::{
+  http::{header, Method},
+  web, HttpResponse,
+};
+use utoipa::OpenApi;
+
+use crate::{
+  error::{ErrorMessage, ErrorResponse, HttpError},
+  openapi::ApiDoc,
+  scopes::Routes,
+  AppState,
+};
+
+pub fn docs_scope() -> Routes {
+  Routes::new("/api")
+    .route(Method::GET, "/openapi.json", |route| route.to(spec))
+    .route(Method::GET, "/docs", |route| route.to(ui))
+}
+
+/// The OpenAPI 3 document, generated once from the handler annotations.
+#[utoipa::path(
+  get,
+  path = "/api/openapi.json",
+  tag = "docs",
+  responses((status = 200, description = "This document", body = Object))
+)]
+pub async fn spec() -> HttpResponse {
+  static SPEC: OnceLock<String> = OnceLock::new();
+  let spec = SPEC.get_or_init(|| {
+    ApiDoc::openapi()
+      .to_json()
+      .expect("Failed to serialise the OpenAPI document")
+  });
+
+  HttpResponse::Ok()
+    .content_type("application/json")
+    .body(spec.as_str())
+}
+
+/// Swagger UI for the document above, loaded from a CDN.
+#[utoipa::path(
+  get,
+  path = "/api/docs",
+  tag = "docs",
+  responses(
+    (status = 200, description = "Swagger UI", content_type = "text/html"),
+    (status = 404, description = "The UI is disabled", body = ErrorResponse),
+  )
+)]
+pub async fn ui(state: web::Data<AppState>) -> Result<HttpResponse, HttpError> {
+  if !state.env.docs_ui {
+    return Err(HttpError::not_found(ErrorMessage::DocsDisabled));
+  }
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type("text/html; charset=utf-8")
+      .insert_header((header::X_FRAME_OPTIONS, "DENY"))
+      .body(SWAGGER_UI),
+  )
+}
+
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8">
+    <title>API docs</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+      SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+    </script>
+  </body>
+</html>
+"##;
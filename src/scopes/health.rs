@@ -0,0 +1,151 @@
+use std::{collections::BTreeMap, future::Future, time::Duration};
+
+use actix_web::{
+  http::{header, Method},
+  rt, web, HttpResponse,
+};
+
+use crate::{
+  config::MailerConfig,
+  db::HealthExt,
+  dtos::{HealthCheckDto, HealthResponseDto},
+  scopes::Routes,
+  AppState,
+};
+
+/// How long a single dependency may take before it is reported as failing.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn health_scope() -> Routes {
+  Routes::new("/health")
+    .route(Method::GET, "/live", |route| route.to(live))
+    .route(Method::GET, "/ready", |route| route.to(ready))
+}
+
+/// Serves `/`, which predates the `/health` probes.
+pub fn root_scope() -> Routes {
+  Routes::new("").route(Method::GET, "/", |route| route.to(root))
+}
+
+/// Plain-text answer that the process is up.
+#[utoipa::path(
+  get,
+  path = "/",
+  tag = "health",
+  responses((status = 200, description = "The process is up", body = String, content_type = "text/plain"))
+)]
+pub async fn root() -> &'static str {
+  "working"
+}
+
+/// Liveness probe. It never touches dependencies, so an outage of Postgres
+/// does not make the orchestrator restart healthy processes.
+#[utoipa::path(
+  get,
+  path = "/health/live",
+  tag = "health",
+  responses((status = 200, description = "The process is up", body = HealthResponseDto))
+)]
+pub async fn live() -> HttpResponse {
+  health_response(true, "ok", BTreeMap::new())
+}
+
+/// Readiness probe. Fails with 503 when the database is unreachable or its
+/// schema is behind this build. The mailer is optional, so an unreachable
+/// relay only marks the instance as degraded.
+#[utoipa::path(
+  get,
+  path = "/health/ready",
+  tag = "health",
+  responses(
+    (status = 200, description = "Ready, possibly degraded", body = HealthResponseDto),
+    (status = 503, description = "A required dependency is unavailable", body = HealthResponseDto),
+  )
+)]
+pub async fn ready(state: web::Data<AppState>) -> HttpResponse {
+  let (database, mut migrations, mailer) = futures_util::join!(
+    check(async { state.db_client.ping().await.map(|_| None) }),
+    check(async { state.db_client.pending_migrations().await.map(Some) }),
+    async {
+      match &state.env.mailer {
+        Some(mailer) => Some(check(connect(mailer)).await),
+        None => None,
+      }
+    },
+  );
+  if migrations.pending.is_some_and(|pending| pending > 0) {
+    migrations.status = "fail".to_string();
+  }
+
+  let healthy = database.status == "ok" && migrations.status == "ok";
+  let degraded = mailer.as_ref().is_some_and(|mailer| mailer.status != "ok");
+
+  let mut checks = BTreeMap::new();
+  checks.insert("database".to_string(), database);
+  checks.insert("migrations".to_string(), migrations);
+  if let Some(mailer) = mailer {
+    checks.insert("mailer".to_string(), mailer);
+  }
+
+  let status = match (healthy, degraded) {
+    (false, _) => "fail",
+    (true, true) => "degraded",
+    (true, false) => "ok",
+  };
+
+  health_response(healthy, status, checks)
+}
+
+fn health_response(
+  healthy: bool,
+  status: &str,
+  checks: BTreeMap<String, HealthCheckDto>,
+) -> HttpResponse {
+  let mut response = match healthy {
+    true => HttpResponse::Ok(),
+    false => HttpResponse::ServiceUnavailable(),
+  };
+
+  response
+    .insert_header((header::CACHE_CONTROL, "no-store"))
+    .json(HealthResponseDto {
+      status: status.to_string(),
+      version: env!("CARGO_PKG_VERSION").to_string(),
+      commit: option_env!("GIT_COMMIT").map(str::to_string),
+      checks,
+    })
+}
+
+/// Runs one dependency check under `CHECK_TIMEOUT`. The underlying error is
+/// logged rather than returned, since probes are served without authentication.
+async fn check<F, E>(probe: F) -> HealthCheckDto
+where
+  F: Future<Output = Result<Option<usize>, E>>,
+  E: std::fmt::Display,
+{
+  let started = std::time::Instant::now();
+  let result = rt::time::timeout(CHECK_TIMEOUT, probe).await;
+  let latency_ms = started.elapsed().as_millis() as u64;
+
+  let (pending, error) = match result {
+    Ok(Ok(pending)) => (pending, None),
+    Ok(Err(e)) => {
+      tracing::warn!(error = %e, "Health check failed");
+      (None, Some("unavailable"))
+    }
+    Err(_) => (None, Some("timed out")),
+  };
+
+  HealthCheckDto {
+    status: if error.is_none() { "ok" } else { "fail" }.to_string(),
+    latency_ms,
+    pending,
+    error: error.map(str::to_string),
+  }
+}
+
+async fn connect(mailer: &MailerConfig) -> Result<Option<usize>, std::io::Error> {
+  rt::net::TcpStream::connect((mailer.host.as_str(), mailer.port))
+    .await
+    .map(|_| None)
+}
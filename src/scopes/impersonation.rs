@@ -0,0 +1,193 @@
+use actix_web::{http::Method, web, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use serde_json::json;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+  db::{ImpersonationExt, UserExt},
+  dtos::{
+    ImpersonationListResponseDto, ImpersonationResponseDto, RequestQueryDto, StartImpersonationDto,
+  },
+  error::{ErrorMessage, ErrorResponse, HttpError},
+  extractors::{
+    auth::{Impersonator, RequireAuth, RequireOnlyAdmin},
+    recent_auth::{RequireRecentAuth, REAUTH_MAXAGE_MINUTES},
+  },
+  models::{AuditEventType, User, UserRole},
+  scopes::Routes,
+  utils::{
+    audit::{self, NewAuditEvent},
+    token,
+  },
+  AppState,
+};
+
+pub fn impersonation_scope() -> Routes {
+  Routes::new("/api/impersonation")
+    .route(Method::GET, "", |route| {
+      route.to(get_impersonations).wrap(RequireOnlyAdmin)
+    })
+    .route(Method::POST, "/stop", |route| {
+      route.to(stop_impersonation).wrap(RequireAuth)
+    })
+    .route(Method::POST, "/{user_id}", |route| {
+      route
+        .to(start_impersonation)
+        .wrap(RequireRecentAuth(REAUTH_MAXAGE_MINUTES))
+        .wrap(RequireOnlyAdmin)
+    })
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/impersonation/{user_id}",
+  tag = "impersonation",
+  params(("user_id" = Uuid, Path, description = "User to impersonate")),
+  request_body = StartImpersonationDto,
+  responses(
+    (status = 200, description = "Impersonation started, use the returned token", body = ImpersonationResponseDto),
+    (status = 400, description = "Invalid request body", body = ErrorResponse),
+    (status = 401, description = "Not signed in, or the sign-in is not recent enough", body = ErrorResponse),
+    (status = 403, description = "Not an admin, or the target cannot be impersonated", body = ErrorResponse),
+    (status = 404, description = "User not found", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn start_impersonation(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  path: web::Path<Uuid>,
+  body: web::Json<StartImpersonationDto>,
+) -> Result<HttpResponse, HttpError> {
+  body.validate().map_err(HttpError::from)?;
+
+  let admin = match req.extensions().get::<User>() {
+    Some(user) => user.clone(),
+    None => return Err(HttpError::server_error(ErrorMessage::ServerError)),
+  };
+
+  let target = state
+    .db_client
+    .get_user(Some(path.into_inner()), None, None)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?
+    .ok_or(HttpError::not_found(ErrorMessage::UserNotFound))?;
+
+  if target.id == admin.id || target.role == UserRole::Admin {
+    return Err(HttpError::forbidden(
+      ErrorMessage::InvalidImpersonationTarget,
+    ));
+  }
+
+  let event = NewAuditEvent::success(AuditEventType::ImpersonationStarted)
+    .actor(admin.id)
+    .target(target.id)
+    .request(&req);
+  let expires_at = Utc::now() + Duration::minutes(state.env.impersonation_maxage);
+
+  let session = state
+    .db_client
+    .start_impersonation(
+      admin.id,
+      target.id,
+      body.reason.as_deref(),
+      event.ip_address.as_deref(),
+      event.user_agent.as_deref(),
+      expires_at,
+    )
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let token = token::create_impersonation_token(
+    &target.id.to_string(),
+    &admin.id.to_string(),
+    &session.id.to_string(),
+    state.env.jwt_secret.as_bytes(),
+    state.env.impersonation_maxage,
+  )
+  .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let event = event.metadata(json!({"sessionId": session.id, "reason": session.reason}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(HttpResponse::Ok().json(ImpersonationResponseDto {
+    status: "success".to_string(),
+    token,
+    session,
+  }))
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/impersonation/stop",
+  tag = "impersonation",
+  responses(
+    (status = 200, description = "Impersonation session ended", body = Object, example = json!({"status": "success"})),
+    (status = 400, description = "The token is not an impersonation token", body = ErrorResponse),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn stop_impersonation(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+) -> Result<HttpResponse, HttpError> {
+  let session_id = match req.extensions().get::<Impersonator>() {
+    Some(impersonator) => impersonator.session_id,
+    None => return Err(HttpError::bad_request(ErrorMessage::NotImpersonating)),
+  };
+
+  let session = state
+    .db_client
+    .end_impersonation(session_id)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  if let Some(session) = session {
+    let event = NewAuditEvent::success(AuditEventType::ImpersonationStopped)
+      .actor(session.impersonator_id)
+      .target(session.target_id)
+      .request(&req)
+      .metadata(json!({"sessionId": session.id}));
+    audit::record(&state.db_client, event).await;
+  }
+
+  Ok(HttpResponse::Ok().json(json!({"status": "success"})))
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/impersonation",
+  tag = "impersonation",
+  params(RequestQueryDto),
+  responses(
+    (status = 200, description = "A page of impersonation sessions", body = ImpersonationListResponseDto),
+    (status = 400, description = "Invalid paging parameters", body = ErrorResponse),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+    (status = 403, description = "Not an admin", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn get_impersonations(
+  state: web::Data<AppState>,
+  query: web::Query<RequestQueryDto>,
+) -> Result<HttpResponse, HttpError> {
+  let query_params = query.into_inner();
+  query_params.validate().map_err(HttpError::from)?;
+
+  let page = query_params.page.unwrap_or(1);
+  let limit = query_params.limit.unwrap_or(10);
+
+  let sessions = state
+    .db_client
+    .get_impersonations(page as u32, limit)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  Ok(HttpResponse::Ok().json(ImpersonationListResponseDto {
+    status: "success".to_owned(),
+    results: sessions.len(),
+    sessions,
+  }))
+}
@@ -0,0 +1,117 @@
+use actix_web::{http::Method, web, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use serde_json::json;
+use validator::Validate;
+
+use crate::{
+  db::InviteExt,
+  dtos::{
+    CreateInviteDto, FilterInviteDto, InviteListResponseDto, InviteResponseDto, RequestQueryDto,
+  },
+  error::{ErrorMessage, ErrorResponse, HttpError},
+  extractors::{
+    auth::RequireOnlyAdmin,
+    recent_auth::{RequireRecentAuth, REAUTH_MAXAGE_MINUTES},
+  },
+  models::{AuditEventType, User},
+  scopes::Routes,
+  utils::audit::{self, NewAuditEvent},
+  AppState,
+};
+
+pub fn invite_scope() -> Routes {
+  Routes::new("/api/invites")
+    .route(Method::POST, "", |route| {
+      route
+        .to(create_invite)
+        .wrap(RequireRecentAuth(REAUTH_MAXAGE_MINUTES))
+        .wrap(RequireOnlyAdmin)
+    })
+    .route(Method::GET, "", |route| {
+      route.to(get_invites).wrap(RequireOnlyAdmin)
+    })
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/invites",
+  tag = "invites",
+  request_body = CreateInviteDto,
+  responses(
+    (status = 200, description = "Invite created", body = InviteResponseDto),
+    (status = 400, description = "Invalid request body", body = ErrorResponse),
+    (status = 401, description = "Not signed in, or the sign-in is not recent enough", body = ErrorResponse),
+    (status = 403, description = "Not an admin", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn create_invite(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  body: web::Json<CreateInviteDto>,
+) -> Result<HttpResponse, HttpError> {
+  body.validate().map_err(HttpError::from)?;
+
+  let admin_id = match req.extensions().get::<User>() {
+    Some(user) => user.id,
+    None => return Err(HttpError::server_error(ErrorMessage::ServerError)),
+  };
+
+  let code = uuid::Uuid::new_v4().simple().to_string();
+  let expires_at = body
+    .expires_in_hours
+    .map(|hours| Utc::now() + Duration::hours(hours));
+
+  let invite = state
+    .db_client
+    .save_invite(&code, body.role.unwrap_or_default(), admin_id, expires_at)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let event = NewAuditEvent::success(AuditEventType::InviteCreated)
+    .actor(admin_id)
+    .request(&req)
+    .metadata(json!({"inviteId": invite.id, "role": invite.role.to_str()}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(HttpResponse::Created().json(InviteResponseDto {
+    status: "success".to_owned(),
+    invite: FilterInviteDto::filter_invite(&invite),
+  }))
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/invites",
+  tag = "invites",
+  params(RequestQueryDto),
+  responses(
+    (status = 200, description = "A page of invites", body = InviteListResponseDto),
+    (status = 400, description = "Invalid paging parameters", body = ErrorResponse),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+    (status = 403, description = "Not an admin", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn get_invites(
+  state: web::Data<AppState>,
+  query: web::Query<RequestQueryDto>,
+) -> Result<HttpResponse, HttpError> {
+  let query_params = query.into_inner();
+  query_params.validate().map_err(HttpError::from)?;
+
+  let page = query_params.page.unwrap_or(1);
+  let limit = query_params.limit.unwrap_or(10);
+
+  let invites = state
+    .db_client
+    .get_invites(page as u32, limit)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  Ok(HttpResponse::Ok().json(InviteListResponseDto {
+    status: "success".to_owned(),
+    results: invites.len(),
+    invites: FilterInviteDto::filter_invites(&invites),
+  }))
+}
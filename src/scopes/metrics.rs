@@ -0,0 +1,61 @@
+use actix_web::{
+  http::{header, Method},
+  web, HttpRequest, HttpResponse,
+};
+
+use crate::{
+  error::{ErrorMessage, ErrorResponse, HttpError},
+  scopes::Routes,
+  utils::{metrics, oauth},
+  AppState,
+};
+
+pub fn metrics_scope() -> Routes {
+  Routes::new("/metrics").route(Method::GET, "", |route| route.to(render))
+}
+
+/// Prometheus text exposition of everything recorded since startup. Served on
+/// `metrics.port` instead of the API port when that is set.
+#[utoipa::path(
+  get,
+  path = "/metrics",
+  tag = "metrics",
+  responses(
+    (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+    (status = 401, description = "Missing or invalid metrics token", body = ErrorResponse),
+    (status = 404, description = "Metrics are disabled", body = ErrorResponse),
+  ),
+  security((), ("metrics_token" = []))
+)]
+pub async fn render(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+) -> Result<HttpResponse, HttpError> {
+  let handle = state
+    .metrics
+    .as_ref()
+    .ok_or_else(|| HttpError::not_found(ErrorMessage::MetricsDisabled))?;
+
+  if let Some(expected) = &state.env.metrics.token {
+    let token = req
+      .headers()
+      .get(header::AUTHORIZATION)
+      .and_then(|h| h.to_str().ok())
+      .and_then(|h| h.strip_prefix("Bearer "))
+      .ok_or_else(|| HttpError::unauthorized(ErrorMessage::TokenNotProvided))?;
+
+    // Compare digests so timing does not reveal how much of the token matched.
+    if oauth::hash_token(expected) != oauth::hash_token(token) {
+      return Err(HttpError::unauthorized(ErrorMessage::InvalidToken));
+    }
+  }
+
+  metrics::record_pool(&state.db_client, state.env.database_max_connections);
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type("text/plain; version=0.0.4")
+      .insert_header((header::CACHE_CONTROL, "no-store"))
+      .body(handle.render()),
+  )
+}
@@ -1,2 +1,119 @@
+use actix_web::{http::Method, web, Route, Scope};
+
+pub mod audit;
 pub mod auth;
+pub mod docs;
+pub mod health;
+pub mod impersonation;
+pub mod invites;
+pub mod metrics;
+pub mod oauth;
+pub mod oidc;
+pub mod saml;
+pub mod scim;
+pub mod social;
 pub mod users;
+
+/// A route as registered. `method` is `None` for routes that accept any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+  pub method: Option<Method>,
+  pub path: String,
+}
+
+/// A scope that records the routes it serves, so that the OpenAPI drift test
+/// sees exactly what the server registers.
+pub struct Routes {
+  prefix: String,
+  scope: Scope,
+  endpoints: Vec<Endpoint>,
+}
+
+impl Routes {
+  pub fn new(prefix: &str) -> Routes {
+    Routes {
+      prefix: prefix.to_string(),
+      scope: web::scope(prefix),
+      endpoints: Vec::new(),
+    }
+  }
+
+  pub fn app_data<U: 'static>(mut self, data: U) -> Routes {
+    self.scope = self.scope.app_data(data);
+    self
+  }
+
+  /// Serves `method` requests to `path` with the handler `route` sets up.
+  pub fn route(self, method: Method, path: &str, route: impl FnOnce(Route) -> Route) -> Routes {
+    let route = route(web::method(method.clone()));
+    self.add(Some(method), path, route)
+  }
+
+  /// Serves requests of every method to `path`.
+  pub fn any(self, path: &str, route: impl FnOnce(Route) -> Route) -> Routes {
+    let route = route(web::route());
+    self.add(None, path, route)
+  }
+
+  pub fn nest(mut self, routes: Routes) -> Routes {
+    self
+      .endpoints
+      .extend(routes.endpoints.into_iter().map(|endpoint| Endpoint {
+        method: endpoint.method,
+        path: format!("{}{}", self.prefix, endpoint.path),
+      }));
+    self.scope = self.scope.service(routes.scope);
+    self
+  }
+
+  #[cfg(test)]
+  pub fn endpoints(&self) -> &[Endpoint] {
+    &self.endpoints
+  }
+
+  pub fn register(self, cfg: &mut web::ServiceConfig) {
+    cfg.service(self.scope);
+  }
+
+  fn add(mut self, method: Option<Method>, path: &str, route: Route) -> Routes {
+    self.endpoints.push(Endpoint {
+      method,
+      path: format!("{}{}", self.prefix, path),
+    });
+    self.scope = self.scope.route(path, route);
+    self
+  }
+}
+
+/// Every scope of the API server, including `/metrics` when it is served on
+/// the API port.
+pub fn routes(metrics: bool) -> Vec<Routes> {
+  let mut routes = vec![
+    auth::auth_scope(),
+    users::user_scope(),
+    invites::invite_scope(),
+    impersonation::impersonation_scope(),
+    audit::audit_scope(),
+    oauth::oauth_scope(),
+    oidc::well_known_scope(),
+    scim::scim_scope(),
+    health::health_scope(),
+  ];
+  if metrics {
+    routes.push(metrics::metrics_scope());
+  }
+  // Last, as their `/api` and empty prefixes would otherwise shadow the
+  // scopes above.
+  routes.push(docs::docs_scope());
+  routes.push(health::root_scope());
+  routes
+}
+
+/// Registers every API route, for the server and for the OpenAPI drift test.
+pub fn configure(metrics: bool) -> impl Fn(&mut web::ServiceConfig) {
+  move |cfg| {
+    for routes in routes(metrics) {
+      routes.register(cfg);
+    }
+  }
+}
@@ -0,0 +1,1358 @@
+use actix_web::{
+  http::{header, Method},
+  web, HttpMessage, HttpRequest, HttpResponse,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde_json::json;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+  db::{ImpersonationExt, OAuthExt, UserExt},
+  dtos::{
+    AuthorizeRequestDto, ConsentDto, CreateOAuthClientDto, FilterUserDto, IntrospectRequestDto,
+    IntrospectResponseDto, OAuthClientListResponseDto, OAuthClientResponseDto, RequestQueryDto,
+    RevokeRequestDto, TokenRequestDto, TokenResponseDto,
+  },
+  error::{ErrorMessage, ErrorResponse, HttpError, OAuthError},
+  extractors::{
+    auth::{CsrfToken, RequireClientToken, RequireNoImpersonation, RequireOnlyAdmin},
+    recent_auth::{RequireRecentAuth, REAUTH_MAXAGE_MINUTES},
+  },
+  models::{AuditEventType, OAuthClient, User, UserRole},
+  scopes::{oidc, Routes},
+  utils::{
+    audit::{self, NewAuditEvent},
+    csrf,
+    oauth::{self, NewAuthorizationCode},
+    oidc::{create_id_token, IdTokenRequest},
+    password,
+    token::{self, TokenClaims},
+  },
+  AppState,
+};
+
+pub fn oauth_scope() -> Routes {
+  Routes::new("/oauth")
+    // Malformed forms are answered in the RFC 6749 format, like other errors
+    // of the token, introspection, revocation and authorization endpoints.
+    .app_data(
+      web::FormConfig::default()
+        .error_handler(|err, _| OAuthError::invalid_request(err.to_string()).into()),
+    )
+    .route(Method::POST, "/clients", |route| {
+      route
+        .to(create_client)
+        .wrap(RequireRecentAuth(REAUTH_MAXAGE_MINUTES))
+        .wrap(RequireOnlyAdmin)
+    })
+    .route(Method::GET, "/clients", |route| {
+      route.to(get_clients).wrap(RequireOnlyAdmin)
+    })
+    .route(Method::GET, "/authorize", |route| {
+      route.to(authorize).wrap(RequireNoImpersonation)
+    })
+    .route(Method::POST, "/authorize", |route| {
+      route.to(consent).wrap(RequireNoImpersonation)
+    })
+    .route(Method::POST, "/token", |route| route.to(token))
+    .route(Method::POST, "/introspect", |route| route.to(introspect))
+    .route(Method::POST, "/revoke", |route| route.to(revoke))
+    .route(Method::GET, "/userinfo", |route| {
+      route.to(oidc::userinfo).wrap(RequireClientToken)
+    })
+    .route(Method::POST, "/userinfo", |route| {
+      route.to(oidc::userinfo).wrap(RequireClientToken)
+    })
+}
+
+#[utoipa::path(
+  post,
+  path = "/oauth/clients",
+  tag = "oauth",
+  request_body = CreateOAuthClientDto,
+  responses(
+    (status = 200, description = "Client registered. A confidential client's secret is only shown here", body = OAuthClientResponseDto),
+    (status = 400, description = "Invalid request body", body = ErrorResponse),
+    (status = 401, description = "Not signed in, or the sign-in is not recent enough", body = ErrorResponse),
+    (status = 403, description = "Not an admin", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn create_client(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  body: web::Json<CreateOAuthClientDto>,
+) -> Result<HttpResponse, HttpError> {
+  body.validate().map_err(HttpError::from)?;
+
+  let admin_id = match req.extensions().get::<User>() {
+    Some(user) => user.id,
+    None => return Err(HttpError::server_error(ErrorMessage::ServerError)),
+  };
+
+  if body.role.is_none() && body.redirect_uris.is_empty() {
+    return Err(HttpError::bad_request(ErrorMessage::RedirectUriRequired));
+  }
+
+  if body.role.is_some() && body.confidential == Some(false) {
+    return Err(HttpError::bad_request(
+      ErrorMessage::ServiceAccountNotConfidential,
+    ));
+  }
+
+  let client_id = Uuid::new_v4().simple().to_string();
+  let client_secret = body
+    .confidential
+    .unwrap_or(true)
+    .then(oauth::generate_token);
+  let hashed_secret = client_secret
+    .as_deref()
+    .map(password::hash)
+    .transpose()
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let client = state
+    .db_client
+    .save_oauth_client(
+      &client_id,
+      hashed_secret.as_deref(),
+      &body.name,
+      &body.redirect_uris,
+      body.role,
+      admin_id,
+    )
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let event = NewAuditEvent::success(AuditEventType::OauthClientCreated)
+    .actor(admin_id)
+    .request(&req)
+    .metadata(json!({
+      "clientId": client.client_id,
+      "name": client.name,
+      "role": client.role,
+    }));
+  audit::record(&state.db_client, event).await;
+
+  Ok(HttpResponse::Created().json(OAuthClientResponseDto {
+    status: "success".to_owned(),
+    client,
+    client_secret,
+  }))
+}
+
+#[utoipa::path(
+  get,
+  path = "/oauth/clients",
+  tag = "oauth",
+  params(RequestQueryDto),
+  responses(
+    (status = 200, description = "A page of registered clients", body = OAuthClientListResponseDto),
+    (status = 400, description = "Invalid paging parameters", body = ErrorResponse),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+    (status = 403, description = "Not an admin", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn get_clients(
+  state: web::Data<AppState>,
+  query: web::Query<RequestQueryDto>,
+) -> Result<HttpResponse, HttpError> {
+  let query_params = query.into_inner();
+  query_params.validate().map_err(HttpError::from)?;
+
+  let page = query_params.page.unwrap_or(1);
+  let limit = query_params.limit.unwrap_or(10);
+
+  let clients = state
+    .db_client
+    .get_oauth_clients(page as u32, limit)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  Ok(HttpResponse::Ok().json(OAuthClientListResponseDto {
+    status: "success".to_owned(),
+    results: clients.len(),
+    clients,
+  }))
+}
+
+/// Authorization endpoint (RFC 6749 section 4.1.1), answered with a consent page.
+#[utoipa::path(
+  get,
+  path = "/oauth/authorize",
+  tag = "oauth",
+  params(AuthorizeRequestDto),
+  responses(
+    (status = 200, description = "Consent page", content_type = "text/html"),
+    (status = 302, description = "Invalid request, redirected back to the client with an error"),
+    (status = 400, description = "Unknown client or redirect URI", body = ErrorResponse),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn authorize(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  query: web::Query<AuthorizeRequestDto>,
+) -> HttpResponse {
+  let request = match validate_authorize_request(&state, &query).await {
+    Ok(request) => request,
+    Err(response) => return response,
+  };
+
+  let user = match req.extensions().get::<User>() {
+    Some(user) => user.clone(),
+    None => return HttpError::server_error(ErrorMessage::ServerError).into_http_response(),
+  };
+  let csrf_token = req.extensions().get::<CsrfToken>().cloned();
+
+  HttpResponse::Ok()
+    .content_type("text/html; charset=utf-8")
+    .insert_header((header::X_FRAME_OPTIONS, "DENY"))
+    .insert_header((header::CACHE_CONTROL, "no-store"))
+    .body(consent_page(&request, &query, &user, csrf_token.as_ref()))
+}
+
+#[utoipa::path(
+  post,
+  path = "/oauth/authorize",
+  tag = "oauth",
+  request_body(content = ConsentDto, content_type = "application/x-www-form-urlencoded"),
+  responses(
+    (status = 302, description = "Redirected back to the client with a code or an error"),
+    (status = 400, description = "Unknown client or redirect URI", body = ErrorResponse),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+    (status = 403, description = "Missing or invalid CSRF token", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn consent(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  form: web::Form<ConsentDto>,
+) -> HttpResponse {
+  let form = form.into_inner();
+  let request = match validate_authorize_request(&state, &form.request).await {
+    Ok(request) => request,
+    Err(response) => return response,
+  };
+
+  let (user_id, auth_time) = match (
+    req.extensions().get::<User>(),
+    req.extensions().get::<TokenClaims>(),
+  ) {
+    (Some(user), Some(claims)) => (
+      user.id,
+      claims.auth_time().unwrap_or_else(|| {
+        Utc
+          .timestamp_opt(claims.iat as i64, 0)
+          .single()
+          .unwrap_or_else(Utc::now)
+      }),
+    ),
+    _ => return HttpError::server_error(ErrorMessage::ServerError).into_http_response(),
+  };
+
+  let approved = form.decision == "approve";
+  let event = NewAuditEvent::success(AuditEventType::OauthConsent)
+    .actor(user_id)
+    .target(user_id)
+    .request(&req)
+    .metadata(json!({
+      "clientId": request.client.client_id,
+      "scope": request.scope,
+      "approved": approved,
+    }));
+  audit::record(&state.db_client, event).await;
+
+  if !approved {
+    return redirect_error(
+      &request,
+      "access_denied",
+      "The resource owner denied the request",
+    );
+  }
+
+  let code = oauth::generate_token();
+  let new_code = NewAuthorizationCode {
+    code_hash: oauth::hash_token(&code),
+    client_id: request.client.client_id.clone(),
+    user_id,
+    redirect_uri: request.redirect_uri.clone(),
+    scope: request.scope.clone(),
+    code_challenge: request.code_challenge.clone(),
+    code_challenge_method: request.code_challenge_method.clone(),
+    nonce: request.nonce.clone(),
+    auth_time,
+    expires_at: Utc::now() + Duration::minutes(oauth::AUTHORIZATION_CODE_MAXAGE_MINUTES),
+  };
+
+  if let Err(e) = state.db_client.save_authorization_code(&new_code).await {
+    tracing::error!(error = %e, "Error saving authorization code");
+    return redirect_error(
+      &request,
+      "server_error",
+      "Could not issue an authorization code",
+    );
+  }
+
+  let mut params = vec![("code", code)];
+  if let Some(state) = &request.state {
+    params.push(("state", state.clone()));
+  }
+  redirect_to(&request.redirect_uri, &params)
+}
+
+/// Token endpoint (RFC 6749 section 3.2) for the authorization code, refresh
+/// token, client credentials and token exchange grants.
+#[utoipa::path(
+  post,
+  path = "/oauth/token",
+  tag = "oauth",
+  request_body(content = TokenRequestDto, content_type = "application/x-www-form-urlencoded"),
+  responses(
+    (status = 200, description = "Issued tokens", body = TokenResponseDto),
+    (status = 400, description = "Invalid grant or request", body = OAuthError),
+    (status = 401, description = "Client authentication failed", body = OAuthError),
+  ),
+  security((), ("client_basic" = []))
+)]
+pub async fn token(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  form: web::Form<TokenRequestDto>,
+) -> Result<HttpResponse, OAuthError> {
+  let form = form.into_inner();
+
+  let response = match form.grant_type.as_deref() {
+    Some("authorization_code") => authorization_code_grant(&req, &state, &form).await?,
+    Some("refresh_token") => refresh_token_grant(&req, &state, &form).await?,
+    Some("client_credentials") => client_credentials_grant(&req, &state, &form).await?,
+    Some(oauth::TOKEN_EXCHANGE_GRANT_TYPE) => token_exchange_grant(&req, &state, &form).await?,
+    Some(grant_type) => {
+      return Err(OAuthError::unsupported_grant_type(format!(
+        "Grant type {} is not supported",
+        grant_type
+      )))
+    }
+    None => return Err(OAuthError::invalid_request("grant_type is required")),
+  };
+
+  Ok(
+    HttpResponse::Ok()
+      .insert_header((header::CACHE_CONTROL, "no-store"))
+      .json(response),
+  )
+}
+
+async fn authorization_code_grant(
+  req: &HttpRequest,
+  state: &AppState,
+  form: &TokenRequestDto,
+) -> Result<TokenResponseDto, OAuthError> {
+  let client = authenticate_client(
+    req,
+    state,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+
+  let code = form
+    .code
+    .as_deref()
+    .ok_or_else(|| OAuthError::invalid_request("code is required"))?;
+  let code_verifier = form
+    .code_verifier
+    .as_deref()
+    .ok_or_else(|| OAuthError::invalid_request("code_verifier is required"))?;
+
+  let code = state
+    .db_client
+    .consume_authorization_code(&oauth::hash_token(code))
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?
+    .ok_or_else(|| OAuthError::invalid_grant("Authorization code is invalid or expired"))?;
+
+  if code.client_id != client.client_id {
+    return Err(OAuthError::invalid_grant(
+      "Authorization code was issued to another client",
+    ));
+  }
+
+  if form.redirect_uri.as_deref() != Some(code.redirect_uri.as_str()) {
+    return Err(OAuthError::invalid_grant("redirect_uri does not match"));
+  }
+
+  if !oauth::verify_code_challenge(
+    code_verifier,
+    &code.code_challenge,
+    &code.code_challenge_method,
+  ) {
+    return Err(OAuthError::invalid_grant("code_verifier is invalid"));
+  }
+
+  let grant = Grant {
+    user_id: code.user_id,
+    scope: &code.scope,
+    nonce: code.nonce.as_deref(),
+    auth_time: code.auth_time,
+  };
+  issue_tokens(state, &client, &grant).await
+}
+
+async fn refresh_token_grant(
+  req: &HttpRequest,
+  state: &AppState,
+  form: &TokenRequestDto,
+) -> Result<TokenResponseDto, OAuthError> {
+  let client = authenticate_client(
+    req,
+    state,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+
+  let refresh_token = form
+    .refresh_token
+    .as_deref()
+    .ok_or_else(|| OAuthError::invalid_request("refresh_token is required"))?;
+
+  let refresh_token = state
+    .db_client
+    .consume_refresh_token(&oauth::hash_token(refresh_token))
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?
+    .ok_or_else(|| OAuthError::invalid_grant("Refresh token is invalid or expired"))?;
+
+  if refresh_token.client_id != client.client_id {
+    return Err(OAuthError::invalid_grant(
+      "Refresh token was issued to another client",
+    ));
+  }
+
+  let granted = oauth::parse_scope(Some(&refresh_token.scope));
+  let scope = match form.scope.as_deref() {
+    Some(requested) => {
+      let requested = oauth::parse_scope(Some(requested));
+      if !requested.iter().all(|scope| granted.contains(scope)) {
+        return Err(OAuthError::invalid_grant(
+          "Requested scope exceeds the original grant",
+        ));
+      }
+      requested.join(" ")
+    }
+    None => granted.join(" "),
+  };
+
+  let grant = Grant {
+    user_id: refresh_token.user_id,
+    scope: &scope,
+    nonce: None,
+    auth_time: refresh_token.auth_time,
+  };
+  issue_tokens(state, &client, &grant).await
+}
+
+/// Issues a token to a service account acting on its own behalf (RFC 6749
+/// section 4.4). No refresh token is issued, the client can simply ask again.
+async fn client_credentials_grant(
+  req: &HttpRequest,
+  state: &AppState,
+  form: &TokenRequestDto,
+) -> Result<TokenResponseDto, OAuthError> {
+  let client = authenticate_client(
+    req,
+    state,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+
+  let role = match (client.role, &client.client_secret) {
+    (Some(role), Some(_)) => role,
+    _ => {
+      return Err(OAuthError::unauthorized_client(
+        "Client is not a service account",
+      ))
+    }
+  };
+
+  if let Some(scope) = oauth::parse_scope(form.scope.as_deref()).first() {
+    return Err(OAuthError::invalid_scope(format!(
+      "Scope {} is not available to service accounts",
+      scope
+    )));
+  }
+
+  let access_token = token::create_service_token(
+    &client.client_id,
+    state.env.jwt_secret.as_bytes(),
+    state.env.jwt_maxage,
+  )
+  .map_err(|e| OAuthError::server_error(e.to_string()))?;
+
+  let event = NewAuditEvent::success(AuditEventType::ServiceTokenIssued)
+    .request(req)
+    .metadata(json!({"clientId": client.client_id, "role": role}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(TokenResponseDto {
+    access_token,
+    token_type: "Bearer".to_string(),
+    expires_in: state.env.jwt_maxage * 60,
+    refresh_token: None,
+    scope: String::new(),
+    id_token: None,
+    issued_token_type: None,
+  })
+}
+
+/// Swaps a user's access token for a narrower one that a service account
+/// presents to a downstream service on the user's behalf (RFC 8693). The new
+/// token is restricted to `audience`, may only carry scopes the subject token
+/// had, and names the calling client in its `act` claim.
+async fn token_exchange_grant(
+  req: &HttpRequest,
+  state: &AppState,
+  form: &TokenRequestDto,
+) -> Result<TokenResponseDto, OAuthError> {
+  let client = authenticate_client(
+    req,
+    state,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+
+  if client.role.is_none() || client.client_secret.is_none() {
+    return Err(OAuthError::unauthorized_client(
+      "Client is not a service account",
+    ));
+  }
+
+  let subject_token = form
+    .subject_token
+    .as_deref()
+    .ok_or_else(|| OAuthError::invalid_request("subject_token is required"))?;
+  match form.subject_token_type.as_deref() {
+    Some(oauth::ACCESS_TOKEN_TYPE) => {}
+    Some(_) => {
+      return Err(OAuthError::invalid_request(
+        "subject_token_type must be an access token",
+      ))
+    }
+    None => {
+      return Err(OAuthError::invalid_request(
+        "subject_token_type is required",
+      ))
+    }
+  }
+  if let Some(requested) = form.requested_token_type.as_deref() {
+    if requested != oauth::ACCESS_TOKEN_TYPE {
+      return Err(OAuthError::invalid_request(
+        "Only access tokens can be issued",
+      ));
+    }
+  }
+
+  let audience = form
+    .audience
+    .as_deref()
+    .ok_or_else(|| OAuthError::invalid_request("audience is required"))?;
+  if !state
+    .env
+    .token_exchange_audiences
+    .iter()
+    .any(|allowed| allowed == audience)
+  {
+    return Err(OAuthError::invalid_target(format!(
+      "Tokens cannot be issued for audience {}",
+      audience
+    )));
+  }
+
+  let subject = token::decode_token(subject_token, state.env.jwt_secret.as_bytes())
+    .map_err(|_| OAuthError::invalid_grant("Subject token is invalid or expired"))?;
+
+  // Only plain user tokens qualify: exchanging a delegated token again would
+  // drop the original actor, and impersonation must stay within this API.
+  if subject.is_service_account() || subject.act.is_some() || subject.aud.is_some() {
+    return Err(OAuthError::invalid_grant(
+      "Subject token cannot be exchanged",
+    ));
+  }
+
+  if let Some(jti) = subject.jti() {
+    let revoked = state
+      .db_client
+      .is_access_token_revoked(jti)
+      .await
+      .map_err(|e| OAuthError::server_error(e.to_string()))?;
+    if revoked {
+      return Err(OAuthError::invalid_grant(
+        "Subject token is invalid or expired",
+      ));
+    }
+  }
+
+  let user = match Uuid::parse_str(&subject.sub) {
+    Ok(user_id) => state
+      .db_client
+      .get_user(Some(user_id), None, None)
+      .await
+      .map_err(|e| OAuthError::server_error(e.to_string()))?,
+    Err(_) => None,
+  }
+  .ok_or_else(|| OAuthError::invalid_grant("User no longer exists"))?;
+
+  if !user.active {
+    return Err(OAuthError::invalid_grant(ErrorMessage::AccountDisabled));
+  }
+
+  let session_revoked = state
+    .db_client
+    .is_session_revoked(user.id, subject.iat as i64)
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?;
+  if session_revoked {
+    return Err(OAuthError::invalid_grant(
+      "Subject token is invalid or expired",
+    ));
+  }
+
+  // A session token from the login endpoint is unrestricted, so the caller must
+  // say what the downstream token is for.
+  let requested = oauth::parse_scope(form.scope.as_deref());
+  let scope = match &subject.scope {
+    Some(granted) => {
+      let granted = oauth::parse_scope(Some(granted));
+      if requested.is_empty() {
+        granted
+      } else if requested.iter().all(|scope| granted.contains(scope)) {
+        requested
+      } else {
+        return Err(OAuthError::invalid_scope(
+          "Requested scope exceeds the subject token",
+        ));
+      }
+    }
+    None if requested.is_empty() => {
+      return Err(OAuthError::invalid_scope(
+        "scope is required to exchange a session token",
+      ))
+    }
+    None => requested,
+  }
+  .join(" ");
+
+  let access_token = token::create_exchanged_token(
+    &subject,
+    &client.client_id,
+    audience,
+    &scope,
+    state.env.jwt_secret.as_bytes(),
+    state.env.token_exchange_maxage,
+  )
+  .map_err(|e| OAuthError::server_error(e.to_string()))?;
+
+  let expires_in = (subject.expires_at() - Utc::now())
+    .num_seconds()
+    .clamp(0, state.env.token_exchange_maxage * 60);
+
+  let event = NewAuditEvent::success(AuditEventType::TokenExchanged)
+    .target(user.id)
+    .request(req)
+    .metadata(json!({"clientId": client.client_id, "audience": audience, "scope": scope}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(TokenResponseDto {
+    access_token,
+    token_type: "Bearer".to_string(),
+    expires_in,
+    refresh_token: None,
+    scope,
+    id_token: None,
+    issued_token_type: Some(oauth::ACCESS_TOKEN_TYPE.to_string()),
+  })
+}
+
+/// Token introspection (RFC 7662) for resource servers that cannot validate
+/// tokens themselves. Only confidential clients may introspect.
+#[utoipa::path(
+  post,
+  path = "/oauth/introspect",
+  tag = "oauth",
+  request_body(content = IntrospectRequestDto, content_type = "application/x-www-form-urlencoded"),
+  responses(
+    (status = 200, description = "Token state", body = IntrospectResponseDto),
+    (status = 401, description = "Client authentication failed", body = OAuthError),
+  ),
+  security((), ("client_basic" = []))
+)]
+pub async fn introspect(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  form: web::Form<IntrospectRequestDto>,
+) -> Result<HttpResponse, OAuthError> {
+  let client = authenticate_client(
+    &req,
+    &state,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+
+  if client.client_secret.is_none() {
+    return Err(OAuthError::unauthorized_client(
+      "Public clients cannot introspect tokens",
+    ));
+  }
+
+  let token = form
+    .token
+    .as_deref()
+    .ok_or_else(|| OAuthError::invalid_request("token is required"))?;
+
+  let response = if form.token_type_hint.as_deref() == Some("refresh_token") {
+    match introspect_refresh_token(&state, token).await? {
+      response if response.active => response,
+      _ => introspect_access_token(&state, token).await?,
+    }
+  } else {
+    match introspect_access_token(&state, token).await? {
+      response if response.active => response,
+      _ => introspect_refresh_token(&state, token).await?,
+    }
+  };
+
+  Ok(
+    HttpResponse::Ok()
+      .insert_header((header::CACHE_CONTROL, "no-store"))
+      .json(response),
+  )
+}
+
+async fn introspect_access_token(
+  state: &AppState,
+  token: &str,
+) -> Result<IntrospectResponseDto, OAuthError> {
+  let claims = match token::decode_token(token, state.env.jwt_secret.as_bytes()) {
+    Ok(claims) => claims,
+    Err(_) => return Ok(IntrospectResponseDto::default()),
+  };
+
+  if let Some(jti) = claims.jti() {
+    let revoked = state
+      .db_client
+      .is_access_token_revoked(jti)
+      .await
+      .map_err(|e| OAuthError::server_error(e.to_string()))?;
+    if revoked {
+      return Ok(IntrospectResponseDto::default());
+    }
+  }
+
+  let (username, role) = if claims.is_service_account() {
+    let client = state
+      .db_client
+      .get_oauth_client(&claims.sub)
+      .await
+      .map_err(|e| OAuthError::server_error(e.to_string()))?;
+    match client.and_then(|client| client.role.map(|role| (client.name, role))) {
+      Some(principal) => principal,
+      None => return Ok(IntrospectResponseDto::default()),
+    }
+  } else {
+    let user = match Uuid::parse_str(&claims.sub) {
+      Ok(user_id) => state
+        .db_client
+        .get_user(Some(user_id), None, None)
+        .await
+        .map_err(|e| OAuthError::server_error(e.to_string()))?,
+      Err(_) => None,
+    };
+    let user = match user.filter(|user| user.active) {
+      Some(user) => user,
+      None => return Ok(IntrospectResponseDto::default()),
+    };
+    let session_revoked = state
+      .db_client
+      .is_session_revoked(user.id, claims.iat as i64)
+      .await
+      .map_err(|e| OAuthError::server_error(e.to_string()))?;
+    if session_revoked {
+      return Ok(IntrospectResponseDto::default());
+    }
+    (user.email, user.role)
+  };
+
+  match (&claims.act, &claims.aud) {
+    // Delegated token from the token exchange grant: the actor must still be a
+    // service account.
+    (Some(actor), Some(_)) => {
+      let client = state
+        .db_client
+        .get_oauth_client(&actor.sub)
+        .await
+        .map_err(|e| OAuthError::server_error(e.to_string()))?;
+      let valid = claims.client_id.as_deref() == Some(actor.sub.as_str())
+        && client.is_some_and(|client| client.role.is_some());
+      if !valid {
+        return Ok(IntrospectResponseDto::default());
+      }
+    }
+    (Some(actor), None) => {
+      let session = match actor
+        .sid
+        .as_deref()
+        .and_then(|sid| Uuid::parse_str(sid).ok())
+      {
+        Some(session_id) => state
+          .db_client
+          .get_active_impersonation(session_id)
+          .await
+          .map_err(|e| OAuthError::server_error(e.to_string()))?,
+        None => None,
+      };
+      let valid = match session {
+        Some(session) => {
+          session.impersonator_id.to_string() == actor.sub
+            && session.target_id.to_string() == claims.sub
+        }
+        None => false,
+      };
+      if !valid {
+        return Ok(IntrospectResponseDto::default());
+      }
+    }
+    (None, _) => {}
+  }
+
+  Ok(IntrospectResponseDto {
+    active: true,
+    sub: Some(claims.sub),
+    username: Some(username),
+    role: Some(role),
+    client_id: claims.client_id,
+    scope: claims.scope,
+    token_type: Some("access_token".to_string()),
+    exp: Some(claims.exp as i64),
+    iat: Some(claims.iat as i64),
+    aud: claims.aud,
+    act: claims.act,
+  })
+}
+
+async fn introspect_refresh_token(
+  state: &AppState,
+  token: &str,
+) -> Result<IntrospectResponseDto, OAuthError> {
+  let refresh_token = state
+    .db_client
+    .get_refresh_token(&oauth::hash_token(token))
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?
+    .filter(|refresh_token| {
+      refresh_token.revoked_at.is_none() && refresh_token.expires_at > Utc::now()
+    });
+
+  let refresh_token = match refresh_token {
+    Some(refresh_token) => refresh_token,
+    None => return Ok(IntrospectResponseDto::default()),
+  };
+
+  let user = state
+    .db_client
+    .get_user(Some(refresh_token.user_id), None, None)
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?;
+
+  let user = match user.filter(|user| user.active) {
+    Some(user) => user,
+    None => return Ok(IntrospectResponseDto::default()),
+  };
+
+  Ok(IntrospectResponseDto {
+    active: true,
+    sub: Some(user.id.to_string()),
+    username: Some(user.email),
+    role: Some(user.role),
+    client_id: Some(refresh_token.client_id),
+    scope: Some(refresh_token.scope),
+    token_type: Some("refresh_token".to_string()),
+    exp: Some(refresh_token.expires_at.timestamp()),
+    iat: Some(refresh_token.created_at.timestamp()),
+    aud: None,
+    act: None,
+  })
+}
+
+/// Token revocation (RFC 7009). Clients may only revoke tokens issued to them,
+/// except admin service accounts, which may also revoke login sessions. Unknown
+/// or already invalid tokens are not an error (section 2.2).
+#[utoipa::path(
+  post,
+  path = "/oauth/revoke",
+  tag = "oauth",
+  request_body(content = RevokeRequestDto, content_type = "application/x-www-form-urlencoded"),
+  responses(
+    (status = 200, description = "Token revoked, or it was not valid"),
+    (status = 401, description = "Client authentication failed", body = OAuthError),
+  ),
+  security((), ("client_basic" = []))
+)]
+pub async fn revoke(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  form: web::Form<RevokeRequestDto>,
+) -> Result<HttpResponse, OAuthError> {
+  let client = authenticate_client(
+    &req,
+    &state,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+
+  let token = form
+    .token
+    .as_deref()
+    .ok_or_else(|| OAuthError::invalid_request("token is required"))?;
+
+  let revoked = if form.token_type_hint.as_deref() == Some("refresh_token") {
+    match revoke_refresh_token(&state, &client, token).await? {
+      None => revoke_access_token(&state, &client, token).await?,
+      revoked => revoked,
+    }
+  } else {
+    match revoke_access_token(&state, &client, token).await? {
+      None => revoke_refresh_token(&state, &client, token).await?,
+      revoked => revoked,
+    }
+  };
+
+  if let Some((token_type, user_id)) = revoked {
+    let mut event = NewAuditEvent::success(AuditEventType::TokenRevoked)
+      .request(&req)
+      .metadata(json!({"clientId": client.client_id, "tokenType": token_type}));
+    if let Some(user_id) = user_id {
+      event = event.target(user_id);
+    }
+    audit::record(&state.db_client, event).await;
+  }
+
+  Ok(
+    HttpResponse::Ok()
+      .insert_header((header::CACHE_CONTROL, "no-store"))
+      .finish(),
+  )
+}
+
+/// Revokes `token` if it is a valid JWT, returning its type and user.
+async fn revoke_access_token(
+  state: &AppState,
+  client: &OAuthClient,
+  token: &str,
+) -> Result<Option<(&'static str, Option<Uuid>)>, OAuthError> {
+  let claims = match token::decode_token(token, state.env.jwt_secret.as_bytes()) {
+    Ok(claims) => claims,
+    Err(_) => return Ok(None),
+  };
+
+  let allowed = match &claims.client_id {
+    Some(client_id) => *client_id == client.client_id,
+    None => client.role == Some(UserRole::Admin),
+  };
+  if !allowed {
+    return Err(OAuthError::unauthorized_client(
+      "Token was not issued to this client",
+    ));
+  }
+
+  let jti = match claims.jti() {
+    Some(jti) => jti,
+    None => {
+      return Err(OAuthError::unsupported_token_type(
+        "Token predates revocation support and cannot be revoked",
+      ))
+    }
+  };
+
+  state
+    .db_client
+    .revoke_access_token(jti, claims.expires_at())
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?;
+
+  let user_id = Uuid::parse_str(&claims.sub).ok();
+  Ok(Some(("access_token", user_id)))
+}
+
+/// Revokes `token` if it is an active refresh token, returning its type and user.
+async fn revoke_refresh_token(
+  state: &AppState,
+  client: &OAuthClient,
+  token: &str,
+) -> Result<Option<(&'static str, Option<Uuid>)>, OAuthError> {
+  let token_hash = oauth::hash_token(token);
+  let refresh_token = state
+    .db_client
+    .get_refresh_token(&token_hash)
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?;
+
+  let refresh_token = match refresh_token {
+    Some(refresh_token) => refresh_token,
+    None => return Ok(None),
+  };
+
+  if refresh_token.client_id != client.client_id {
+    return Err(OAuthError::unauthorized_client(
+      "Token was not issued to this client",
+    ));
+  }
+
+  state
+    .db_client
+    .consume_refresh_token(&token_hash)
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?;
+
+  Ok(Some(("refresh_token", Some(refresh_token.user_id))))
+}
+
+/// Authenticates the client with HTTP Basic credentials or `client_id` and
+/// `client_secret` form fields. Public clients only send their `client_id`.
+async fn authenticate_client(
+  req: &HttpRequest,
+  state: &AppState,
+  client_id: Option<&str>,
+  client_secret: Option<&str>,
+) -> Result<OAuthClient, OAuthError> {
+  let basic_credentials = req
+    .headers()
+    .get(header::AUTHORIZATION)
+    .and_then(|h| h.to_str().ok())
+    .and_then(|h| h.strip_prefix("Basic "))
+    .and_then(|encoded| STANDARD.decode(encoded).ok())
+    .and_then(|decoded| String::from_utf8(decoded).ok())
+    .and_then(|decoded| {
+      decoded
+        .split_once(':')
+        .map(|(id, secret)| (id.to_string(), Some(secret.to_string())))
+    });
+
+  let (client_id, client_secret) = match basic_credentials {
+    Some(credentials) => credentials,
+    None => (
+      client_id
+        .map(str::to_string)
+        .ok_or_else(|| OAuthError::invalid_client("Client authentication failed"))?,
+      client_secret.map(str::to_string),
+    ),
+  };
+
+  let client = state
+    .db_client
+    .get_oauth_client(&client_id)
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?
+    .ok_or_else(|| OAuthError::invalid_client("Client authentication failed"))?;
+
+  let authenticated = match (&client.client_secret, client_secret) {
+    (Some(hashed_secret), Some(secret)) => {
+      password::compare(&secret, hashed_secret).unwrap_or(false)
+    }
+    (None, None) => true,
+    _ => false,
+  };
+
+  if !authenticated {
+    return Err(OAuthError::invalid_client("Client authentication failed"));
+  }
+
+  Ok(client)
+}
+
+/// What the resource owner granted, carried over from the code or refresh token.
+struct Grant<'a> {
+  user_id: Uuid,
+  scope: &'a str,
+  nonce: Option<&'a str>,
+  auth_time: DateTime<Utc>,
+}
+
+async fn issue_tokens(
+  state: &AppState,
+  client: &OAuthClient,
+  grant: &Grant<'_>,
+) -> Result<TokenResponseDto, OAuthError> {
+  let scope = grant.scope;
+  let user = state
+    .db_client
+    .get_user(Some(grant.user_id), None, None)
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?
+    .ok_or_else(|| OAuthError::invalid_grant("User no longer exists"))?;
+
+  if !user.active {
+    return Err(OAuthError::invalid_grant(ErrorMessage::AccountDisabled));
+  }
+
+  let access_token = token::create_oauth_token(
+    &user.id.to_string(),
+    &client.client_id,
+    scope,
+    grant.auth_time,
+    state.env.jwt_secret.as_bytes(),
+    state.env.jwt_maxage,
+  )
+  .map_err(|e| OAuthError::server_error(e.to_string()))?;
+
+  let refresh_token = oauth::generate_token();
+  state
+    .db_client
+    .save_refresh_token(
+      &oauth::hash_token(&refresh_token),
+      &client.client_id,
+      user.id,
+      scope,
+      grant.auth_time,
+      Utc::now() + Duration::minutes(state.env.oauth_refresh_maxage),
+    )
+    .await
+    .map_err(|e| OAuthError::server_error(e.to_string()))?;
+
+  let scopes = oauth::parse_scope(Some(scope));
+  let id_token = if scopes.iter().any(|scope| scope == "openid") {
+    let request = IdTokenRequest {
+      issuer: &state.env.oidc_issuer,
+      client_id: &client.client_id,
+      user: &FilterUserDto::filter_user(&user),
+      scopes: &scopes,
+      nonce: grant.nonce,
+      auth_time: grant.auth_time,
+      expires_in_minutes: state.env.jwt_maxage,
+    };
+    Some(
+      create_id_token(&state.oidc_keys, &request)
+        .map_err(|e| OAuthError::server_error(e.to_string()))?,
+    )
+  } else {
+    None
+  };
+
+  Ok(TokenResponseDto {
+    access_token,
+    token_type: "Bearer".to_string(),
+    expires_in: state.env.jwt_maxage * 60,
+    refresh_token: Some(refresh_token),
+    scope: scope.to_string(),
+    id_token,
+    issued_token_type: None,
+  })
+}
+
+/// An authorization request whose client and redirect URI have been verified.
+struct AuthorizeRequest {
+  client: OAuthClient,
+  redirect_uri: String,
+  scope: String,
+  state: Option<String>,
+  code_challenge: String,
+  code_challenge_method: String,
+  nonce: Option<String>,
+}
+
+/// Validates an authorization request (RFC 6749 section 4.1.1). Until the
+/// redirect URI is known to belong to the client, errors are shown to the user
+/// instead of being redirected (section 4.1.2.1).
+async fn validate_authorize_request(
+  state: &AppState,
+  params: &AuthorizeRequestDto,
+) -> Result<AuthorizeRequest, HttpResponse> {
+  let client_id = params
+    .client_id
+    .as_deref()
+    .ok_or_else(|| HttpError::bad_request(ErrorMessage::ClientIdRequired).into_http_response())?;
+
+  let client = state
+    .db_client
+    .get_oauth_client(client_id)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()).into_http_response())?
+    .ok_or_else(|| HttpError::bad_request(ErrorMessage::UnknownClient).into_http_response())?;
+
+  let redirect_uri = match params.redirect_uri.as_deref() {
+    Some(uri) if client.redirect_uris.iter().any(|allowed| allowed == uri) => uri.to_string(),
+    None if client.redirect_uris.len() == 1 => client.redirect_uris[0].clone(),
+    _ => {
+      return Err(
+        HttpError::bad_request(ErrorMessage::RedirectUriNotRegistered).into_http_response(),
+      )
+    }
+  };
+
+  let mut request = AuthorizeRequest {
+    client,
+    redirect_uri,
+    scope: oauth::parse_scope(params.scope.as_deref()).join(" "),
+    state: params.state.clone(),
+    code_challenge: String::new(),
+    code_challenge_method: String::new(),
+    nonce: params.nonce.clone(),
+  };
+
+  if params.response_type.as_deref() != Some("code") {
+    return Err(redirect_error(
+      &request,
+      "unsupported_response_type",
+      "Only response_type=code is supported",
+    ));
+  }
+
+  if let Some(scope) = oauth::parse_scope(params.scope.as_deref())
+    .iter()
+    .find(|scope| !oauth::SUPPORTED_SCOPES.contains(&scope.as_str()))
+  {
+    return Err(redirect_error(
+      &request,
+      "invalid_scope",
+      &format!("Scope {} is not supported", scope),
+    ));
+  }
+
+  request.code_challenge = match &params.code_challenge {
+    Some(code_challenge) => code_challenge.clone(),
+    None => {
+      return Err(redirect_error(
+        &request,
+        "invalid_request",
+        "code_challenge is required",
+      ))
+    }
+  };
+
+  // `plain` only protects against attackers who cannot read the authorization
+  // request, so it is not supported.
+  request.code_challenge_method = match params.code_challenge_method.as_deref() {
+    Some("S256") | None => "S256".to_string(),
+    Some(_) => {
+      return Err(redirect_error(
+        &request,
+        "invalid_request",
+        "code_challenge_method must be S256",
+      ))
+    }
+  };
+
+  Ok(request)
+}
+
+fn redirect_error(request: &AuthorizeRequest, error: &str, description: &str) -> HttpResponse {
+  let mut params = vec![
+    ("error", error.to_string()),
+    ("error_description", description.to_string()),
+  ];
+  if let Some(state) = &request.state {
+    params.push(("state", state.clone()));
+  }
+  redirect_to(&request.redirect_uri, &params)
+}
+
+fn redirect_to(redirect_uri: &str, params: &[(&str, String)]) -> HttpResponse {
+  let mut url = match url::Url::parse(redirect_uri) {
+    Ok(url) => url,
+    Err(e) => return HttpError::server_error(e.to_string()).into_http_response(),
+  };
+  url.query_pairs_mut().extend_pairs(params);
+
+  HttpResponse::Found()
+    .insert_header((header::LOCATION, url.to_string()))
+    .insert_header((header::CACHE_CONTROL, "no-store"))
+    .finish()
+}
+
+fn consent_page(
+  request: &AuthorizeRequest,
+  params: &AuthorizeRequestDto,
+  user: &User,
+  csrf_token: Option<&CsrfToken>,
+) -> String {
+  let hidden_fields = [
+    ("response_type", params.response_type.as_deref()),
+    ("client_id", Some(request.client.client_id.as_str())),
+    ("redirect_uri", Some(request.redirect_uri.as_str())),
+    ("scope", Some(request.scope.as_str())),
+    ("state", request.state.as_deref()),
+    ("code_challenge", Some(request.code_challenge.as_str())),
+    (
+      "code_challenge_method",
+      Some(request.code_challenge_method.as_str()),
+    ),
+    ("nonce", request.nonce.as_deref()),
+    (csrf::FORM_FIELD, csrf_token.map(|token| token.0.as_str())),
+  ]
+  .iter()
+  .filter_map(|(name, value)| {
+    value.map(|value| {
+      format!(
+        r#"<input type="hidden" name="{}" value="{}">"#,
+        name,
+        escape_html(value)
+      )
+    })
+  })
+  .collect::<Vec<_>>()
+  .join("\n      ");
+
+  let scopes = oauth::parse_scope(Some(&request.scope))
+    .iter()
+    .map(|scope| format!("<li>{}</li>", escape_html(scope)))
+    .collect::<Vec<_>>()
+    .join("");
+  let scopes = if scopes.is_empty() {
+    "<p>No additional permissions were requested.</p>".to_string()
+  } else {
+    format!("<ul>{}</ul>", scopes)
+  };
+
+  format!(
+    r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8">
+    <title>Authorize {client}</title>
+  </head>
+  <body>
+    <h1>Authorize {client}</h1>
+    <p><strong>{client}</strong> wants to access your account ({email}).</p>
+    {scopes}
+    <form method="post" action="/oauth/authorize">
+      {hidden_fields}
+      <button type="submit" name="decision" value="approve">Allow</button>
+      <button type="submit" name="decision" value="deny">Deny</button>
+    </form>
+  </body>
+</html>
+"#,
+    client = escape_html(&request.client.name),
+    email = escape_html(&user.email),
+    scopes = scopes,
+    hidden_fields = hidden_fields,
+  )
+}
+
+fn escape_html(value: &str) -> String {
+  value
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&#x27;")
+}
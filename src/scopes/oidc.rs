@@ -0,0 +1,105 @@
+use actix_web::{
+  http::{header, Method},
+  web, HttpMessage, HttpRequest, HttpResponse,
+};
+use serde_json::json;
+
+use crate::{
+  dtos::FilterUserDto,
+  error::{ErrorResponse, OAuthError},
+  models::User,
+  scopes::Routes,
+  utils::{oauth, oidc::user_claims, token::TokenClaims},
+  AppState,
+};
+
+pub fn well_known_scope() -> Routes {
+  Routes::new("/.well-known")
+    .route(Method::GET, "/openid-configuration", |route| {
+      route.to(discovery)
+    })
+    .route(Method::GET, "/jwks.json", |route| route.to(jwks))
+}
+
+/// OpenID Provider metadata (OpenID Connect Discovery 1.0 section 3).
+#[utoipa::path(
+  get,
+  path = "/.well-known/openid-configuration",
+  tag = "oidc",
+  responses((status = 200, description = "Provider metadata", body = Object))
+)]
+pub async fn discovery(state: web::Data<AppState>) -> HttpResponse {
+  let issuer = &state.env.oidc_issuer;
+
+  HttpResponse::Ok().json(json!({
+    "issuer": issuer,
+    "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+    "token_endpoint": format!("{}/oauth/token", issuer),
+    "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+    "introspection_endpoint": format!("{}/oauth/introspect", issuer),
+    "revocation_endpoint": format!("{}/oauth/revoke", issuer),
+    "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+    "scopes_supported": oauth::SUPPORTED_SCOPES,
+    "response_types_supported": ["code"],
+    "grant_types_supported": [
+      "authorization_code",
+      "refresh_token",
+      "client_credentials",
+      oauth::TOKEN_EXCHANGE_GRANT_TYPE,
+    ],
+    "subject_types_supported": ["public"],
+    "id_token_signing_alg_values_supported": ["RS256"],
+    "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+    "code_challenge_methods_supported": ["S256"],
+    "claims_supported": [
+      "iss", "sub", "aud", "exp", "iat", "auth_time", "nonce",
+      "name", "updated_at", "email", "email_verified",
+    ],
+  }))
+}
+
+/// Public keys that verify ID tokens.
+#[utoipa::path(
+  get,
+  path = "/.well-known/jwks.json",
+  tag = "oidc",
+  responses((status = 200, description = "JSON Web Key Set", body = Object))
+)]
+pub async fn jwks(state: web::Data<AppState>) -> HttpResponse {
+  HttpResponse::Ok().json(&state.oidc_keys.jwks)
+}
+
+/// Returns the claims released to the client for the scopes of its access
+/// token (OpenID Connect Core 1.0 section 5.3).
+#[utoipa::path(
+  method(get, post),
+  path = "/oauth/userinfo",
+  tag = "oidc",
+  responses(
+    (status = 200, description = "Claims about the token subject", body = Object),
+    (status = 401, description = "Missing or invalid access token", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []))
+)]
+pub async fn userinfo(req: HttpRequest) -> Result<HttpResponse, OAuthError> {
+  let (user, claims) = match (
+    req.extensions().get::<User>(),
+    req.extensions().get::<TokenClaims>(),
+  ) {
+    (Some(user), Some(claims)) => (FilterUserDto::filter_user(user), claims.clone()),
+    _ => return Err(OAuthError::server_error("User not found")),
+  };
+
+  let scopes = oauth::parse_scope(claims.scope.as_deref());
+  if claims.client_id.is_none() || !scopes.iter().any(|scope| scope == "openid") {
+    return Err(OAuthError::insufficient_scope(
+      "The access token was not granted the openid scope",
+    ));
+  }
+
+  Ok(
+    HttpResponse::Ok()
+      .insert_header((header::CACHE_CONTROL, "no-store"))
+      .json(user_claims(&user, &scopes)),
+  )
+}
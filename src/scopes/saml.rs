@@ -0,0 +1,467 @@
+use actix_web::{
+  http::{header, Method},
+  web, HttpRequest, HttpResponse,
+};
+use chrono::{Duration, Utc};
+use serde_json::json;
+
+use crate::{
+  config::SamlConfig,
+  db::{SamlExt, SocialExt, UserExt},
+  dtos::{SamlAcsDto, UserLoginResponseDto},
+  error::{ErrorMessage, ErrorResponse, HttpError},
+  models::{AuditEventType, User},
+  scopes::Routes,
+  utils::{
+    audit::{self, NewAuditEvent},
+    cookie, oauth, password,
+    saml::{self, SamlAssertion},
+    token,
+  },
+  AppState,
+};
+
+pub fn saml_scope() -> Routes {
+  Routes::new("/saml")
+    .route(Method::GET, "/metadata", |route| route.to(metadata))
+    .route(Method::GET, "/login", |route| route.to(start_login))
+    .route(Method::POST, "/acs", |route| route.to(acs))
+}
+
+/// Service provider metadata to register with the IdP.
+#[utoipa::path(
+  get,
+  path = "/api/auth/saml/metadata",
+  tag = "saml",
+  responses(
+    (status = 200, description = "SP metadata", content_type = "application/samlmetadata+xml"),
+    (status = 404, description = "SAML is not configured", body = ErrorResponse),
+  )
+)]
+pub async fn metadata(state: web::Data<AppState>) -> Result<HttpResponse, HttpError> {
+  let config = saml_settings(&state)?;
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type("application/samlmetadata+xml")
+      .body(saml::sp_metadata(config, &acs_url(&state))),
+  )
+}
+
+/// Sends the browser to the IdP with a fresh AuthnRequest (SP-initiated SSO).
+/// The request ID is also set in a cookie that the ACS checks.
+#[utoipa::path(
+  get,
+  path = "/api/auth/saml/login",
+  tag = "saml",
+  responses(
+    (status = 302, description = "Redirected to the IdP"),
+    (status = 404, description = "SAML is not configured", body = ErrorResponse),
+  )
+)]
+pub async fn start_login(state: web::Data<AppState>) -> Result<HttpResponse, HttpError> {
+  let config = saml_settings(&state)?;
+
+  let request = saml::authn_request(config, &acs_url(&state))
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+  state
+    .db_client
+    .save_saml_request(
+      &request.id,
+      Utc::now() + Duration::minutes(saml::REQUEST_MAXAGE_MINUTES),
+    )
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  Ok(
+    HttpResponse::Found()
+      .cookie(cookie::cross_site_login_state_cookie(
+        &state.env,
+        state.env.cookie.saml_request_name(),
+        request.id,
+        saml::REQUEST_MAXAGE_MINUTES,
+      ))
+      .insert_header((header::LOCATION, request.redirect_url))
+      .insert_header((header::CACHE_CONTROL, "no-store"))
+      .finish(),
+  )
+}
+
+/// Assertion consumer service. Only responses to an AuthnRequest issued by
+/// `start_login` to the same browser are accepted, and each of them only once.
+#[utoipa::path(
+  post,
+  path = "/api/auth/saml/acs",
+  tag = "saml",
+  request_body(content = SamlAcsDto, content_type = "application/x-www-form-urlencoded"),
+  responses(
+    (status = 200, description = "Signed in", body = UserLoginResponseDto),
+    (status = 302, description = "Signed in and redirected to the application"),
+    (status = 400, description = "Unknown or already used AuthnRequest, or it was issued to another browser", body = ErrorResponse),
+    (status = 401, description = "Invalid SAML response", body = ErrorResponse),
+    (status = 403, description = "Account is disabled or registration is not allowed", body = ErrorResponse),
+    (status = 404, description = "SAML is not configured", body = ErrorResponse),
+  )
+)]
+pub async fn acs(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  form: web::Form<SamlAcsDto>,
+) -> Result<HttpResponse, HttpError> {
+  let config = saml_settings(&state)?;
+
+  let failed = |reason: &str| {
+    NewAuditEvent::failure(AuditEventType::LoginFailed)
+      .request(&req)
+      .metadata(json!({"provider": saml::IDENTITY_PROVIDER, "reason": reason}))
+  };
+
+  let assertion = match saml::parse_response(&form.saml_response, config, &acs_url(&state)) {
+    Ok(assertion) => assertion,
+    Err(error) => {
+      let event = NewAuditEvent::failure(AuditEventType::LoginFailed)
+        .request(&req)
+        .metadata(json!({
+          "provider": saml::IDENTITY_PROVIDER,
+          "reason": "invalid_response",
+          "error": error,
+        }));
+      audit::record(&state.db_client, event).await;
+      return Err(HttpError::unauthorized(ErrorMessage::InvalidSamlResponse));
+    }
+  };
+
+  let request_id = assertion
+    .in_response_to
+    .as_deref()
+    .ok_or_else(|| HttpError::bad_request(ErrorMessage::InvalidLoginState))?;
+  // A response to someone else's AuthnRequest would sign this browser in to
+  // their account.
+  let request_cookie = req.cookie(state.env.cookie.saml_request_name());
+  if request_cookie.as_ref().map(|cookie| cookie.value()) != Some(request_id) {
+    audit::record(&state.db_client, failed("request_mismatch")).await;
+    return Err(HttpError::bad_request(ErrorMessage::InvalidLoginState));
+  }
+  state
+    .db_client
+    .consume_saml_request(request_id)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?
+    .ok_or_else(|| HttpError::bad_request(ErrorMessage::InvalidLoginState))?;
+
+  let email = match assertion.email(config) {
+    Some(email) if email.contains('@') => email.to_string(),
+    _ => {
+      audit::record(&state.db_client, failed("email_missing")).await;
+      return Err(HttpError::forbidden(ErrorMessage::ExternalEmailNotVerified));
+    }
+  };
+
+  let subject = subject(&assertion, &email);
+  let existing_user = state
+    .db_client
+    .get_user_by_identity(saml::IDENTITY_PROVIDER, &subject)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+  let user = match existing_user {
+    Some(user) => user,
+    None => link_or_create_user(&req, &state, config, &assertion, &subject, &email).await?,
+  };
+
+  if !user.active {
+    audit::record(&state.db_client, failed("account_disabled").target(user.id)).await;
+    return Err(HttpError::forbidden(ErrorMessage::AccountDisabled));
+  }
+
+  let user = match assertion.role(config) {
+    Some(role) if role != user.role => {
+      let previous_role = user.role;
+      let user = state
+        .db_client
+        .update_user_role(user.id, role)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+      let event = NewAuditEvent::success(AuditEventType::RoleChanged)
+        .target(user.id)
+        .request(&req)
+        .metadata(json!({
+          "from": previous_role.to_str(),
+          "to": user.role.to_str(),
+          "provider": saml::IDENTITY_PROVIDER,
+        }));
+      audit::record(&state.db_client, event).await;
+
+      user
+    }
+    _ => user,
+  };
+
+  let token = token::create_token(
+    &user.id.to_string(),
+    token::AMR_FEDERATED,
+    state.env.jwt_secret.as_bytes(),
+    state.env.jwt_maxage,
+  )
+  .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let [session_cookie, csrf_cookie] = cookie::session_cookies(&state.env, &token);
+  let request_cookie =
+    cookie::login_state_removal_cookie(&state.env, state.env.cookie.saml_request_name());
+
+  let event = NewAuditEvent::success(AuditEventType::Login)
+    .actor(user.id)
+    .target(user.id)
+    .request(&req)
+    .metadata(json!({"provider": saml::IDENTITY_PROVIDER}));
+  audit::record(&state.db_client, event).await;
+
+  match &config.login_redirect {
+    Some(location) => Ok(
+      HttpResponse::Found()
+        .cookie(session_cookie)
+        .cookie(csrf_cookie)
+        .cookie(request_cookie)
+        .insert_header((header::LOCATION, location.as_str()))
+        .finish(),
+    ),
+    None => Ok(
+      HttpResponse::Ok()
+        .cookie(session_cookie)
+        .cookie(csrf_cookie)
+        .cookie(request_cookie)
+        .json(UserLoginResponseDto {
+          status: "success".to_string(),
+          token,
+        }),
+    ),
+  }
+}
+
+/// First sign-in through the IdP: link the user with the same email or
+/// provision a new one. The IdP is trusted like a directory, so the
+/// registration policy does not apply.
+async fn link_or_create_user(
+  req: &HttpRequest,
+  state: &AppState,
+  config: &SamlConfig,
+  assertion: &SamlAssertion,
+  subject: &str,
+  email: &str,
+) -> Result<User, HttpError> {
+  let existing_user = state
+    .db_client
+    .get_user(None, None, Some(email))
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  if let Some(user) = existing_user {
+    state
+      .db_client
+      .save_user_identity(user.id, saml::IDENTITY_PROVIDER, subject, Some(email))
+      .await
+      .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let event = NewAuditEvent::success(AuditEventType::IdentityLinked)
+      .actor(user.id)
+      .target(user.id)
+      .request(req)
+      .metadata(json!({"provider": saml::IDENTITY_PROVIDER, "subject": subject}));
+    audit::record(&state.db_client, event).await;
+
+    return Ok(user);
+  }
+
+  // The IdP owns the credentials; store a random, unusable password.
+  let hashed_password = password::hash(oauth::generate_token()).map_err(HttpError::server_error)?;
+  let name = assertion
+    .attribute(&config.name_attribute)
+    .map(str::to_string)
+    .or_else(|| email.split('@').next().map(str::to_string))
+    .unwrap_or_default();
+
+  let user = state
+    .db_client
+    .save_social_user(
+      &name,
+      email,
+      &hashed_password,
+      saml::IDENTITY_PROVIDER,
+      subject,
+    )
+    .await
+    .map_err(|e| match e {
+      sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+        HttpError::unique_constraint_voilation(ErrorMessage::EmailExist)
+      }
+      e => HttpError::server_error(e.to_string()),
+    })?;
+
+  let event = NewAuditEvent::success(AuditEventType::Register)
+    .actor(user.id)
+    .target(user.id)
+    .request(req)
+    .metadata(json!({"role": user.role.to_str(), "provider": saml::IDENTITY_PROVIDER}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(user)
+}
+
+/// Transient NameIDs change on every login, so such users are linked by email.
+fn subject(assertion: &SamlAssertion, email: &str) -> String {
+  match assertion.name_id_format.as_deref() {
+    Some(saml::TRANSIENT_NAME_ID_FORMAT) => email.to_lowercase(),
+    _ => assertion.name_id.clone(),
+  }
+}
+
+fn saml_settings(state: &AppState) -> Result<&SamlConfig, HttpError> {
+  state
+    .env
+    .saml
+    .as_ref()
+    .ok_or(HttpError::not_found(ErrorMessage::UnknownProvider))
+}
+
+fn acs_url(state: &AppState) -> String {
+  format!("{}/api/auth/saml/acs", state.env.oidc_issuer)
+}
+
+#[cfg(test)]
+mod tests {
+  use actix_web::{
+    cookie::Cookie,
+    http::{header, StatusCode},
+    test, web, App,
+  };
+  use base64::{engine::general_purpose::STANDARD, Engine};
+  use chrono::{Duration, Utc};
+  use serde_json::Value;
+
+  use crate::{
+    config::SamlConfig,
+    db::{SamlExt, UserExt},
+    scopes, test_utils,
+    utils::saml::SamlIdp,
+    AppState,
+  };
+
+  const REQUEST_ID: &str = "_request-fixture";
+  const EMAIL: &str = "saml.fixture@example.com";
+  const SIGNED_ASSERTION: &str = include_str!("../../tests/fixtures/saml/signed-assertion.xml");
+
+  /// State whose ACS URL and entity ID match the fixtures.
+  async fn app_state() -> (AppState, sqlx::PgPool) {
+    let saml = SamlConfig {
+      idp: SamlIdp::from_metadata(include_str!("../../tests/fixtures/saml/idp-metadata.xml"))
+        .unwrap(),
+      sp_entity_id: "http://localhost:8080/api/auth/saml/metadata".to_string(),
+      email_attribute: None,
+      name_attribute: "displayName".to_string(),
+      group_attribute: "groups".to_string(),
+      admin_groups: Vec::new(),
+      moderator_groups: Vec::new(),
+      login_redirect: None,
+    };
+    let mut config = test_utils::config();
+    config.oidc_issuer = "http://localhost:8080".to_string();
+    config.saml = Some(saml);
+
+    test_utils::app_state(config).await
+  }
+
+  #[actix_web::test]
+  async fn login_sets_the_request_cookie() {
+    let (state, _) = app_state().await;
+    let db_client = state.db_client.clone();
+    let cookie_name = state.env.cookie.saml_request_name();
+    let app = test::init_service(
+      App::new()
+        .app_data(web::Data::new(state))
+        .configure(scopes::configure(false)),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+      .uri("/api/auth/saml/login")
+      .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert!(res.headers().contains_key(header::LOCATION));
+
+    let cookie = res
+      .response()
+      .cookies()
+      .find(|cookie| cookie.name() == cookie_name)
+      .expect("No request cookie");
+    assert!(cookie.http_only().unwrap_or(false));
+    let request = db_client
+      .consume_saml_request(cookie.value())
+      .await
+      .unwrap();
+    assert!(request.is_some(), "Cookie does not name the AuthnRequest");
+  }
+
+  #[actix_web::test]
+  async fn acs_accepts_only_the_browser_that_started_the_login() {
+    let (state, pool) = app_state().await;
+    let db_client = state.db_client.clone();
+    let cookie_name = state.env.cookie.saml_request_name();
+    let app = test::init_service(
+      App::new()
+        .app_data(web::Data::new(state))
+        .configure(scopes::configure(false)),
+    )
+    .await;
+
+    sqlx::query("DELETE FROM saml_requests WHERE request_id = $1")
+      .bind(REQUEST_ID)
+      .execute(&pool)
+      .await
+      .unwrap();
+    if let Some(user) = db_client.get_user(None, None, Some(EMAIL)).await.unwrap() {
+      db_client.delete_user(user.id).await.unwrap();
+    }
+    db_client
+      .save_saml_request(REQUEST_ID, Utc::now() + Duration::minutes(10))
+      .await
+      .unwrap();
+
+    let post = |cookie: Option<&str>| {
+      let mut req = test::TestRequest::post()
+        .uri("/api/auth/saml/acs")
+        .set_form([("SAMLResponse", STANDARD.encode(SIGNED_ASSERTION))]);
+      if let Some(value) = cookie {
+        req = req.cookie(Cookie::new(cookie_name, value.to_string()));
+      }
+      req.to_request()
+    };
+
+    for cookie in [None, Some("_other-request")] {
+      let res = test::call_service(&app, post(cookie)).await;
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST, "cookie {:?}", cookie);
+      let body: Value = test::read_body_json(res).await;
+      assert_eq!(body["code"], "invalid_login_state");
+    }
+
+    let res = test::call_service(&app, post(Some(REQUEST_ID))).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let removed = res
+      .response()
+      .cookies()
+      .find(|cookie| cookie.name() == cookie_name)
+      .expect("Request cookie is not cleared");
+    assert!(removed.value().is_empty());
+
+    // Each AuthnRequest is answered only once.
+    let res = test::call_service(&app, post(Some(REQUEST_ID))).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let user = db_client
+      .get_user(None, None, Some(EMAIL))
+      .await
+      .unwrap()
+      .expect("User was not provisioned");
+    db_client.delete_user(user.id).await.unwrap();
+  }
+}
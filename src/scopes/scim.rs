@@ -0,0 +1,715 @@
+use actix_web::{
+  http::{header, Method},
+  web, HttpRequest, HttpResponse,
+};
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+use crate::{
+  db::{ScimExt, UserExt},
+  dtos::{ScimListQueryDto, ScimListResponseDto, ScimPatchDto, ScimUserDto},
+  error::ScimError,
+  extractors::scim::RequireScimToken,
+  models::{AuditEventType, User},
+  scopes::Routes,
+  utils::{
+    audit::{self, NewAuditEvent},
+    oauth, password,
+    scim::{self, ScimUserFilter, UserAttributes},
+  },
+  AppState,
+};
+
+pub fn scim_scope() -> Routes {
+  Routes::new("/scim/v2")
+    .app_data(
+      web::JsonConfig::default()
+        .error_handler(|err, _| ScimError::bad_request("invalidSyntax", err.to_string()).into()),
+    )
+    .app_data(
+      web::QueryConfig::default()
+        .error_handler(|err, _| ScimError::bad_request("invalidValue", err.to_string()).into()),
+    )
+    .route(Method::GET, "/ServiceProviderConfig", |route| {
+      route.to(service_provider_config).wrap(RequireScimToken)
+    })
+    .route(Method::GET, "/ResourceTypes", |route| {
+      route.to(get_resource_types).wrap(RequireScimToken)
+    })
+    .route(Method::GET, "/ResourceTypes/{id}", |route| {
+      route.to(get_resource_type).wrap(RequireScimToken)
+    })
+    .route(Method::GET, "/Schemas", |route| {
+      route.to(get_schemas).wrap(RequireScimToken)
+    })
+    .route(Method::GET, "/Schemas/{id}", |route| {
+      route.to(get_schema).wrap(RequireScimToken)
+    })
+    .route(Method::GET, "/Users", |route| {
+      route.to(get_users).wrap(RequireScimToken)
+    })
+    .route(Method::POST, "/Users", |route| {
+      route.to(create_user).wrap(RequireScimToken)
+    })
+    .route(Method::GET, "/Users/{id}", |route| {
+      route.to(get_user).wrap(RequireScimToken)
+    })
+    .route(Method::PUT, "/Users/{id}", |route| {
+      route.to(replace_user).wrap(RequireScimToken)
+    })
+    .route(Method::PATCH, "/Users/{id}", |route| {
+      route.to(patch_user).wrap(RequireScimToken)
+    })
+    .route(Method::DELETE, "/Users/{id}", |route| {
+      route.to(delete_user).wrap(RequireScimToken)
+    })
+}
+
+fn base_url(state: &AppState) -> String {
+  format!("{}/scim/v2", state.env.oidc_issuer)
+}
+
+fn list_response<T>(
+  resources: Vec<T>,
+  total_results: i64,
+  start_index: i64,
+) -> ScimListResponseDto<T> {
+  ScimListResponseDto {
+    schemas: vec![scim::LIST_RESPONSE_SCHEMA.to_string()],
+    total_results,
+    start_index,
+    items_per_page: resources.len() as i64,
+    resources,
+  }
+}
+
+#[utoipa::path(
+  get,
+  path = "/scim/v2/ServiceProviderConfig",
+  tag = "scim",
+  responses(
+    (status = 200, description = "Supported SCIM features", body = Object, content_type = "application/scim+json"),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn service_provider_config(state: web::Data<AppState>) -> HttpResponse {
+  HttpResponse::Ok()
+    .content_type(scim::CONTENT_TYPE)
+    .json(scim::service_provider_config(&base_url(&state)))
+}
+
+#[utoipa::path(
+  get,
+  path = "/scim/v2/ResourceTypes",
+  tag = "scim",
+  responses(
+    (status = 200, description = "Supported resource types", body = Object, content_type = "application/scim+json"),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn get_resource_types(state: web::Data<AppState>) -> HttpResponse {
+  let resource_types = scim::resource_types(&base_url(&state));
+  let total = resource_types.len() as i64;
+
+  HttpResponse::Ok()
+    .content_type(scim::CONTENT_TYPE)
+    .json(list_response(resource_types, total, 1))
+}
+
+#[utoipa::path(
+  get,
+  path = "/scim/v2/ResourceTypes/{id}",
+  tag = "scim",
+  params(("id" = String, Path, description = "Resource type name")),
+  responses(
+    (status = 200, description = "Resource type", body = Object, content_type = "application/scim+json"),
+    (status = 404, description = "Unknown resource type", body = Object),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn get_resource_type(
+  state: web::Data<AppState>,
+  path: web::Path<String>,
+) -> Result<HttpResponse, ScimError> {
+  let resource_type = scim::resource_types(&base_url(&state))
+    .into_iter()
+    .find(|resource_type| resource_type["id"] == path.as_str())
+    .ok_or_else(|| ScimError::not_found(format!("Resource type {} not found", path)))?;
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type(scim::CONTENT_TYPE)
+      .json(resource_type),
+  )
+}
+
+#[utoipa::path(
+  get,
+  path = "/scim/v2/Schemas",
+  tag = "scim",
+  responses(
+    (status = 200, description = "Supported schemas", body = Object, content_type = "application/scim+json"),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn get_schemas(state: web::Data<AppState>) -> HttpResponse {
+  let schemas = scim::schemas(&base_url(&state));
+  let total = schemas.len() as i64;
+
+  HttpResponse::Ok()
+    .content_type(scim::CONTENT_TYPE)
+    .json(list_response(schemas, total, 1))
+}
+
+#[utoipa::path(
+  get,
+  path = "/scim/v2/Schemas/{id}",
+  tag = "scim",
+  params(("id" = String, Path, description = "Schema URI")),
+  responses(
+    (status = 200, description = "Schema", body = Object, content_type = "application/scim+json"),
+    (status = 404, description = "Unknown schema", body = Object),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn get_schema(
+  state: web::Data<AppState>,
+  path: web::Path<String>,
+) -> Result<HttpResponse, ScimError> {
+  let schema = scim::schemas(&base_url(&state))
+    .into_iter()
+    .find(|schema| schema["id"] == path.as_str())
+    .ok_or_else(|| ScimError::not_found(format!("Schema {} not found", path)))?;
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type(scim::CONTENT_TYPE)
+      .json(schema),
+  )
+}
+
+#[utoipa::path(
+  get,
+  path = "/scim/v2/Users",
+  tag = "scim",
+  params(ScimListQueryDto),
+  responses(
+    (status = 200, description = "Matching users", body = ScimListResponseDto<ScimUserDto>, content_type = "application/scim+json"),
+    (status = 400, description = "Unsupported filter", body = Object),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn get_users(
+  state: web::Data<AppState>,
+  query: web::Query<ScimListQueryDto>,
+) -> Result<HttpResponse, ScimError> {
+  let start_index = query.start_index.unwrap_or(1).max(1);
+  let count = query
+    .count
+    .unwrap_or(scim::MAX_RESULTS)
+    .clamp(0, scim::MAX_RESULTS);
+  let filter = match &query.filter {
+    Some(filter) => scim::parse_filter(filter)?,
+    None => Default::default(),
+  };
+
+  let (users, total) = state
+    .db_client
+    .get_scim_users(&filter, start_index - 1, count)
+    .await
+    .map_err(|e| ScimError::server_error(e.to_string()))?;
+
+  let user_ids: Vec<Uuid> = users.iter().map(|user| user.id).collect();
+  let mut external_ids = state
+    .db_client
+    .get_scim_external_ids(&user_ids)
+    .await
+    .map_err(|e| ScimError::server_error(e.to_string()))?;
+
+  let base_url = base_url(&state);
+  let resources: Vec<ScimUserDto> = users
+    .iter()
+    .map(|user| scim::user_resource(user, external_ids.remove(&user.id), &base_url))
+    .collect();
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type(scim::CONTENT_TYPE)
+      .json(list_response(resources, total, start_index)),
+  )
+}
+
+#[utoipa::path(
+  get,
+  path = "/scim/v2/Users/{id}",
+  tag = "scim",
+  params(("id" = String, Path, description = "User id")),
+  responses(
+    (status = 200, description = "User", body = ScimUserDto, content_type = "application/scim+json"),
+    (status = 404, description = "User not found", body = Object),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn get_user(
+  state: web::Data<AppState>,
+  path: web::Path<String>,
+) -> Result<HttpResponse, ScimError> {
+  let (user, external_id) = find_user(&state, &path).await?;
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type(scim::CONTENT_TYPE)
+      .json(scim::user_resource(&user, external_id, &base_url(&state))),
+  )
+}
+
+#[utoipa::path(
+  post,
+  path = "/scim/v2/Users",
+  tag = "scim",
+  request_body(content = ScimUserDto, content_type = "application/scim+json"),
+  responses(
+    (status = 201, description = "User provisioned", body = ScimUserDto, content_type = "application/scim+json"),
+    (status = 400, description = "Invalid user", body = Object),
+    (status = 409, description = "User name or email is taken", body = Object),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn create_user(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  body: web::Json<ScimUserDto>,
+) -> Result<HttpResponse, ScimError> {
+  let attributes = UserAttributes::from_resource(body.into_inner())?;
+  ensure_unique_email(&state, &attributes.email, None).await?;
+  check_password_policy(&state, &attributes)?;
+
+  // Users provisioned without a password can only sign in through another
+  // backend, so store a random, unusable one.
+  let hashed_password = password::hash(
+    attributes
+      .password
+      .clone()
+      .unwrap_or_else(oauth::generate_token),
+  )
+  .map_err(|e| ScimError::server_error(e.to_string()))?;
+
+  let user = state
+    .db_client
+    .save_scim_user(&attributes, &hashed_password)
+    .await
+    .map_err(map_db_error)?;
+
+  let event = NewAuditEvent::success(AuditEventType::Register)
+    .target(user.id)
+    .request(&req)
+    .metadata(json!({"role": user.role.to_str(), "source": "scim"}));
+  audit::record(&state.db_client, event).await;
+
+  let resource = scim::user_resource(&user, attributes.external_id, &base_url(&state));
+  let location = resource
+    .meta
+    .as_ref()
+    .map(|meta| meta.location.clone())
+    .unwrap_or_default();
+
+  Ok(
+    HttpResponse::Created()
+      .content_type(scim::CONTENT_TYPE)
+      .insert_header((header::LOCATION, location))
+      .json(resource),
+  )
+}
+
+#[utoipa::path(
+  put,
+  path = "/scim/v2/Users/{id}",
+  tag = "scim",
+  params(("id" = String, Path, description = "User id")),
+  request_body(content = ScimUserDto, content_type = "application/scim+json"),
+  responses(
+    (status = 200, description = "Updated user", body = ScimUserDto, content_type = "application/scim+json"),
+    (status = 400, description = "Invalid user", body = Object),
+    (status = 404, description = "User not found", body = Object),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn replace_user(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  path: web::Path<String>,
+  body: web::Json<ScimUserDto>,
+) -> Result<HttpResponse, ScimError> {
+  let (user, external_id) = find_user(&state, &path).await?;
+  let previous = UserAttributes::from_user(&user, external_id);
+
+  // Clients that do not manage roles or activation leave them out of the
+  // resource; keep the current values instead of resetting them.
+  let keep_role = body.roles.is_empty();
+  let keep_active = body.active.is_none();
+  let mut attributes = UserAttributes::from_resource(body.into_inner())?;
+  if keep_role {
+    attributes.role = previous.role;
+  }
+  if keep_active {
+    attributes.active = previous.active;
+  }
+
+  save_changes(&req, &state, user, previous, attributes).await
+}
+
+#[utoipa::path(
+  patch,
+  path = "/scim/v2/Users/{id}",
+  tag = "scim",
+  params(("id" = String, Path, description = "User id")),
+  request_body(content = ScimPatchDto, content_type = "application/scim+json"),
+  responses(
+    (status = 200, description = "Updated user", body = ScimUserDto, content_type = "application/scim+json"),
+    (status = 400, description = "Invalid patch operation", body = Object),
+    (status = 404, description = "User not found", body = Object),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn patch_user(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  path: web::Path<String>,
+  body: web::Json<ScimPatchDto>,
+) -> Result<HttpResponse, ScimError> {
+  if !body.schemas.is_empty() && !body.schemas.iter().any(|s| s == scim::PATCH_OP_SCHEMA) {
+    return Err(ScimError::bad_request(
+      "invalidSyntax",
+      format!(
+        "Patch requests must use the {} schema",
+        scim::PATCH_OP_SCHEMA
+      ),
+    ));
+  }
+
+  let (user, external_id) = find_user(&state, &path).await?;
+  let previous = UserAttributes::from_user(&user, external_id);
+  let mut attributes = previous.clone();
+  attributes.apply_patch(&body.operations)?;
+
+  save_changes(&req, &state, user, previous, attributes).await
+}
+
+#[utoipa::path(
+  delete,
+  path = "/scim/v2/Users/{id}",
+  tag = "scim",
+  params(("id" = String, Path, description = "User id")),
+  responses(
+    (status = 204, description = "User deactivated and signed out everywhere"),
+    (status = 404, description = "User not found", body = Object),
+    (status = 401, description = "Missing or invalid SCIM token", body = Object),
+  ),
+  security(("scim_token" = []))
+)]
+pub async fn delete_user(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  path: web::Path<String>,
+) -> Result<HttpResponse, ScimError> {
+  let (user, external_id) = find_user(&state, &path).await?;
+  let user_id = user.id;
+  let previous = UserAttributes::from_user(&user, external_id);
+  let attributes = UserAttributes {
+    active: false,
+    ..previous.clone()
+  };
+
+  save_changes(&req, &state, user, previous, attributes).await?;
+  state
+    .db_client
+    .revoke_user_sessions(user_id)
+    .await
+    .map_err(|e| ScimError::server_error(e.to_string()))?;
+
+  Ok(HttpResponse::NoContent().finish())
+}
+
+async fn find_user(state: &AppState, id: &str) -> Result<(User, Option<String>), ScimError> {
+  let not_found = || ScimError::not_found(format!("User {} not found", id));
+
+  let user_id = Uuid::parse_str(id).map_err(|_| not_found())?;
+  let user = state
+    .db_client
+    .get_user(Some(user_id), None, None)
+    .await
+    .map_err(|e| ScimError::server_error(e.to_string()))?
+    .ok_or_else(not_found)?;
+
+  let external_id = state
+    .db_client
+    .get_scim_external_ids(&[user.id])
+    .await
+    .map_err(|e| ScimError::server_error(e.to_string()))?
+    .remove(&user.id);
+
+  Ok((user, external_id))
+}
+
+/// Persists `attributes` and records which of them changed. Deactivation is a
+/// change of `active`; the user is kept and can no longer sign in.
+async fn save_changes(
+  req: &HttpRequest,
+  state: &AppState,
+  user: User,
+  previous: UserAttributes,
+  attributes: UserAttributes,
+) -> Result<HttpResponse, ScimError> {
+  if previous == attributes {
+    return Ok(
+      HttpResponse::Ok()
+        .content_type(scim::CONTENT_TYPE)
+        .json(scim::user_resource(
+          &user,
+          attributes.external_id,
+          &base_url(state),
+        )),
+    );
+  }
+
+  if !previous.email.eq_ignore_ascii_case(&attributes.email) {
+    ensure_unique_email(state, &attributes.email, Some(user.id)).await?;
+  }
+
+  check_password_policy(state, &attributes)?;
+  let hashed_password = match &attributes.password {
+    Some(new_password) => {
+      Some(password::hash(new_password).map_err(|e| ScimError::server_error(e.to_string()))?)
+    }
+    None => None,
+  };
+
+  let user = state
+    .db_client
+    .update_scim_user(user.id, &attributes, hashed_password.as_deref())
+    .await
+    .map_err(map_db_error)?
+    .ok_or_else(|| ScimError::not_found(format!("User {} not found", user.id)))?;
+
+  let mut changes = Map::new();
+  let mut record_change = |field: &str, from: Value, to: Value| {
+    if from != to {
+      changes.insert(field.to_string(), json!({"from": from, "to": to}));
+    }
+  };
+  record_change("email", json!(previous.email), json!(attributes.email));
+  record_change("name", json!(previous.name), json!(attributes.name));
+  record_change(
+    "externalId",
+    json!(previous.external_id),
+    json!(attributes.external_id),
+  );
+  record_change("active", json!(previous.active), json!(attributes.active));
+  record_change(
+    "role",
+    json!(previous.role.to_str()),
+    json!(attributes.role.to_str()),
+  );
+  if attributes.password.is_some() {
+    changes.insert("password".to_string(), json!(true));
+  }
+
+  let event = NewAuditEvent::success(AuditEventType::UserUpdated)
+    .target(user.id)
+    .request(req)
+    .metadata(json!({"changes": changes, "source": "scim"}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type(scim::CONTENT_TYPE)
+      .json(scim::user_resource(
+        &user,
+        attributes.external_id,
+        &base_url(state),
+      )),
+  )
+}
+
+/// `userName` is case-insensitive in SCIM, while the `users.email` constraint
+/// is not.
+async fn ensure_unique_email(
+  state: &AppState,
+  email: &str,
+  except: Option<Uuid>,
+) -> Result<(), ScimError> {
+  let filter = ScimUserFilter {
+    email: Some(email.to_string()),
+    ..Default::default()
+  };
+  let (users, _) = state
+    .db_client
+    .get_scim_users(&filter, 0, 2)
+    .await
+    .map_err(|e| ScimError::server_error(e.to_string()))?;
+
+  if users.iter().any(|user| Some(user.id) != except) {
+    return Err(ScimError::conflict(format!(
+      "A user with userName {} already exists",
+      email
+    )));
+  }
+
+  Ok(())
+}
+
+/// Passwords pushed by the provisioning client follow the same policy as
+/// passwords chosen by users.
+fn check_password_policy(state: &AppState, attributes: &UserAttributes) -> Result<(), ScimError> {
+  match &attributes.password {
+    Some(password) => password::check_policy(password, &state.env.password_policy)
+      .map_err(|e| ScimError::bad_request("invalidValue", e.to_string())),
+    None => Ok(()),
+  }
+}
+
+fn map_db_error(error: sqlx::Error) -> ScimError {
+  match error {
+    sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+      ScimError::conflict("A user with this userName or externalId already exists")
+    }
+    e => ScimError::server_error(e.to_string()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use actix_web::{
+    http::{header, StatusCode},
+    test, web, App,
+  };
+  use serde_json::{json, Value};
+  use uuid::Uuid;
+
+  use crate::{db::UserExt, scopes, test_utils, utils::scim};
+
+  const TOKEN: &str = "scim-test-token";
+  const PASSWORD: &str = "Correct-Horse-42-battery";
+
+  macro_rules! init_app {
+    ($state:expr) => {
+      test::init_service(
+        App::new()
+          .app_data(web::Data::new($state))
+          .configure(scopes::configure(false)),
+      )
+      .await
+    };
+  }
+
+  fn scim_request(method: &str, uri: &str, token: &str) -> test::TestRequest {
+    test::TestRequest::default()
+      .method(method.parse().unwrap())
+      .uri(uri)
+      .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+      .insert_header((header::CONTENT_TYPE, scim::CONTENT_TYPE))
+  }
+
+  fn login(email: &str) -> actix_http::Request {
+    test::TestRequest::post()
+      .uri("/api/auth/login")
+      .set_json(json!({"email": email, "password": PASSWORD}))
+      .to_request()
+  }
+
+  #[actix_web::test]
+  async fn rejects_wrong_token() {
+    let mut config = test_utils::config();
+    config.scim_token = Some(TOKEN.to_string());
+    let (state, _) = test_utils::app_state(config).await;
+    let app = init_app!(state);
+
+    // The middleware rejects the request before it reaches a handler.
+    let err = test::try_call_service(
+      &app,
+      scim_request("GET", "/scim/v2/Users", "wrong").to_request(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+      err.as_response_error().status_code(),
+      StatusCode::UNAUTHORIZED
+    );
+
+    let res = test::call_service(
+      &app,
+      scim_request("GET", "/scim/v2/Users", TOKEN).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+  }
+
+  #[actix_web::test]
+  async fn deactivated_user_cannot_log_in() {
+    let email = test_utils::unique_email();
+    let mut config = test_utils::config();
+    config.scim_token = Some(TOKEN.to_string());
+    let (state, _) = test_utils::app_state(config).await;
+    let db_client = state.db_client.clone();
+    let app = init_app!(state);
+
+    let req = scim_request("POST", "/scim/v2/Users", TOKEN)
+      .set_payload(
+        json!({
+          "schemas": [scim::USER_SCHEMA],
+          "userName": email,
+          "displayName": "Provisioned User",
+          "password": PASSWORD,
+        })
+        .to_string(),
+      )
+      .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let resource: Value = test::read_body_json(res).await;
+    let id: Uuid = resource["id"].as_str().unwrap().parse().unwrap();
+
+    let res = test::call_service(&app, login(&email)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = scim_request("PATCH", &format!("/scim/v2/Users/{}", id), TOKEN)
+      .set_payload(
+        json!({
+          "schemas": [scim::PATCH_OP_SCHEMA],
+          "Operations": [{"op": "replace", "path": "active", "value": false}],
+        })
+        .to_string(),
+      )
+      .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = test::call_service(&app, login(&email)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "account_disabled");
+
+    // DELETE keeps the row, so the audit trail still points at a user.
+    let req = scim_request("DELETE", &format!("/scim/v2/Users/{}", id), TOKEN).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let user = db_client
+      .get_user(Some(id), None, None)
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(!user.active);
+
+    db_client.delete_user(id).await.unwrap();
+  }
+}
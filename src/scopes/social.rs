@@ -0,0 +1,713 @@
+use actix_web::{
+  http::{header, Method},
+  web, HttpRequest, HttpResponse,
+};
+use chrono::{Duration, Utc};
+use serde_json::json;
+
+use crate::{
+  config::{RegistrationMode, SocialProvider},
+  db::{SocialExt, UserExt},
+  dtos::{SocialCallbackDto, SocialProviderListResponseDto, UserLoginResponseDto},
+  error::{ErrorMessage, ErrorResponse, HttpError},
+  models::{AuditEventType, User},
+  scopes::Routes,
+  utils::{
+    audit::{self, NewAuditEvent},
+    cookie, oauth, password,
+    social::{self, ExternalProfile},
+    token,
+  },
+  AppState,
+};
+
+pub fn social_scope() -> Routes {
+  Routes::new("/social")
+    .route(Method::GET, "", |route| route.to(get_providers))
+    .route(Method::GET, "/{provider}", |route| route.to(start_login))
+    .route(Method::GET, "/{provider}/callback", |route| {
+      route.to(callback)
+    })
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/auth/social",
+  tag = "social",
+  responses((status = 200, description = "Names of the configured providers", body = SocialProviderListResponseDto))
+)]
+pub async fn get_providers(state: web::Data<AppState>) -> HttpResponse {
+  HttpResponse::Ok().json(SocialProviderListResponseDto {
+    status: "success".to_string(),
+    providers: state
+      .env
+      .social_providers
+      .iter()
+      .map(|provider| provider.name.clone())
+      .collect(),
+  })
+}
+
+/// Sends the browser to the provider with a fresh `state` and PKCE challenge.
+/// The `state` is also set in a cookie that the callback checks.
+#[utoipa::path(
+  get,
+  path = "/api/auth/social/{provider}",
+  tag = "social",
+  params(("provider" = String, Path, description = "Provider name, e.g. google")),
+  responses(
+    (status = 302, description = "Redirected to the provider"),
+    (status = 404, description = "Unknown provider", body = ErrorResponse),
+  )
+)]
+pub async fn start_login(
+  state: web::Data<AppState>,
+  path: web::Path<String>,
+) -> Result<HttpResponse, HttpError> {
+  let provider = find_provider(&state, &path)?;
+  let endpoints = social::endpoints(provider).await?;
+
+  let login_state = oauth::generate_token();
+  let code_verifier = oauth::generate_token();
+  state
+    .db_client
+    .save_social_login_request(
+      &oauth::hash_token(&login_state),
+      &provider.name,
+      &code_verifier,
+      Utc::now() + Duration::minutes(social::LOGIN_REQUEST_MAXAGE_MINUTES),
+    )
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let url = social::authorization_url(
+    provider,
+    &endpoints,
+    &redirect_uri(&state, provider),
+    &login_state,
+    &oauth::s256_code_challenge(&code_verifier),
+  )?;
+
+  Ok(
+    HttpResponse::Found()
+      .cookie(cookie::login_state_cookie(
+        &state.env,
+        state.env.cookie.social_state_name(),
+        login_state,
+        social::LOGIN_REQUEST_MAXAGE_MINUTES,
+      ))
+      .insert_header((header::LOCATION, url))
+      .insert_header((header::CACHE_CONTROL, "no-store"))
+      .finish(),
+  )
+}
+
+/// Completes a social sign-in. When `SOCIAL_LOGIN_REDIRECT` is set the browser
+/// is redirected there with the session cookie instead of receiving the token.
+#[utoipa::path(
+  get,
+  path = "/api/auth/social/{provider}/callback",
+  tag = "social",
+  params(("provider" = String, Path, description = "Provider name"), SocialCallbackDto),
+  responses(
+    (status = 200, description = "Signed in", body = UserLoginResponseDto),
+    (status = 302, description = "Signed in and redirected to the application"),
+    (status = 400, description = "Unknown or expired login state, or it was started in another browser", body = ErrorResponse),
+    (status = 401, description = "The provider reported an error", body = ErrorResponse),
+    (status = 403, description = "Account is disabled, unverified, or registration is not allowed", body = ErrorResponse),
+    (status = 404, description = "Unknown provider", body = ErrorResponse),
+  )
+)]
+pub async fn callback(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  path: web::Path<String>,
+  query: web::Query<SocialCallbackDto>,
+) -> Result<HttpResponse, HttpError> {
+  let provider = find_provider(&state, &path)?;
+
+  let failed = |reason: &str| {
+    NewAuditEvent::failure(AuditEventType::LoginFailed)
+      .request(&req)
+      .metadata(json!({"provider": provider.name, "reason": reason}))
+  };
+
+  if let Some(error) = &query.error {
+    audit::record(&state.db_client, failed("provider_error")).await;
+    return Err(HttpError::unauthorized(ErrorMessage::ProviderLoginFailed(
+      provider.name.clone(),
+      query
+        .error_description
+        .clone()
+        .unwrap_or_else(|| error.clone()),
+    )));
+  }
+
+  let (code, login_state) = match (&query.code, &query.state) {
+    (Some(code), Some(login_state)) => (code, login_state),
+    _ => return Err(HttpError::bad_request(ErrorMessage::InvalidLoginState)),
+  };
+
+  // A callback carrying someone else's state would sign this browser in to
+  // their account.
+  let state_cookie = req.cookie(state.env.cookie.social_state_name());
+  if state_cookie.map(|cookie| oauth::hash_token(cookie.value()))
+    != Some(oauth::hash_token(login_state))
+  {
+    audit::record(&state.db_client, failed("state_mismatch")).await;
+    return Err(HttpError::bad_request(ErrorMessage::InvalidLoginState));
+  }
+
+  let login_request = state
+    .db_client
+    .consume_social_login_request(&oauth::hash_token(login_state), &provider.name)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?
+    .ok_or_else(|| HttpError::bad_request(ErrorMessage::InvalidLoginState))?;
+
+  let endpoints = social::endpoints(provider).await?;
+  let access_token = social::exchange_code(
+    provider,
+    &endpoints,
+    &redirect_uri(&state, provider),
+    code,
+    &login_request.code_verifier,
+  )
+  .await?;
+  let profile = social::fetch_profile(provider, &endpoints, &access_token).await?;
+
+  let existing_user = state
+    .db_client
+    .get_user_by_identity(&provider.name, &profile.subject)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+  let user = match existing_user {
+    Some(user) => user,
+    None => link_or_create_user(&req, &state, provider, &profile).await?,
+  };
+
+  if !user.active {
+    audit::record(&state.db_client, failed("account_disabled").target(user.id)).await;
+    return Err(HttpError::forbidden(ErrorMessage::AccountDisabled));
+  }
+
+  let token = token::create_token(
+    &user.id.to_string(),
+    token::AMR_FEDERATED,
+    state.env.jwt_secret.as_bytes(),
+    state.env.jwt_maxage,
+  )
+  .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let [session_cookie, csrf_cookie] = cookie::session_cookies(&state.env, &token);
+  let state_cookie =
+    cookie::login_state_removal_cookie(&state.env, state.env.cookie.social_state_name());
+
+  let event = NewAuditEvent::success(AuditEventType::Login)
+    .actor(user.id)
+    .target(user.id)
+    .request(&req)
+    .metadata(json!({"provider": provider.name}));
+  audit::record(&state.db_client, event).await;
+
+  match &state.env.social_login_redirect {
+    Some(location) => Ok(
+      HttpResponse::Found()
+        .cookie(session_cookie)
+        .cookie(csrf_cookie)
+        .cookie(state_cookie)
+        .insert_header((header::LOCATION, location.as_str()))
+        .finish(),
+    ),
+    None => Ok(
+      HttpResponse::Ok()
+        .cookie(session_cookie)
+        .cookie(csrf_cookie)
+        .cookie(state_cookie)
+        .json(UserLoginResponseDto {
+          status: "success".to_string(),
+          token,
+        }),
+    ),
+  }
+}
+
+/// First sign-in with this external account: link it to the verified user with
+/// the same email, or register a new user under the registration policy.
+async fn link_or_create_user(
+  req: &HttpRequest,
+  state: &AppState,
+  provider: &SocialProvider,
+  profile: &ExternalProfile,
+) -> Result<User, HttpError> {
+  let email = match (&profile.email, profile.email_verified) {
+    (Some(email), true) => email,
+    _ => {
+      let event = NewAuditEvent::failure(AuditEventType::LoginFailed)
+        .request(req)
+        .metadata(json!({"provider": provider.name, "reason": "email_not_verified"}));
+      audit::record(&state.db_client, event).await;
+      return Err(HttpError::forbidden(ErrorMessage::ExternalEmailNotVerified));
+    }
+  };
+
+  let existing_user = state
+    .db_client
+    .get_user(None, None, Some(email))
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  if let Some(user) = existing_user {
+    // Anyone can register an unverified account under someone else's email,
+    // so it must not gain access to that person's external account.
+    if !user.verified {
+      let event = NewAuditEvent::failure(AuditEventType::LoginFailed)
+        .target(user.id)
+        .request(req)
+        .metadata(json!({"provider": provider.name, "reason": "account_not_verified"}));
+      audit::record(&state.db_client, event).await;
+      return Err(HttpError::forbidden(ErrorMessage::AccountNotVerified));
+    }
+
+    state
+      .db_client
+      .save_user_identity(user.id, &provider.name, &profile.subject, Some(email))
+      .await
+      .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let event = NewAuditEvent::success(AuditEventType::IdentityLinked)
+      .actor(user.id)
+      .target(user.id)
+      .request(req)
+      .metadata(json!({"provider": provider.name, "subject": profile.subject}));
+    audit::record(&state.db_client, event).await;
+
+    return Ok(user);
+  }
+
+  let rejected = |reason: &str| {
+    NewAuditEvent::failure(AuditEventType::Register)
+      .request(req)
+      .metadata(json!({"email": email, "provider": provider.name, "reason": reason}))
+  };
+
+  match state.env.registration_mode {
+    RegistrationMode::Open => {}
+    RegistrationMode::InviteOnly => {
+      audit::record(&state.db_client, rejected("invite_required")).await;
+      return Err(HttpError::forbidden(ErrorMessage::InviteCodeRequired));
+    }
+    RegistrationMode::DomainRestricted => {
+      if !state.env.is_email_domain_allowed(email) {
+        audit::record(&state.db_client, rejected("domain_not_allowed")).await;
+        return Err(HttpError::forbidden(ErrorMessage::EmailDomainNotAllowed));
+      }
+    }
+  }
+
+  // Social users have no password of their own; store a random, unusable one.
+  let hashed_password = password::hash(oauth::generate_token()).map_err(HttpError::server_error)?;
+  let name = profile
+    .name
+    .clone()
+    .or_else(|| email.split('@').next().map(str::to_string))
+    .unwrap_or_default();
+
+  let user = state
+    .db_client
+    .save_social_user(
+      &name,
+      email,
+      &hashed_password,
+      &provider.name,
+      &profile.subject,
+    )
+    .await
+    .map_err(|e| match e {
+      sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+        HttpError::unique_constraint_voilation(ErrorMessage::EmailExist)
+      }
+      e => HttpError::server_error(e.to_string()),
+    })?;
+
+  let event = NewAuditEvent::success(AuditEventType::Register)
+    .actor(user.id)
+    .target(user.id)
+    .request(req)
+    .metadata(json!({"role": user.role.to_str(), "provider": provider.name}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(user)
+}
+
+fn find_provider<'a>(state: &'a AppState, name: &str) -> Result<&'a SocialProvider, HttpError> {
+  state
+    .env
+    .social_providers
+    .iter()
+    .find(|provider| provider.name == name)
+    .ok_or_else(|| HttpError::not_found(ErrorMessage::UnknownProvider))
+}
+
+fn redirect_uri(state: &AppState, provider: &SocialProvider) -> String {
+  format!(
+    "{}/api/auth/social/{}/callback",
+    state.env.oidc_issuer, provider.name
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+  };
+
+  use actix_http::Request;
+  use actix_web::{
+    cookie::Cookie,
+    dev::{Service, ServiceResponse},
+    http::{header, StatusCode},
+    test, web, App, HttpRequest, HttpResponse, HttpServer,
+  };
+  use serde_json::{json, Value};
+  use sqlx::PgPool;
+
+  use crate::{
+    config::{RegistrationMode, SocialProvider, SocialProviderKind},
+    db::{SocialExt, UserExt},
+    scopes, test_utils,
+    utils::{oauth, password},
+    AppState,
+  };
+
+  /// What the mock provider remembers between requests.
+  #[derive(Default)]
+  struct Provider {
+    /// PKCE challenge of each authorization code handed out.
+    challenges: HashMap<String, String>,
+    profile: Value,
+    discovery_requests: usize,
+  }
+
+  /// An OpenID Connect provider on a local port that checks PKCE and the
+  /// client secret the way a real one does.
+  fn start_provider(profile: Value) -> (String, Arc<Mutex<Provider>>) {
+    let provider = Arc::new(Mutex::new(Provider {
+      profile,
+      ..Default::default()
+    }));
+    let data = web::Data::from(Arc::clone(&provider));
+    let server = HttpServer::new(move || {
+      App::new()
+        .app_data(data.clone())
+        .route(
+          "/.well-known/openid-configuration",
+          web::get().to(discovery),
+        )
+        .route("/token", web::post().to(token))
+        .route("/userinfo", web::get().to(userinfo))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .expect("Could not start the mock provider");
+
+    let issuer = format!("http://{}", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+    (issuer, provider)
+  }
+
+  async fn discovery(req: HttpRequest, provider: web::Data<Mutex<Provider>>) -> HttpResponse {
+    provider.lock().unwrap().discovery_requests += 1;
+    let issuer = format!("http://{}", req.connection_info().host());
+    HttpResponse::Ok().json(json!({
+      "issuer": issuer,
+      "authorization_endpoint": format!("{}/authorize", issuer),
+      "token_endpoint": format!("{}/token", issuer),
+      "userinfo_endpoint": format!("{}/userinfo", issuer),
+    }))
+  }
+
+  async fn token(
+    form: web::Form<HashMap<String, String>>,
+    provider: web::Data<Mutex<Provider>>,
+  ) -> HttpResponse {
+    let challenge = provider.lock().unwrap().challenges.remove(&form["code"]);
+    let verifier = form.get("code_verifier").map(String::as_str).unwrap_or("");
+    match challenge {
+      Some(challenge)
+        if challenge == oauth::s256_code_challenge(verifier)
+          && form["client_secret"] == "secret" =>
+      {
+        HttpResponse::Ok().json(json!({"access_token": "access-token", "token_type": "Bearer"}))
+      }
+      _ => HttpResponse::BadRequest().json(json!({"error": "invalid_grant"})),
+    }
+  }
+
+  async fn userinfo(req: HttpRequest, provider: web::Data<Mutex<Provider>>) -> HttpResponse {
+    match req.headers().get(header::AUTHORIZATION) {
+      Some(value) if value == "Bearer access-token" => {
+        HttpResponse::Ok().json(&provider.lock().unwrap().profile)
+      }
+      _ => HttpResponse::Unauthorized().json(json!({"error": "invalid_token"})),
+    }
+  }
+
+  fn profile(email: &str) -> Value {
+    json!({
+      "sub": uuid::Uuid::new_v4().to_string(),
+      "email": email,
+      "email_verified": true,
+      "name": "Mock User",
+    })
+  }
+
+  async fn app_state(issuer: &str) -> (AppState, PgPool) {
+    let mut config = test_utils::config();
+    config.registration_mode = RegistrationMode::Open;
+    config.social_login_redirect = None;
+    config.social_providers = vec![SocialProvider {
+      name: "mock".to_string(),
+      kind: SocialProviderKind::Oidc,
+      client_id: "client".to_string(),
+      client_secret: "secret".to_string(),
+      issuer: Some(issuer.to_string()),
+      authorize_url: None,
+      token_url: None,
+      userinfo_url: None,
+      scopes: "openid email profile".to_string(),
+    }];
+    test_utils::app_state(config).await
+  }
+
+  /// Starts a sign-in and plays the provider approving it. Returns the
+  /// callback the provider redirects to and the state cookie that was set.
+  async fn authorize<S>(app: &S, provider: &Mutex<Provider>) -> (String, Cookie<'static>)
+  where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+  {
+    let req = test::TestRequest::get()
+      .uri("/api/auth/social/mock")
+      .to_request();
+    let res = test::call_service(app, req).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+
+    let location = res
+      .headers()
+      .get(header::LOCATION)
+      .unwrap()
+      .to_str()
+      .unwrap();
+    let query: HashMap<String, String> = url::Url::parse(location)
+      .unwrap()
+      .query_pairs()
+      .into_owned()
+      .collect();
+    assert_eq!(query["code_challenge_method"], "S256");
+
+    let code = oauth::generate_token();
+    provider
+      .lock()
+      .unwrap()
+      .challenges
+      .insert(code.clone(), query["code_challenge"].clone());
+    let cookie = res
+      .response()
+      .cookies()
+      .find(|cookie| cookie.name() == "social_state")
+      .expect("No state cookie")
+      .into_owned();
+
+    let callback = format!(
+      "/api/auth/social/mock/callback?code={}&state={}",
+      code, query["state"]
+    );
+    (callback, cookie)
+  }
+
+  async fn call<S>(app: &S, callback: &str, cookie: Option<Cookie<'static>>) -> ServiceResponse
+  where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+  {
+    let mut req = test::TestRequest::get().uri(callback);
+    if let Some(cookie) = cookie {
+      req = req.cookie(cookie);
+    }
+    test::call_service(app, req.to_request()).await
+  }
+
+  async fn error_code(res: ServiceResponse) -> String {
+    let body: Value = test::read_body_json(res).await;
+    body["code"].as_str().unwrap_or_default().to_string()
+  }
+
+  macro_rules! init_app {
+    ($state:expr) => {
+      test::init_service(
+        App::new()
+          .app_data(web::Data::new($state))
+          .configure(scopes::configure(false)),
+      )
+      .await
+    };
+  }
+
+  #[actix_web::test]
+  async fn signs_in_new_user_with_pkce() {
+    let email = test_utils::unique_email();
+    let (issuer, provider) = start_provider(profile(&email));
+    let (state, _) = app_state(&issuer).await;
+    let db_client = state.db_client.clone();
+    let app = init_app!(state);
+
+    let (callback, cookie) = authorize(&app, &provider).await;
+    let res = call(&app, &callback, Some(cookie)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let removed = res
+      .response()
+      .cookies()
+      .find(|cookie| cookie.name() == "social_state")
+      .expect("State cookie is not cleared");
+    assert!(removed.value().is_empty());
+
+    let user = db_client
+      .get_user(None, None, Some(&email))
+      .await
+      .unwrap()
+      .expect("User was not created");
+    assert!(user.verified);
+
+    // A second sign-in finds the user by identity and reuses the discovery document.
+    let (callback, cookie) = authorize(&app, &provider).await;
+    let res = call(&app, &callback, Some(cookie)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(provider.lock().unwrap().discovery_requests, 1);
+
+    db_client.delete_user(user.id).await.unwrap();
+  }
+
+  #[actix_web::test]
+  async fn rejects_callback_from_another_browser() {
+    let email = test_utils::unique_email();
+    let (issuer, provider) = start_provider(profile(&email));
+    let (state, _) = app_state(&issuer).await;
+    let db_client = state.db_client.clone();
+    let app = init_app!(state);
+
+    let (callback, cookie) = authorize(&app, &provider).await;
+    let (_, other_cookie) = authorize(&app, &provider).await;
+
+    let res = call(&app, &callback, None).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(res).await, "invalid_login_state");
+
+    let res = call(&app, &callback, Some(other_cookie)).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(res).await, "invalid_login_state");
+
+    assert!(db_client
+      .get_user(None, None, Some(&email))
+      .await
+      .unwrap()
+      .is_none());
+
+    // Rejected callbacks leave the request for the browser that started it.
+    let res = call(&app, &callback, Some(cookie)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let user = db_client
+      .get_user(None, None, Some(&email))
+      .await
+      .unwrap()
+      .unwrap();
+    db_client.delete_user(user.id).await.unwrap();
+  }
+
+  #[actix_web::test]
+  async fn rejects_code_issued_for_another_challenge() {
+    let email = test_utils::unique_email();
+    let (issuer, provider) = start_provider(profile(&email));
+    let (state, _) = app_state(&issuer).await;
+    let db_client = state.db_client.clone();
+    let app = init_app!(state);
+
+    let (callback, cookie) = authorize(&app, &provider).await;
+    for challenge in provider.lock().unwrap().challenges.values_mut() {
+      *challenge = oauth::s256_code_challenge(&oauth::generate_token());
+    }
+
+    let res = call(&app, &callback, Some(cookie)).await;
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(error_code(res).await, "provider_error");
+    assert!(db_client
+      .get_user(None, None, Some(&email))
+      .await
+      .unwrap()
+      .is_none());
+  }
+
+  #[actix_web::test]
+  async fn links_verified_account_by_email() {
+    let email = test_utils::unique_email();
+    let profile = profile(&email);
+    let subject = profile["sub"].as_str().unwrap().to_string();
+    let (issuer, provider) = start_provider(profile);
+    let (state, pool) = app_state(&issuer).await;
+    let db_client = state.db_client.clone();
+    let app = init_app!(state);
+
+    let hashed_password = password::hash("password").unwrap();
+    let local = db_client
+      .save_user("Local User", &email, &hashed_password)
+      .await
+      .unwrap();
+    sqlx::query("UPDATE users SET verified = true WHERE id = $1")
+      .bind(local.id)
+      .execute(&pool)
+      .await
+      .unwrap();
+
+    let (callback, cookie) = authorize(&app, &provider).await;
+    let res = call(&app, &callback, Some(cookie)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let linked = db_client
+      .get_user_by_identity("mock", &subject)
+      .await
+      .unwrap()
+      .expect("Identity was not linked");
+    assert_eq!(linked.id, local.id);
+
+    db_client.delete_user(local.id).await.unwrap();
+  }
+
+  #[actix_web::test]
+  async fn refuses_to_link_unverified_account() {
+    let email = test_utils::unique_email();
+    let profile = profile(&email);
+    let subject = profile["sub"].as_str().unwrap().to_string();
+    let (issuer, provider) = start_provider(profile);
+    let (state, _) = app_state(&issuer).await;
+    let db_client = state.db_client.clone();
+    let app = init_app!(state);
+
+    let hashed_password = password::hash("password").unwrap();
+    let local = db_client
+      .save_user("Squatter", &email, &hashed_password)
+      .await
+      .unwrap();
+
+    let (callback, cookie) = authorize(&app, &provider).await;
+    let res = call(&app, &callback, Some(cookie)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(res).await, "account_not_verified");
+    assert!(db_client
+      .get_user_by_identity("mock", &subject)
+      .await
+      .unwrap()
+      .is_none());
+
+    db_client.delete_user(local.id).await.unwrap();
+  }
+}
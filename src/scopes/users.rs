@@ -1,22 +1,67 @@
-use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder, Scope};
+use actix_web::{http::Method, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use serde_json::json;
+use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
   db::UserExt,
-  dtos::{FilterUserDto, RequestQueryDto, UserData, UserListResponseDto, UserResponseDto},
-  error::HttpError,
-  extractors::auth::{RequireAuth, RequireOnlyAdmin},
-  models::User,
+  dtos::{
+    ChangePasswordDto, FilterUserDto, RequestQueryDto, UpdateUserRoleDto, UserData,
+    UserListResponseDto, UserResponseDto,
+  },
+  error::{ErrorMessage, ErrorResponse, HttpError},
+  extractors::auth::{
+    Impersonator, RequireAdminOrServiceAccount, RequireAuth, RequireNoImpersonation,
+    RequireOnlyAdmin,
+  },
+  extractors::recent_auth::{RequireRecentAuth, REAUTH_MAXAGE_MINUTES},
+  models::{AuditEventType, User},
+  scopes::Routes,
+  utils::{
+    audit::{self, NewAuditEvent},
+    password,
+  },
   AppState,
 };
 
-pub fn user_scope() -> Scope {
-  web::scope("/api/users")
-    .route("", web::get().to(get_users).wrap(RequireOnlyAdmin))
-    .route("/me", web::get().to(get_me).wrap(RequireAuth))
+pub fn user_scope() -> Routes {
+  Routes::new("/api/users")
+    .route(Method::GET, "", |route| {
+      route.to(get_users).wrap(RequireAdminOrServiceAccount)
+    })
+    .route(Method::GET, "/me", |route| {
+      route.to(get_me).wrap(RequireAuth)
+    })
+    .route(Method::POST, "/me/password", |route| {
+      route
+        .to(change_password)
+        .wrap(RequireRecentAuth(REAUTH_MAXAGE_MINUTES))
+        .wrap(RequireNoImpersonation)
+    })
+    .route(Method::PATCH, "/{user_id}/role", |route| {
+      route
+        .to(update_user_role)
+        .wrap(RequireRecentAuth(REAUTH_MAXAGE_MINUTES))
+        .wrap(RequireOnlyAdmin)
+    })
 }
 
+#[utoipa::path(
+  get,
+  path = "/api/users/me",
+  tag = "users",
+  responses(
+    (status = 200, description = "The signed-in user, and the admin impersonating them if any", body = UserResponseDto),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
 pub async fn get_me(req: HttpRequest) -> impl Responder {
+  let impersonator = req
+    .extensions()
+    .get::<Impersonator>()
+    .map(|impersonator| FilterUserDto::filter_user(&impersonator.user));
+
   match req.extensions().get::<User>() {
     Some(user) => {
       let filtered_user = FilterUserDto::filter_user(user);
@@ -25,22 +70,34 @@ pub async fn get_me(req: HttpRequest) -> impl Responder {
         status: "success".to_owned(),
         data: UserData {
           user: filtered_user,
+          impersonator,
         },
       };
       Ok(HttpResponse::Ok().json(response_data))
     }
-    None => Err(HttpError::server_error("User not found")),
+    None => Err(HttpError::server_error(ErrorMessage::ServerError)),
   }
 }
 
+#[utoipa::path(
+  get,
+  path = "/api/users",
+  tag = "users",
+  params(RequestQueryDto),
+  responses(
+    (status = 200, description = "A page of users", body = UserListResponseDto),
+    (status = 400, description = "Invalid paging parameters", body = ErrorResponse),
+    (status = 401, description = "Not signed in", body = ErrorResponse),
+    (status = 403, description = "Not an admin or service account", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
 pub async fn get_users(
   state: web::Data<AppState>,
   query: web::Query<RequestQueryDto>,
 ) -> Result<HttpResponse, HttpError> {
   let query_params = query.into_inner();
-  query_params
-    .validate()
-    .map_err(|e| HttpError::bad_request(e.to_string()))?;
+  query_params.validate().map_err(HttpError::from)?;
 
   let offset = query_params.page.unwrap_or(1);
   let limit = query_params.limit.unwrap_or(10);
@@ -59,3 +116,120 @@ pub async fn get_users(
 
   Ok(HttpResponse::Ok().json(response_data))
 }
+
+#[utoipa::path(
+  post,
+  path = "/api/users/me/password",
+  tag = "users",
+  request_body = ChangePasswordDto,
+  responses(
+    (status = 200, description = "Password changed", body = Object, example = json!({"status": "success"})),
+    (status = 400, description = "Invalid request body or password policy violation", body = ErrorResponse),
+    (status = 401, description = "Wrong current password, or the sign-in is not recent enough", body = ErrorResponse),
+    (status = 403, description = "Not allowed while impersonating", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn change_password(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  body: web::Json<ChangePasswordDto>,
+) -> Result<HttpResponse, HttpError> {
+  body.validate().map_err(HttpError::from)?;
+  password::check_policy(&body.new_password, &state.env.password_policy)
+    .map_err(HttpError::bad_request)?;
+
+  let user = match req.extensions().get::<User>() {
+    Some(user) => user.clone(),
+    None => return Err(HttpError::server_error(ErrorMessage::ServerError)),
+  };
+
+  let password_matches = password::compare(&body.current_password, &user.password)
+    .map_err(|_| HttpError::unauthorized(ErrorMessage::WrongCredentials))?;
+
+  if !password_matches {
+    let event = NewAuditEvent::failure(AuditEventType::PasswordChanged)
+      .actor(user.id)
+      .target(user.id)
+      .request(&req)
+      .metadata(json!({"reason": "wrong_password"}));
+    audit::record(&state.db_client, event).await;
+
+    return Err(HttpError::unauthorized(ErrorMessage::WrongCredentials));
+  }
+
+  let hashed_password = password::hash(&body.new_password).map_err(HttpError::server_error)?;
+
+  state
+    .db_client
+    .update_user_password(user.id, &hashed_password)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let event = NewAuditEvent::success(AuditEventType::PasswordChanged)
+    .actor(user.id)
+    .target(user.id)
+    .request(&req);
+  audit::record(&state.db_client, event).await;
+
+  Ok(HttpResponse::Ok().json(json!({"status": "success"})))
+}
+
+#[utoipa::path(
+  patch,
+  path = "/api/users/{user_id}/role",
+  tag = "users",
+  params(("user_id" = Uuid, Path, description = "User to update")),
+  request_body = UpdateUserRoleDto,
+  responses(
+    (status = 200, description = "Updated user", body = UserResponseDto),
+    (status = 401, description = "Not signed in, or the sign-in is not recent enough", body = ErrorResponse),
+    (status = 403, description = "Not an admin, or changing your own role", body = ErrorResponse),
+    (status = 404, description = "User not found", body = ErrorResponse),
+  ),
+  security(("bearer_auth" = []), ("cookie_auth" = []))
+)]
+pub async fn update_user_role(
+  req: HttpRequest,
+  state: web::Data<AppState>,
+  path: web::Path<Uuid>,
+  body: web::Json<UpdateUserRoleDto>,
+) -> Result<HttpResponse, HttpError> {
+  let admin_id = match req.extensions().get::<User>() {
+    Some(user) => user.id,
+    None => return Err(HttpError::server_error(ErrorMessage::ServerError)),
+  };
+
+  let user_id = path.into_inner();
+  if user_id == admin_id {
+    return Err(HttpError::forbidden(ErrorMessage::CannotChangeOwnRole));
+  }
+
+  let user = state
+    .db_client
+    .get_user(Some(user_id), None, None)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?
+    .ok_or(HttpError::not_found(ErrorMessage::UserNotFound))?;
+
+  let updated_user = state
+    .db_client
+    .update_user_role(user.id, body.role)
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+  let event = NewAuditEvent::success(AuditEventType::RoleChanged)
+    .actor(admin_id)
+    .target(user.id)
+    .request(&req)
+    .metadata(json!({"from": user.role.to_str(), "to": updated_user.role.to_str()}));
+  audit::record(&state.db_client, event).await;
+
+  Ok(HttpResponse::Ok().json(UserResponseDto {
+    status: "success".to_owned(),
+    data: UserData {
+      user: FilterUserDto::filter_user(&updated_user),
+      impersonator: None,
+    },
+  }))
+}
@@ -0,0 +1,64 @@
+//! Helpers for tests that run against the database at `DATABASE_URL`, which
+//! must be migrated already, as it is for `sqlx` to check the queries.
+
+use std::{sync::Arc, time::Duration};
+
+use sqlx::{postgres::PgPoolOptions, PgPool};
+
+use crate::{backends, config::Config, db::DBClient, utils::oidc::OidcKeys, AppState};
+
+/// Configuration from the environment, with the secrets the server refuses to
+/// start without filled in when they are missing.
+pub fn config() -> Config {
+  for (key, value) in [
+    ("JWT_SECRET_KEY", "secret"),
+    ("AUDIT_SIGNING_KEY", "audit-secret"),
+  ] {
+    if std::env::var(key).is_err() {
+      std::env::set_var(key, value);
+    }
+  }
+  Config::init().expect("Invalid test configuration")
+}
+
+/// State for `config`, and the pool behind it for fixtures that have no query
+/// of their own.
+pub async fn app_state(config: Config) -> (AppState, PgPool) {
+  let pool = PgPoolOptions::new()
+    .max_connections(2)
+    .connect(&config.database_url)
+    .await
+    .expect("Tests need the database at DATABASE_URL");
+
+  let state = AppState {
+    db_client: DBClient::new(pool.clone()),
+    oidc_keys: Arc::new(OidcKeys::init(&config).unwrap()),
+    auth_backends: Arc::new(backends::from_config(&config)),
+    metrics: None,
+    env: config,
+  };
+  (state, pool)
+}
+
+/// State for an app that never reaches a database: queries fail after a short
+/// wait, which is enough for tests that stop in the middleware or only need to
+/// know that a route exists.
+pub fn offline_app_state(config: Config) -> AppState {
+  let pool = PgPoolOptions::new()
+    .acquire_timeout(Duration::from_millis(100))
+    .connect_lazy("postgresql://127.0.0.1:1/unreachable")
+    .expect("Invalid database URL");
+
+  AppState {
+    db_client: DBClient::new(pool),
+    oidc_keys: Arc::new(OidcKeys::init(&config).unwrap()),
+    auth_backends: Arc::new(backends::from_config(&config)),
+    metrics: None,
+    env: config,
+  }
+}
+
+/// An address no other test run uses, so fixtures never collide.
+pub fn unique_email() -> String {
+  format!("{}@example.com", uuid::Uuid::new_v4())
+}
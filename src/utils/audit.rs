@@ -0,0 +1,539 @@
+use std::{net::IpAddr, time::Duration};
+
+use actix_web::{
+  http::header::{self, X_FORWARDED_FOR},
+  web, HttpRequest,
+};
+use chrono::{DateTime, SubsecRound, Utc};
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+  db::{AuditExt, DBClient},
+  models::{AuditCheckpoint, AuditEvent, AuditEventType, AuditOutcome},
+  utils::metrics,
+  AppState,
+};
+
+/// `prev_hash` of the first event in the chain.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A security-relevant event waiting to be written to `audit_events`.
+#[derive(Debug, Clone)]
+pub struct NewAuditEvent {
+  pub event_type: AuditEventType,
+  pub outcome: AuditOutcome,
+  pub actor_id: Option<Uuid>,
+  pub target_id: Option<Uuid>,
+  pub ip_address: Option<String>,
+  pub user_agent: Option<String>,
+  pub metadata: serde_json::Value,
+}
+
+impl NewAuditEvent {
+  pub fn new(event_type: AuditEventType, outcome: AuditOutcome) -> Self {
+    NewAuditEvent {
+      event_type,
+      outcome,
+      actor_id: None,
+      target_id: None,
+      ip_address: None,
+      user_agent: None,
+      metadata: json!({}),
+    }
+  }
+
+  pub fn success(event_type: AuditEventType) -> Self {
+    Self::new(event_type, AuditOutcome::Success)
+  }
+
+  pub fn failure(event_type: AuditEventType) -> Self {
+    Self::new(event_type, AuditOutcome::Failure)
+  }
+
+  pub fn actor(mut self, actor_id: Uuid) -> Self {
+    self.actor_id = Some(actor_id);
+    self
+  }
+
+  pub fn target(mut self, target_id: Uuid) -> Self {
+    self.target_id = Some(target_id);
+    self
+  }
+
+  pub fn metadata(mut self, metadata: serde_json::Value) -> Self {
+    self.metadata = metadata;
+    self
+  }
+
+  /// Hashes the event contents together with the hash of the previous event.
+  /// `created_at` is included with microsecond precision, as stored by Postgres.
+  pub fn chain_hash(&self, prev_hash: &str, created_at: DateTime<Utc>) -> String {
+    let contents = json!([
+      prev_hash,
+      self.event_type,
+      self.outcome,
+      self.actor_id,
+      self.target_id,
+      self.ip_address,
+      self.user_agent,
+      canonical_json(&self.metadata),
+      created_at.timestamp_micros(),
+    ]);
+
+    hex::encode(Sha256::digest(contents.to_string().as_bytes()))
+  }
+
+  /// Captures the client IP and user agent of the request that triggered the event.
+  pub fn request(mut self, req: &HttpRequest) -> Self {
+    let trusted_proxies = req
+      .app_data::<web::Data<AppState>>()
+      .map(|state| state.env.trusted_proxies.as_slice())
+      .unwrap_or_default();
+    self.ip_address = client_ip(req, trusted_proxies).map(|ip| ip.to_string());
+    self.user_agent = req
+      .headers()
+      .get(header::USER_AGENT)
+      .and_then(|ua| ua.to_str().ok())
+      .map(|ua| ua.chars().take(512).collect());
+    self
+  }
+}
+
+/// The peer address of the connection, unless it is a trusted proxy: then the
+/// rightmost `X-Forwarded-For` entry that is not itself a trusted proxy. Entries
+/// further left are set by the client and cannot be believed.
+fn client_ip(req: &HttpRequest, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+  let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+
+  let mut client = req.peer_addr()?.ip();
+  let forwarded_for = req
+    .headers()
+    .get_all(X_FORWARDED_FOR)
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .collect::<Vec<_>>();
+
+  for hop in forwarded_for.into_iter().rev() {
+    if !is_trusted(&client) {
+      break;
+    }
+    match hop.trim().parse::<IpAddr>() {
+      Ok(ip) => client = ip,
+      Err(_) => break,
+    }
+  }
+
+  Some(client)
+}
+
+/// Writes an audit event. Failures are logged rather than returned so that
+/// auditing never turns a successful request into an error.
+pub async fn record(db_client: &DBClient, event: NewAuditEvent) {
+  metrics::record_audit_event(&event);
+
+  if let Err(e) = db_client.save_audit_event(&event).await {
+    tracing::error!(event_type = ?event.event_type, error = %e, "Error recording audit event");
+  }
+}
+
+impl From<&AuditEvent> for NewAuditEvent {
+  fn from(event: &AuditEvent) -> Self {
+    NewAuditEvent {
+      event_type: event.event_type,
+      outcome: event.outcome,
+      actor_id: event.actor_id,
+      target_id: event.target_id,
+      ip_address: event.ip_address.clone(),
+      user_agent: event.user_agent.clone(),
+      metadata: event.metadata.clone(),
+    }
+  }
+}
+
+/// Serialises JSON with object keys sorted, so the output does not depend on
+/// how Postgres or serde happen to order JSONB keys.
+fn canonical_json(value: &serde_json::Value) -> String {
+  match value {
+    serde_json::Value::Object(map) => {
+      let mut entries: Vec<_> = map.iter().collect();
+      entries.sort_by(|a, b| a.0.cmp(b.0));
+      let fields: Vec<String> = entries
+        .into_iter()
+        .map(|(key, value)| format!("{}:{}", json!(key), canonical_json(value)))
+        .collect();
+      format!("{{{}}}", fields.join(","))
+    }
+    serde_json::Value::Array(items) => {
+      let items: Vec<String> = items.iter().map(canonical_json).collect();
+      format!("[{}]", items.join(","))
+    }
+    _ => value.to_string(),
+  }
+}
+
+pub fn sign_checkpoint(
+  key: &[u8],
+  event_id: i64,
+  event_hash: &str,
+  created_at: DateTime<Utc>,
+) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+  mac.update(
+    format!(
+      "{}:{}:{}",
+      event_id,
+      event_hash,
+      created_at.timestamp_micros()
+    )
+    .as_bytes(),
+  );
+  hex::encode(mac.finalize().into_bytes())
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChainBreak {
+  #[serde(rename = "eventId")]
+  pub event_id: i64,
+  pub reason: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckpointBreak {
+  #[serde(rename = "checkpointId")]
+  pub checkpoint_id: i64,
+  #[serde(rename = "eventId")]
+  pub event_id: i64,
+  pub reason: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChainReport {
+  pub valid: bool,
+  #[serde(rename = "eventsChecked")]
+  pub events_checked: u64,
+  #[serde(rename = "checkpointsChecked")]
+  pub checkpoints_checked: u64,
+  #[serde(rename = "firstBrokenLink")]
+  pub first_broken_link: Option<ChainBreak>,
+  #[serde(rename = "firstBrokenCheckpoint")]
+  pub first_broken_checkpoint: Option<CheckpointBreak>,
+}
+
+const VERIFY_BATCH_SIZE: usize = 500;
+
+/// Walks the whole chain in id order and checks every signed checkpoint
+/// against it, stopping at the first broken link of each kind.
+pub async fn verify_chain(db_client: &DBClient, key: &[u8]) -> Result<ChainReport, sqlx::Error> {
+  let mut prev_hash = GENESIS_HASH.to_string();
+  let mut after_id = 0;
+  let mut events_checked = 0;
+  let mut first_broken_link = None;
+
+  'walk: loop {
+    let events = db_client
+      .get_audit_events_after(after_id, VERIFY_BATCH_SIZE)
+      .await?;
+    if events.is_empty() {
+      break;
+    }
+
+    for event in &events {
+      if let Some(reason) = check_link(event, &prev_hash) {
+        first_broken_link = Some(ChainBreak {
+          event_id: event.id,
+          reason,
+        });
+        break 'walk;
+      }
+
+      prev_hash = event.hash.clone().unwrap_or_default();
+      after_id = event.id;
+      events_checked += 1;
+    }
+  }
+
+  let mut checkpoints_checked = 0;
+  let mut first_broken_checkpoint = None;
+  let mut page = 1;
+
+  'checkpoints: loop {
+    let mut checkpoints = db_client
+      .get_audit_checkpoints(page, VERIFY_BATCH_SIZE)
+      .await?;
+    if checkpoints.is_empty() {
+      break;
+    }
+    checkpoints.reverse();
+
+    for checkpoint in &checkpoints {
+      let event = db_client.get_audit_event(checkpoint.event_id).await?;
+      let reason = check_checkpoint(key, checkpoint, event.as_ref());
+
+      if let Some(reason) = reason {
+        first_broken_checkpoint = Some(CheckpointBreak {
+          checkpoint_id: checkpoint.id,
+          event_id: checkpoint.event_id,
+          reason,
+        });
+        break 'checkpoints;
+      }
+
+      checkpoints_checked += 1;
+    }
+
+    page += 1;
+  }
+
+  Ok(ChainReport {
+    valid: first_broken_link.is_none() && first_broken_checkpoint.is_none(),
+    events_checked,
+    checkpoints_checked,
+    first_broken_link,
+    first_broken_checkpoint,
+  })
+}
+
+/// Why `event` does not follow the event whose hash is `prev_hash`, if it does not.
+fn check_link(event: &AuditEvent, prev_hash: &str) -> Option<&'static str> {
+  match (&event.prev_hash, &event.hash) {
+    (Some(stored_prev), _) if stored_prev != prev_hash => Some("prev_hash_mismatch"),
+    (Some(_), Some(hash))
+      if *hash != NewAuditEvent::from(event).chain_hash(prev_hash, event.created_at) =>
+    {
+      Some("hash_mismatch")
+    }
+    (Some(_), Some(_)) => None,
+    _ => Some("unchained"),
+  }
+}
+
+/// Why `checkpoint` is not a valid signature of `event` as stored, if it is not.
+fn check_checkpoint(
+  key: &[u8],
+  checkpoint: &AuditCheckpoint,
+  event: Option<&AuditEvent>,
+) -> Option<&'static str> {
+  let signature = sign_checkpoint(
+    key,
+    checkpoint.event_id,
+    &checkpoint.event_hash,
+    checkpoint.created_at,
+  );
+
+  if signature != checkpoint.signature {
+    return Some("invalid_signature");
+  }
+  match event {
+    None => Some("event_missing"),
+    Some(event) if event.hash.as_deref() != Some(&checkpoint.event_hash) => Some("hash_mismatch"),
+    Some(_) => None,
+  }
+}
+
+/// Signs the hash of the latest event. Returns `None` if nothing was
+/// recorded since the previous checkpoint.
+pub async fn create_checkpoint(
+  db_client: &DBClient,
+  key: &[u8],
+) -> Result<Option<AuditCheckpoint>, sqlx::Error> {
+  let latest_event = db_client.get_latest_audit_event().await?;
+  let Some((event_id, Some(event_hash))) = latest_event.map(|event| (event.id, event.hash)) else {
+    return Ok(None);
+  };
+
+  let latest_checkpoint = db_client.get_latest_audit_checkpoint().await?;
+  if latest_checkpoint.is_some_and(|checkpoint| checkpoint.event_id == event_id) {
+    return Ok(None);
+  }
+
+  let created_at = Utc::now().trunc_subsecs(6);
+  let signature = sign_checkpoint(key, event_id, &event_hash, created_at);
+  let checkpoint = db_client
+    .save_audit_checkpoint(event_id, &event_hash, &signature, created_at)
+    .await?;
+
+  Ok(Some(checkpoint))
+}
+
+/// Creates a checkpoint every `interval_minutes` for as long as the server runs.
+pub fn spawn_checkpoints(db_client: DBClient, key: String, interval_minutes: u64) {
+  if interval_minutes == 0 {
+    return;
+  }
+
+  actix_web::rt::spawn(async move {
+    let mut interval = actix_web::rt::time::interval(Duration::from_secs(interval_minutes * 60));
+    loop {
+      interval.tick().await;
+      if let Err(e) = create_checkpoint(&db_client, key.as_bytes()).await {
+        tracing::error!(error = %e, "Error creating audit checkpoint");
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::{Duration, SubsecRound, Utc};
+  use serde_json::json;
+  use uuid::Uuid;
+
+  use super::*;
+
+  const KEY: &[u8] = b"audit-test-key";
+
+  /// A chain of `len` events, hashed the way `save_audit_event` stores them.
+  fn chain(len: usize) -> Vec<AuditEvent> {
+    let start = Utc::now().trunc_subsecs(6);
+    let mut prev_hash = GENESIS_HASH.to_string();
+
+    (0..len)
+      .map(|i| {
+        let mut event = AuditEvent {
+          id: i as i64 + 1,
+          event_type: AuditEventType::LoginFailed,
+          outcome: AuditOutcome::Failure,
+          actor_id: None,
+          target_id: Some(Uuid::new_v4()),
+          ip_address: Some("192.0.2.1".to_string()),
+          user_agent: None,
+          metadata: json!({"reason": "wrong_password", "attempt": i}),
+          created_at: start + Duration::seconds(i as i64),
+          prev_hash: Some(prev_hash.clone()),
+          hash: None,
+        };
+        prev_hash = NewAuditEvent::from(&event).chain_hash(&prev_hash, event.created_at);
+        event.hash = Some(prev_hash.clone());
+        event
+      })
+      .collect()
+  }
+
+  /// Mirrors the walk in `verify_chain`.
+  fn first_broken_link(events: &[AuditEvent]) -> Option<(i64, &'static str)> {
+    let mut prev_hash = GENESIS_HASH.to_string();
+    for event in events {
+      if let Some(reason) = check_link(event, &prev_hash) {
+        return Some((event.id, reason));
+      }
+      prev_hash = event.hash.clone().unwrap_or_default();
+    }
+    None
+  }
+
+  fn checkpoint(event: &AuditEvent, key: &[u8]) -> AuditCheckpoint {
+    let event_hash = event.hash.clone().unwrap();
+    let created_at = Utc::now().trunc_subsecs(6);
+    AuditCheckpoint {
+      id: 1,
+      event_id: event.id,
+      signature: sign_checkpoint(key, event.id, &event_hash, created_at),
+      event_hash,
+      created_at,
+    }
+  }
+
+  #[test]
+  fn intact_chain_verifies() {
+    assert_eq!(first_broken_link(&chain(5)), None);
+  }
+
+  #[test]
+  fn tampered_row_is_the_first_broken_link() {
+    let mut events = chain(5);
+    events[2].metadata = json!({"reason": "success", "attempt": 2});
+
+    assert_eq!(first_broken_link(&events), Some((3, "hash_mismatch")));
+  }
+
+  #[test]
+  fn rehashed_row_breaks_the_next_link() {
+    let mut events = chain(5);
+    events[1].ip_address = Some("198.51.100.7".to_string());
+    let prev_hash = events[0].hash.clone().unwrap();
+    events[1].hash =
+      Some(NewAuditEvent::from(&events[1]).chain_hash(&prev_hash, events[1].created_at));
+
+    assert_eq!(first_broken_link(&events), Some((3, "prev_hash_mismatch")));
+  }
+
+  #[test]
+  fn deleted_row_breaks_the_chain() {
+    let mut events = chain(5);
+    events.remove(3);
+
+    assert_eq!(first_broken_link(&events), Some((5, "prev_hash_mismatch")));
+  }
+
+  #[test]
+  fn unchained_row_is_reported() {
+    let mut events = chain(3);
+    events[1].hash = None;
+
+    assert_eq!(first_broken_link(&events), Some((2, "unchained")));
+  }
+
+  #[test]
+  fn canonical_json_ignores_key_order() {
+    let a = json!({"b": 1, "a": {"d": [1, {"y": 2, "x": 1}], "c": null}});
+    let b = json!({"a": {"c": null, "d": [1, {"x": 1, "y": 2}]}, "b": 1});
+
+    assert_eq!(canonical_json(&a), canonical_json(&b));
+    assert_eq!(
+      canonical_json(&a),
+      r#"{"a":{"c":null,"d":[1,{"x":1,"y":2}]},"b":1}"#
+    );
+  }
+
+  #[test]
+  fn checkpoint_verifies_against_its_event() {
+    let events = chain(3);
+    let checkpoint = checkpoint(&events[2], KEY);
+
+    assert_eq!(check_checkpoint(KEY, &checkpoint, Some(&events[2])), None);
+  }
+
+  #[test]
+  fn checkpoint_signed_with_another_key_is_rejected() {
+    let events = chain(3);
+    let checkpoint = checkpoint(&events[2], b"another-key");
+
+    assert_eq!(
+      check_checkpoint(KEY, &checkpoint, Some(&events[2])),
+      Some("invalid_signature")
+    );
+  }
+
+  #[test]
+  fn checkpoint_detects_a_rewritten_event() {
+    let mut events = chain(3);
+    let checkpoint = checkpoint(&events[2], KEY);
+    events[2].hash = Some("0".repeat(64));
+
+    assert_eq!(
+      check_checkpoint(KEY, &checkpoint, Some(&events[2])),
+      Some("hash_mismatch")
+    );
+    assert_eq!(
+      check_checkpoint(KEY, &checkpoint, None),
+      Some("event_missing")
+    );
+  }
+
+  #[test]
+  fn checkpoint_with_edited_hash_is_rejected() {
+    let events = chain(3);
+    let mut checkpoint = checkpoint(&events[2], KEY);
+    checkpoint.event_hash = events[1].hash.clone().unwrap();
+
+    assert_eq!(
+      check_checkpoint(KEY, &checkpoint, Some(&events[1])),
+      Some("invalid_signature")
+    );
+  }
+}
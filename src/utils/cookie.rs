@@ -0,0 +1,119 @@
+use actix_web::cookie::{time::Duration as ActixWebDuration, Cookie, SameSite};
+
+use crate::{config::Config, utils::csrf};
+
+/// The session cookie and the readable CSRF cookie that goes with it.
+pub fn session_cookies(config: &Config, token: &str) -> [Cookie<'static>; 2] {
+  let max_age = ActixWebDuration::new(60 * config.jwt_maxage, 0);
+  let csrf_token = csrf::token(token, config.jwt_secret.as_bytes());
+
+  [
+    build(
+      config,
+      config.cookie.session_name(),
+      token.to_string(),
+      true,
+      max_age,
+    ),
+    build(
+      config,
+      config.cookie.csrf_name(),
+      csrf_token,
+      false,
+      max_age,
+    ),
+  ]
+}
+
+/// Cookies that clear the session on logout.
+pub fn removal_cookies(config: &Config) -> [Cookie<'static>; 2] {
+  let expired = ActixWebDuration::new(-1, 0);
+
+  [
+    build(
+      config,
+      config.cookie.session_name(),
+      String::new(),
+      true,
+      expired,
+    ),
+    build(
+      config,
+      config.cookie.csrf_name(),
+      String::new(),
+      false,
+      expired,
+    ),
+  ]
+}
+
+/// Short-lived cookie holding the `state` of a sign-in with an external
+/// provider, so the callback only completes in the browser that started it.
+/// The provider sends the browser back with a cross-site redirect, which a
+/// `SameSite=Strict` cookie would not survive.
+pub fn login_state_cookie(
+  config: &Config,
+  name: &'static str,
+  value: String,
+  max_age_minutes: i64,
+) -> Cookie<'static> {
+  let mut cookie = build(
+    config,
+    name,
+    value,
+    true,
+    ActixWebDuration::minutes(max_age_minutes),
+  );
+  if config.cookie.same_site == SameSite::Strict {
+    cookie.set_same_site(SameSite::Lax);
+  }
+  cookie
+}
+
+/// Like `login_state_cookie`, for identity providers that send the browser
+/// back with a cross-site POST, as the SAML HTTP-POST binding does. Browsers
+/// only send `SameSite=None` cookies along, and only secure ones, so without
+/// `cookie.secure` the IdP has to be on the same site.
+pub fn cross_site_login_state_cookie(
+  config: &Config,
+  name: &'static str,
+  value: String,
+  max_age_minutes: i64,
+) -> Cookie<'static> {
+  let mut cookie = login_state_cookie(config, name, value, max_age_minutes);
+  if config.cookie.secure {
+    cookie.set_same_site(SameSite::None);
+  }
+  cookie
+}
+
+/// Clears a login state cookie once the sign-in it belongs to is complete.
+pub fn login_state_removal_cookie(config: &Config, name: &'static str) -> Cookie<'static> {
+  build(
+    config,
+    name,
+    String::new(),
+    true,
+    ActixWebDuration::new(-1, 0),
+  )
+}
+
+fn build(
+  config: &Config,
+  name: &'static str,
+  value: String,
+  http_only: bool,
+  max_age: ActixWebDuration,
+) -> Cookie<'static> {
+  let mut cookie = Cookie::build(name, value)
+    .path("/")
+    .max_age(max_age)
+    .http_only(http_only)
+    .secure(config.cookie.secure)
+    .same_site(config.cookie.same_site)
+    .finish();
+  if let Some(domain) = &config.cookie.domain {
+    cookie.set_domain(domain.clone());
+  }
+  cookie
+}
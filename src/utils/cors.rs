@@ -0,0 +1,59 @@
+use actix_cors::Cors;
+
+use crate::{config::CorsConfig, extractors::request_id};
+
+/// Builds the CORS middleware. The settings were validated when the config
+/// was loaded, so the builder cannot reject them here.
+pub fn middleware(config: &CorsConfig) -> Cors {
+  let mut cors = Cors::default()
+    .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+    .allowed_headers(config.allowed_headers.iter().map(String::as_str))
+    .expose_headers([request_id::HEADER])
+    .max_age(config.max_age);
+
+  if config.allowed_origins.iter().any(|origin| origin == "*") {
+    cors = cors.allow_any_origin();
+  } else {
+    let (patterns, origins): (Vec<String>, Vec<String>) = config
+      .allowed_origins
+      .iter()
+      .cloned()
+      .partition(|origin| origin.contains('*'));
+
+    for origin in &origins {
+      cors = cors.allowed_origin(origin);
+    }
+    if !patterns.is_empty() {
+      cors = cors.allowed_origin_fn(move |origin, _| {
+        origin
+          .to_str()
+          .is_ok_and(|origin| patterns.iter().any(|pattern| matches(pattern, origin)))
+      });
+    }
+  }
+
+  if config.allow_credentials {
+    cors = cors.supports_credentials();
+  }
+
+  cors
+}
+
+/// Matches `https://*.example.com` against `https://app.example.com` or
+/// `https://a.b.example.com`, but not `https://example.com` itself.
+fn matches(pattern: &str, origin: &str) -> bool {
+  let Some((scheme, domain)) = pattern.split_once("://*.") else {
+    return false;
+  };
+  let origin = origin.to_lowercase();
+
+  origin
+    .strip_prefix(&format!("{}://", scheme.to_lowercase()))
+    .and_then(|host| host.strip_suffix(&format!(".{}", domain.to_lowercase())))
+    .is_some_and(|subdomain| {
+      !subdomain.is_empty()
+        && subdomain
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    })
+}
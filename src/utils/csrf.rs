@@ -0,0 +1,38 @@
+use actix_web::http::Method;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Header carrying the CSRF token on requests authenticated by the session cookie.
+pub const HEADER: &str = "X-CSRF-Token";
+/// Form field accepted instead of the header, for plain HTML forms.
+pub const FORM_FIELD: &str = "csrf_token";
+
+/// The CSRF token belonging to a session token. Deriving it from the session
+/// means nothing has to be stored, and a token planted by another site cannot
+/// match a session it does not know.
+pub fn token(session_token: &str, secret: &[u8]) -> String {
+  URL_SAFE_NO_PAD.encode(mac(session_token, secret).finalize().into_bytes())
+}
+
+pub fn verify(candidate: &str, session_token: &str, secret: &[u8]) -> bool {
+  match URL_SAFE_NO_PAD.decode(candidate) {
+    Ok(candidate) => mac(session_token, secret).verify_slice(&candidate).is_ok(),
+    Err(_) => false,
+  }
+}
+
+/// Safe methods must not change state, so they need no CSRF token.
+pub fn is_protected(method: &Method) -> bool {
+  !matches!(
+    *method,
+    Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+  )
+}
+
+fn mac(session_token: &str, secret: &[u8]) -> Hmac<Sha256> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+  mac.update(b"csrf:");
+  mac.update(session_token.as_bytes());
+  mac
+}
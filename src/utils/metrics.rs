@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, Unit};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+  db::DBClient,
+  error::ErrorMessage,
+  models::{AuditEventType, AuditOutcome},
+  utils::audit::NewAuditEvent,
+};
+
+pub const HTTP_REQUESTS: &str = "http_requests_total";
+pub const HTTP_REQUEST_DURATION: &str = "http_request_duration_seconds";
+pub const LOGINS: &str = "auth_logins_total";
+pub const REGISTRATIONS: &str = "auth_registrations_total";
+pub const TOKEN_VALIDATION_FAILURES: &str = "auth_token_validation_failures_total";
+pub const DB_POOL_CONNECTIONS: &str = "db_pool_connections";
+pub const DB_POOL_IDLE_CONNECTIONS: &str = "db_pool_idle_connections";
+pub const DB_POOL_MAX_CONNECTIONS: &str = "db_pool_max_connections";
+
+const LATENCY_BUCKETS: &[f64] = &[
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Installs the global Prometheus recorder. Histograms are drained in the
+/// background so they do not grow between scrapes.
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+  let handle = PrometheusBuilder::new()
+    .set_buckets_for_metric(
+      Matcher::Full(HTTP_REQUEST_DURATION.to_string()),
+      LATENCY_BUCKETS,
+    )
+    .and_then(|builder| builder.install_recorder())?;
+
+  describe_counter!(HTTP_REQUESTS, "HTTP requests by method, route and status");
+  describe_histogram!(
+    HTTP_REQUEST_DURATION,
+    Unit::Seconds,
+    "HTTP request latency by method, route and status"
+  );
+  describe_counter!(LOGINS, "Login attempts by outcome, method and reason");
+  describe_counter!(REGISTRATIONS, "Registrations by outcome, method and reason");
+  describe_counter!(
+    TOKEN_VALIDATION_FAILURES,
+    "Rejected access tokens by error kind"
+  );
+  describe_gauge!(DB_POOL_CONNECTIONS, "Open database connections");
+  describe_gauge!(DB_POOL_IDLE_CONNECTIONS, "Idle database connections");
+  describe_gauge!(DB_POOL_MAX_CONNECTIONS, "Database connection limit");
+
+  let upkeep = handle.clone();
+  actix_web::rt::spawn(async move {
+    let mut interval = actix_web::rt::time::interval(Duration::from_secs(5));
+    loop {
+      interval.tick().await;
+      upkeep.run_upkeep();
+    }
+  });
+
+  Ok(handle)
+}
+
+/// Values of the `reason` label. Audit metadata may carry any text, so reasons
+/// not listed here are counted as `other` to keep the label set bounded.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Reason {
+  WrongPassword,
+  UnknownEmail,
+  AccountDisabled,
+  AccountNotVerified,
+  EmailNotVerified,
+  EmailMissing,
+  EmailExists,
+  InviteRequired,
+  InvalidInvite,
+  DomainNotAllowed,
+  ProviderError,
+  StateMismatch,
+  RequestMismatch,
+  InvalidResponse,
+  #[serde(other)]
+  Other,
+}
+
+impl Reason {
+  fn from_metadata(metadata: &Value) -> Option<Reason> {
+    metadata
+      .get("reason")
+      .map(|reason| Reason::deserialize(reason).unwrap_or(Reason::Other))
+  }
+
+  fn to_str(self) -> &'static str {
+    match self {
+      Reason::WrongPassword => "wrong_password",
+      Reason::UnknownEmail => "unknown_email",
+      Reason::AccountDisabled => "account_disabled",
+      Reason::AccountNotVerified => "account_not_verified",
+      Reason::EmailNotVerified => "email_not_verified",
+      Reason::EmailMissing => "email_missing",
+      Reason::EmailExists => "email_exists",
+      Reason::InviteRequired => "invite_required",
+      Reason::InvalidInvite => "invalid_invite",
+      Reason::DomainNotAllowed => "domain_not_allowed",
+      Reason::ProviderError => "provider_error",
+      Reason::StateMismatch => "state_mismatch",
+      Reason::RequestMismatch => "request_mismatch",
+      Reason::InvalidResponse => "invalid_response",
+      Reason::Other => "other",
+    }
+  }
+}
+
+/// Counts logins and registrations from their audit events, so every backend
+/// and identity provider is covered by the same labels.
+pub fn record_audit_event(event: &NewAuditEvent) {
+  let name = match event.event_type {
+    AuditEventType::Login | AuditEventType::LoginFailed => LOGINS,
+    AuditEventType::Register => REGISTRATIONS,
+    _ => return,
+  };
+  let outcome = match (event.event_type, event.outcome) {
+    (AuditEventType::LoginFailed, _) | (_, AuditOutcome::Failure) => "failure",
+    _ => "success",
+  };
+  let label =
+    |key: &str, default: &str| event.metadata[key].as_str().unwrap_or(default).to_string();
+  let method = match event.metadata["source"].as_str() {
+    Some(source) => source.to_string(),
+    None => label("provider", &label("backend", "password")),
+  };
+
+  counter!(
+    name,
+    "outcome" => outcome,
+    "method" => method,
+    "reason" => Reason::from_metadata(&event.metadata).map_or("none", Reason::to_str),
+  )
+  .increment(1);
+}
+
+pub fn record_token_rejected(reason: &ErrorMessage) {
+  counter!(TOKEN_VALIDATION_FAILURES, "kind" => reason.code()).increment(1);
+}
+
+/// Pool statistics are sampled when the endpoint is scraped.
+pub fn record_pool(db_client: &DBClient, max_connections: u32) {
+  let (size, idle) = db_client.pool_stats();
+
+  gauge!(DB_POOL_CONNECTIONS).set(size as f64);
+  gauge!(DB_POOL_IDLE_CONNECTIONS).set(idle as f64);
+  gauge!(DB_POOL_MAX_CONNECTIONS).set(max_connections as f64);
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::Reason;
+
+  #[test]
+  fn reasons_map_to_fixed_labels() {
+    let label = |metadata| Reason::from_metadata(&metadata).map_or("none", Reason::to_str);
+
+    assert_eq!(label(json!({"reason": "wrong_password"})), "wrong_password");
+    assert_eq!(label(json!({"reason": "Signature is invalid"})), "other");
+    assert_eq!(label(json!({"reason": 42})), "other");
+    assert_eq!(label(json!({"provider": "github"})), "none");
+  }
+}
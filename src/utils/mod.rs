@@ -1,2 +1,14 @@
+pub mod audit;
+pub mod cookie;
+pub mod cors;
+pub mod csrf;
+pub mod metrics;
+pub mod oauth;
+pub mod oidc;
 pub mod password;
+pub mod saml;
+pub mod scim;
+pub mod social;
+pub mod telemetry;
 pub mod token;
+pub mod xmldsig;
@@ -0,0 +1,125 @@
+use std::fmt;
+
+use argon2::password_hash::rand_core::OsRng;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{
+  encode,
+  jwk::{
+    AlgorithmParameters, CommonParameters, Jwk, JwkSet, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+  },
+  Algorithm, EncodingKey, Header,
+};
+use rsa::{pkcs1::EncodeRsaPrivateKey, traits::PublicKeyParts, RsaPrivateKey};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, dtos::FilterUserDto};
+
+/// The RSA key ID tokens are signed with, and its public half as a JWK set.
+#[derive(Clone)]
+pub struct OidcKeys {
+  pub kid: String,
+  pub jwks: JwkSet,
+  encoding_key: EncodingKey,
+}
+
+impl fmt::Debug for OidcKeys {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("OidcKeys").field("kid", &self.kid).finish()
+  }
+}
+
+impl OidcKeys {
+  pub fn init(config: &Config) -> Result<OidcKeys, rsa::Error> {
+    let private_key = match &config.oidc_private_key {
+      Some(private_key) => private_key.clone(),
+      None => {
+        tracing::warn!(
+          "OIDC_PRIVATE_KEY_PATH is not set, ID tokens are signed with an ephemeral key"
+        );
+        RsaPrivateKey::new(&mut OsRng, 2048)?
+      }
+    };
+
+    let der = private_key.to_pkcs1_der()?;
+    let n = URL_SAFE_NO_PAD.encode(private_key.n().to_bytes_be());
+    let e = URL_SAFE_NO_PAD.encode(private_key.e().to_bytes_be());
+    let kid = hex::encode(&Sha256::digest(n.as_bytes())[..8]);
+
+    let jwk = Jwk {
+      common: CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        algorithm: Some(Algorithm::RS256),
+        key_id: Some(kid.clone()),
+        ..Default::default()
+      },
+      algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+        key_type: RSAKeyType::RSA,
+        n,
+        e,
+      }),
+    };
+
+    Ok(OidcKeys {
+      kid,
+      jwks: JwkSet { keys: vec![jwk] },
+      encoding_key: EncodingKey::from_rsa_der(der.as_bytes()),
+    })
+  }
+}
+
+/// Claims about `user` released for the granted scopes (OIDC Core section 5.4).
+pub fn user_claims(user: &FilterUserDto, scopes: &[String]) -> Map<String, Value> {
+  let mut claims = Map::new();
+  claims.insert("sub".to_string(), json!(user.id));
+
+  if scopes.iter().any(|scope| scope == "profile") {
+    claims.insert("name".to_string(), json!(user.name));
+    claims.insert("updated_at".to_string(), json!(user.updated_at.timestamp()));
+  }
+
+  if scopes.iter().any(|scope| scope == "email") {
+    claims.insert("email".to_string(), json!(user.email));
+    claims.insert("email_verified".to_string(), json!(user.verified));
+  }
+
+  claims
+}
+
+pub struct IdTokenRequest<'a> {
+  pub issuer: &'a str,
+  pub client_id: &'a str,
+  pub user: &'a FilterUserDto,
+  pub scopes: &'a [String],
+  pub nonce: Option<&'a str>,
+  pub auth_time: DateTime<Utc>,
+  pub expires_in_minutes: i64,
+}
+
+pub fn create_id_token(
+  keys: &OidcKeys,
+  request: &IdTokenRequest,
+) -> Result<String, jsonwebtoken::errors::Error> {
+  let now = Utc::now();
+  let mut claims = user_claims(request.user, request.scopes);
+  claims.insert("iss".to_string(), json!(request.issuer));
+  claims.insert("aud".to_string(), json!(request.client_id));
+  claims.insert("iat".to_string(), json!(now.timestamp()));
+  claims.insert(
+    "exp".to_string(),
+    json!((now + Duration::minutes(request.expires_in_minutes)).timestamp()),
+  );
+  claims.insert(
+    "auth_time".to_string(),
+    json!(request.auth_time.timestamp()),
+  );
+  if let Some(nonce) = request.nonce {
+    claims.insert("nonce".to_string(), json!(nonce));
+  }
+
+  let mut header = Header::new(Algorithm::RS256);
+  header.kid = Some(keys.kid.clone());
+
+  encode(&header, &claims, &keys.encoding_key)
+}
@@ -3,9 +3,9 @@ use argon2::{
   Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
 
-use crate::error::ErrorMessage;
+use crate::{config::PasswordPolicy, error::ErrorMessage};
 
-const MAX_PASSWORD_LENGTH: usize = 64;
+pub const MAX_PASSWORD_LENGTH: usize = 64;
 
 pub fn hash(password: impl Into<String>) -> Result<String, ErrorMessage> {
   let password: String = password.into();
@@ -27,8 +27,30 @@ pub fn hash(password: impl Into<String>) -> Result<String, ErrorMessage> {
   Ok(hashed_password)
 }
 
+/// Checks a new password against the configured policy before it is hashed.
+pub fn check_policy(password: &str, policy: &PasswordPolicy) -> Result<(), ErrorMessage> {
+  if password.chars().count() < policy.min_length {
+    return Err(ErrorMessage::PasswordTooShort(policy.min_length));
+  }
+
+  let has = |matches: fn(&char) -> bool| password.chars().any(|c| matches(&c));
+  if policy.require_uppercase && !has(|c| c.is_uppercase()) {
+    return Err(ErrorMessage::PasswordMissingCharacter("uppercase letter"));
+  }
+  if policy.require_lowercase && !has(|c| c.is_lowercase()) {
+    return Err(ErrorMessage::PasswordMissingCharacter("lowercase letter"));
+  }
+  if policy.require_digit && !has(char::is_ascii_digit) {
+    return Err(ErrorMessage::PasswordMissingCharacter("digit"));
+  }
+  if policy.require_symbol && !has(|c| !c.is_alphanumeric() && !c.is_whitespace()) {
+    return Err(ErrorMessage::PasswordMissingCharacter("symbol"));
+  }
+
+  Ok(())
+}
+
 pub fn compare(password: &str, hashed_password: &str) -> Result<bool, ErrorMessage> {
-  // dbg!(hash(password).unwrap());
   if password.is_empty() {
     return Err(ErrorMessage::EmptyPassword);
   }
@@ -40,6 +62,7 @@ pub fn compare(password: &str, hashed_password: &str) -> Result<bool, ErrorMessa
   let parsed_hash =
     PasswordHash::new(hashed_password).map_err(|_| ErrorMessage::InvalidHashFormat)?;
 
+  #[allow(clippy::unnecessary_map_or)]
   let password_matches = Argon2::default()
     .verify_password(password.as_bytes(), &parsed_hash)
     .map_or(false, |_| true);
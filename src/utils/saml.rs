@@ -0,0 +1,588 @@
+use std::{collections::HashMap, fmt, io::Write};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use flate2::{write::DeflateEncoder, Compression};
+use roxmltree::{Document, Node, ParsingOptions};
+use rsa::RsaPublicKey;
+use uuid::Uuid;
+
+use crate::{
+  config::SamlConfig,
+  models::UserRole,
+  utils::xmldsig::{self, DSIG_NS},
+};
+
+const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const METADATA_NS: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+const HTTP_REDIRECT_BINDING: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Redirect";
+const HTTP_POST_BINDING: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER_CONFIRMATION: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+const EMAIL_NAME_ID_FORMAT: &str = "urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress";
+pub const TRANSIENT_NAME_ID_FORMAT: &str = "urn:oasis:names:tc:SAML:2.0:nameid-format:transient";
+
+/// `user_identities.provider` under which SAML NameIDs are linked.
+pub const IDENTITY_PROVIDER: &str = "saml";
+
+/// The IdP must answer an AuthnRequest within this time.
+pub const REQUEST_MAXAGE_MINUTES: i64 = 10;
+
+/// Tolerated clock difference to the IdP when checking validity windows.
+const CLOCK_SKEW_SECONDS: i64 = 120;
+
+/// Upper bound on the size of parsed SAML documents.
+const MAX_NODES: u32 = 10_000;
+
+/// The identity provider described by the configured metadata.
+#[derive(Clone)]
+pub struct SamlIdp {
+  pub entity_id: String,
+  pub sso_url: String,
+  keys: Vec<RsaPublicKey>,
+}
+
+impl fmt::Debug for SamlIdp {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("SamlIdp")
+      .field("entity_id", &self.entity_id)
+      .field("sso_url", &self.sso_url)
+      .field("keys", &self.keys.len())
+      .finish()
+  }
+}
+
+impl SamlIdp {
+  /// Reads the IdP from its SAML metadata document.
+  pub fn from_metadata(metadata: &str) -> Result<SamlIdp, String> {
+    let doc = parse(metadata)?;
+    let descriptor = doc
+      .descendants()
+      .find(|node| node.has_tag_name((METADATA_NS, "IDPSSODescriptor")))
+      .ok_or("No IDPSSODescriptor")?;
+    let entity_id = descriptor
+      .parent_element()
+      .filter(|node| node.has_tag_name((METADATA_NS, "EntityDescriptor")))
+      .and_then(|node| node.attribute("entityID"))
+      .ok_or("No entityID")?;
+
+    let sso_url = descriptor
+      .children()
+      .find(|node| {
+        node.has_tag_name((METADATA_NS, "SingleSignOnService"))
+          && node.attribute("Binding") == Some(HTTP_REDIRECT_BINDING)
+      })
+      .and_then(|node| node.attribute("Location"))
+      .ok_or("No SingleSignOnService with the HTTP-Redirect binding")?;
+
+    let keys = descriptor
+      .children()
+      .filter(|node| {
+        node.has_tag_name((METADATA_NS, "KeyDescriptor"))
+          && node.attribute("use").unwrap_or("signing") == "signing"
+      })
+      .flat_map(|node| node.descendants())
+      .filter(|node| node.has_tag_name((DSIG_NS, "X509Certificate")))
+      .map(|node| xmldsig::public_key_from_certificate(node.text().unwrap_or_default()))
+      .collect::<Result<Vec<_>, _>>()?;
+    if keys.is_empty() {
+      return Err("No signing certificate".to_string());
+    }
+
+    Ok(SamlIdp {
+      entity_id: entity_id.to_string(),
+      sso_url: sso_url.to_string(),
+      keys,
+    })
+  }
+}
+
+/// What the application reads from a validated assertion.
+#[derive(Debug, Clone)]
+pub struct SamlAssertion {
+  pub name_id: String,
+  pub name_id_format: Option<String>,
+  pub in_response_to: Option<String>,
+  pub attributes: HashMap<String, Vec<String>>,
+}
+
+impl SamlAssertion {
+  pub fn attribute(&self, name: &str) -> Option<&str> {
+    self
+      .attributes
+      .get(name)
+      .and_then(|values| values.first())
+      .map(String::as_str)
+  }
+
+  pub fn email(&self, config: &SamlConfig) -> Option<&str> {
+    match &config.email_attribute {
+      Some(attribute) => self.attribute(attribute),
+      None => Some(self.name_id.as_str()),
+    }
+  }
+
+  /// The mapped role, or `None` when roles are not managed by the IdP.
+  pub fn role(&self, config: &SamlConfig) -> Option<UserRole> {
+    if config.admin_groups.is_empty() && config.moderator_groups.is_empty() {
+      return None;
+    }
+
+    let groups = self
+      .attributes
+      .get(&config.group_attribute)
+      .cloned()
+      .unwrap_or_default();
+    let member_of = |mapped: &[String]| {
+      groups
+        .iter()
+        .any(|group| mapped.iter().any(|m| m.eq_ignore_ascii_case(group)))
+    };
+
+    Some(if member_of(&config.admin_groups) {
+      UserRole::Admin
+    } else if member_of(&config.moderator_groups) {
+      UserRole::Moderator
+    } else {
+      UserRole::User
+    })
+  }
+}
+
+/// An AuthnRequest and the IdP URL that delivers it (HTTP-Redirect binding).
+#[derive(Debug, Clone)]
+pub struct AuthnRequest {
+  pub id: String,
+  pub redirect_url: String,
+}
+
+pub fn authn_request(config: &SamlConfig, acs_url: &str) -> Result<AuthnRequest, String> {
+  let idp = &config.idp;
+  let id = format!("_{}", Uuid::new_v4().simple());
+  let request = format!(
+    concat!(
+      r#"<samlp:AuthnRequest xmlns:samlp="{}" xmlns:saml="{}" ID="{}" Version="2.0" "#,
+      r#"IssueInstant="{}" Destination="{}" AssertionConsumerServiceURL="{}" "#,
+      r#"ProtocolBinding="{}"><saml:Issuer>{}</saml:Issuer></samlp:AuthnRequest>"#
+    ),
+    PROTOCOL_NS,
+    ASSERTION_NS,
+    id,
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+    escape(&idp.sso_url),
+    escape(acs_url),
+    HTTP_POST_BINDING,
+    escape(&config.sp_entity_id),
+  );
+
+  let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+  encoder
+    .write_all(request.as_bytes())
+    .map_err(|e| e.to_string())?;
+  let deflated = encoder.finish().map_err(|e| e.to_string())?;
+
+  let mut redirect_url = url::Url::parse(&idp.sso_url).map_err(|e| e.to_string())?;
+  redirect_url
+    .query_pairs_mut()
+    .append_pair("SAMLRequest", &STANDARD.encode(deflated));
+
+  Ok(AuthnRequest {
+    id,
+    redirect_url: redirect_url.to_string(),
+  })
+}
+
+pub fn sp_metadata(config: &SamlConfig, acs_url: &str) -> String {
+  format!(
+    concat!(
+      r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+      r#"<md:EntityDescriptor xmlns:md="{}" entityID="{}">"#,
+      r#"<md:SPSSODescriptor AuthnRequestsSigned="false" WantAssertionsSigned="true" "#,
+      r#"protocolSupportEnumeration="{}">"#,
+      r#"<md:NameIDFormat>{}</md:NameIDFormat>"#,
+      r#"<md:AssertionConsumerService Binding="{}" Location="{}" index="0" isDefault="true"/>"#,
+      r#"</md:SPSSODescriptor></md:EntityDescriptor>"#
+    ),
+    METADATA_NS,
+    escape(&config.sp_entity_id),
+    PROTOCOL_NS,
+    EMAIL_NAME_ID_FORMAT,
+    HTTP_POST_BINDING,
+    escape(acs_url),
+  )
+}
+
+/// Decodes and validates a `SAMLResponse` posted to the ACS. The assertion must
+/// be signed by the IdP, directly or through the enclosing response, and be
+/// addressed to this service provider.
+pub fn parse_response(
+  encoded: &str,
+  config: &SamlConfig,
+  acs_url: &str,
+) -> Result<SamlAssertion, String> {
+  let idp = &config.idp;
+  let encoded: String = encoded.split_whitespace().collect();
+  let xml = STANDARD.decode(encoded).map_err(|e| e.to_string())?;
+  let xml = String::from_utf8(xml).map_err(|e| e.to_string())?;
+  let doc = parse(&xml)?;
+
+  let response = doc.root_element();
+  if !response.has_tag_name((PROTOCOL_NS, "Response")) {
+    return Err("Not a SAML response".to_string());
+  }
+  if let Some(destination) = response.attribute("Destination") {
+    if destination != acs_url {
+      return Err("Response is addressed to a different destination".to_string());
+    }
+  }
+  if let Some(issuer) = child(response, ASSERTION_NS, "Issuer") {
+    if issuer.text().map(str::trim) != Some(idp.entity_id.as_str()) {
+      return Err("Response was issued by an unknown IdP".to_string());
+    }
+  }
+
+  let status = child(response, PROTOCOL_NS, "Status")
+    .and_then(|status| child(status, PROTOCOL_NS, "StatusCode"))
+    .and_then(|code| code.attribute("Value"))
+    .ok_or("Response has no status")?;
+  if status != STATUS_SUCCESS {
+    return Err(format!("IdP returned status {}", status));
+  }
+
+  if child(response, ASSERTION_NS, "EncryptedAssertion").is_some() {
+    return Err("Encrypted assertions are not supported".to_string());
+  }
+  let mut assertions = response
+    .children()
+    .filter(|node| node.has_tag_name((ASSERTION_NS, "Assertion")));
+  let assertion = match (assertions.next(), assertions.next()) {
+    (Some(assertion), None) => assertion,
+    _ => return Err("Response must contain exactly one assertion".to_string()),
+  };
+
+  let response_signed = xmldsig::is_signed(response);
+  let assertion_signed = xmldsig::is_signed(assertion);
+  if !response_signed && !assertion_signed {
+    return Err("Assertion is not signed".to_string());
+  }
+  if response_signed {
+    xmldsig::verify_enveloped(response, &idp.keys)?;
+  }
+  if assertion_signed {
+    xmldsig::verify_enveloped(assertion, &idp.keys)?;
+  }
+
+  let issuer = child(assertion, ASSERTION_NS, "Issuer").and_then(|issuer| issuer.text());
+  if issuer.map(str::trim) != Some(idp.entity_id.as_str()) {
+    return Err("Assertion was issued by an unknown IdP".to_string());
+  }
+
+  let now = Utc::now();
+  let skew = Duration::seconds(CLOCK_SKEW_SECONDS);
+  let in_response_to = response.attribute("InResponseTo");
+
+  let subject = child(assertion, ASSERTION_NS, "Subject").ok_or("Assertion has no subject")?;
+  let name_id = child(subject, ASSERTION_NS, "NameID").ok_or("Assertion has no NameID")?;
+  let name_id_value = name_id
+    .text()
+    .map(str::trim)
+    .filter(|value| !value.is_empty())
+    .ok_or("Assertion has an empty NameID")?;
+
+  let confirmed = subject
+    .children()
+    .filter(|node| {
+      node.has_tag_name((ASSERTION_NS, "SubjectConfirmation"))
+        && node.attribute("Method") == Some(BEARER_CONFIRMATION)
+    })
+    .filter_map(|node| child(node, ASSERTION_NS, "SubjectConfirmationData"))
+    .any(|data| {
+      data.attribute("Recipient") == Some(acs_url)
+        && data.attribute("InResponseTo") == in_response_to
+        && data
+          .attribute("NotOnOrAfter")
+          .and_then(parse_instant)
+          .is_some_and(|not_on_or_after| now < not_on_or_after + skew)
+    });
+  if !confirmed {
+    return Err("Assertion has no valid bearer subject confirmation".to_string());
+  }
+
+  let conditions =
+    child(assertion, ASSERTION_NS, "Conditions").ok_or("Assertion has no conditions")?;
+  if let Some(not_before) = conditions.attribute("NotBefore") {
+    if parse_instant(not_before).ok_or("Invalid NotBefore")? > now + skew {
+      return Err("Assertion is not yet valid".to_string());
+    }
+  }
+  if let Some(not_on_or_after) = conditions.attribute("NotOnOrAfter") {
+    if parse_instant(not_on_or_after).ok_or("Invalid NotOnOrAfter")? + skew <= now {
+      return Err("Assertion has expired".to_string());
+    }
+  }
+  let mut restrictions = conditions
+    .children()
+    .filter(|node| node.has_tag_name((ASSERTION_NS, "AudienceRestriction")))
+    .peekable();
+  if restrictions.peek().is_none() {
+    return Err("Assertion has no audience restriction".to_string());
+  }
+  let audience_allowed = restrictions.all(|restriction| {
+    restriction.children().any(|audience| {
+      audience.has_tag_name((ASSERTION_NS, "Audience"))
+        && audience.text().map(str::trim) == Some(config.sp_entity_id.as_str())
+    })
+  });
+  if !audience_allowed {
+    return Err("Assertion is meant for a different audience".to_string());
+  }
+
+  let mut attributes: HashMap<String, Vec<String>> = HashMap::new();
+  for attribute in assertion
+    .children()
+    .filter(|node| node.has_tag_name((ASSERTION_NS, "AttributeStatement")))
+    .flat_map(|statement| statement.children())
+    .filter(|node| node.has_tag_name((ASSERTION_NS, "Attribute")))
+  {
+    let values: Vec<String> = attribute
+      .children()
+      .filter(|node| node.has_tag_name((ASSERTION_NS, "AttributeValue")))
+      .filter_map(|node| node.text())
+      .map(|value| value.trim().to_string())
+      .collect();
+
+    for name in [
+      attribute.attribute("Name"),
+      attribute.attribute("FriendlyName"),
+    ]
+    .into_iter()
+    .flatten()
+    {
+      attributes
+        .entry(name.to_string())
+        .or_default()
+        .extend(values.iter().cloned());
+    }
+  }
+
+  Ok(SamlAssertion {
+    name_id: name_id_value.to_string(),
+    name_id_format: name_id.attribute("Format").map(str::to_string),
+    in_response_to: in_response_to.map(str::to_string),
+    attributes,
+  })
+}
+
+fn parse(xml: &str) -> Result<Document<'_>, String> {
+  // DTDs stay disabled, which rules out entity expansion attacks.
+  let options = ParsingOptions {
+    allow_dtd: false,
+    nodes_limit: MAX_NODES,
+  };
+  Document::parse_with_options(xml, options).map_err(|e| e.to_string())
+}
+
+fn child<'a, 'input>(
+  node: Node<'a, 'input>,
+  namespace: &str,
+  name: &str,
+) -> Option<Node<'a, 'input>> {
+  node
+    .children()
+    .find(|child| child.has_tag_name((namespace, name)))
+}
+
+fn parse_instant(value: &str) -> Option<DateTime<Utc>> {
+  DateTime::parse_from_rfc3339(value)
+    .ok()
+    .map(|instant| instant.with_timezone(&Utc))
+}
+
+fn escape(value: &str) -> String {
+  value
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+  use base64::{engine::general_purpose::STANDARD, Engine};
+
+  use super::{parse_response, SamlAssertion, SamlIdp};
+  use crate::config::SamlConfig;
+
+  const ACS_URL: &str = "http://localhost:8080/api/auth/saml/acs";
+  const METADATA: &str = include_str!("../../tests/fixtures/saml/idp-metadata.xml");
+  const SIGNED_ASSERTION: &str = include_str!("../../tests/fixtures/saml/signed-assertion.xml");
+  const SIGNED_RESPONSE: &str = include_str!("../../tests/fixtures/saml/signed-response.xml");
+  const UNSIGNED_ASSERTION: &str = include_str!("../../tests/fixtures/saml/unsigned-assertion.xml");
+  const EXPIRED_ASSERTION: &str = include_str!("../../tests/fixtures/saml/expired-assertion.xml");
+  const WRONG_RECIPIENT: &str = include_str!("../../tests/fixtures/saml/wrong-recipient.xml");
+  const UNTRUSTED_SIGNATURE: &str =
+    include_str!("../../tests/fixtures/saml/untrusted-signature.xml");
+
+  fn config() -> SamlConfig {
+    SamlConfig {
+      idp: SamlIdp::from_metadata(METADATA).unwrap(),
+      sp_entity_id: "http://localhost:8080/api/auth/saml/metadata".to_string(),
+      email_attribute: None,
+      name_attribute: "displayName".to_string(),
+      group_attribute: "groups".to_string(),
+      admin_groups: Vec::new(),
+      moderator_groups: Vec::new(),
+      login_redirect: None,
+    }
+  }
+
+  fn parse(xml: &str) -> Result<SamlAssertion, String> {
+    parse_with(xml, &config(), ACS_URL)
+  }
+
+  fn parse_with(xml: &str, config: &SamlConfig, acs_url: &str) -> Result<SamlAssertion, String> {
+    parse_response(&STANDARD.encode(xml), config, acs_url)
+  }
+
+  fn assertion_of(xml: &str) -> &str {
+    let start = xml.find("<saml:Assertion").unwrap();
+    let end = xml.find("</saml:Assertion>").unwrap() + "</saml:Assertion>".len();
+    &xml[start..end]
+  }
+
+  #[test]
+  fn accepts_signed_assertion() {
+    let assertion = parse(SIGNED_ASSERTION).unwrap();
+    assert_eq!(assertion.name_id, "saml.fixture@example.com");
+    assert_eq!(
+      assertion.in_response_to.as_deref(),
+      Some("_request-fixture")
+    );
+    assert_eq!(assertion.attribute("displayName"), Some("SAML Fixture"));
+    assert_eq!(assertion.email(&config()), Some("saml.fixture@example.com"));
+  }
+
+  #[test]
+  fn accepts_signed_response() {
+    let assertion = parse(SIGNED_RESPONSE).unwrap();
+    assert_eq!(assertion.name_id, "saml.fixture@example.com");
+  }
+
+  #[test]
+  fn rejects_unsigned_assertion() {
+    assert_eq!(
+      parse(UNSIGNED_ASSERTION).unwrap_err(),
+      "Assertion is not signed"
+    );
+  }
+
+  #[test]
+  fn rejects_untrusted_signature() {
+    assert_eq!(
+      parse(UNTRUSTED_SIGNATURE).unwrap_err(),
+      "Signature was not made by a trusted key"
+    );
+  }
+
+  #[test]
+  fn rejects_tampered_attribute() {
+    let tampered = SIGNED_ASSERTION.replace("SAML Fixture", "SAML Admin");
+    assert_eq!(
+      parse(&tampered).unwrap_err(),
+      "Digest of the signed element does not match"
+    );
+
+    let tampered = SIGNED_RESPONSE.replace("SAML Fixture", "SAML Admin");
+    assert_eq!(
+      parse(&tampered).unwrap_err(),
+      "Digest of the signed element does not match"
+    );
+  }
+
+  #[test]
+  fn rejects_signature_wrapping() {
+    let genuine = assertion_of(SIGNED_ASSERTION);
+    let forged = genuine.replace("saml.fixture@example.com", "admin@example.com");
+
+    // The signed assertion hidden in an extension, an unsigned one in its place.
+    let unsigned = forged.replace("_assertion-fixture", "_forged");
+    let start = unsigned.find("<ds:Signature").unwrap();
+    let end = unsigned.find("</ds:Signature>").unwrap() + "</ds:Signature>".len();
+    let unsigned = format!("{}{}", &unsigned[..start], &unsigned[end..]);
+    let wrapped = SIGNED_ASSERTION.replace(
+      genuine,
+      &format!(
+        "<samlp:Extensions>{}</samlp:Extensions>{}",
+        genuine, unsigned
+      ),
+    );
+    assert_eq!(parse(&wrapped).unwrap_err(), "Assertion is not signed");
+
+    // The same, with the forged assertion carrying the genuine ID and signature.
+    let wrapped = SIGNED_ASSERTION.replace(
+      genuine,
+      &format!("<samlp:Extensions>{}</samlp:Extensions>{}", genuine, forged),
+    );
+    assert_eq!(parse(&wrapped).unwrap_err(), "Duplicate element IDs");
+
+    // Two assertions, only one of them signed.
+    let doubled = SIGNED_ASSERTION.replace(genuine, &format!("{}{}", genuine, unsigned));
+    assert_eq!(
+      parse(&doubled).unwrap_err(),
+      "Response must contain exactly one assertion"
+    );
+  }
+
+  #[test]
+  fn rejects_wrong_audience() {
+    let config = SamlConfig {
+      sp_entity_id: "https://sp.example.org/metadata".to_string(),
+      ..config()
+    };
+    assert_eq!(
+      parse_with(SIGNED_ASSERTION, &config, ACS_URL).unwrap_err(),
+      "Assertion is meant for a different audience"
+    );
+  }
+
+  #[test]
+  fn rejects_wrong_destination_or_recipient() {
+    assert_eq!(
+      parse_with(
+        SIGNED_ASSERTION,
+        &config(),
+        "https://sp.example.org/api/auth/saml/acs"
+      )
+      .unwrap_err(),
+      "Response is addressed to a different destination"
+    );
+    assert_eq!(
+      parse(WRONG_RECIPIENT).unwrap_err(),
+      "Assertion has no valid bearer subject confirmation"
+    );
+  }
+
+  #[test]
+  fn rejects_in_response_to_other_request() {
+    // The response element is not covered by the assertion's signature, but
+    // its InResponseTo has to match the signed subject confirmation.
+    let redirected = SIGNED_ASSERTION.replacen(
+      r#"InResponseTo="_request-fixture""#,
+      r#"InResponseTo="_other-request""#,
+      1,
+    );
+    assert_eq!(
+      parse(&redirected).unwrap_err(),
+      "Assertion has no valid bearer subject confirmation"
+    );
+  }
+
+  #[test]
+  fn rejects_expired_assertion() {
+    assert_eq!(
+      parse(EXPIRED_ASSERTION).unwrap_err(),
+      "Assertion has no valid bearer subject confirmation"
+    );
+  }
+}
@@ -0,0 +1,761 @@
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+  dtos::{ScimMetaDto, ScimMultiValuedDto, ScimNameDto, ScimPatchOperationDto, ScimUserDto},
+  error::ScimError,
+  models::{User, UserRole},
+};
+
+pub const CONTENT_TYPE: &str = "application/scim+json";
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const PATCH_OP_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+pub const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const SERVICE_PROVIDER_CONFIG_SCHEMA: &str =
+  "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+const RESOURCE_TYPE_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:ResourceType";
+const SCHEMA_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Schema";
+
+/// `user_identities.provider` under which a user's SCIM `externalId` is stored.
+pub const IDENTITY_PROVIDER: &str = "scim";
+
+/// Upper bound on `count` for list requests.
+pub const MAX_RESULTS: i64 = 100;
+
+const MAX_NAME_LENGTH: usize = 100;
+
+/// The subset of SCIM filters identity providers send when looking up users:
+/// `eq` comparisons joined by `and`.
+#[derive(Debug, Default, Clone)]
+pub struct ScimUserFilter {
+  pub id: Option<Uuid>,
+  pub email: Option<String>,
+  pub external_id: Option<String>,
+  pub active: Option<bool>,
+}
+
+#[derive(Debug)]
+enum FilterToken {
+  Word(String),
+  Text(String),
+}
+
+pub fn parse_filter(filter: &str) -> Result<ScimUserFilter, ScimError> {
+  let invalid = |detail: String| ScimError::bad_request("invalidFilter", detail);
+
+  let mut result = ScimUserFilter::default();
+  let mut tokens = tokenize_filter(filter)?.into_iter();
+  loop {
+    let (attribute, operator, value) = match (tokens.next(), tokens.next(), tokens.next()) {
+      (Some(FilterToken::Word(attribute)), Some(FilterToken::Word(operator)), Some(value)) => {
+        (attribute, operator, value)
+      }
+      _ => {
+        return Err(invalid(
+          "Expected a filter of the form `<attribute> eq <value>`".to_string(),
+        ))
+      }
+    };
+
+    if !operator.eq_ignore_ascii_case("eq") {
+      return Err(invalid(format!(
+        "Unsupported filter operator: {}",
+        operator
+      )));
+    }
+
+    match (attribute_name(&attribute).as_str(), value) {
+      ("username" | "emails" | "emails.value", FilterToken::Text(email)) => {
+        set_once(&mut result.email, email, &attribute)?
+      }
+      ("externalid", FilterToken::Text(external_id)) => {
+        set_once(&mut result.external_id, external_id, &attribute)?
+      }
+      // An id that is not a UUID cannot match any user.
+      ("id", FilterToken::Text(id)) => set_once(
+        &mut result.id,
+        Uuid::parse_str(&id).unwrap_or(Uuid::nil()),
+        &attribute,
+      )?,
+      ("active", FilterToken::Word(active)) => match active.to_lowercase().as_str() {
+        "true" => set_once(&mut result.active, true, &attribute)?,
+        "false" => set_once(&mut result.active, false, &attribute)?,
+        _ => return Err(invalid(format!("Invalid boolean: {}", active))),
+      },
+      _ => {
+        return Err(invalid(format!(
+          "Unsupported filter on attribute: {}",
+          attribute
+        )))
+      }
+    }
+
+    match tokens.next() {
+      None => return Ok(result),
+      Some(FilterToken::Word(word)) if word.eq_ignore_ascii_case("and") => {}
+      Some(_) => {
+        return Err(invalid(
+          "Comparisons may only be combined with `and`".to_string(),
+        ))
+      }
+    }
+  }
+}
+
+fn tokenize_filter(filter: &str) -> Result<Vec<FilterToken>, ScimError> {
+  let mut tokens = Vec::new();
+  let mut chars = filter.chars().peekable();
+
+  while let Some(&c) = chars.peek() {
+    if c.is_whitespace() {
+      chars.next();
+    } else if c == '"' {
+      chars.next();
+      let mut text = String::new();
+      loop {
+        match chars.next() {
+          Some('"') => break,
+          Some('\\') => match chars.next() {
+            Some(escaped) => text.push(escaped),
+            None => break,
+          },
+          Some(c) => text.push(c),
+          None => {
+            return Err(ScimError::bad_request(
+              "invalidFilter",
+              "Unterminated string in filter",
+            ))
+          }
+        }
+      }
+      tokens.push(FilterToken::Text(text));
+    } else {
+      let mut word = String::new();
+      while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+          break;
+        }
+        word.push(c);
+        chars.next();
+      }
+      tokens.push(FilterToken::Word(word));
+    }
+  }
+
+  Ok(tokens)
+}
+
+fn set_once<T>(slot: &mut Option<T>, value: T, attribute: &str) -> Result<(), ScimError> {
+  if slot.is_some() {
+    return Err(ScimError::bad_request(
+      "invalidFilter",
+      format!("Attribute may only be filtered once: {}", attribute),
+    ));
+  }
+  *slot = Some(value);
+  Ok(())
+}
+
+/// Lowercases an attribute path and strips the optional core schema prefix.
+fn attribute_name(path: &str) -> String {
+  let path = path.trim().to_lowercase();
+  let prefix = format!("{}:", USER_SCHEMA.to_lowercase());
+  path
+    .strip_prefix(&prefix)
+    .map(str::to_string)
+    .unwrap_or(path)
+}
+
+/// The writable attributes of a SCIM user, as stored in the `users` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserAttributes {
+  pub email: String,
+  pub name: String,
+  pub external_id: Option<String>,
+  pub active: bool,
+  pub role: UserRole,
+  pub password: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct NameParts {
+  given: Option<String>,
+  family: Option<String>,
+  formatted: bool,
+}
+
+impl UserAttributes {
+  pub fn from_user(user: &User, external_id: Option<String>) -> Self {
+    UserAttributes {
+      email: user.email.clone(),
+      name: user.name.clone(),
+      external_id,
+      active: user.active,
+      role: user.role,
+      password: None,
+    }
+  }
+
+  /// Attributes of a full resource, as sent by POST and PUT.
+  pub fn from_resource(resource: ScimUserDto) -> Result<Self, ScimError> {
+    let email = resource.user_name.trim().to_string();
+    let name =
+      display_name(resource.display_name, resource.name.as_ref()).unwrap_or_else(|| email.clone());
+    let role = match primary_value(&resource.roles) {
+      Some(role) => parse_role(role)?,
+      None => UserRole::User,
+    };
+
+    let attributes = UserAttributes {
+      email,
+      name,
+      external_id: resource.external_id,
+      active: resource.active.unwrap_or(true),
+      role,
+      password: resource.password,
+    };
+    attributes.validate()?;
+
+    Ok(attributes)
+  }
+
+  /// Applies PATCH operations (RFC 7644 section 3.5.2). Operation names and
+  /// attribute paths are case-insensitive, and booleans may arrive as strings.
+  pub fn apply_patch(&mut self, operations: &[ScimPatchOperationDto]) -> Result<(), ScimError> {
+    let mut name = NameParts::default();
+
+    for operation in operations {
+      match (operation.op.to_lowercase().as_str(), &operation.path) {
+        ("add" | "replace", Some(path)) => {
+          let value = operation.value.as_ref().ok_or_else(|| {
+            ScimError::bad_request("invalidValue", "Patch operation requires a value")
+          })?;
+          self.set(path, value, &mut name)?;
+        }
+        ("add" | "replace", None) => match &operation.value {
+          Some(Value::Object(attributes)) => {
+            for (path, value) in attributes {
+              self.set(path, value, &mut name)?;
+            }
+          }
+          _ => {
+            return Err(ScimError::bad_request(
+              "invalidValue",
+              "Patch operation without a path requires an object value",
+            ))
+          }
+        },
+        ("remove", Some(path)) => self.remove(path)?,
+        ("remove", None) => {
+          return Err(ScimError::bad_request(
+            "noTarget",
+            "Remove operation requires a path",
+          ))
+        }
+        (op, _) => {
+          return Err(ScimError::bad_request(
+            "invalidSyntax",
+            format!("Unsupported patch operation: {}", op),
+          ))
+        }
+      }
+    }
+
+    if !name.formatted && (name.given.is_some() || name.family.is_some()) {
+      let parts = ScimNameDto {
+        formatted: None,
+        given_name: name.given,
+        family_name: name.family,
+      };
+      if let Some(name) = display_name(None, Some(&parts)) {
+        self.name = name;
+      }
+    }
+
+    self.validate()
+  }
+
+  fn set(&mut self, path: &str, value: &Value, name: &mut NameParts) -> Result<(), ScimError> {
+    match attribute_name(path).as_str() {
+      "username" => self.email = string_value(value)?,
+      "displayname" | "name.formatted" => {
+        self.name = string_value(value)?;
+        name.formatted = true;
+      }
+      "name" => {
+        let parts: ScimNameDto = serde_json::from_value(value.clone())
+          .map_err(|e| ScimError::bad_request("invalidValue", e.to_string()))?;
+        name.formatted = parts.formatted.is_some();
+        if let Some(formatted) = display_name(None, Some(&parts)) {
+          self.name = formatted;
+        }
+      }
+      "name.givenname" => name.given = Some(string_value(value)?),
+      "name.familyname" => name.family = Some(string_value(value)?),
+      "externalid" => self.external_id = Some(string_value(value)?),
+      "active" => self.active = bool_value(value)?,
+      "password" => self.password = Some(string_value(value)?),
+      "emails" => {
+        if let Some(email) = primary_value(&multi_valued(value)?) {
+          self.email = email.to_string();
+        }
+      }
+      "roles" => {
+        if let Some(role) = primary_value(&multi_valued(value)?) {
+          self.role = parse_role(role)?;
+        }
+      }
+      path if path.starts_with("emails[") && path.ends_with("].value") => {
+        self.email = string_value(value)?
+      }
+      path if path.starts_with("roles[") && path.ends_with("].value") => {
+        self.role = parse_role(&string_value(value)?)?
+      }
+      path => {
+        return Err(ScimError::bad_request(
+          "invalidPath",
+          format!("Unsupported attribute: {}", path),
+        ))
+      }
+    }
+
+    Ok(())
+  }
+
+  fn remove(&mut self, path: &str) -> Result<(), ScimError> {
+    match attribute_name(path).as_str() {
+      "externalid" => self.external_id = None,
+      path if path == "roles" || path.starts_with("roles[") => self.role = UserRole::User,
+      path => {
+        return Err(ScimError::bad_request(
+          "mutability",
+          format!("Attribute cannot be removed: {}", path),
+        ))
+      }
+    }
+
+    Ok(())
+  }
+
+  fn validate(&self) -> Result<(), ScimError> {
+    if !validator::validate_email(&self.email) {
+      return Err(ScimError::bad_request(
+        "invalidValue",
+        "userName must be a valid email address",
+      ));
+    }
+
+    if self.name.trim().is_empty() || self.name.chars().count() > MAX_NAME_LENGTH {
+      return Err(ScimError::bad_request(
+        "invalidValue",
+        format!("Name must be 1 to {} characters", MAX_NAME_LENGTH),
+      ));
+    }
+
+    Ok(())
+  }
+}
+
+fn display_name(display_name: Option<String>, name: Option<&ScimNameDto>) -> Option<String> {
+  let non_empty = |value: &Option<String>| {
+    value
+      .as_deref()
+      .map(str::trim)
+      .filter(|value| !value.is_empty())
+      .map(str::to_string)
+  };
+
+  non_empty(&display_name)
+    .or_else(|| name.and_then(|name| non_empty(&name.formatted)))
+    .or_else(|| {
+      let name = name?;
+      let parts: Vec<String> = [non_empty(&name.given_name), non_empty(&name.family_name)]
+        .into_iter()
+        .flatten()
+        .collect();
+      (!parts.is_empty()).then(|| parts.join(" "))
+    })
+}
+
+/// The primary value of a multi-valued attribute, or the first one.
+fn primary_value(values: &[ScimMultiValuedDto]) -> Option<&str> {
+  values
+    .iter()
+    .find(|value| value.primary == Some(true))
+    .or_else(|| values.first())
+    .map(|value| value.value.as_str())
+}
+
+fn multi_valued(value: &Value) -> Result<Vec<ScimMultiValuedDto>, ScimError> {
+  let values = match value {
+    Value::Array(_) => value.clone(),
+    _ => Value::Array(vec![value.clone()]),
+  };
+  serde_json::from_value(values).map_err(|e| ScimError::bad_request("invalidValue", e.to_string()))
+}
+
+fn string_value(value: &Value) -> Result<String, ScimError> {
+  match value {
+    Value::String(value) => Ok(value.trim().to_string()),
+    _ => Err(ScimError::bad_request(
+      "invalidValue",
+      format!("Expected a string, got {}", value),
+    )),
+  }
+}
+
+fn bool_value(value: &Value) -> Result<bool, ScimError> {
+  match value {
+    Value::Bool(value) => Ok(*value),
+    Value::String(value) if value.eq_ignore_ascii_case("true") => Ok(true),
+    Value::String(value) if value.eq_ignore_ascii_case("false") => Ok(false),
+    _ => Err(ScimError::bad_request(
+      "invalidValue",
+      format!("Expected a boolean, got {}", value),
+    )),
+  }
+}
+
+fn parse_role(role: &str) -> Result<UserRole, ScimError> {
+  UserRole::parse(role)
+    .ok_or_else(|| ScimError::bad_request("invalidValue", format!("Unknown role: {}", role)))
+}
+
+pub fn user_resource(user: &User, external_id: Option<String>, base_url: &str) -> ScimUserDto {
+  ScimUserDto {
+    schemas: vec![USER_SCHEMA.to_string()],
+    id: Some(user.id.to_string()),
+    external_id,
+    user_name: user.email.clone(),
+    name: Some(ScimNameDto {
+      formatted: Some(user.name.clone()),
+      ..Default::default()
+    }),
+    display_name: Some(user.name.clone()),
+    emails: vec![ScimMultiValuedDto {
+      value: user.email.clone(),
+      kind: Some("work".to_string()),
+      primary: Some(true),
+    }],
+    active: Some(user.active),
+    roles: vec![ScimMultiValuedDto {
+      value: user.role.to_str().to_string(),
+      kind: None,
+      primary: Some(true),
+    }],
+    password: None,
+    meta: Some(ScimMetaDto {
+      resource_type: "User".to_string(),
+      created: user.created_at,
+      last_modified: user.updated_at,
+      location: format!("{}/Users/{}", base_url, user.id),
+    }),
+  }
+}
+
+pub fn service_provider_config(base_url: &str) -> Value {
+  json!({
+    "schemas": [SERVICE_PROVIDER_CONFIG_SCHEMA],
+    "patch": {"supported": true},
+    "bulk": {"supported": false, "maxOperations": 0, "maxPayloadSize": 0},
+    "filter": {"supported": true, "maxResults": MAX_RESULTS},
+    "changePassword": {"supported": true},
+    "sort": {"supported": false},
+    "etag": {"supported": false},
+    "authenticationSchemes": [{
+      "type": "oauthbearertoken",
+      "name": "Bearer Token",
+      "description": "Authentication with the SCIM_TOKEN shared secret",
+      "primary": true,
+    }],
+    "meta": {
+      "resourceType": "ServiceProviderConfig",
+      "location": format!("{}/ServiceProviderConfig", base_url),
+    },
+  })
+}
+
+pub fn resource_types(base_url: &str) -> Vec<Value> {
+  vec![json!({
+    "schemas": [RESOURCE_TYPE_SCHEMA],
+    "id": "User",
+    "name": "User",
+    "endpoint": "/Users",
+    "description": "User Account",
+    "schema": USER_SCHEMA,
+    "meta": {
+      "resourceType": "ResourceType",
+      "location": format!("{}/ResourceTypes/User", base_url),
+    },
+  })]
+}
+
+pub fn schemas(base_url: &str) -> Vec<Value> {
+  let attribute = |name: &str, kind: &str, required: bool, uniqueness: &str| {
+    json!({
+      "name": name,
+      "type": kind,
+      "multiValued": false,
+      "required": required,
+      "caseExact": false,
+      "mutability": "readWrite",
+      "returned": "default",
+      "uniqueness": uniqueness,
+    })
+  };
+  let multi_valued_attribute = |name: &str| {
+    json!({
+      "name": name,
+      "type": "complex",
+      "multiValued": true,
+      "required": false,
+      "mutability": "readWrite",
+      "returned": "default",
+      "subAttributes": [
+        attribute("value", "string", true, "none"),
+        attribute("type", "string", false, "none"),
+        attribute("primary", "boolean", false, "none"),
+      ],
+    })
+  };
+
+  let mut password = attribute("password", "string", false, "none");
+  password["mutability"] = "writeOnly".into();
+  password["returned"] = "never".into();
+
+  vec![json!({
+    "schemas": [SCHEMA_SCHEMA],
+    "id": USER_SCHEMA,
+    "name": "User",
+    "description": "User Account",
+    "attributes": [
+      attribute("userName", "string", true, "server"),
+      {
+        "name": "name",
+        "type": "complex",
+        "multiValued": false,
+        "required": false,
+        "mutability": "readWrite",
+        "returned": "default",
+        "subAttributes": [
+          attribute("formatted", "string", false, "none"),
+          attribute("givenName", "string", false, "none"),
+          attribute("familyName", "string", false, "none"),
+        ],
+      },
+      attribute("displayName", "string", false, "none"),
+      multi_valued_attribute("emails"),
+      attribute("active", "boolean", false, "none"),
+      multi_valued_attribute("roles"),
+      password,
+    ],
+    "meta": {
+      "resourceType": "Schema",
+      "location": format!("{}/Schemas/{}", base_url, USER_SCHEMA),
+    },
+  })]
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+
+  use super::{parse_filter, UserAttributes};
+  use crate::{dtos::ScimPatchOperationDto, error::ScimError, models::UserRole};
+
+  fn attributes() -> UserAttributes {
+    UserAttributes {
+      email: "ada@example.com".to_string(),
+      name: "Ada Lovelace".to_string(),
+      external_id: Some("ada".to_string()),
+      active: true,
+      role: UserRole::User,
+      password: None,
+    }
+  }
+
+  fn op(op: &str, path: Option<&str>, value: Option<Value>) -> ScimPatchOperationDto {
+    ScimPatchOperationDto {
+      op: op.to_string(),
+      path: path.map(str::to_string),
+      value,
+    }
+  }
+
+  fn patch(operations: &[ScimPatchOperationDto]) -> Result<UserAttributes, ScimError> {
+    let mut attributes = attributes();
+    attributes.apply_patch(operations)?;
+    Ok(attributes)
+  }
+
+  fn scim_type<T: std::fmt::Debug>(result: Result<T, ScimError>) -> &'static str {
+    result.unwrap_err().scim_type.unwrap()
+  }
+
+  #[test]
+  fn parses_eq_comparisons() {
+    let filter = parse_filter(r#"userName eq "ada@example.com""#).unwrap();
+    assert_eq!(filter.email.as_deref(), Some("ada@example.com"));
+
+    let filter =
+      parse_filter(r#"urn:ietf:params:scim:schemas:core:2.0:User:externalId EQ "ada""#).unwrap();
+    assert_eq!(filter.external_id.as_deref(), Some("ada"));
+
+    // An id that is not a UUID matches nothing rather than failing.
+    let filter = parse_filter(r#"id eq "not-a-uuid""#).unwrap();
+    assert!(filter.id.unwrap().is_nil());
+  }
+
+  #[test]
+  fn joins_comparisons_with_and() {
+    let filter = parse_filter(r#"emails.value eq "ada@example.com" and active eq false"#).unwrap();
+    assert_eq!(filter.email.as_deref(), Some("ada@example.com"));
+    assert_eq!(filter.active, Some(false));
+  }
+
+  #[test]
+  fn unescapes_quoted_values() {
+    let filter = parse_filter(r#"externalId eq "a \"quoted\" and \\ id""#).unwrap();
+    assert_eq!(
+      filter.external_id.as_deref(),
+      Some(r#"a "quoted" and \ id"#)
+    );
+  }
+
+  #[test]
+  fn rejects_operators_other_than_eq() {
+    for filter in [
+      r#"userName co "example.com""#,
+      r#"userName sw "ada""#,
+      "externalId pr",
+      r#"userName eq "ada@example.com" or active eq true"#,
+    ] {
+      assert_eq!(
+        scim_type(parse_filter(filter)),
+        "invalidFilter",
+        "{}",
+        filter
+      );
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_filters() {
+    for filter in [
+      "",
+      r#"userName eq "ada@example.com"#,
+      r#"userName eq "ada@example.com" and"#,
+      r#"userName eq "a@example.com" and userName eq "b@example.com""#,
+      "active eq maybe",
+      r#"nickName eq "ada""#,
+    ] {
+      assert_eq!(
+        scim_type(parse_filter(filter)),
+        "invalidFilter",
+        "{}",
+        filter
+      );
+    }
+  }
+
+  #[test]
+  fn patches_attributes_by_path() {
+    let attributes = patch(&[
+      op(
+        "replace",
+        Some("userName"),
+        Some(json!("grace@example.com")),
+      ),
+      op("Add", Some("name.givenName"), Some(json!("Grace"))),
+      op("add", Some("name.familyName"), Some(json!("Hopper"))),
+      op(
+        "replace",
+        Some("roles[primary eq true].value"),
+        Some(json!("moderator")),
+      ),
+    ])
+    .unwrap();
+
+    assert_eq!(attributes.email, "grace@example.com");
+    assert_eq!(attributes.name, "Grace Hopper");
+    assert_eq!(attributes.role, UserRole::Moderator);
+  }
+
+  #[test]
+  fn patches_attributes_from_value_map() {
+    let attributes = patch(&[op(
+      "replace",
+      None,
+      Some(json!({"displayName": "Countess", "externalId": "countess"})),
+    )])
+    .unwrap();
+
+    assert_eq!(attributes.name, "Countess");
+    assert_eq!(attributes.external_id.as_deref(), Some("countess"));
+  }
+
+  #[test]
+  fn deactivates_with_boolean_or_string() {
+    assert!(
+      !patch(&[op("replace", Some("active"), Some(json!(false)))])
+        .unwrap()
+        .active
+    );
+    assert!(
+      !patch(&[op("replace", None, Some(json!({"active": "False"})))])
+        .unwrap()
+        .active
+    );
+  }
+
+  #[test]
+  fn removes_only_optional_attributes() {
+    let attributes = patch(&[
+      op(
+        "replace",
+        Some("roles"),
+        Some(json!([{"value": "admin", "primary": true}])),
+      ),
+      op("remove", Some("roles"), None),
+      op("remove", Some("externalId"), None),
+    ])
+    .unwrap();
+    assert_eq!(attributes.role, UserRole::User);
+    assert_eq!(attributes.external_id, None);
+
+    assert_eq!(
+      scim_type(patch(&[op("remove", Some("userName"), None)])),
+      "mutability"
+    );
+    assert_eq!(scim_type(patch(&[op("remove", None, None)])), "noTarget");
+  }
+
+  #[test]
+  fn rejects_invalid_patches() {
+    assert_eq!(
+      scim_type(patch(&[op("move", Some("active"), None)])),
+      "invalidSyntax"
+    );
+    assert_eq!(
+      scim_type(patch(&[op(
+        "replace",
+        Some("nickName"),
+        Some(json!("ada"))
+      )])),
+      "invalidPath"
+    );
+    assert_eq!(
+      scim_type(patch(&[op("replace", Some("active"), Some(json!("yes")))])),
+      "invalidValue"
+    );
+    assert_eq!(
+      scim_type(patch(&[op(
+        "replace",
+        Some("userName"),
+        Some(json!("not-an-email"))
+      )])),
+      "invalidValue"
+    );
+  }
+}
@@ -0,0 +1,254 @@
+use std::{
+  collections::HashMap,
+  sync::{Mutex, OnceLock},
+  time::{Duration, Instant},
+};
+
+use awc::Client;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+
+use crate::{
+  config::{SocialProvider, SocialProviderKind},
+  error::{ErrorMessage, HttpError},
+};
+
+/// How long a user has to finish signing in with the provider.
+pub const LOGIN_REQUEST_MAXAGE_MINUTES: i64 = 10;
+/// How long a provider's discovery document is reused before it is fetched again.
+const DISCOVERY_MAXAGE: Duration = Duration::from_secs(60 * 60);
+
+pub struct Endpoints {
+  pub authorize_url: String,
+  pub token_url: String,
+  pub userinfo_url: String,
+}
+
+/// The account a user signed in with at the provider.
+#[derive(Debug)]
+pub struct ExternalProfile {
+  pub subject: String,
+  pub email: Option<String>,
+  pub email_verified: bool,
+  pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+  access_token: Option<String>,
+  error: Option<String>,
+  error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+  id: i64,
+  login: String,
+  name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubEmail {
+  email: String,
+  primary: bool,
+  verified: bool,
+}
+
+/// Configured endpoints win; missing ones come from OpenID Connect discovery.
+pub async fn endpoints(provider: &SocialProvider) -> Result<Endpoints, HttpError> {
+  if let (Some(authorize_url), Some(token_url), Some(userinfo_url)) = (
+    &provider.authorize_url,
+    &provider.token_url,
+    &provider.userinfo_url,
+  ) {
+    return Ok(Endpoints {
+      authorize_url: authorize_url.clone(),
+      token_url: token_url.clone(),
+      userinfo_url: userinfo_url.clone(),
+    });
+  }
+
+  let issuer = provider
+    .issuer
+    .as_deref()
+    .ok_or_else(|| HttpError::server_error(ErrorMessage::ProviderNotConfigured))?;
+  let discovery = discovery(issuer).await?;
+
+  let endpoint = |configured: &Option<String>, key: &str| {
+    configured
+      .clone()
+      .or_else(|| discovery[key].as_str().map(str::to_string))
+      .ok_or_else(|| provider_error(format!("Provider metadata has no {}", key)))
+  };
+
+  Ok(Endpoints {
+    authorize_url: endpoint(&provider.authorize_url, "authorization_endpoint")?,
+    token_url: endpoint(&provider.token_url, "token_endpoint")?,
+    userinfo_url: endpoint(&provider.userinfo_url, "userinfo_endpoint")?,
+  })
+}
+
+/// The issuer's discovery document, fetched at most once per `DISCOVERY_MAXAGE`.
+async fn discovery(issuer: &str) -> Result<Value, HttpError> {
+  static CACHE: OnceLock<Mutex<HashMap<String, (Instant, Value)>>> = OnceLock::new();
+  let cache = CACHE.get_or_init(Default::default);
+
+  if let Some((fetched_at, discovery)) = cache.lock().unwrap().get(issuer) {
+    if fetched_at.elapsed() < DISCOVERY_MAXAGE {
+      return Ok(discovery.clone());
+    }
+  }
+
+  let discovery: Value = get_json(
+    &format!("{}/.well-known/openid-configuration", issuer),
+    None,
+  )
+  .await?;
+  cache
+    .lock()
+    .unwrap()
+    .insert(issuer.to_string(), (Instant::now(), discovery.clone()));
+
+  Ok(discovery)
+}
+
+pub fn authorization_url(
+  provider: &SocialProvider,
+  endpoints: &Endpoints,
+  redirect_uri: &str,
+  state: &str,
+  code_challenge: &str,
+) -> Result<String, HttpError> {
+  let mut url = url::Url::parse(&endpoints.authorize_url)
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+  url
+    .query_pairs_mut()
+    .append_pair("response_type", "code")
+    .append_pair("client_id", &provider.client_id)
+    .append_pair("redirect_uri", redirect_uri)
+    .append_pair("scope", &provider.scopes)
+    .append_pair("state", state)
+    .append_pair("code_challenge", code_challenge)
+    .append_pair("code_challenge_method", "S256");
+
+  Ok(url.to_string())
+}
+
+/// Redeems the authorization code for an access token at the provider.
+pub async fn exchange_code(
+  provider: &SocialProvider,
+  endpoints: &Endpoints,
+  redirect_uri: &str,
+  code: &str,
+  code_verifier: &str,
+) -> Result<String, HttpError> {
+  let mut response = Client::default()
+    .post(&endpoints.token_url)
+    .insert_header(("Accept", "application/json"))
+    .send_form(&[
+      ("grant_type", "authorization_code"),
+      ("code", code),
+      ("redirect_uri", redirect_uri),
+      ("client_id", &provider.client_id),
+      ("client_secret", &provider.client_secret),
+      ("code_verifier", code_verifier),
+    ])
+    .await
+    .map_err(|e| provider_error(format!("Provider token request failed: {}", e)))?;
+
+  let token: TokenResponse = response
+    .json()
+    .await
+    .map_err(|e| provider_error(format!("Invalid provider token response: {}", e)))?;
+
+  match (token.access_token, token.error) {
+    (Some(access_token), None) => Ok(access_token),
+    (_, error) => Err(provider_error(format!(
+      "Provider rejected the authorization code: {}",
+      token
+        .error_description
+        .or(error)
+        .unwrap_or("no access token".to_string())
+    ))),
+  }
+}
+
+pub async fn fetch_profile(
+  provider: &SocialProvider,
+  endpoints: &Endpoints,
+  access_token: &str,
+) -> Result<ExternalProfile, HttpError> {
+  match provider.kind {
+    SocialProviderKind::Github => {
+      let user: GithubUser = get_json(&endpoints.userinfo_url, Some(access_token)).await?;
+      let emails: Vec<GithubEmail> = get_json(
+        &format!("{}/emails", endpoints.userinfo_url),
+        Some(access_token),
+      )
+      .await?;
+      let email = emails
+        .iter()
+        .filter(|email| email.verified)
+        .max_by_key(|email| email.primary);
+
+      Ok(ExternalProfile {
+        subject: user.id.to_string(),
+        email: email.map(|email| email.email.clone()),
+        email_verified: email.is_some(),
+        name: user.name.or(Some(user.login)),
+      })
+    }
+    SocialProviderKind::Oidc => {
+      let userinfo: Value = get_json(&endpoints.userinfo_url, Some(access_token)).await?;
+      let subject = userinfo["sub"]
+        .as_str()
+        .ok_or_else(|| provider_error("Provider userinfo has no subject"))?;
+      // Some providers send email_verified as a string.
+      let email_verified = match &userinfo["email_verified"] {
+        Value::Bool(verified) => *verified,
+        Value::String(verified) => verified == "true",
+        _ => false,
+      };
+
+      Ok(ExternalProfile {
+        subject: subject.to_string(),
+        email: userinfo["email"].as_str().map(str::to_string),
+        email_verified,
+        name: userinfo["name"].as_str().map(str::to_string),
+      })
+    }
+  }
+}
+
+async fn get_json<T: DeserializeOwned>(
+  url: &str,
+  access_token: Option<&str>,
+) -> Result<T, HttpError> {
+  let mut request = Client::default()
+    .get(url)
+    .insert_header(("Accept", "application/json"));
+  if let Some(access_token) = access_token {
+    request = request.bearer_auth(access_token);
+  }
+
+  let mut response = request
+    .send()
+    .await
+    .map_err(|e| provider_error(format!("Provider request failed: {}", e)))?;
+  if !response.status().is_success() {
+    return Err(provider_error(format!(
+      "Provider responded with {}",
+      response.status()
+    )));
+  }
+
+  response
+    .json()
+    .await
+    .map_err(|e| provider_error(format!("Invalid provider response: {}", e)))
+}
+
+/// The provider misbehaved; `detail` says how, for the user and the logs.
+fn provider_error(detail: impl Into<String>) -> HttpError {
+  HttpError::bad_gateway(ErrorMessage::ProviderError(detail.into()))
+}
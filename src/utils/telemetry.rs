@@ -0,0 +1,90 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::config::{LogFormat, TelemetryConfig};
+
+/// Query parameters whose values are never written to logs or spans.
+const SENSITIVE_PARAMS: &[&str] = &[
+  "access_token",
+  "client_secret",
+  "code",
+  "code_verifier",
+  "id_token_hint",
+  "password",
+  "refresh_token",
+  "samlresponse",
+  "state",
+  "subject_token",
+  "token",
+];
+
+/// Keeps the trace exporter alive. Call `shutdown` before exiting so buffered
+/// spans reach the collector.
+pub struct Telemetry {
+  tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+  pub fn shutdown(&self) {
+    if let Some(provider) = &self.tracer_provider {
+      if let Err(e) = provider.shutdown() {
+        eprintln!("Error flushing spans: {}", e);
+      }
+    }
+  }
+}
+
+/// Installs the global subscriber. Records from the `log` crate, such as
+/// those from sqlx, are forwarded to it as well.
+pub fn init(config: &TelemetryConfig) -> Telemetry {
+  let tracer_provider = config.otlp_endpoint.as_ref().map(|endpoint| {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+      .with_http()
+      .with_endpoint(format!("{}/v1/traces", endpoint))
+      .build()
+      .expect("Failed to build the OTLP span exporter");
+
+    SdkTracerProvider::builder()
+      .with_batch_exporter(exporter)
+      .with_resource(
+        Resource::builder()
+          .with_service_name(config.service_name.clone())
+          .build(),
+      )
+      .build()
+  });
+  let otel_layer = tracer_provider.as_ref().map(|provider| {
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+  });
+
+  let json = config.log_format == LogFormat::Json;
+  tracing_subscriber::registry()
+    .with(EnvFilter::new(&config.log_filter))
+    .with(json.then(|| {
+      tracing_subscriber::fmt::layer()
+        .json()
+        .with_current_span(true)
+        .with_span_list(false)
+    }))
+    .with((!json).then(tracing_subscriber::fmt::layer))
+    .with(otel_layer)
+    .init();
+
+  Telemetry { tracer_provider }
+}
+
+/// Replaces the values of sensitive parameters in a query string.
+pub fn redact_query(query: &str) -> String {
+  query
+    .split('&')
+    .map(|pair| match pair.split_once('=') {
+      Some((key, _)) if SENSITIVE_PARAMS.contains(&key.to_lowercase().as_str()) => {
+        format!("{}=[REDACTED]", key)
+      }
+      _ => pair.to_string(),
+    })
+    .collect::<Vec<_>>()
+    .join("&")
+}
@@ -1,22 +1,171 @@
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
 
 use crate::error::{ErrorMessage, HttpError};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Authentication method references (RFC 8176) recorded in the `amr` claim.
+pub const AMR_PASSWORD: &str = "pwd";
+/// Sign-in through an external identity provider (social login or SAML).
+pub const AMR_FEDERATED: &str = "fed";
+
+/// The party acting on behalf of the token subject (RFC 8693 `act` claim).
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ActorClaim {
+  pub sub: String,
+  /// Impersonation session. Delegated tokens from the token exchange grant have none.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sid: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TokenClaims {
   pub sub: String,
   pub iat: usize,
   pub exp: usize,
+  /// Unique token ID, so a token can be revoked before it expires.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub jti: Option<String>,
+  /// When the user last entered credentials, which may be long before `iat`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub auth_time: Option<usize>,
+  /// How the user authenticated, see [`AMR_PASSWORD`] and [`AMR_FEDERATED`].
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub amr: Option<Vec<String>>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub act: Option<ActorClaim>,
+  /// Downstream service an exchanged token is restricted to. Tokens for this
+  /// API have no audience.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub aud: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub client_id: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub scope: Option<String>,
+}
+
+impl TokenClaims {
+  /// Tokens from the client credentials grant name the client as their subject.
+  pub fn is_service_account(&self) -> bool {
+    self.client_id.as_deref() == Some(self.sub.as_str())
+  }
+
+  /// Tokens issued before `jti` was introduced cannot be revoked individually.
+  pub fn jti(&self) -> Option<Uuid> {
+    self
+      .jti
+      .as_deref()
+      .and_then(|jti| Uuid::parse_str(jti).ok())
+  }
+
+  /// Tokens issued before `auth_time` was introduced have none.
+  pub fn auth_time(&self) -> Option<DateTime<Utc>> {
+    self
+      .auth_time
+      .and_then(|auth_time| Utc.timestamp_opt(auth_time as i64, 0).single())
+  }
+
+  pub fn expires_at(&self) -> DateTime<Utc> {
+    Utc
+      .timestamp_opt(self.exp as i64, 0)
+      .single()
+      .unwrap_or_else(Utc::now)
+  }
 }
 
+/// Creates a session token right after the user authenticated with `method`.
 pub fn create_token(
   user_id: &str,
+  method: &str,
+  secret: &[u8],
+  expires_in_seconds: i64,
+) -> Result<String, jsonwebtoken::errors::Error> {
+  let mut claims = new_claims(user_id, expires_in_seconds)?;
+  claims.auth_time = Some(claims.iat);
+  claims.amr = Some(vec![method.to_string()]);
+
+  encode_claims(&claims, secret)
+}
+
+/// Creates a token for `user_id` that records `impersonator_id` and the
+/// impersonation session it belongs to in the `act` claim.
+pub fn create_impersonation_token(
+  user_id: &str,
+  impersonator_id: &str,
+  session_id: &str,
+  secret: &[u8],
+  expires_in_seconds: i64,
+) -> Result<String, jsonwebtoken::errors::Error> {
+  let mut claims = new_claims(user_id, expires_in_seconds)?;
+  claims.act = Some(ActorClaim {
+    sub: impersonator_id.to_string(),
+    sid: Some(session_id.to_string()),
+  });
+
+  encode_claims(&claims, secret)
+}
+
+/// Creates an access token issued to an OAuth client on behalf of `user_id`.
+pub fn create_oauth_token(
+  user_id: &str,
+  client_id: &str,
+  scope: &str,
+  auth_time: DateTime<Utc>,
+  secret: &[u8],
+  expires_in_seconds: i64,
+) -> Result<String, jsonwebtoken::errors::Error> {
+  let mut claims = new_claims(user_id, expires_in_seconds)?;
+  claims.auth_time = Some(auth_time.timestamp() as usize);
+  claims.client_id = Some(client_id.to_string());
+  claims.scope = Some(scope.to_string());
+
+  encode_claims(&claims, secret)
+}
+
+/// Creates an access token for a service account, whose subject is the client itself.
+pub fn create_service_token(
+  client_id: &str,
+  secret: &[u8],
+  expires_in_seconds: i64,
+) -> Result<String, jsonwebtoken::errors::Error> {
+  let mut claims = new_claims(client_id, expires_in_seconds)?;
+  claims.client_id = Some(client_id.to_string());
+
+  encode_claims(&claims, secret)
+}
+
+/// Creates a token for `subject.sub` that `client_id` presents to `audience`
+/// on the user's behalf (RFC 8693). It never outlives the subject token.
+pub fn create_exchanged_token(
+  subject: &TokenClaims,
+  client_id: &str,
+  audience: &str,
+  scope: &str,
   secret: &[u8],
   expires_in_seconds: i64,
 ) -> Result<String, jsonwebtoken::errors::Error> {
+  let mut claims = new_claims(&subject.sub, expires_in_seconds)?;
+  claims.exp = claims.exp.min(subject.exp);
+  claims.auth_time = subject.auth_time;
+  claims.amr = subject.amr.clone();
+  claims.act = Some(ActorClaim {
+    sub: client_id.to_string(),
+    sid: None,
+  });
+  claims.aud = Some(audience.to_string());
+  claims.client_id = Some(client_id.to_string());
+  claims.scope = Some(scope.to_string());
+
+  encode_claims(&claims, secret)
+}
+
+fn new_claims(
+  user_id: &str,
+  expires_in_seconds: i64,
+) -> Result<TokenClaims, jsonwebtoken::errors::Error> {
   if user_id.is_empty() {
     return Err(jsonwebtoken::errors::ErrorKind::InvalidSubject.into());
   }
@@ -24,20 +173,33 @@ pub fn create_token(
   let now = Utc::now();
   let iat = now.timestamp() as usize;
   let exp = (now + Duration::minutes(expires_in_seconds)).timestamp() as usize;
-  let claims = TokenClaims {
+
+  Ok(TokenClaims {
     sub: user_id.to_string(),
     iat,
     exp,
-  };
+    jti: Some(Uuid::new_v4().to_string()),
+    auth_time: None,
+    amr: None,
+    act: None,
+    aud: None,
+    client_id: None,
+    scope: None,
+  })
+}
 
+fn encode_claims(
+  claims: &TokenClaims,
+  secret: &[u8],
+) -> Result<String, jsonwebtoken::errors::Error> {
   encode(
     &Header::default(),
-    &claims,
+    claims,
     &EncodingKey::from_secret(secret),
   )
 }
 
-pub fn decode_token<T: Into<String>>(token: T, secret: &[u8]) -> Result<String, HttpError> {
+pub fn decode_token<T: Into<String>>(token: T, secret: &[u8]) -> Result<TokenClaims, HttpError> {
   let decoded = decode::<TokenClaims>(
     &token.into(),
     &DecodingKey::from_secret(secret),
@@ -45,10 +207,10 @@ pub fn decode_token<T: Into<String>>(token: T, secret: &[u8]) -> Result<String,
   );
 
   match decoded {
-    Ok(token) => Ok(token.claims.sub),
+    Ok(token) => Ok(token.claims),
     Err(e) => {
-      dbg!(e);
-      Err(HttpError::new(ErrorMessage::InvalidToken.to_string(), 402))
+      tracing::debug!(error = %e, "Rejected access token");
+      Err(HttpError::unauthorized(ErrorMessage::InvalidToken))
     }
   }
 }
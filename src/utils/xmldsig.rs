@@ -0,0 +1,473 @@
+//! Verification of enveloped XML signatures (XML-DSig), as used by SAML.
+//! Only RSA-SHA256 over exclusive canonicalization is accepted, which is what
+//! current identity providers produce by default.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use roxmltree::{Node, NodeId, NodeType};
+use rsa::{
+  pkcs1v15::{Signature, VerifyingKey},
+  pkcs8::DecodePublicKey,
+  signature::Verifier,
+  RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+use x509_cert::{
+  der::{Decode, Encode},
+  Certificate,
+};
+
+pub const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+
+/// Reads the RSA public key of a base64 DER certificate, as found in
+/// `ds:X509Certificate` elements.
+pub fn public_key_from_certificate(certificate: &str) -> Result<RsaPublicKey, String> {
+  let der = decode_base64(certificate)?;
+  let certificate = Certificate::from_der(&der).map_err(|e| e.to_string())?;
+  let spki = certificate
+    .tbs_certificate
+    .subject_public_key_info
+    .to_der()
+    .map_err(|e| e.to_string())?;
+
+  RsaPublicKey::from_public_key_der(&spki).map_err(|e| e.to_string())
+}
+
+/// Whether `element` carries its own `ds:Signature` child.
+pub fn is_signed(element: Node) -> bool {
+  element
+    .children()
+    .any(|child| child.has_tag_name((DSIG_NS, "Signature")))
+}
+
+/// Verifies the enveloped signature that is a direct child of `element` and
+/// must reference it by its `ID` attribute. Keys embedded in the signature are
+/// ignored; only `keys` are trusted.
+pub fn verify_enveloped(element: Node, keys: &[RsaPublicKey]) -> Result<(), String> {
+  let signature = single_child(element, DSIG_NS, "Signature")?;
+  let signed_info = single_child(signature, DSIG_NS, "SignedInfo")?;
+
+  let c14n_method = single_child(signed_info, DSIG_NS, "CanonicalizationMethod")?;
+  if c14n_method.attribute("Algorithm") != Some(EXC_C14N) {
+    return Err("Unsupported canonicalization method".to_string());
+  }
+  let signature_method = single_child(signed_info, DSIG_NS, "SignatureMethod")?;
+  if signature_method.attribute("Algorithm") != Some(RSA_SHA256) {
+    return Err("Unsupported signature method".to_string());
+  }
+
+  let reference = single_child(signed_info, DSIG_NS, "Reference")?;
+  let id = element.attribute("ID").ok_or("Signed element has no ID")?;
+  if reference.attribute("URI") != Some(format!("#{}", id).as_str()) {
+    return Err("Signature does not reference the signed element".to_string());
+  }
+  // With duplicate IDs a signature could be moved onto a different element
+  // than the one the application reads.
+  let duplicates = element
+    .document()
+    .descendants()
+    .filter(|node| node.attribute("ID") == Some(id))
+    .count();
+  if duplicates != 1 {
+    return Err("Duplicate element IDs".to_string());
+  }
+
+  let mut reference_prefixes = None;
+  let transforms = single_child(reference, DSIG_NS, "Transforms")?;
+  for transform in transforms
+    .children()
+    .filter(|child| child.has_tag_name((DSIG_NS, "Transform")))
+  {
+    match transform.attribute("Algorithm") {
+      Some(ENVELOPED_SIGNATURE) => {}
+      Some(EXC_C14N) => reference_prefixes = Some(inclusive_prefixes(transform)),
+      _ => return Err("Unsupported reference transform".to_string()),
+    }
+  }
+  let reference_prefixes =
+    reference_prefixes.ok_or("Reference must use exclusive canonicalization")?;
+
+  let digest_method = single_child(reference, DSIG_NS, "DigestMethod")?;
+  if digest_method.attribute("Algorithm") != Some(SHA256) {
+    return Err("Unsupported digest method".to_string());
+  }
+  let digest_value = decode_base64(
+    single_child(reference, DSIG_NS, "DigestValue")?
+      .text()
+      .unwrap_or_default(),
+  )?;
+
+  let canonical = canonicalize(element, Some(signature.id()), &reference_prefixes)?;
+  if Sha256::digest(canonical.as_bytes()).as_slice() != digest_value.as_slice() {
+    return Err("Digest of the signed element does not match".to_string());
+  }
+
+  let signature_value = decode_base64(
+    single_child(signature, DSIG_NS, "SignatureValue")?
+      .text()
+      .unwrap_or_default(),
+  )?;
+  let signature_value =
+    Signature::try_from(signature_value.as_slice()).map_err(|e| e.to_string())?;
+  let canonical_signed_info = canonicalize(signed_info, None, &inclusive_prefixes(c14n_method))?;
+
+  let verified = keys.iter().any(|key| {
+    VerifyingKey::<Sha256>::new(key.clone())
+      .verify(canonical_signed_info.as_bytes(), &signature_value)
+      .is_ok()
+  });
+
+  if verified {
+    Ok(())
+  } else {
+    Err("Signature was not made by a trusted key".to_string())
+  }
+}
+
+fn single_child<'a, 'input>(
+  node: Node<'a, 'input>,
+  namespace: &str,
+  name: &str,
+) -> Result<Node<'a, 'input>, String> {
+  let mut children = node
+    .children()
+    .filter(|child| child.has_tag_name((namespace, name)));
+
+  match (children.next(), children.next()) {
+    (Some(child), None) => Ok(child),
+    (None, _) => Err(format!("Missing {} element", name)),
+    (Some(_), Some(_)) => Err(format!("Duplicate {} element", name)),
+  }
+}
+
+fn inclusive_prefixes(method: Node) -> Vec<String> {
+  method
+    .children()
+    .find(|child| child.has_tag_name((EXC_C14N, "InclusiveNamespaces")))
+    .and_then(|node| node.attribute("PrefixList"))
+    .map(|list| {
+      list
+        .split_whitespace()
+        .map(|prefix| match prefix {
+          "#default" => String::new(),
+          prefix => prefix.to_string(),
+        })
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>, String> {
+  let value: String = value.split_whitespace().collect();
+  STANDARD.decode(value).map_err(|e| e.to_string())
+}
+
+/// Exclusive XML canonicalization without comments (xml-exc-c14n) of the
+/// subtree at `node`, leaving out the `exclude` subtree.
+pub fn canonicalize(
+  node: Node,
+  exclude: Option<NodeId>,
+  inclusive_prefixes: &[String],
+) -> Result<String, String> {
+  let mut output = String::new();
+  write_element(
+    node,
+    exclude,
+    inclusive_prefixes,
+    &BTreeMap::new(),
+    &mut output,
+  )?;
+  Ok(output)
+}
+
+fn write_element(
+  node: Node,
+  exclude: Option<NodeId>,
+  inclusive_prefixes: &[String],
+  rendered: &BTreeMap<String, String>,
+  output: &mut String,
+) -> Result<(), String> {
+  let (element_prefix, attribute_prefixes) = source_prefixes(node)?;
+
+  let in_scope: BTreeMap<&str, &str> = node
+    .namespaces()
+    .map(|namespace| (namespace.name().unwrap_or_default(), namespace.uri()))
+    .collect();
+
+  // Namespaces are rendered where they are visibly utilized, plus those named
+  // in the InclusiveNamespaces prefix list.
+  let mut utilized: BTreeSet<&str> = BTreeSet::new();
+  utilized.insert(element_prefix);
+  utilized.extend(
+    attribute_prefixes
+      .iter()
+      .filter(|prefix| !prefix.is_empty()),
+  );
+  utilized.extend(
+    inclusive_prefixes
+      .iter()
+      .map(String::as_str)
+      .filter(|prefix| prefix.is_empty() || in_scope.contains_key(prefix)),
+  );
+
+  let mut rendered_here = rendered.clone();
+  let mut declarations = String::new();
+  for prefix in utilized {
+    if prefix == "xml" {
+      continue;
+    }
+    let uri = in_scope.get(prefix).copied().unwrap_or_default();
+    let already_rendered = match rendered.get(prefix) {
+      Some(rendered_uri) => rendered_uri == uri,
+      None => prefix.is_empty() && uri.is_empty(),
+    };
+    if already_rendered {
+      continue;
+    }
+
+    if prefix.is_empty() {
+      declarations.push_str(&format!(" xmlns=\"{}\"", escape_attribute(uri)));
+    } else {
+      declarations.push_str(&format!(" xmlns:{}=\"{}\"", prefix, escape_attribute(uri)));
+    }
+    rendered_here.insert(prefix.to_string(), uri.to_string());
+  }
+
+  let mut attributes: Vec<(&str, &str, String, &str)> = node
+    .attributes()
+    .zip(attribute_prefixes.iter())
+    .map(|(attribute, prefix)| {
+      let qualified_name = if prefix.is_empty() {
+        attribute.name().to_string()
+      } else {
+        format!("{}:{}", prefix, attribute.name())
+      };
+      (
+        attribute.namespace().unwrap_or_default(),
+        attribute.name(),
+        qualified_name,
+        attribute.value(),
+      )
+    })
+    .collect();
+  attributes.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+  let qualified_name = if element_prefix.is_empty() {
+    node.tag_name().name().to_string()
+  } else {
+    format!("{}:{}", element_prefix, node.tag_name().name())
+  };
+
+  output.push('<');
+  output.push_str(&qualified_name);
+  output.push_str(&declarations);
+  for (_, _, name, value) in attributes {
+    output.push_str(&format!(" {}=\"{}\"", name, escape_attribute(value)));
+  }
+  output.push('>');
+
+  for child in node.children() {
+    if Some(child.id()) == exclude {
+      continue;
+    }
+    match child.node_type() {
+      NodeType::Element => {
+        write_element(child, exclude, inclusive_prefixes, &rendered_here, output)?
+      }
+      NodeType::Text => output.push_str(&escape_text(child.text().unwrap_or_default())),
+      NodeType::PI => {
+        if let Some(pi) = child.pi() {
+          output.push_str("<?");
+          output.push_str(pi.target);
+          if let Some(value) = pi.value {
+            output.push(' ');
+            output.push_str(value);
+          }
+          output.push_str("?>");
+        }
+      }
+      NodeType::Comment | NodeType::Root => {}
+    }
+  }
+
+  output.push_str("</");
+  output.push_str(&qualified_name);
+  output.push('>');
+
+  Ok(())
+}
+
+/// The parsed tree keeps namespace URIs but not prefixes, which canonical
+/// output needs, so they are read back from the element's start tag. Returns
+/// the element prefix and one prefix per attribute in document order.
+fn source_prefixes<'input>(
+  node: Node<'_, 'input>,
+) -> Result<(&'input str, Vec<&'input str>), String> {
+  let source = &node.document().input_text()[node.range().start..];
+  let invalid = || "Unexpected start tag".to_string();
+  let prefix_of = |name: &'input str| name.split_once(':').map_or("", |(prefix, _)| prefix);
+
+  let mut rest = source.strip_prefix('<').ok_or_else(invalid)?;
+  let end = rest
+    .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+    .ok_or_else(invalid)?;
+  let element_prefix = prefix_of(&rest[..end]);
+  rest = &rest[end..];
+
+  let mut attribute_prefixes = Vec::new();
+  loop {
+    rest = rest.trim_start();
+    if rest.starts_with('>') || rest.starts_with('/') {
+      break;
+    }
+
+    let end = rest
+      .find(|c: char| c.is_whitespace() || c == '=')
+      .ok_or_else(invalid)?;
+    let name = &rest[..end];
+    rest = rest[end..]
+      .trim_start()
+      .strip_prefix('=')
+      .ok_or_else(invalid)?;
+    rest = rest.trim_start();
+
+    let quote = rest.chars().next().ok_or_else(invalid)?;
+    if quote != '"' && quote != '\'' {
+      return Err(invalid());
+    }
+    let end = rest[1..].find(quote).ok_or_else(invalid)?;
+    rest = &rest[end + 2..];
+
+    if name != "xmlns" && !name.starts_with("xmlns:") {
+      attribute_prefixes.push(prefix_of(name));
+    }
+  }
+
+  if attribute_prefixes.len() != node.attributes().len() {
+    return Err(invalid());
+  }
+
+  Ok((element_prefix, attribute_prefixes))
+}
+
+fn escape_text(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '\r' => escaped.push_str("&#xD;"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+fn escape_attribute(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\t' => escaped.push_str("&#x9;"),
+      '\n' => escaped.push_str("&#xA;"),
+      '\r' => escaped.push_str("&#xD;"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod tests {
+  use roxmltree::{Document, Node};
+  use rsa::RsaPublicKey;
+
+  use super::{public_key_from_certificate, verify_enveloped, DSIG_NS};
+
+  const METADATA: &str = include_str!("../../tests/fixtures/saml/idp-metadata.xml");
+  const SIGNED_ASSERTION: &str = include_str!("../../tests/fixtures/saml/signed-assertion.xml");
+  const SIGNED_RESPONSE: &str = include_str!("../../tests/fixtures/saml/signed-response.xml");
+  const UNTRUSTED_SIGNATURE: &str =
+    include_str!("../../tests/fixtures/saml/untrusted-signature.xml");
+
+  fn idp_key() -> RsaPublicKey {
+    let metadata = Document::parse(METADATA).unwrap();
+    let certificate = metadata
+      .descendants()
+      .find(|node| node.has_tag_name((DSIG_NS, "X509Certificate")))
+      .and_then(|node| node.text())
+      .unwrap();
+    public_key_from_certificate(certificate).unwrap()
+  }
+
+  fn assertion<'a, 'input>(doc: &'a Document<'input>) -> Node<'a, 'input> {
+    doc
+      .descendants()
+      .find(|node| node.tag_name().name() == "Assertion")
+      .unwrap()
+  }
+
+  fn verify_assertion(xml: &str) -> Result<(), String> {
+    let doc = Document::parse(xml).unwrap();
+    verify_enveloped(assertion(&doc), &[idp_key()])
+  }
+
+  #[test]
+  fn verifies_signed_assertion() {
+    assert_eq!(verify_assertion(SIGNED_ASSERTION), Ok(()));
+  }
+
+  #[test]
+  fn verifies_signed_response() {
+    let doc = Document::parse(SIGNED_RESPONSE).unwrap();
+    assert_eq!(verify_enveloped(doc.root_element(), &[idp_key()]), Ok(()));
+  }
+
+  #[test]
+  fn rejects_tampered_attribute() {
+    let tampered = SIGNED_ASSERTION.replace("SAML Fixture", "SAML Admin");
+    assert_eq!(
+      verify_assertion(&tampered),
+      Err("Digest of the signed element does not match".to_string())
+    );
+  }
+
+  #[test]
+  fn rejects_untrusted_key() {
+    assert_eq!(
+      verify_assertion(UNTRUSTED_SIGNATURE),
+      Err("Signature was not made by a trusted key".to_string())
+    );
+  }
+
+  #[test]
+  fn rejects_duplicate_ids() {
+    // A forged copy of the assertion, reusing its ID and signature, is placed
+    // before the genuine one, which still verifies on its own.
+    let start = SIGNED_ASSERTION.find("<saml:Assertion").unwrap();
+    let end = SIGNED_ASSERTION.find("</saml:Assertion>").unwrap() + "</saml:Assertion>".len();
+    let genuine = &SIGNED_ASSERTION[start..end];
+    let forged = genuine.replace("saml.fixture@example.com", "admin@example.com");
+    let wrapped = SIGNED_ASSERTION.replace(genuine, &format!("{}{}", forged, genuine));
+
+    assert_eq!(
+      verify_assertion(&wrapped),
+      Err("Duplicate element IDs".to_string())
+    );
+  }
+
+  #[test]
+  fn rejects_signature_of_another_element() {
+    let doc = Document::parse(SIGNED_ASSERTION).unwrap();
+    let response = doc.root_element();
+    assert!(verify_enveloped(response, &[idp_key()]).is_err());
+  }
+}